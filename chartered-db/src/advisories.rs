@@ -0,0 +1,359 @@
+//! Advisories raised internally against versions of our own crates, either flagging them as
+//! vulnerable or as deprecated, with a message explaining why and what to move to instead.
+
+use crate::{
+    audit::{AuditAction, AuditActor, NewAuditEvent},
+    crates::{Crate, CrateWithPermissions},
+    permissions::UserPermission,
+    schema::{crate_advisories, users},
+    users::User,
+    uuid::SqlUuid,
+    ConnectionPool, Error, Result,
+};
+use diesel::{insert_into, prelude::*, Associations, Identifiable, Queryable};
+use serde::{Deserialize, Serialize};
+use std::{io::Write, sync::Arc};
+
+#[derive(Identifiable, Queryable, Associations, PartialEq, Eq, Debug)]
+#[belongs_to(Crate)]
+#[table_name = "crate_advisories"]
+pub struct CrateAdvisory {
+    pub id: i32,
+    pub uuid: SqlUuid,
+    pub crate_id: i32,
+    pub kind: CrateAdvisoryKind,
+    pub affected_versions: String,
+    pub message: String,
+    pub user_id: i32,
+    pub created_at: chrono::NaiveDateTime,
+    pub withdrawn_at: Option<chrono::NaiveDateTime>,
+    pub withdrawn_by_user_id: Option<i32>,
+}
+
+impl CrateAdvisory {
+    /// Checks if the given version matches the `affected_versions` requirement of this advisory.
+    #[must_use]
+    pub fn affects(&self, version: &str) -> bool {
+        match (
+            semver::VersionReq::parse(&self.affected_versions),
+            semver::Version::parse(version),
+        ) {
+            (Ok(req), Ok(version)) => req.matches(&version),
+            _ => false,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, FromSqlRow, AsExpression, Debug, Clone, Copy, PartialEq, Eq)]
+#[sql_type = "diesel::sql_types::Text"]
+#[serde(rename_all = "snake_case")]
+pub enum CrateAdvisoryKind {
+    Vulnerability,
+    Deprecation,
+}
+
+impl CrateAdvisoryKind {
+    #[must_use]
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Vulnerability => "vulnerability",
+            Self::Deprecation => "deprecation",
+        }
+    }
+}
+
+impl<B: diesel::backend::Backend> diesel::deserialize::FromSql<diesel::sql_types::Text, B>
+    for CrateAdvisoryKind
+where
+    String: diesel::deserialize::FromSql<diesel::sql_types::Text, B>,
+{
+    fn from_sql(bytes: Option<&B::RawValue>) -> diesel::deserialize::Result<Self> {
+        match String::from_sql(bytes)?.as_str() {
+            "vulnerability" => Ok(Self::Vulnerability),
+            "deprecation" => Ok(Self::Deprecation),
+            _ => Err("Unknown advisory kind".into()),
+        }
+    }
+}
+
+impl<B: diesel::backend::Backend> diesel::serialize::ToSql<diesel::sql_types::Text, B>
+    for CrateAdvisoryKind
+{
+    fn to_sql<W: Write>(
+        &self,
+        out: &mut diesel::serialize::Output<'_, W, B>,
+    ) -> diesel::serialize::Result {
+        out.write_all(self.as_str().as_bytes())
+            .map(|_| diesel::serialize::IsNull::No)
+            .map_err(Into::into)
+    }
+}
+
+impl CrateWithPermissions {
+    /// Lists all the advisories for this crate, including withdrawn ones, along with the user
+    /// that raised them.
+    pub async fn advisories(
+        self: Arc<Self>,
+        conn: ConnectionPool,
+    ) -> Result<Vec<(CrateAdvisory, User)>> {
+        tokio::task::spawn_blocking(move || {
+            let conn = conn.get()?;
+
+            Ok(CrateAdvisory::belonging_to(&self.crate_)
+                .inner_join(users::table.on(users::id.eq(crate_advisories::user_id)))
+                .order_by(crate_advisories::id.desc())
+                .load(&conn)?)
+        })
+        .await?
+    }
+
+    /// Lists all the advisories for this crate that haven't been withdrawn.
+    pub async fn active_advisories(
+        self: Arc<Self>,
+        conn: ConnectionPool,
+    ) -> Result<Vec<CrateAdvisory>> {
+        tokio::task::spawn_blocking(move || {
+            let conn = conn.get()?;
+
+            Ok(CrateAdvisory::belonging_to(&self.crate_)
+                .filter(crate_advisories::withdrawn_at.is_null())
+                .order_by(crate_advisories::id.desc())
+                .load(&conn)?)
+        })
+        .await?
+    }
+
+    /// Raises a new advisory against all versions of this crate matching the `affected_versions`
+    /// requirement (ie. `<2.0.0`).
+    pub async fn create_advisory(
+        self: Arc<Self>,
+        conn: ConnectionPool,
+        actor: AuditActor,
+        given_kind: CrateAdvisoryKind,
+        given_affected_versions: String,
+        given_message: String,
+    ) -> Result<CrateAdvisory> {
+        use crate::schema::crate_advisories::dsl::{
+            affected_versions, crate_id, kind, message, user_id, uuid,
+        };
+
+        if !self.permissions.contains(UserPermission::YANK_VERSION) {
+            return Err(Error::MissingCratePermission(UserPermission::YANK_VERSION));
+        }
+
+        if semver::VersionReq::parse(&given_affected_versions).is_err() {
+            return Err(Error::InvalidVersionRequirement(given_affected_versions));
+        }
+
+        if given_message.trim().is_empty() {
+            return Err(Error::MissingAdvisoryMessage);
+        }
+
+        tokio::task::spawn_blocking(move || {
+            let conn = conn.get()?;
+
+            let generated_uuid = SqlUuid::random();
+
+            conn.transaction::<_, crate::Error, _>(|| {
+                insert_into(crate_advisories::table)
+                    .values((
+                        uuid.eq(generated_uuid),
+                        crate_id.eq(self.crate_.id),
+                        kind.eq(given_kind),
+                        affected_versions.eq(&given_affected_versions),
+                        message.eq(&given_message),
+                        user_id.eq(actor.user_id),
+                    ))
+                    .execute(&conn)?;
+
+                NewAuditEvent::new(AuditAction::AdvisoryCreated)
+                    .crate_(&self.crate_)
+                    .after(serde_json::json!({
+                        "uuid": generated_uuid.to_string(),
+                        "kind": given_kind,
+                        "affected_versions": given_affected_versions,
+                        "message": given_message,
+                    }))
+                    .record(&conn, &actor)?;
+
+                Ok(crate_advisories::table
+                    .filter(uuid.eq(generated_uuid))
+                    .get_result(&conn)?)
+            })
+        })
+        .await?
+    }
+
+    /// Withdraws an advisory so it's no longer shown against the versions it affected, the
+    /// advisory is kept around for posterity.
+    pub async fn withdraw_advisory(
+        self: Arc<Self>,
+        conn: ConnectionPool,
+        actor: AuditActor,
+        given_uuid: uuid::Uuid,
+    ) -> Result<bool> {
+        use crate::schema::crate_advisories::dsl::{
+            crate_id, uuid, withdrawn_at, withdrawn_by_user_id,
+        };
+
+        if !self.permissions.contains(UserPermission::YANK_VERSION) {
+            return Err(Error::MissingCratePermission(UserPermission::YANK_VERSION));
+        }
+
+        tokio::task::spawn_blocking(move || {
+            let conn = conn.get()?;
+
+            conn.transaction::<_, crate::Error, _>(|| {
+                let rows = diesel::update(
+                    crate_advisories::table
+                        .filter(crate_id.eq(self.crate_.id))
+                        .filter(uuid.eq(SqlUuid(given_uuid)))
+                        .filter(withdrawn_at.is_null()),
+                )
+                .set((
+                    withdrawn_at.eq(diesel::dsl::now),
+                    withdrawn_by_user_id.eq(actor.user_id),
+                ))
+                .execute(&conn)?;
+
+                if rows > 0 {
+                    NewAuditEvent::new(AuditAction::AdvisoryWithdrawn)
+                        .crate_(&self.crate_)
+                        .before(serde_json::json!({ "uuid": given_uuid.to_string() }))
+                        .record(&conn, &actor)?;
+                }
+
+                Ok(rows > 0)
+            })
+        })
+        .await?
+    }
+}
+
+#[cfg(all(test, feature = "sqlite"))]
+mod tests {
+    use super::CrateAdvisoryKind;
+    use crate::{
+        crates::{Crate, CrateWithPermissions},
+        permissions::UserPermission,
+        schema::crates,
+        test_actor, test_crate, test_user, Error,
+    };
+    use diesel::prelude::*;
+    use std::sync::Arc;
+
+    #[tokio::test]
+    async fn create_and_withdraw() {
+        let conn = crate::test_pool();
+        let owner = test_user(&conn, "owner").await;
+        let crate_ = test_crate(&conn, owner, "org", "crate").await;
+
+        let advisory = crate_
+            .clone()
+            .create_advisory(
+                conn.clone(),
+                test_actor(owner),
+                CrateAdvisoryKind::Vulnerability,
+                ">=1.2, <1.5".into(),
+                "use 1.5 instead".into(),
+            )
+            .await
+            .unwrap();
+
+        assert!(!advisory.affects("1.1.0"));
+        assert!(advisory.affects("1.2.0"));
+        assert!(advisory.affects("1.4.9"));
+        assert!(!advisory.affects("1.5.0"));
+        assert!(!advisory.affects("not-a-version"));
+
+        assert!(crate_
+            .clone()
+            .withdraw_advisory(conn.clone(), test_actor(owner), advisory.uuid.0)
+            .await
+            .unwrap());
+        // the handler turns this into a 404
+        assert!(!crate_
+            .clone()
+            .withdraw_advisory(conn.clone(), test_actor(owner), advisory.uuid.0)
+            .await
+            .unwrap());
+
+        let advisories = crate_.clone().advisories(conn.clone()).await.unwrap();
+        assert_eq!(advisories.len(), 1);
+        assert!(advisories[0].0.withdrawn_at.is_some());
+        assert!(crate_.active_advisories(conn).await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn create_invalid() {
+        let conn = crate::test_pool();
+        let owner = test_user(&conn, "owner").await;
+        let crate_ = test_crate(&conn, owner, "org", "crate").await;
+
+        let create = |affected_versions: &str, message: &str| {
+            crate_.clone().create_advisory(
+                conn.clone(),
+                test_actor(owner),
+                CrateAdvisoryKind::Deprecation,
+                affected_versions.to_string(),
+                message.to_string(),
+            )
+        };
+
+        assert!(matches!(
+            create("not a requirement", "message").await,
+            Err(Error::InvalidVersionRequirement(_))
+        ));
+        assert!(matches!(
+            create("<2", "  ").await,
+            Err(Error::MissingAdvisoryMessage)
+        ));
+    }
+
+    #[tokio::test]
+    async fn requires_yank_version() {
+        let conn = crate::test_pool();
+        let owner = test_user(&conn, "owner").await;
+        let crate_ = test_crate(&conn, owner, "org", "crate").await;
+
+        let advisory = crate_
+            .clone()
+            .create_advisory(
+                conn.clone(),
+                test_actor(owner),
+                CrateAdvisoryKind::Deprecation,
+                "<2".into(),
+                "use 2.0 instead".into(),
+            )
+            .await
+            .unwrap();
+
+        let viewer = Arc::new(CrateWithPermissions {
+            crate_: crates::table
+                .find(crate_.crate_.id)
+                .get_result::<Crate>(&conn.get().unwrap())
+                .unwrap(),
+            permissions: UserPermission::all() - UserPermission::YANK_VERSION,
+        });
+
+        assert!(matches!(
+            viewer
+                .clone()
+                .create_advisory(
+                    conn.clone(),
+                    test_actor(owner),
+                    CrateAdvisoryKind::Deprecation,
+                    "<2".into(),
+                    "use 2.0 instead".into(),
+                )
+                .await,
+            Err(Error::MissingCratePermission(v)) if v == UserPermission::YANK_VERSION
+        ));
+        assert!(matches!(
+            viewer
+                .withdraw_advisory(conn.clone(), test_actor(owner), advisory.uuid.0)
+                .await,
+            Err(Error::MissingCratePermission(v)) if v == UserPermission::YANK_VERSION
+        ));
+    }
+}
@@ -0,0 +1,335 @@
+//! An append-only log of every change made through chartered, recording who made the change,
+//! where they made it from, and the state of the thing they changed before and after.
+//!
+//! Events are written in the same transaction as the change they're recording wherever
+//! possible, so we'll never have a change without a matching event.
+
+use crate::{
+    crates::Crate,
+    organisations::OrganisationWithPermissions,
+    permissions::UserPermission,
+    schema::{audit_events, crates, users},
+    users::User,
+    ConnectionPool, Error, Result,
+};
+use diesel::{insert_into, prelude::*, Identifiable, Queryable};
+use serde::{Deserialize, Serialize};
+use std::{collections::HashMap, io::Write, sync::Arc};
+
+/// The user performing an action, along with the IP and user agent they performed it from.
+#[derive(Clone, Debug)]
+pub struct AuditActor {
+    pub user_id: i32,
+    pub ip: Option<String>,
+    pub user_agent: Option<String>,
+}
+
+#[derive(Identifiable, Queryable, PartialEq, Eq, Debug)]
+pub struct AuditEvent {
+    pub id: i32,
+    pub action: AuditAction,
+    pub user_id: i32,
+    pub organisation_id: Option<i32>,
+    pub crate_id: Option<i32>,
+    pub target_user_id: Option<i32>,
+    pub ip: Option<String>,
+    pub user_agent: Option<String>,
+    pub state_before: Option<AuditState>,
+    pub state_after: Option<AuditState>,
+    pub created_at: chrono::NaiveDateTime,
+}
+
+#[derive(Serialize, Deserialize, FromSqlRow, AsExpression, Debug, Clone, Copy, PartialEq, Eq)]
+#[sql_type = "diesel::sql_types::Text"]
+#[serde(rename_all = "snake_case")]
+pub enum AuditAction {
+    CrateCreated,
+    CrateTransferred,
+    VersionPublished,
+    VersionYanked,
+    VersionUnyanked,
+    CrateMemberAdded,
+    CrateMemberUpdated,
+    CrateMemberRemoved,
+    AdvisoryCreated,
+    AdvisoryWithdrawn,
+    OrganisationCreated,
+    OrganisationUpdated,
+    OrganisationDeleted,
+    OrganisationMemberAdded,
+    OrganisationMemberUpdated,
+    OrganisationMemberRemoved,
+    OrganisationInvitationCreated,
+    OrganisationInvitationRevoked,
+    OrganisationInvitationDeclined,
+    TeamCreated,
+    TeamDeleted,
+    TeamMemberAdded,
+    TeamMemberRemoved,
+    TeamPermissionsUpdated,
+    CrateTeamAdded,
+    CrateTeamUpdated,
+    CrateTeamRemoved,
+    SshKeyAdded,
+    SshKeyDeleted,
+    WebauthnCredentialAdded,
+    WebauthnCredentialDeleted,
+    SessionDeleted,
+    TwoFactorEnabled,
+    TwoFactorDisabled,
+    RecoveryCodesRegenerated,
+    PasswordChanged,
+    PasswordReset,
+    EmailChanged,
+    EmailVerified,
+    AccountLocked,
+}
+
+impl<B: diesel::backend::Backend> diesel::deserialize::FromSql<diesel::sql_types::Text, B>
+    for AuditAction
+where
+    String: diesel::deserialize::FromSql<diesel::sql_types::Text, B>,
+{
+    fn from_sql(bytes: Option<&B::RawValue>) -> diesel::deserialize::Result<Self> {
+        let value = String::from_sql(bytes)?;
+        serde_json::from_value(serde_json::Value::String(value))
+            .map_err(|_| "Unknown audit action".into())
+    }
+}
+
+impl<B: diesel::backend::Backend> diesel::serialize::ToSql<diesel::sql_types::Text, B>
+    for AuditAction
+{
+    fn to_sql<W: Write>(
+        &self,
+        out: &mut diesel::serialize::Output<'_, W, B>,
+    ) -> diesel::serialize::Result {
+        match serde_json::to_value(self)? {
+            serde_json::Value::String(v) => out
+                .write_all(v.as_bytes())
+                .map(|_| diesel::serialize::IsNull::No)
+                .map_err(Into::into),
+            _ => Err("Audit action didn't serialise to a string".into()),
+        }
+    }
+}
+
+/// A JSON snapshot of the thing that was changed, before or after the change was made.
+#[derive(Serialize, Deserialize, FromSqlRow, AsExpression, Debug, Clone, PartialEq, Eq)]
+#[sql_type = "diesel::sql_types::Blob"]
+pub struct AuditState(pub serde_json::Value);
+
+derive_diesel_json!(AuditState);
+
+/// Builds up an event to be written to the audit log by one of the mutating methods.
+pub(crate) struct NewAuditEvent {
+    action: AuditAction,
+    organisation_id: Option<i32>,
+    crate_id: Option<i32>,
+    target_user_id: Option<i32>,
+    state_before: Option<AuditState>,
+    state_after: Option<AuditState>,
+}
+
+impl NewAuditEvent {
+    pub(crate) fn new(action: AuditAction) -> Self {
+        Self {
+            action,
+            organisation_id: None,
+            crate_id: None,
+            target_user_id: None,
+            state_before: None,
+            state_after: None,
+        }
+    }
+
+    pub(crate) fn organisation(mut self, organisation_id: i32) -> Self {
+        self.organisation_id = Some(organisation_id);
+        self
+    }
+
+    /// Sets both the crate and the organisation the crate belongs to.
+    pub(crate) fn crate_(mut self, crate_: &Crate) -> Self {
+        self.crate_id = Some(crate_.id);
+        self.organisation_id = Some(crate_.organisation_id);
+        self
+    }
+
+    pub(crate) fn target_user(mut self, user_id: i32) -> Self {
+        self.target_user_id = Some(user_id);
+        self
+    }
+
+    pub(crate) fn before(mut self, state: serde_json::Value) -> Self {
+        self.state_before = Some(AuditState(state));
+        self
+    }
+
+    pub(crate) fn after(mut self, state: serde_json::Value) -> Self {
+        self.state_after = Some(AuditState(state));
+        self
+    }
+
+    pub(crate) fn record(self, conn: &crate::Connection, actor: &AuditActor) -> QueryResult<()> {
+        use crate::schema::audit_events::dsl::{
+            action, crate_id, ip, organisation_id, state_after, state_before, target_user_id,
+            user_agent, user_id,
+        };
+
+        insert_into(audit_events::table)
+            .values((
+                action.eq(self.action),
+                user_id.eq(actor.user_id),
+                organisation_id.eq(self.organisation_id),
+                crate_id.eq(self.crate_id),
+                target_user_id.eq(self.target_user_id),
+                ip.eq(actor.ip.as_deref()),
+                user_agent.eq(actor.user_agent.as_deref()),
+                state_before.eq(self.state_before),
+                state_after.eq(self.state_after),
+            ))
+            .execute(conn)?;
+
+        Ok(())
+    }
+}
+
+/// Narrows down the events returned by [`OrganisationWithPermissions::audit_events`].
+#[derive(Default, Debug)]
+pub struct AuditEventFilter {
+    pub action: Option<AuditAction>,
+    pub user_id: Option<i32>,
+    pub crate_name: Option<String>,
+}
+
+#[derive(Debug)]
+pub struct AuditEventWithUsers {
+    pub event: AuditEvent,
+    pub user: User,
+    pub target_user: Option<User>,
+    pub crate_name: Option<String>,
+}
+
+impl OrganisationWithPermissions {
+    /// Lists the events recorded against this organisation and its crates, most recent first.
+    /// Only those able to manage the organisation are able to view them.
+    pub async fn audit_events(
+        self: Arc<Self>,
+        conn: ConnectionPool,
+        filter: AuditEventFilter,
+        offset: i64,
+        limit: i64,
+    ) -> Result<Vec<AuditEventWithUsers>> {
+        if !self
+            .permissions()
+            .contains(UserPermission::MANAGE_ORGANISATION)
+        {
+            return Err(Error::MissingOrganisationPermission(
+                UserPermission::MANAGE_ORGANISATION,
+            ));
+        }
+
+        tokio::task::spawn_blocking(move || {
+            let conn = conn.get()?;
+
+            let mut query = audit_events::table
+                .inner_join(users::table.on(users::id.eq(audit_events::user_id)))
+                .left_join(crates::table)
+                .filter(audit_events::organisation_id.eq(self.organisation().id))
+                .select((
+                    audit_events::all_columns,
+                    users::all_columns,
+                    crates::name.nullable(),
+                ))
+                .into_boxed();
+
+            if let Some(given_action) = filter.action {
+                query = query.filter(audit_events::action.eq(given_action));
+            }
+
+            if let Some(given_user_id) = filter.user_id {
+                query = query.filter(audit_events::user_id.eq(given_user_id));
+            }
+
+            if let Some(given_crate_name) = filter.crate_name {
+                query = query.filter(crates::name.eq(given_crate_name));
+            }
+
+            let events: Vec<(AuditEvent, User, Option<String>)> = query
+                .order_by(audit_events::id.desc())
+                .offset(offset)
+                .limit(limit)
+                .load(&conn)?;
+
+            with_target_users(&conn, events)
+        })
+        .await?
+    }
+}
+
+impl User {
+    /// Lists the events recorded against this user's own account that don't belong to any
+    /// organisation, ie. sessions, keys and lockouts, most recent first.
+    pub async fn audit_events(
+        self: Arc<Self>,
+        conn: ConnectionPool,
+        offset: i64,
+        limit: i64,
+    ) -> Result<Vec<AuditEventWithUsers>> {
+        tokio::task::spawn_blocking(move || {
+            let conn = conn.get()?;
+
+            let events: Vec<(AuditEvent, User)> = audit_events::table
+                .inner_join(users::table.on(users::id.eq(audit_events::user_id)))
+                .filter(audit_events::organisation_id.is_null())
+                .filter(audit_events::target_user_id.eq(self.id))
+                .select((audit_events::all_columns, users::all_columns))
+                .order_by(audit_events::id.desc())
+                .offset(offset)
+                .limit(limit)
+                .load(&conn)?;
+
+            with_target_users(
+                &conn,
+                events
+                    .into_iter()
+                    .map(|(event, user)| (event, user, None))
+                    .collect(),
+            )
+        })
+        .await?
+    }
+}
+
+fn with_target_users(
+    conn: &crate::Connection,
+    events: Vec<(AuditEvent, User, Option<String>)>,
+) -> Result<Vec<AuditEventWithUsers>> {
+    // the users table can't be joined twice, so we'll grab the users that the events
+    // targeted separately
+    let target_users: HashMap<i32, User> = users::table
+        .filter(
+            users::id.eq_any(
+                events
+                    .iter()
+                    .filter_map(|(event, _, _)| event.target_user_id)
+                    .collect::<Vec<_>>(),
+            ),
+        )
+        .load::<User>(conn)?
+        .into_iter()
+        .map(|user| (user.id, user))
+        .collect();
+
+    Ok(events
+        .into_iter()
+        .map(|(event, user, crate_name)| AuditEventWithUsers {
+            target_user: event
+                .target_user_id
+                .and_then(|id| target_users.get(&id).cloned()),
+            event,
+            user,
+            crate_name,
+        })
+        .collect())
+}
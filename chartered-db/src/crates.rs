@@ -1,15 +1,22 @@
 use super::{
+    audit::{AuditAction, AuditActor, NewAuditEvent},
     coalesce,
     organisations::Organisation,
     permissions::UserPermission,
-    schema::{crate_versions, crates, organisations, user_crate_permissions, users},
+    schema::{
+        crate_version_dependencies, crate_version_yanks, crate_versions, crates, organisations,
+        user_crate_permissions, users,
+    },
     users::User,
     BitwiseExpressionMethods, ConnectionPool, Error, Result,
 };
 use diesel::{insert_into, prelude::*, Associations, Identifiable, Queryable};
 use itertools::Itertools;
 use serde::{Deserialize, Serialize};
-use std::{collections::HashMap, sync::Arc};
+use std::{
+    collections::{HashMap, HashSet},
+    sync::Arc,
+};
 
 #[derive(Identifiable, Queryable, Associations, Default, PartialEq, Eq, Hash, Debug)]
 #[belongs_to(User)]
@@ -81,7 +88,7 @@ macro_rules! crate_with_permissions {
 }
 
 macro_rules! select_permissions {
-    () => {
+    ($user_id:ident) => {
         coalesce(
             crate::schema::user_crate_permissions::permissions.nullable(),
             0,
@@ -90,11 +97,31 @@ macro_rules! select_permissions {
             crate::schema::user_organisation_permissions::permissions.nullable(),
             0,
         ))
+        .bitwise_or(diesel::dsl::sql::<diesel::sql_types::Integer>(
+            &crate::teams::team_permissions_sql(
+                $user_id,
+                "team_crate_permissions",
+                "crate_id",
+                "crates.id",
+            ),
+        ))
+        .bitwise_or(diesel::dsl::sql::<diesel::sql_types::Integer>(
+            &crate::teams::team_permissions_sql(
+                $user_id,
+                "team_organisation_permissions",
+                "organisation_id",
+                "crates.organisation_id",
+            ),
+        ))
         .bitwise_or(diesel::dsl::sql::<diesel::sql_types::Integer>(&format!(
             "COALESCE(CASE WHEN {} THEN {} ELSE 0 END, 0)",
             "organisations.public",
             UserPermission::VISIBLE.bits(),
         )))
+        // operators are applied left to right, so the mask applies to everything ORed above
+        .bitwise_and(diesel::dsl::sql::<diesel::sql_types::Integer>(
+            &crate::two_factor::two_factor_permissions_mask_sql($user_id),
+        ))
     };
 }
 
@@ -111,7 +138,7 @@ impl Crate {
             let crates = crate_with_permissions!(requesting_user_id)
                 .inner_join(organisations::table)
                 .filter(
-                    select_permissions!()
+                    select_permissions!(requesting_user_id)
                         .bitwise_and(UserPermission::VISIBLE.bits())
                         .eq(UserPermission::VISIBLE.bits()),
                 )
@@ -122,7 +149,7 @@ impl Crate {
                 .select((
                     organisations::all_columns,
                     crates::all_columns,
-                    select_permissions!(),
+                    select_permissions!(requesting_user_id),
                 ))
                 .limit(limit)
                 .load(&conn)?
@@ -148,18 +175,34 @@ impl Crate {
         requesting_user_id: i32,
         given_org_name: String,
     ) -> Result<HashMap<Crate, Vec<CrateVersion<'static>>>> {
-        use crate::schema::organisations::dsl::{name as org_name, organisations};
+        use crate::schema::organisations::dsl::organisations;
 
         tokio::task::spawn_blocking(move || {
             let conn = conn.get()?;
+            let given_org_name = crate::organisations::resolve_alias(&conn, given_org_name)?;
+            let crate_ids = crate::transfers::organisation_crate_ids(&conn, &given_org_name)?;
+
+            // crates transferred out of the organisation are visible to its members through
+            // their aliases, regardless of their permissions on the crate's new organisation
+            let aliased_crate_ids =
+                if crate::transfers::alias_permissions(&conn, requesting_user_id, &given_org_name)?
+                    .contains(UserPermission::VISIBLE)
+                {
+                    crate::transfers::aliased_crate_ids(&conn, &given_org_name)?
+                } else {
+                    Vec::new()
+                };
 
             let crate_versions = crate_with_permissions!(requesting_user_id)
                 .inner_join(organisations)
-                .filter(org_name.eq(given_org_name))
                 .filter(
-                    select_permissions!()
-                        .bitwise_and(UserPermission::VISIBLE.bits())
-                        .eq(UserPermission::VISIBLE.bits()),
+                    crates::id
+                        .eq_any(aliased_crate_ids)
+                        .or(crates::id.eq_any(crate_ids).and(
+                            select_permissions!(requesting_user_id)
+                                .bitwise_and(UserPermission::VISIBLE.bits())
+                                .eq(UserPermission::VISIBLE.bits()),
+                        )),
                 )
                 .inner_join(crate_versions::table)
                 .select((crates::all_columns, crate_versions::all_columns))
@@ -179,7 +222,7 @@ impl Crate {
 
             let crates = crate_with_permissions!(requesting_user_id)
                 .filter(
-                    select_permissions!()
+                    select_permissions!(requesting_user_id)
                         .bitwise_and(UserPermission::VISIBLE.bits())
                         .eq(UserPermission::VISIBLE.bits()),
                 )
@@ -203,7 +246,7 @@ impl Crate {
 
             let crates = crate_with_permissions!(requesting_user_id)
                 .filter(
-                    select_permissions!()
+                    select_permissions!(requesting_user_id)
                         .bitwise_and(UserPermission::VISIBLE.bits())
                         .eq(UserPermission::VISIBLE.bits()),
                 )
@@ -227,7 +270,7 @@ impl Crate {
 
             let crates = crate_with_permissions!(requesting_user_id)
                 .filter(
-                    select_permissions!()
+                    select_permissions!(requesting_user_id)
                         .bitwise_and(UserPermission::VISIBLE.bits())
                         .eq(UserPermission::VISIBLE.bits()),
                 )
@@ -253,20 +296,36 @@ impl Crate {
         given_org_name: String,
         given_crate_name: String,
     ) -> Result<CrateWithPermissions> {
-        use crate::schema::crates::dsl::name as crate_name;
-        use crate::schema::organisations::dsl::{name as org_name, organisations};
+        use crate::schema::organisations::dsl::organisations;
 
         tokio::task::spawn_blocking(move || {
             let conn = conn.get()?;
-
-            let (crate_, permissions) = crate_with_permissions!(requesting_user_id)
-                .inner_join(organisations)
-                .filter(org_name.eq(given_org_name))
-                .filter(crate_name.eq(given_crate_name))
-                .select((crate::schema::crates::all_columns, select_permissions!()))
-                .first::<(Crate, UserPermission)>(&conn)
-                .optional()?
-                .ok_or(Error::MissingCrate)?;
+            let given_org_name = crate::organisations::resolve_alias(&conn, given_org_name)?;
+            let crate_id =
+                crate::transfers::resolve_crate(&conn, &given_org_name, &given_crate_name)?
+                    .ok_or(Error::MissingCrate)?;
+
+            let (crate_, current_org_name, mut permissions) =
+                crate_with_permissions!(requesting_user_id)
+                    .inner_join(organisations)
+                    .filter(crates::id.eq(crate_id))
+                    .select((
+                        crate::schema::crates::all_columns,
+                        crate::schema::organisations::name,
+                        select_permissions!(requesting_user_id),
+                    ))
+                    .first::<(Crate, String, UserPermission)>(&conn)
+                    .optional()?
+                    .ok_or(Error::MissingCrate)?;
+
+            // the crate was resolved through an alias left behind by a transfer
+            if current_org_name != given_org_name {
+                permissions |= crate::transfers::alias_permissions(
+                    &conn,
+                    requesting_user_id,
+                    &given_org_name,
+                )?;
+            }
 
             if permissions.contains(UserPermission::VISIBLE) {
                 Ok(CrateWithPermissions {
@@ -282,7 +341,7 @@ impl Crate {
 
     pub async fn create(
         conn: ConnectionPool,
-        requesting_user_id: i32,
+        actor: AuditActor,
         given_org_name: String,
         given_crate_name: String,
     ) -> Result<CrateWithPermissions> {
@@ -294,13 +353,32 @@ impl Crate {
         tokio::task::spawn_blocking(move || {
             let conn = conn.get()?;
 
+            // permissions can be granted either directly or through any of the user's teams
+            let team_permissions = crate::teams::team_permissions_sql(
+                actor.user_id,
+                "team_organisation_permissions",
+                "organisation_id",
+                "organisations.id",
+            );
+            let two_factor_mask = crate::two_factor::two_factor_permissions_mask_sql(actor.user_id);
+            let given_org_name = crate::organisations::resolve_alias(&conn, given_org_name)?;
+
             let (org_id, perms) = organisations
                 .filter(org_name.eq(given_org_name))
-                .inner_join(
+                .left_join(
                     crate::schema::user_organisation_permissions::table
-                        .on(organisation_id.eq(id).and(user_id.eq(requesting_user_id))),
+                        .on(organisation_id.eq(id).and(user_id.eq(actor.user_id))),
                 )
-                .select((id, permissions))
+                .select((
+                    id,
+                    coalesce(permissions.nullable(), 0)
+                        .bitwise_or(diesel::dsl::sql::<diesel::sql_types::Integer>(
+                            &team_permissions,
+                        ))
+                        .bitwise_and(diesel::dsl::sql::<diesel::sql_types::Integer>(
+                            &two_factor_mask,
+                        )),
+                ))
                 .first::<(i32, UserPermission)>(&conn)
                 .optional()?
                 .ok_or(Error::MissingOrganisation)?;
@@ -313,18 +391,29 @@ impl Crate {
             } else {
                 use crate::schema::crates::dsl::{crates, name, organisation_id};
 
-                insert_into(crates)
-                    .values((name.eq(&given_crate_name), organisation_id.eq(org_id)))
-                    .execute(&conn)?;
-
-                let crate_ = crates
-                    .filter(name.eq(given_crate_name).and(organisation_id.eq(org_id)))
-                    .select(crate::schema::crates::all_columns)
-                    .first::<Crate>(&conn)?;
-
-                Ok(CrateWithPermissions {
-                    crate_,
-                    permissions: perms,
+                conn.transaction::<_, crate::Error, _>(|| {
+                    insert_into(crates)
+                        .values((name.eq(&given_crate_name), organisation_id.eq(org_id)))
+                        .execute(&conn)?;
+
+                    // a crate transferred out of the organisation can't keep resolving under a
+                    // name that's now in use
+                    crate::transfers::remove_alias(&conn, org_id, &given_crate_name)?;
+
+                    let crate_ = crates
+                        .filter(name.eq(&given_crate_name).and(organisation_id.eq(org_id)))
+                        .select(crate::schema::crates::all_columns)
+                        .first::<Crate>(&conn)?;
+
+                    NewAuditEvent::new(AuditAction::CrateCreated)
+                        .crate_(&crate_)
+                        .after(serde_json::json!({ "name": given_crate_name }))
+                        .record(&conn, &actor)?;
+
+                    Ok(CrateWithPermissions {
+                        crate_,
+                        permissions: perms,
+                    })
                 })
             }
         })
@@ -442,6 +531,7 @@ impl CrateWithPermissions {
     pub async fn update_permissions(
         self: Arc<Self>,
         conn: ConnectionPool,
+        actor: AuditActor,
         given_user_id: i32,
         given_permissions: UserPermission,
     ) -> Result<usize> {
@@ -456,13 +546,35 @@ impl CrateWithPermissions {
 
             let conn = conn.get()?;
 
-            Ok(diesel::update(
-                user_crate_permissions
+            conn.transaction::<_, crate::Error, _>(|| {
+                let previous_permissions = user_crate_permissions
                     .filter(user_id.eq(given_user_id))
-                    .filter(crate_id.eq(self.crate_.id)),
-            )
-            .set(permissions.eq(given_permissions.bits()))
-            .execute(&conn)?)
+                    .filter(crate_id.eq(self.crate_.id))
+                    .select(permissions)
+                    .get_result::<UserPermission>(&conn)
+                    .optional()?;
+
+                let Some(previous_permissions) = previous_permissions else {
+                    return Ok(0);
+                };
+
+                let rows = diesel::update(
+                    user_crate_permissions
+                        .filter(user_id.eq(given_user_id))
+                        .filter(crate_id.eq(self.crate_.id)),
+                )
+                .set(permissions.eq(given_permissions.bits()))
+                .execute(&conn)?;
+
+                NewAuditEvent::new(AuditAction::CrateMemberUpdated)
+                    .crate_(&self.crate_)
+                    .target_user(given_user_id)
+                    .before(serde_json::json!({ "permissions": previous_permissions }))
+                    .after(serde_json::json!({ "permissions": given_permissions }))
+                    .record(&conn, &actor)?;
+
+                Ok(rows)
+            })
         })
         .await?
     }
@@ -470,6 +582,7 @@ impl CrateWithPermissions {
     pub async fn insert_permissions(
         self: Arc<Self>,
         conn: ConnectionPool,
+        actor: AuditActor,
         given_user_id: i32,
         given_permissions: UserPermission,
     ) -> Result<usize> {
@@ -484,13 +597,23 @@ impl CrateWithPermissions {
 
             let conn = conn.get()?;
 
-            Ok(diesel::insert_into(user_crate_permissions)
-                .values((
-                    user_id.eq(given_user_id),
-                    crate_id.eq(self.crate_.id),
-                    permissions.eq(given_permissions.bits()),
-                ))
-                .execute(&conn)?)
+            conn.transaction::<_, crate::Error, _>(|| {
+                let rows = diesel::insert_into(user_crate_permissions)
+                    .values((
+                        user_id.eq(given_user_id),
+                        crate_id.eq(self.crate_.id),
+                        permissions.eq(given_permissions.bits()),
+                    ))
+                    .execute(&conn)?;
+
+                NewAuditEvent::new(AuditAction::CrateMemberAdded)
+                    .crate_(&self.crate_)
+                    .target_user(given_user_id)
+                    .after(serde_json::json!({ "permissions": given_permissions }))
+                    .record(&conn, &actor)?;
+
+                Ok(rows)
+            })
         })
         .await?
     }
@@ -498,6 +621,7 @@ impl CrateWithPermissions {
     pub async fn delete_member(
         self: Arc<Self>,
         conn: ConnectionPool,
+        actor: AuditActor,
         given_user_id: i32,
     ) -> Result<()> {
         if !self.permissions.contains(UserPermission::MANAGE_USERS) {
@@ -506,19 +630,38 @@ impl CrateWithPermissions {
 
         tokio::task::spawn_blocking(move || {
             use crate::schema::user_crate_permissions::dsl::{
-                crate_id, user_crate_permissions, user_id,
+                crate_id, permissions, user_crate_permissions, user_id,
             };
 
             let conn = conn.get()?;
 
-            diesel::delete(
-                user_crate_permissions
+            conn.transaction::<_, crate::Error, _>(|| {
+                let previous_permissions = user_crate_permissions
                     .filter(user_id.eq(given_user_id))
-                    .filter(crate_id.eq(self.crate_.id)),
-            )
-            .execute(&conn)?;
+                    .filter(crate_id.eq(self.crate_.id))
+                    .select(permissions)
+                    .get_result::<UserPermission>(&conn)
+                    .optional()?;
+
+                let Some(previous_permissions) = previous_permissions else {
+                    return Ok(());
+                };
+
+                diesel::delete(
+                    user_crate_permissions
+                        .filter(user_id.eq(given_user_id))
+                        .filter(crate_id.eq(self.crate_.id)),
+                )
+                .execute(&conn)?;
 
-            Ok(())
+                NewAuditEvent::new(AuditAction::CrateMemberRemoved)
+                    .crate_(&self.crate_)
+                    .target_user(given_user_id)
+                    .before(serde_json::json!({ "permissions": previous_permissions }))
+                    .record(&conn, &actor)?;
+
+                Ok(())
+            })
         })
         .await?
     }
@@ -527,7 +670,7 @@ impl CrateWithPermissions {
     pub async fn publish_version(
         self: Arc<Self>,
         conn: ConnectionPool,
-        user: Arc<User>,
+        actor: AuditActor,
         file_identifier: chartered_fs::FileReference,
         file_checksum: String,
         file_size: i32,
@@ -572,22 +715,41 @@ impl CrateWithPermissions {
                         crate_id.eq(self.crate_.id),
                         filesystem_object.eq(file_identifier.to_string()),
                         size.eq(file_size),
-                        checksum.eq(file_checksum),
+                        checksum.eq(&file_checksum),
                         version.eq(&given.vers),
-                        dependencies.eq(CrateDependencies(given.deps)),
+                        dependencies.eq(CrateDependencies(given.deps.clone())),
                         features.eq(CrateFeatures(given.features)),
                         links.eq(given.links),
-                        user_id.eq(user.id),
+                        user_id.eq(actor.user_id),
                     ))
                     .execute(&conn);
 
                 match res {
-                    Ok(_) => Ok(()),
+                    Ok(_) => {}
                     Err(DieselError::DatabaseError(DatabaseErrorKind::UniqueViolation, _)) => {
-                        Err(Error::VersionConflict(given.vers.into_owned()))
+                        return Err(Error::VersionConflict(given.vers.into_owned()));
                     }
-                    Err(e) => Err(e.into()),
+                    Err(e) => return Err(e.into()),
                 }
+
+                let inserted_id = crate_versions
+                    .filter(crate_id.eq(self.crate_.id))
+                    .filter(version.eq(&given.vers))
+                    .select(crate::schema::crate_versions::id)
+                    .get_result::<i32>(&conn)?;
+
+                insert_dependencies(&conn, self.crate_.organisation_id, inserted_id, &given.deps)?;
+
+                NewAuditEvent::new(AuditAction::VersionPublished)
+                    .crate_(&self.crate_)
+                    .after(serde_json::json!({
+                        "version": given.vers,
+                        "checksum": file_checksum,
+                        "size": file_size,
+                    }))
+                    .record(&conn, &actor)?;
+
+                Ok(())
             })?;
 
             Ok(())
@@ -595,13 +757,54 @@ impl CrateWithPermissions {
         .await?
     }
 
+    /// Finds every version of every crate that depends on this crate, along with the
+    /// requirement it depends on us with. Only crates visible to `requesting_user_id` are
+    /// returned.
+    pub async fn dependents(
+        self: Arc<Self>,
+        conn: ConnectionPool,
+        requesting_user_id: i32,
+    ) -> Result<Vec<CrateDependent>> {
+        tokio::task::spawn_blocking(move || {
+            let conn = conn.get()?;
+
+            Ok(crate_with_permissions!(requesting_user_id)
+                .inner_join(organisations::table)
+                .inner_join(crate_versions::table)
+                .inner_join(
+                    crate_version_dependencies::table
+                        .on(crate_version_dependencies::crate_version_id.eq(crate_versions::id)),
+                )
+                .filter(
+                    select_permissions!(requesting_user_id)
+                        .bitwise_and(UserPermission::VISIBLE.bits())
+                        .eq(UserPermission::VISIBLE.bits()),
+                )
+                .filter(crate_version_dependencies::dependency_crate_id.eq(self.crate_.id))
+                .select((
+                    organisations::name,
+                    crates::name,
+                    crate_versions::version,
+                    crate_version_dependencies::req,
+                    crate_version_dependencies::kind,
+                ))
+                .order_by((organisations::name, crates::name, crate_versions::id.desc()))
+                .load(&conn)?)
+        })
+        .await?
+    }
+
+    /// Yanks or unyanks the given version, recording who did it and why so consumers can see
+    /// the reasoning when their build starts complaining.
     pub async fn yank_version(
         self: Arc<Self>,
         conn: ConnectionPool,
+        actor: AuditActor,
         given_version: String,
         yank: bool,
+        given_reason: Option<String>,
     ) -> Result<()> {
-        use crate::schema::crate_versions::dsl::{crate_id, crate_versions, version, yanked};
+        use crate::schema::crate_versions::dsl::{crate_id, crate_versions, id, version, yanked};
 
         if !self.permissions.contains(UserPermission::YANK_VERSION) {
             return Err(Error::MissingCratePermission(UserPermission::YANK_VERSION));
@@ -610,20 +813,161 @@ impl CrateWithPermissions {
         tokio::task::spawn_blocking(move || {
             let conn = conn.get()?;
 
-            diesel::update(
-                crate_versions
+            conn.transaction::<_, crate::Error, _>(|| {
+                let (version_id, previously_yanked) = crate_versions
                     .filter(crate_id.eq(self.crate_.id))
-                    .filter(version.eq(given_version)),
-            )
-            .set(yanked.eq(yank))
-            .execute(&conn)?;
+                    .filter(version.eq(&given_version))
+                    .select((id, yanked))
+                    .get_result::<(i32, bool)>(&conn)
+                    .optional()?
+                    .ok_or(Error::MissingVersion)?;
+
+                // there's nothing to record if the version's already in the requested state
+                if previously_yanked == yank {
+                    return Ok(());
+                }
 
-            Ok(())
+                diesel::update(crate_versions.filter(id.eq(version_id)))
+                    .set(yanked.eq(yank))
+                    .execute(&conn)?;
+
+                insert_into(crate_version_yanks::table)
+                    .values((
+                        crate_version_yanks::crate_version_id.eq(version_id),
+                        crate_version_yanks::yanked.eq(yank),
+                        crate_version_yanks::reason.eq(&given_reason),
+                        crate_version_yanks::user_id.eq(actor.user_id),
+                    ))
+                    .execute(&conn)?;
+
+                NewAuditEvent::new(if yank {
+                    AuditAction::VersionYanked
+                } else {
+                    AuditAction::VersionUnyanked
+                })
+                .crate_(&self.crate_)
+                .before(
+                    serde_json::json!({ "version": given_version, "yanked": previously_yanked }),
+                )
+                .after(serde_json::json!({
+                    "version": given_version,
+                    "yanked": yank,
+                    "reason": given_reason,
+                }))
+                .record(&conn, &actor)?;
+
+                Ok(())
+            })
+        })
+        .await?
+    }
+
+    /// Returns every yank and unyank made against versions of this crate, most recent first,
+    /// along with the version it was made against and the user that made it.
+    pub async fn yank_history(
+        self: Arc<Self>,
+        conn: ConnectionPool,
+    ) -> Result<Vec<(CrateVersionYank, String, User)>> {
+        tokio::task::spawn_blocking(move || {
+            let conn = conn.get()?;
+
+            Ok(crate_version_yanks::table
+                .inner_join(crate_versions::table)
+                .inner_join(users::table)
+                .filter(crate_versions::crate_id.eq(self.crate_.id))
+                .select((
+                    crate_version_yanks::all_columns,
+                    crate_versions::version,
+                    users::all_columns,
+                ))
+                .order_by(crate_version_yanks::id.desc())
+                .load(&conn)?)
         })
         .await?
     }
 }
 
+/// Writes out each of the dependencies of a newly published version to
+/// `crate_version_dependencies` so they can be queried in reverse, resolving each one to a
+/// crate in the registry where possible.
+///
+/// Dependencies without a `registry` belong to the same organisation as the dependent,
+/// otherwise the last path segment of the registry's index URL is the organisation name
+/// (ie. `ssh://ssh.chart.rs/my-organisation`).
+fn insert_dependencies(
+    conn: &crate::Connection,
+    given_organisation_id: i32,
+    given_crate_version_id: i32,
+    deps: &[chartered_types::cargo::CrateDependency<'_>],
+) -> QueryResult<()> {
+    use crate::schema::crate_version_dependencies::dsl::{
+        crate_version_dependencies, crate_version_id, dependency_crate_id, kind, name, optional,
+        registry, req,
+    };
+
+    for dep in deps {
+        // `name` is the name the dependency was given in the dependent's Cargo.toml, `package`
+        // is only set if it was renamed
+        let dep_name = dep.package.as_deref().unwrap_or_else(|| dep.name.as_ref());
+
+        let dep_organisation_id = match dep.registry.as_deref() {
+            None => Some(given_organisation_id),
+            Some(dep_registry) => {
+                // organisations can be renamed, so the registry might be using an old name
+                let dep_org_name = crate::organisations::resolve_alias(
+                    conn,
+                    dep_registry
+                        .rsplit('/')
+                        .next()
+                        .unwrap_or_default()
+                        .to_string(),
+                )?;
+
+                organisations::table
+                    .filter(organisations::name.eq(dep_org_name))
+                    .select(organisations::id)
+                    .get_result::<i32>(conn)
+                    .optional()?
+            }
+        };
+
+        let resolved_crate_id = match dep_organisation_id {
+            Some(dep_organisation_id) => crates::table
+                .filter(crates::organisation_id.eq(dep_organisation_id))
+                .filter(crates::name.eq(dep_name))
+                .select(crates::id)
+                .get_result::<i32>(conn)
+                .optional()?,
+            None => None,
+        };
+
+        insert_into(crate_version_dependencies)
+            .values((
+                crate_version_id.eq(given_crate_version_id),
+                name.eq(dep_name),
+                req.eq(dep.req.as_ref()),
+                kind.eq(dep.kind.as_ref()),
+                optional.eq(dep.optional),
+                registry.eq(dep.registry.as_deref()),
+                dependency_crate_id.eq(resolved_crate_id),
+            ))
+            .execute(conn)?;
+    }
+
+    Ok(())
+}
+
+/// A version of another crate that depends on a crate, returned by
+/// [`CrateWithPermissions::dependents`].
+#[derive(Queryable, Debug)]
+pub struct CrateDependent {
+    pub organisation: String,
+    pub name: String,
+    pub version: String,
+    pub req: String,
+    pub kind: String,
+}
+
 #[derive(Identifiable, Queryable, Associations, PartialEq, Eq, Debug)]
 #[belongs_to(Crate)]
 #[belongs_to(User)]
@@ -643,6 +987,96 @@ pub struct CrateVersion<'a> {
 }
 
 impl<'a> CrateVersion<'a> {
+    /// Loads the storage reference of every version of every crate, used to find objects in
+    /// storage that nothing refers to anymore.
+    pub async fn all_filesystem_objects(conn: ConnectionPool) -> Result<HashSet<String>> {
+        tokio::task::spawn_blocking(move || {
+            let conn = conn.get()?;
+
+            Ok(crate_versions::table
+                .select(crate_versions::filesystem_object)
+                .load::<String>(&conn)?
+                .into_iter()
+                .collect())
+        })
+        .await?
+    }
+
+    /// Loads the id, storage reference and checksum of up to `limit` versions stored on the
+    /// given kind of file system with an id greater than `after_id`, in order of their id. Used
+    /// to migrate objects between file systems in batches.
+    pub async fn stored_on(
+        conn: ConnectionPool,
+        file_system: chartered_fs::FileSystemKind,
+        after_id: i32,
+        limit: i64,
+    ) -> Result<Vec<(i32, String, String)>> {
+        tokio::task::spawn_blocking(move || {
+            let conn = conn.get()?;
+
+            Ok(crate_versions::table
+                .filter(
+                    crate_versions::filesystem_object
+                        .like(format!("{}:%", file_system))
+                        .or(crate_versions::filesystem_object.like(format!("{}@%", file_system))),
+                )
+                .filter(crate_versions::id.gt(after_id))
+                .order_by(crate_versions::id.asc())
+                .limit(limit)
+                .select((
+                    crate_versions::id,
+                    crate_versions::filesystem_object,
+                    crate_versions::checksum,
+                ))
+                .load(&conn)?)
+        })
+        .await?
+    }
+
+    /// Points the version at a new copy of its file, provided it hasn't been changed since `from`
+    /// was read. Returns whether the version was updated.
+    pub async fn move_filesystem_object(
+        conn: ConnectionPool,
+        id: i32,
+        from: String,
+        to: String,
+    ) -> Result<bool> {
+        tokio::task::spawn_blocking(move || {
+            let conn = conn.get()?;
+
+            let updated = diesel::update(
+                crate_versions::table
+                    .filter(crate_versions::id.eq(id))
+                    .filter(crate_versions::filesystem_object.eq(from)),
+            )
+            .set(crate_versions::filesystem_object.eq(to))
+            .execute(&conn)?;
+
+            Ok(updated > 0)
+        })
+        .await?
+    }
+
+    /// Loads the storage reference and checksum of every version of every crate, used to check
+    /// storage for missing or corrupt objects.
+    pub async fn all_stored(conn: ConnectionPool) -> Result<Vec<StoredCrateVersion>> {
+        tokio::task::spawn_blocking(move || {
+            let conn = conn.get()?;
+
+            Ok(crate_versions::table
+                .inner_join(crates::table.inner_join(organisations::table))
+                .select((
+                    organisations::name,
+                    crates::name,
+                    crate_versions::version,
+                    crate_versions::filesystem_object,
+                    crate_versions::checksum,
+                ))
+                .load(&conn)?)
+        })
+        .await?
+    }
+
     #[must_use]
     pub fn into_cargo_format(self, crate_: &'a Crate) -> chartered_types::cargo::CrateVersion<'a> {
         chartered_types::cargo::CrateVersion {
@@ -655,6 +1089,27 @@ impl<'a> CrateVersion<'a> {
     }
 }
 
+/// Where a crate version's file is stored, along with the crate it belongs to.
+#[derive(Queryable, Debug)]
+pub struct StoredCrateVersion {
+    pub organisation: String,
+    pub crate_name: String,
+    pub version: String,
+    pub filesystem_object: String,
+    pub checksum: String,
+}
+
+#[derive(Identifiable, Queryable, Associations, PartialEq, Eq, Debug)]
+#[belongs_to(User)]
+pub struct CrateVersionYank {
+    pub id: i32,
+    pub crate_version_id: i32,
+    pub yanked: bool,
+    pub reason: Option<String>,
+    pub user_id: i32,
+    pub created_at: chrono::NaiveDateTime,
+}
+
 #[derive(Serialize, Deserialize, FromSqlRow, AsExpression, Debug, Clone, PartialEq, Eq)]
 #[sql_type = "diesel::sql_types::Blob"]
 pub struct CrateDependencies<'a>(pub Vec<chartered_types::cargo::CrateDependency<'a>>);
@@ -678,3 +1133,45 @@ impl From<chartered_types::cargo::CrateFeatures> for CrateFeatures {
         Self(o)
     }
 }
+
+#[cfg(all(test, feature = "sqlite"))]
+mod tests {
+    use crate::{test_actor, test_crate, test_publish, test_user, Error};
+
+    #[tokio::test]
+    async fn yank_version() {
+        let conn = crate::test_pool();
+        let owner = test_user(&conn, "owner").await;
+        let crate_ = test_crate(&conn, owner, "org", "crate").await;
+        test_publish(&conn, &crate_, owner, "1.0.0").await;
+
+        let yank = |version: &str, yank: bool| {
+            crate_.clone().yank_version(
+                conn.clone(),
+                test_actor(owner),
+                version.to_string(),
+                yank,
+                Some("broken".to_string()),
+            )
+        };
+
+        yank("1.0.0", true).await.unwrap();
+        yank("1.0.0", true).await.unwrap();
+        yank("1.0.0", false).await.unwrap();
+        yank("1.0.0", false).await.unwrap();
+
+        // yanking a version that's already yanked doesn't add to its history
+        let history = crate_.clone().yank_history(conn.clone()).await.unwrap();
+        assert_eq!(
+            history
+                .iter()
+                .map(|(yank, version, _)| (yank.yanked, version.as_str()))
+                .collect::<Vec<_>>(),
+            vec![(false, "1.0.0"), (true, "1.0.0")]
+        );
+
+        let err = yank("2.0.0", true).await.unwrap_err();
+        assert!(matches!(err, Error::MissingVersion));
+        assert_eq!(err.status_code(), http::StatusCode::NOT_FOUND);
+    }
+}
@@ -0,0 +1,385 @@
+//! Invitations for users to join an organisation. Rather than members being added directly, an
+//! invitation is sent to either an existing user or an email address and the organisation's
+//! permissions are only granted once the invitee has accepted it.
+//!
+//! Invitations sent to an email address can be accepted by any user that has verified that
+//! email. Accepted, declined and revoked invitations are removed, leaving the audit log as the
+//! record of what happened to them.
+
+use crate::{
+    audit::{AuditAction, AuditActor, NewAuditEvent},
+    organisations::{Organisation, OrganisationWithPermissions},
+    permissions::UserPermission,
+    schema::{organisation_invitations, organisations, user_organisation_permissions, users},
+    users::User,
+    uuid::SqlUuid,
+    ConnectionPool, Error, Result,
+};
+use chrono::{NaiveDateTime, Utc};
+use diesel::{insert_into, prelude::*, Associations, Identifiable, Queryable};
+use std::sync::Arc;
+
+#[derive(Identifiable, Queryable, Associations, PartialEq, Eq, Hash, Debug)]
+#[belongs_to(Organisation)]
+pub struct OrganisationInvitation {
+    pub id: i32,
+    pub uuid: SqlUuid,
+    pub organisation_id: i32,
+    /// The user that sent the invitation
+    pub invited_by: i32,
+    /// The user the invitation was sent to, if it was sent to an existing user
+    pub user_id: Option<i32>,
+    /// The email the invitation was sent to, if it wasn't sent to an existing user
+    pub email: Option<String>,
+    pub permissions: UserPermission,
+    pub expires_at: NaiveDateTime,
+    pub created_at: NaiveDateTime,
+}
+
+impl OrganisationInvitation {
+    fn audit_state(&self) -> serde_json::Value {
+        serde_json::json!({
+            "invitation": self.uuid.to_string(),
+            "email": self.email,
+            "permissions": self.permissions,
+        })
+    }
+}
+
+/// Who an invitation is being sent to.
+#[derive(Debug)]
+pub enum Invitee {
+    User(i32),
+    Email(String),
+}
+
+/// Grants the invitation's permissions to the user, on top of any they already have on the
+/// organisation, and removes the invitation.
+fn accept_invitation(
+    conn: &crate::Connection,
+    actor: &AuditActor,
+    invitation: &OrganisationInvitation,
+    provider: Option<&str>,
+) -> Result<()> {
+    use crate::schema::user_organisation_permissions::dsl::{
+        organisation_id, permissions, user_id,
+    };
+
+    diesel::delete(organisation_invitations::table.find(invitation.id)).execute(conn)?;
+
+    let previous_permissions: Option<UserPermission> = user_organisation_permissions::table
+        .filter(user_id.eq(actor.user_id))
+        .filter(organisation_id.eq(invitation.organisation_id))
+        .select(permissions)
+        .get_result(conn)
+        .optional()?;
+
+    let mut after = invitation.audit_state();
+    if let Some(provider) = provider {
+        after["provider"] = serde_json::json!(provider);
+    }
+
+    if let Some(previous_permissions) = previous_permissions {
+        let given_permissions = previous_permissions | invitation.permissions;
+        after["permissions"] = serde_json::json!(given_permissions);
+
+        diesel::update(
+            user_organisation_permissions::table
+                .filter(user_id.eq(actor.user_id))
+                .filter(organisation_id.eq(invitation.organisation_id)),
+        )
+        .set(permissions.eq(given_permissions.bits()))
+        .execute(conn)?;
+
+        NewAuditEvent::new(AuditAction::OrganisationMemberUpdated)
+            .organisation(invitation.organisation_id)
+            .target_user(actor.user_id)
+            .before(serde_json::json!({ "permissions": previous_permissions }))
+            .after(after)
+            .record(conn, actor)?;
+    } else {
+        insert_into(user_organisation_permissions::table)
+            .values((
+                user_id.eq(actor.user_id),
+                organisation_id.eq(invitation.organisation_id),
+                permissions.eq(invitation.permissions.bits()),
+            ))
+            .execute(conn)?;
+
+        NewAuditEvent::new(AuditAction::OrganisationMemberAdded)
+            .organisation(invitation.organisation_id)
+            .target_user(actor.user_id)
+            .after(after)
+            .record(conn, actor)?;
+    }
+
+    Ok(())
+}
+
+/// Loads all the unexpired invitations that have been sent to the user, either directly or to
+/// their verified email.
+fn pending_invitations(
+    conn: &crate::Connection,
+    given_user_id: i32,
+    organisation_names: Option<&[String]>,
+) -> Result<Vec<(OrganisationInvitation, Organisation)>> {
+    use crate::schema::organisation_invitations::dsl::{email, expires_at, user_id};
+
+    let (user_email, email_verified_at): (Option<String>, Option<NaiveDateTime>) = users::table
+        .find(given_user_id)
+        .select((users::email, users::email_verified_at))
+        .get_result(conn)?;
+    let verified_email = email_verified_at.and(user_email);
+
+    let mut query = organisation_invitations::table
+        .inner_join(organisations::table)
+        .filter(expires_at.gt(Utc::now().naive_utc()))
+        // comparisons against a NULL email are never true, so unverified emails match nothing
+        .filter(user_id.eq(given_user_id).or(email.eq(verified_email)))
+        .into_boxed();
+
+    if let Some(organisation_names) = organisation_names {
+        query = query.filter(organisations::name.eq_any(organisation_names));
+    }
+
+    Ok(query.load(conn)?)
+}
+
+impl OrganisationWithPermissions {
+    /// Lists all the unexpired invitations to the organisation, along with the user each was sent
+    /// to.
+    pub async fn invitations(
+        self: Arc<Self>,
+        conn: ConnectionPool,
+    ) -> Result<Vec<(OrganisationInvitation, Option<User>)>> {
+        use crate::schema::organisation_invitations::dsl::{expires_at, organisation_id, user_id};
+
+        if !self.permissions().contains(UserPermission::MANAGE_USERS) {
+            return Err(Error::MissingOrganisationPermission(
+                UserPermission::MANAGE_USERS,
+            ));
+        }
+
+        tokio::task::spawn_blocking(move || {
+            let conn = conn.get()?;
+
+            Ok(organisation_invitations::table
+                .left_join(users::table.on(user_id.eq(users::id.nullable())))
+                .filter(organisation_id.eq(self.organisation().id))
+                .filter(expires_at.gt(Utc::now().naive_utc()))
+                .select((
+                    organisation_invitations::all_columns,
+                    users::all_columns.nullable(),
+                ))
+                .load(&conn)?)
+        })
+        .await?
+    }
+
+    /// Invites a user to the organisation, replacing any invitation they've already been sent.
+    pub async fn invite(
+        self: Arc<Self>,
+        conn: ConnectionPool,
+        actor: AuditActor,
+        invitee: Invitee,
+        given_permissions: UserPermission,
+        given_expires_at: NaiveDateTime,
+    ) -> Result<OrganisationInvitation> {
+        use crate::schema::organisation_invitations::dsl::{
+            email, expires_at, invited_by, organisation_id, permissions, user_id, uuid,
+        };
+
+        if !self.permissions().contains(UserPermission::MANAGE_USERS) {
+            return Err(Error::MissingOrganisationPermission(
+                UserPermission::MANAGE_USERS,
+            ));
+        }
+
+        self.permissions()
+            .check_can_change(UserPermission::empty(), given_permissions)?;
+
+        tokio::task::spawn_blocking(move || {
+            let conn = conn.get()?;
+
+            conn.transaction::<_, crate::Error, _>(|| {
+                let (given_user_id, given_email) = match invitee {
+                    Invitee::User(v) => (Some(v), None),
+                    Invitee::Email(v) => (None, Some(v)),
+                };
+
+                if let Some(given_user_id) = given_user_id {
+                    let existing_member = user_organisation_permissions::table
+                        .filter(user_organisation_permissions::user_id.eq(given_user_id))
+                        .filter(
+                            user_organisation_permissions::organisation_id
+                                .eq(self.organisation().id),
+                        )
+                        .count()
+                        .get_result::<i64>(&conn)?;
+
+                    if existing_member > 0 {
+                        return Err(Error::AlreadyMember);
+                    }
+                }
+
+                diesel::delete(
+                    organisation_invitations::table
+                        .filter(organisation_id.eq(self.organisation().id))
+                        .filter(user_id.eq(given_user_id).or(email.eq(&given_email))),
+                )
+                .execute(&conn)?;
+
+                let generated_uuid = SqlUuid::random();
+
+                insert_into(organisation_invitations::table)
+                    .values((
+                        uuid.eq(generated_uuid),
+                        organisation_id.eq(self.organisation().id),
+                        invited_by.eq(actor.user_id),
+                        user_id.eq(given_user_id),
+                        email.eq(&given_email),
+                        permissions.eq(given_permissions.bits()),
+                        expires_at.eq(given_expires_at),
+                    ))
+                    .execute(&conn)?;
+
+                let invitation: OrganisationInvitation = organisation_invitations::table
+                    .filter(uuid.eq(generated_uuid))
+                    .get_result(&conn)?;
+
+                let mut event = NewAuditEvent::new(AuditAction::OrganisationInvitationCreated)
+                    .organisation(invitation.organisation_id)
+                    .after(invitation.audit_state());
+
+                if let Some(given_user_id) = given_user_id {
+                    event = event.target_user(given_user_id);
+                }
+
+                event.record(&conn, &actor)?;
+
+                Ok(invitation)
+            })
+        })
+        .await?
+    }
+
+    /// Withdraws an invitation that hasn't yet been responded to.
+    pub async fn revoke_invitation(
+        self: Arc<Self>,
+        conn: ConnectionPool,
+        actor: AuditActor,
+        given_uuid: uuid::Uuid,
+    ) -> Result<()> {
+        use crate::schema::organisation_invitations::dsl::{organisation_id, uuid};
+
+        if !self.permissions().contains(UserPermission::MANAGE_USERS) {
+            return Err(Error::MissingOrganisationPermission(
+                UserPermission::MANAGE_USERS,
+            ));
+        }
+
+        tokio::task::spawn_blocking(move || {
+            let conn = conn.get()?;
+
+            conn.transaction::<_, crate::Error, _>(|| {
+                let invitation: OrganisationInvitation = organisation_invitations::table
+                    .filter(organisation_id.eq(self.organisation().id))
+                    .filter(uuid.eq(SqlUuid(given_uuid)))
+                    .get_result(&conn)
+                    .optional()?
+                    .ok_or(Error::MissingInvitation)?;
+
+                // withdrawing an invitation is still taking away the permissions it would've
+                // granted
+                self.permissions()
+                    .check_can_change(invitation.permissions, UserPermission::empty())?;
+
+                diesel::delete(organisation_invitations::table.find(invitation.id))
+                    .execute(&conn)?;
+
+                let mut event = NewAuditEvent::new(AuditAction::OrganisationInvitationRevoked)
+                    .organisation(invitation.organisation_id)
+                    .before(invitation.audit_state());
+
+                if let Some(given_user_id) = invitation.user_id {
+                    event = event.target_user(given_user_id);
+                }
+
+                event.record(&conn, &actor)?;
+
+                Ok(())
+            })
+        })
+        .await?
+    }
+}
+
+impl User {
+    /// Lists all the unexpired invitations the user has been sent, along with the organisation
+    /// each is for.
+    pub async fn pending_invitations(
+        self: Arc<Self>,
+        conn: ConnectionPool,
+    ) -> Result<Vec<(OrganisationInvitation, Organisation)>> {
+        tokio::task::spawn_blocking(move || {
+            let conn = conn.get()?;
+            pending_invitations(&conn, self.id, None)
+        })
+        .await?
+    }
+
+    /// Accepts or declines one of the user's pending invitations.
+    pub async fn respond_to_invitation(
+        self: Arc<Self>,
+        conn: ConnectionPool,
+        actor: AuditActor,
+        given_uuid: uuid::Uuid,
+        accept: bool,
+    ) -> Result<()> {
+        tokio::task::spawn_blocking(move || {
+            let conn = conn.get()?;
+
+            conn.transaction::<_, crate::Error, _>(|| {
+                let (invitation, _) = pending_invitations(&conn, self.id, None)?
+                    .into_iter()
+                    .find(|(invitation, _)| invitation.uuid.0 == given_uuid)
+                    .ok_or(Error::MissingInvitation)?;
+
+                if accept {
+                    return accept_invitation(&conn, &actor, &invitation, None);
+                }
+
+                diesel::delete(organisation_invitations::table.find(invitation.id))
+                    .execute(&conn)?;
+
+                NewAuditEvent::new(AuditAction::OrganisationInvitationDeclined)
+                    .organisation(invitation.organisation_id)
+                    .target_user(self.id)
+                    .before(invitation.audit_state())
+                    .record(&conn, &actor)?;
+
+                Ok(())
+            })
+        })
+        .await?
+    }
+}
+
+/// Accepts all the pending invitations the user has to the given organisations on their behalf,
+/// used when an identity provider has vouched for the user being part of them.
+pub(crate) fn accept_provider_invitations(
+    conn: &crate::Connection,
+    actor: &AuditActor,
+    provider: &str,
+    organisation_names: &[String],
+) -> Result<()> {
+    if organisation_names.is_empty() {
+        return Ok(());
+    }
+
+    for (invitation, _) in pending_invitations(conn, actor.user_id, Some(organisation_names))? {
+        accept_invitation(conn, actor, &invitation, Some(provider))?;
+    }
+
+    Ok(())
+}
@@ -32,11 +32,20 @@ macro_rules! derive_diesel_json {
     };
 }
 
+pub mod advisories;
+pub mod audit;
 pub mod crates;
+pub mod invitations;
+pub mod login_lockout;
 pub mod organisations;
 pub mod permissions;
+pub mod provider_memberships;
 pub mod schema;
 pub mod server_private_key;
+pub mod teams;
+pub mod transfers;
+pub mod two_factor;
+pub mod user_tokens;
 pub mod users;
 pub mod uuid;
 
@@ -88,6 +97,111 @@ pub fn init(connection_uri: &str) -> Result<ConnectionPool> {
     Ok(Arc::new(pool))
 }
 
+/// An empty in-memory database for tests. Each in-memory SQLite connection is its own database,
+/// so the pool is limited to the single connection the migrations were ran against.
+#[cfg(all(test, feature = "sqlite"))]
+pub(crate) fn test_pool() -> ConnectionPool {
+    let pool = Pool::builder()
+        .max_size(1)
+        .build(ConnectionManager::new(":memory:"))
+        .unwrap();
+
+    embedded_migrations::run(&pool.get().unwrap()).unwrap();
+
+    Arc::new(pool)
+}
+
+/// Registers a user with no password for tests, returning their id.
+#[cfg(all(test, feature = "sqlite"))]
+pub(crate) async fn test_user(conn: &ConnectionPool, username: &str) -> i32 {
+    users::User::register(conn.clone(), username.to_string(), String::new())
+        .await
+        .unwrap();
+
+    users::User::find_by_username(conn.clone(), username.to_string())
+        .await
+        .unwrap()
+        .unwrap()
+        .id
+}
+
+#[cfg(all(test, feature = "sqlite"))]
+pub(crate) fn test_actor(user_id: i32) -> audit::AuditActor {
+    audit::AuditActor {
+        user_id,
+        ip: None,
+        user_agent: None,
+    }
+}
+
+/// Creates the crate `name` in a new organisation `organisation` owned by `owner`.
+#[cfg(all(test, feature = "sqlite"))]
+pub(crate) async fn test_crate(
+    conn: &ConnectionPool,
+    owner: i32,
+    organisation: &str,
+    name: &str,
+) -> Arc<crates::CrateWithPermissions> {
+    organisations::Organisation::create(
+        conn.clone(),
+        organisation.to_string(),
+        String::new(),
+        false,
+        test_actor(owner),
+    )
+    .await
+    .unwrap();
+
+    Arc::new(
+        crates::Crate::create(
+            conn.clone(),
+            test_actor(owner),
+            organisation.to_string(),
+            name.to_string(),
+        )
+        .await
+        .unwrap(),
+    )
+}
+
+/// Publishes `version` of the crate as `user_id`, stored under a random reference.
+#[cfg(all(test, feature = "sqlite"))]
+pub(crate) async fn test_publish(
+    conn: &ConnectionPool,
+    crate_: &Arc<crates::CrateWithPermissions>,
+    user_id: i32,
+    version: &str,
+) {
+    use std::str::FromStr;
+
+    crate_
+        .clone()
+        .publish_version(
+            conn.clone(),
+            test_actor(user_id),
+            chartered_fs::FileReference::from_str(&format!("local:{}", uuid::SqlUuid::random().0))
+                .unwrap(),
+            String::new(),
+            0,
+            chartered_types::cargo::CrateVersion {
+                name: crate_.crate_.name.clone().into(),
+                vers: version.to_string().into(),
+                deps: Vec::new(),
+                features: chartered_types::cargo::CrateFeatures(std::collections::BTreeMap::new()),
+                links: None,
+            },
+            chartered_types::cargo::CrateVersionMetadata {
+                description: None,
+                readme: None,
+                repository: None,
+                homepage: None,
+                documentation: None,
+            },
+        )
+        .await
+        .unwrap();
+}
+
 #[cfg(feature = "sqlite")]
 pub fn parse_connection_uri(connection_uri: &str) -> Result<&str> {
     if connection_uri.starts_with("sqlite://") {
@@ -133,17 +247,46 @@ pub enum Error {
     MissingCrate,
     /// The requested organisation does not exist
     MissingOrganisation,
+    /// The requested version does not exist
+    MissingVersion,
+    /// The requested team does not exist
+    MissingTeam,
     /// Version {0} already exists for this crate
     VersionConflict(String),
     /// Username is already taken
     UsernameTaken,
+    /// A team with that name already exists in this organisation
+    TeamNameTaken,
+    /// `{0}` is not a valid version requirement
+    InvalidVersionRequirement(String),
+    /// An advisory needs a message explaining why it was raised
+    MissingAdvisoryMessage,
+    /// Two-factor authentication is already enabled for this account
+    TwoFactorAlreadyEnabled,
+    /// The requested invitation does not exist or has expired
+    MissingInvitation,
+    /// That user is already a member of this organisation
+    AlreadyMember,
+    /// That user isn't a member of this organisation, they need to accept an invitation to it first
+    NotOrganisationMember,
+    /// An organisation with that name already exists, or previously existed
+    OrganisationNameTaken,
+    /// A crate with that name already exists in the organisation
+    CrateNameTaken,
+    /// The crate already belongs to that organisation
+    AlreadyInOrganisation,
+    /// The organisation still has {0} crates, they must be moved to another organisation before it can be deleted
+    OrganisationHasCrates(i64),
 }
 
 impl Error {
     #[must_use]
     pub fn status_code(&self) -> http::StatusCode {
         match self {
-            Self::MissingCrate => http::StatusCode::NOT_FOUND,
+            Self::MissingCrate
+            | Self::MissingVersion
+            | Self::MissingTeam
+            | Self::MissingInvitation => http::StatusCode::NOT_FOUND,
             Self::MissingCratePermission(v) | Self::MissingOrganisationPermission(v)
                 if v.contains(crate::permissions::UserPermission::VISIBLE) =>
             {
@@ -152,7 +295,18 @@ impl Error {
             Self::MissingCratePermission(_) | Self::MissingOrganisationPermission(_) => {
                 http::StatusCode::FORBIDDEN
             }
-            Self::KeyParse(_) | Self::VersionConflict(_) => http::StatusCode::BAD_REQUEST,
+            Self::KeyParse(_)
+            | Self::VersionConflict(_)
+            | Self::InvalidVersionRequirement(_)
+            | Self::MissingAdvisoryMessage
+            | Self::TeamNameTaken
+            | Self::TwoFactorAlreadyEnabled
+            | Self::AlreadyMember
+            | Self::NotOrganisationMember
+            | Self::OrganisationNameTaken
+            | Self::CrateNameTaken
+            | Self::AlreadyInOrganisation
+            | Self::OrganisationHasCrates(_) => http::StatusCode::BAD_REQUEST,
             _ => http::StatusCode::INTERNAL_SERVER_ERROR,
         }
     }
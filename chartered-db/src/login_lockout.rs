@@ -0,0 +1,183 @@
+//! Per-account counters of failed login attempts, once a user has failed to login too many times
+//! in a row their account is locked for a period that doubles with every further failure.
+//!
+//! Successful logins, and password resets, clear the counter.
+
+use crate::{
+    audit::{AuditAction, AuditActor, NewAuditEvent},
+    schema::users,
+    users::User,
+    ConnectionPool, Result,
+};
+use chrono::{Duration, NaiveDateTime, Utc};
+use diesel::prelude::*;
+use std::sync::Arc;
+
+/// How many failed attempts are allowed before an account is locked, and how long for.
+#[derive(Clone, Copy, Debug)]
+pub struct LockoutPolicy {
+    /// Number of consecutive failures before the account is locked, locking is disabled if this
+    /// is 0
+    pub threshold: i32,
+    /// How long the account is locked for once `threshold` is hit
+    pub base: Duration,
+    /// The longest the account can be locked for, regardless of how many failures there have been
+    pub max: Duration,
+}
+
+impl LockoutPolicy {
+    /// How long to lock an account for after the given number of consecutive failures, if at all.
+    #[must_use]
+    pub fn lockout_for(&self, attempts: i32) -> Option<Duration> {
+        if self.threshold <= 0 || attempts < self.threshold {
+            return None;
+        }
+
+        let exponent = (attempts - self.threshold).min(30);
+        let seconds = self
+            .base
+            .num_seconds()
+            .saturating_mul(1 << exponent)
+            .min(self.max.num_seconds());
+
+        Some(Duration::seconds(seconds))
+    }
+}
+
+impl User {
+    /// Returns when the user's lockout ends if they're currently locked out.
+    #[must_use]
+    pub fn locked_out_until(&self) -> Option<NaiveDateTime> {
+        self.locked_until
+            .filter(|locked_until| *locked_until > Utc::now().naive_utc())
+    }
+
+    /// Records a failed login attempt against the user, locking their account if they've hit the
+    /// policy's threshold. Returns when the lockout ends if the account was locked.
+    pub async fn record_failed_login(
+        self: Arc<Self>,
+        conn: ConnectionPool,
+        actor: AuditActor,
+        policy: LockoutPolicy,
+    ) -> Result<Option<NaiveDateTime>> {
+        tokio::task::spawn_blocking(move || {
+            let conn = conn.get()?;
+
+            conn.transaction::<_, crate::Error, _>(|| {
+                diesel::update(users::table.filter(users::id.eq(self.id)))
+                    .set(users::failed_login_attempts.eq(users::failed_login_attempts + 1))
+                    .execute(&conn)?;
+
+                let attempts: i32 = users::table
+                    .filter(users::id.eq(self.id))
+                    .select(users::failed_login_attempts)
+                    .get_result(&conn)?;
+
+                let Some(lockout) = policy.lockout_for(attempts) else {
+                    return Ok(None);
+                };
+
+                let locked_until = (Utc::now() + lockout).naive_utc();
+
+                diesel::update(users::table.filter(users::id.eq(self.id)))
+                    .set(users::locked_until.eq(locked_until))
+                    .execute(&conn)?;
+
+                NewAuditEvent::new(AuditAction::AccountLocked)
+                    .target_user(self.id)
+                    .after(serde_json::json!({
+                        "failed_login_attempts": attempts,
+                        "lockout_seconds": lockout.num_seconds(),
+                    }))
+                    .record(&conn, &actor)?;
+
+                Ok(Some(locked_until))
+            })
+        })
+        .await?
+    }
+
+    /// Resets the user's failed login counter after they've successfully logged in.
+    pub async fn clear_failed_logins(self: Arc<Self>, conn: ConnectionPool) -> Result<()> {
+        if self.failed_login_attempts == 0 && self.locked_until.is_none() {
+            return Ok(());
+        }
+
+        tokio::task::spawn_blocking(move || {
+            let conn = conn.get()?;
+
+            diesel::update(users::table.filter(users::id.eq(self.id)))
+                .set((
+                    users::failed_login_attempts.eq(0),
+                    users::locked_until.eq(None::<NaiveDateTime>),
+                ))
+                .execute(&conn)?;
+
+            Ok(())
+        })
+        .await?
+    }
+}
+
+#[cfg(all(test, feature = "sqlite"))]
+mod tests {
+    use super::LockoutPolicy;
+    use crate::{audit::AuditAction, test_actor, test_pool, test_user, users::User};
+    use chrono::Duration;
+    use std::sync::Arc;
+
+    #[tokio::test]
+    async fn lockout_is_audited() {
+        let conn = test_pool();
+        let user_id = test_user(&conn, "jordan").await;
+        let user = Arc::new(
+            User::find_by_username(conn.clone(), "jordan".to_string())
+                .await
+                .unwrap()
+                .unwrap(),
+        );
+
+        let policy = LockoutPolicy {
+            threshold: 2,
+            base: Duration::seconds(30),
+            max: Duration::seconds(3600),
+        };
+
+        let locked_until = user
+            .clone()
+            .record_failed_login(conn.clone(), test_actor(user_id), policy)
+            .await
+            .unwrap();
+        assert!(locked_until.is_none());
+        assert!(user
+            .clone()
+            .audit_events(conn.clone(), 0, 10)
+            .await
+            .unwrap()
+            .is_empty());
+
+        let locked_until = user
+            .clone()
+            .record_failed_login(conn.clone(), test_actor(user_id), policy)
+            .await
+            .unwrap();
+        assert!(locked_until.is_some());
+
+        let reloaded = User::find_by_username(conn.clone(), "jordan".to_string())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(reloaded.locked_out_until(), locked_until);
+
+        // lockouts aren't scoped to any organisation, so the user's own audit log is the only
+        // place they can be seen
+        let events = user.audit_events(conn, 0, 10).await.unwrap();
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].event.action, AuditAction::AccountLocked);
+        assert_eq!(events[0].event.organisation_id, None);
+        assert_eq!(
+            events[0].target_user.as_ref().map(|user| user.id),
+            Some(user_id)
+        );
+    }
+}
@@ -1,32 +1,96 @@
 use crate::{
-    coalesce, crates::Crate, permissions::UserPermission, users::User, BitwiseExpressionMethods,
-    Error,
+    audit::{AuditAction, AuditActor, NewAuditEvent},
+    coalesce,
+    crates::Crate,
+    permissions::UserPermission,
+    users::User,
+    BitwiseExpressionMethods, Error,
 };
 
 use super::{
-    schema::{organisations, user_organisation_permissions, users},
+    schema::{
+        audit_events, crate_aliases, crates, organisation_aliases, organisation_invitations,
+        organisations, team_crate_permissions, team_members, team_organisation_permissions, teams,
+        user_organisation_permissions, users,
+    },
     uuid::SqlUuid,
     ConnectionPool, Result,
 };
 
-use diesel::{prelude::*, Associations, Identifiable, Queryable};
+use diesel::{
+    prelude::*,
+    result::{DatabaseErrorKind, Error as DieselError},
+    Associations, Identifiable, Queryable,
+};
 
 use std::sync::Arc;
 
 macro_rules! select_permissions {
-    () => {
+    ($user_id:ident) => {
         coalesce(
             crate::schema::user_organisation_permissions::permissions.nullable(),
             0,
         )
+        .bitwise_or(diesel::dsl::sql::<diesel::sql_types::Integer>(
+            &crate::teams::team_permissions_sql(
+                $user_id,
+                "team_organisation_permissions",
+                "organisation_id",
+                "organisations.id",
+            ),
+        ))
         .bitwise_or(diesel::dsl::sql::<diesel::sql_types::Integer>(&format!(
             "COALESCE(CASE WHEN {} THEN {} ELSE 0 END, 0)",
             "public",
             UserPermission::VISIBLE.bits(),
         )))
+        // operators are applied left to right, so the mask applies to everything ORed above
+        .bitwise_and(diesel::dsl::sql::<diesel::sql_types::Integer>(
+            &crate::two_factor::two_factor_permissions_mask_sql($user_id),
+        ))
     };
 }
 
+/// Maps a name an organisation was previously known by to its current name, so registries and
+/// links using the old name keep working after a rename. Names that aren't an alias are returned
+/// as they are.
+pub(crate) fn resolve_alias(conn: &crate::Connection, given_name: String) -> QueryResult<String> {
+    Ok(organisation_aliases::table
+        .inner_join(organisations::table)
+        .filter(organisation_aliases::name.eq(&given_name))
+        .select(organisations::name)
+        .get_result(conn)
+        .optional()?
+        .unwrap_or(given_name))
+}
+
+/// Looks up an organisation by its current name, along with the permissions the user has on it.
+pub(crate) fn find_with_permissions(
+    conn: &crate::Connection,
+    requesting_user_id: i32,
+    given_name: &str,
+) -> Result<OrganisationWithPermissions> {
+    let (permissions, organisation) = organisations::table
+        .left_join(
+            user_organisation_permissions::table.on(user_organisation_permissions::user_id
+                .eq(requesting_user_id)
+                .and(user_organisation_permissions::organisation_id.eq(organisations::dsl::id))),
+        )
+        .filter(organisations::name.eq(given_name))
+        .select((
+            select_permissions!(requesting_user_id),
+            organisations::all_columns,
+        ))
+        .get_result(conn)
+        .optional()?
+        .ok_or(Error::MissingOrganisation)?;
+
+    Ok(OrganisationWithPermissions {
+        organisation,
+        permissions,
+    })
+}
+
 #[derive(Identifiable, Queryable, Associations, PartialEq, Eq, Hash, Debug)]
 pub struct Organisation {
     pub id: i32,
@@ -34,6 +98,7 @@ pub struct Organisation {
     pub name: String,
     pub description: String,
     pub public: bool,
+    pub require_two_factor: bool,
 }
 
 impl Organisation {
@@ -51,7 +116,7 @@ impl Organisation {
                         )),
                 )
                 .filter(
-                    select_permissions!()
+                    select_permissions!(requesting_user_id)
                         .bitwise_and(UserPermission::VISIBLE.bits())
                         .eq(UserPermission::VISIBLE.bits()),
                 )
@@ -67,30 +132,11 @@ impl Organisation {
         requesting_user_id: i32,
         given_name: String,
     ) -> Result<OrganisationWithPermissions> {
-        use organisations::dsl::name as organisation_name;
-
         tokio::task::spawn_blocking(move || {
             let conn = conn.get()?;
+            let given_name = resolve_alias(&conn, given_name)?;
 
-            let (permissions, organisation) = organisations::table
-                .left_join(
-                    user_organisation_permissions::table.on(user_organisation_permissions::user_id
-                        .eq(requesting_user_id)
-                        .and(
-                            user_organisation_permissions::organisation_id
-                                .eq(organisations::dsl::id),
-                        )),
-                )
-                .filter(organisation_name.eq(given_name))
-                .select((select_permissions!(), organisations::all_columns))
-                .get_result(&conn)
-                .optional()?
-                .ok_or(Error::MissingOrganisation)?;
-
-            Ok(OrganisationWithPermissions {
-                organisation,
-                permissions,
-            })
+            find_with_permissions(&conn, requesting_user_id, &given_name)
         })
         .await?
     }
@@ -100,7 +146,7 @@ impl Organisation {
         given_name: String,
         given_description: String,
         given_public: bool,
-        requesting_user_id: i32,
+        actor: AuditActor,
     ) -> Result<()> {
         tokio::task::spawn_blocking(move || {
             let conn = conn.get()?;
@@ -109,16 +155,35 @@ impl Organisation {
                 use organisations::dsl::{description, id, name, public, uuid};
                 use user_organisation_permissions::dsl::{organisation_id, permissions, user_id};
 
+                // names organisations were previously known by are reserved so they can't be
+                // taken over by anyone else
+                let aliased = organisation_aliases::table
+                    .filter(organisation_aliases::name.eq(&given_name))
+                    .count()
+                    .get_result::<i64>(&conn)?;
+
+                if aliased > 0 {
+                    return Err(Error::OrganisationNameTaken);
+                }
+
                 let generated_uuid = SqlUuid::random();
 
-                diesel::insert_into(organisations::table)
+                let res = diesel::insert_into(organisations::table)
                     .values((
                         uuid.eq(generated_uuid),
-                        name.eq(given_name),
-                        description.eq(given_description),
+                        name.eq(&given_name),
+                        description.eq(&given_description),
                         public.eq(given_public),
                     ))
-                    .execute(&conn)?;
+                    .execute(&conn);
+
+                match res {
+                    Ok(_) => {}
+                    Err(DieselError::DatabaseError(DatabaseErrorKind::UniqueViolation, _)) => {
+                        return Err(Error::OrganisationNameTaken);
+                    }
+                    Err(e) => return Err(e.into()),
+                }
 
                 let inserted_id: i32 = organisations::table
                     .filter(uuid.eq(generated_uuid))
@@ -127,12 +192,21 @@ impl Organisation {
 
                 diesel::insert_into(user_organisation_permissions::table)
                     .values((
-                        user_id.eq(requesting_user_id),
+                        user_id.eq(actor.user_id),
                         organisation_id.eq(inserted_id),
                         permissions.eq(UserPermission::all().bits()),
                     ))
                     .execute(&conn)?;
 
+                NewAuditEvent::new(AuditAction::OrganisationCreated)
+                    .organisation(inserted_id)
+                    .after(serde_json::json!({
+                        "name": given_name,
+                        "description": given_description,
+                        "public": given_public,
+                    }))
+                    .record(&conn, &actor)?;
+
                 Ok(())
             })?;
 
@@ -204,6 +278,7 @@ impl OrganisationWithPermissions {
     pub async fn update_permissions(
         self: Arc<Self>,
         conn: ConnectionPool,
+        actor: AuditActor,
         given_user_id: i32,
         given_permissions: UserPermission,
     ) -> Result<usize> {
@@ -218,23 +293,48 @@ impl OrganisationWithPermissions {
 
             let conn = conn.get()?;
 
-            Ok(diesel::update(
-                user_organisation_permissions
+            conn.transaction::<_, crate::Error, _>(|| {
+                let previous_permissions = user_organisation_permissions
                     .filter(user_id.eq(given_user_id))
-                    .filter(organisation_id.eq(self.organisation.id)),
-            )
-            .set(permissions.eq(given_permissions.bits()))
-            .execute(&conn)?)
+                    .filter(organisation_id.eq(self.organisation.id))
+                    .select(permissions)
+                    .get_result::<UserPermission>(&conn)
+                    .optional()?;
+
+                let Some(previous_permissions) = previous_permissions else {
+                    return Ok(0);
+                };
+
+                self.permissions
+                    .check_can_change(previous_permissions, given_permissions)?;
+
+                let rows = diesel::update(
+                    user_organisation_permissions
+                        .filter(user_id.eq(given_user_id))
+                        .filter(organisation_id.eq(self.organisation.id)),
+                )
+                .set(permissions.eq(given_permissions.bits()))
+                .execute(&conn)?;
+
+                NewAuditEvent::new(AuditAction::OrganisationMemberUpdated)
+                    .organisation(self.organisation.id)
+                    .target_user(given_user_id)
+                    .before(serde_json::json!({ "permissions": previous_permissions }))
+                    .after(serde_json::json!({ "permissions": given_permissions }))
+                    .record(&conn, &actor)?;
+
+                Ok(rows)
+            })
         })
         .await?
     }
 
-    pub async fn insert_permissions(
+    pub async fn delete_member(
         self: Arc<Self>,
         conn: ConnectionPool,
+        actor: AuditActor,
         given_user_id: i32,
-        given_permissions: UserPermission,
-    ) -> Result<usize> {
+    ) -> Result<()> {
         if !self.permissions.contains(UserPermission::MANAGE_USERS) {
             return Err(Error::MissingCratePermission(UserPermission::MANAGE_USERS));
         }
@@ -246,42 +346,552 @@ impl OrganisationWithPermissions {
 
             let conn = conn.get()?;
 
-            Ok(diesel::insert_into(user_organisation_permissions)
-                .values((
-                    user_id.eq(given_user_id),
-                    organisation_id.eq(self.organisation.id),
-                    permissions.eq(given_permissions.bits()),
-                ))
-                .execute(&conn)?)
+            conn.transaction::<_, crate::Error, _>(|| {
+                let previous_permissions = user_organisation_permissions
+                    .filter(user_id.eq(given_user_id))
+                    .filter(organisation_id.eq(self.organisation.id))
+                    .select(permissions)
+                    .get_result::<UserPermission>(&conn)
+                    .optional()?;
+
+                let Some(previous_permissions) = previous_permissions else {
+                    return Ok(());
+                };
+
+                self.permissions
+                    .check_can_change(previous_permissions, UserPermission::empty())?;
+
+                diesel::delete(
+                    user_organisation_permissions
+                        .filter(user_id.eq(given_user_id))
+                        .filter(organisation_id.eq(self.organisation.id)),
+                )
+                .execute(&conn)?;
+
+                NewAuditEvent::new(AuditAction::OrganisationMemberRemoved)
+                    .organisation(self.organisation.id)
+                    .target_user(given_user_id)
+                    .before(serde_json::json!({ "permissions": previous_permissions }))
+                    .record(&conn, &actor)?;
+
+                // teams belong to the organisation, so the user loses their place in them (and
+                // any permissions they grant on the organisation's crates) along with it
+                crate::teams::remove_organisation_memberships(
+                    &conn,
+                    &actor,
+                    self.organisation.id,
+                    given_user_id,
+                )?;
+
+                Ok(())
+            })
         })
         .await?
     }
 
-    pub async fn delete_member(
+    /// Updates the organisation's name, description and visibility, leaving any that aren't given
+    /// as they are. The organisation's previous name is kept as an alias when it's renamed so
+    /// existing registry configs continue to work.
+    pub async fn update(
         self: Arc<Self>,
         conn: ConnectionPool,
-        given_user_id: i32,
+        actor: AuditActor,
+        given_name: Option<String>,
+        given_description: Option<String>,
+        given_public: Option<bool>,
     ) -> Result<()> {
-        if !self.permissions.contains(UserPermission::MANAGE_USERS) {
-            return Err(Error::MissingCratePermission(UserPermission::MANAGE_USERS));
+        if !self
+            .permissions
+            .contains(UserPermission::MANAGE_ORGANISATION)
+        {
+            return Err(Error::MissingOrganisationPermission(
+                UserPermission::MANAGE_ORGANISATION,
+            ));
         }
 
         tokio::task::spawn_blocking(move || {
-            use crate::schema::user_organisation_permissions::dsl::{
-                organisation_id, user_id, user_organisation_permissions,
-            };
+            let conn = conn.get()?;
+
+            conn.transaction::<_, crate::Error, _>(|| {
+                let organisation = &self.organisation;
+                let mut before = serde_json::Map::new();
+                let mut after = serde_json::Map::new();
+
+                if let Some(given_name) = given_name.filter(|v| *v != organisation.name) {
+                    rename(&conn, organisation, &given_name)?;
+                    before.insert("name".into(), organisation.name.clone().into());
+                    after.insert("name".into(), given_name.into());
+                }
+
+                if let Some(given_description) =
+                    given_description.filter(|v| *v != organisation.description)
+                {
+                    diesel::update(organisations::table.find(organisation.id))
+                        .set(organisations::description.eq(&given_description))
+                        .execute(&conn)?;
+                    before.insert(
+                        "description".into(),
+                        organisation.description.clone().into(),
+                    );
+                    after.insert("description".into(), given_description.into());
+                }
+
+                if let Some(given_public) = given_public.filter(|v| *v != organisation.public) {
+                    diesel::update(organisations::table.find(organisation.id))
+                        .set(organisations::public.eq(given_public))
+                        .execute(&conn)?;
+                    before.insert("public".into(), organisation.public.into());
+                    after.insert("public".into(), given_public.into());
+                }
+
+                if after.is_empty() {
+                    return Ok(());
+                }
+
+                NewAuditEvent::new(AuditAction::OrganisationUpdated)
+                    .organisation(organisation.id)
+                    .before(before.into())
+                    .after(after.into())
+                    .record(&conn, &actor)?;
+
+                Ok(())
+            })
+        })
+        .await?
+    }
+
+    /// Deletes the organisation along with its members, teams, pending invitations and aliases.
+    /// Crates have to be moved to another organisation first, so this refuses to delete an
+    /// organisation that still has any.
+    ///
+    /// The organisation's audit log is kept, but is no longer associated with it.
+    pub async fn delete(self: Arc<Self>, conn: ConnectionPool, actor: AuditActor) -> Result<()> {
+        if !self
+            .permissions
+            .contains(UserPermission::MANAGE_ORGANISATION)
+        {
+            return Err(Error::MissingOrganisationPermission(
+                UserPermission::MANAGE_ORGANISATION,
+            ));
+        }
 
+        tokio::task::spawn_blocking(move || {
             let conn = conn.get()?;
 
-            diesel::delete(
-                user_organisation_permissions
-                    .filter(user_id.eq(given_user_id))
-                    .filter(organisation_id.eq(self.organisation.id)),
-            )
-            .execute(&conn)?;
+            conn.transaction::<_, crate::Error, _>(|| {
+                let organisation = &self.organisation;
 
-            Ok(())
+                let crate_count = crates::table
+                    .filter(crates::organisation_id.eq(organisation.id))
+                    .count()
+                    .get_result::<i64>(&conn)?;
+
+                if crate_count > 0 {
+                    return Err(Error::OrganisationHasCrates(crate_count));
+                }
+
+                let team_ids: Vec<i32> = teams::table
+                    .filter(teams::organisation_id.eq(organisation.id))
+                    .select(teams::id)
+                    .load(&conn)?;
+
+                diesel::delete(team_members::table.filter(team_members::team_id.eq_any(&team_ids)))
+                    .execute(&conn)?;
+                diesel::delete(
+                    team_crate_permissions::table
+                        .filter(team_crate_permissions::team_id.eq_any(&team_ids)),
+                )
+                .execute(&conn)?;
+                diesel::delete(
+                    team_organisation_permissions::table.filter(
+                        team_organisation_permissions::team_id
+                            .eq_any(&team_ids)
+                            .or(team_organisation_permissions::organisation_id.eq(organisation.id)),
+                    ),
+                )
+                .execute(&conn)?;
+                diesel::delete(teams::table.filter(teams::organisation_id.eq(organisation.id)))
+                    .execute(&conn)?;
+                diesel::delete(
+                    user_organisation_permissions::table
+                        .filter(user_organisation_permissions::organisation_id.eq(organisation.id)),
+                )
+                .execute(&conn)?;
+                diesel::delete(
+                    organisation_invitations::table
+                        .filter(organisation_invitations::organisation_id.eq(organisation.id)),
+                )
+                .execute(&conn)?;
+                diesel::delete(
+                    organisation_aliases::table
+                        .filter(organisation_aliases::organisation_id.eq(organisation.id)),
+                )
+                .execute(&conn)?;
+                diesel::delete(
+                    crate_aliases::table.filter(crate_aliases::organisation_id.eq(organisation.id)),
+                )
+                .execute(&conn)?;
+                diesel::update(
+                    audit_events::table.filter(audit_events::organisation_id.eq(organisation.id)),
+                )
+                .set(audit_events::organisation_id.eq(None::<i32>))
+                .execute(&conn)?;
+                diesel::delete(organisations::table.find(organisation.id)).execute(&conn)?;
+
+                NewAuditEvent::new(AuditAction::OrganisationDeleted)
+                    .before(serde_json::json!({
+                        "name": organisation.name,
+                        "description": organisation.description,
+                        "public": organisation.public,
+                    }))
+                    .record(&conn, &actor)?;
+
+                Ok(())
+            })
         })
         .await?
     }
 }
+
+/// Renames the organisation, keeping its old name as an alias. If the new name is one of the
+/// organisation's own aliases it's reclaimed, otherwise aliases are reserved for the organisation
+/// that previously had the name.
+fn rename(conn: &crate::Connection, organisation: &Organisation, given_name: &str) -> Result<()> {
+    let alias_owner = organisation_aliases::table
+        .filter(organisation_aliases::name.eq(given_name))
+        .select(organisation_aliases::organisation_id)
+        .get_result::<i32>(conn)
+        .optional()?;
+
+    match alias_owner {
+        Some(owner) if owner == organisation.id => {
+            diesel::delete(
+                organisation_aliases::table.filter(organisation_aliases::name.eq(given_name)),
+            )
+            .execute(conn)?;
+        }
+        Some(_) => return Err(Error::OrganisationNameTaken),
+        None => {}
+    }
+
+    let res = diesel::update(organisations::table.find(organisation.id))
+        .set(organisations::name.eq(given_name))
+        .execute(conn);
+
+    match res {
+        Ok(_) => {}
+        Err(DieselError::DatabaseError(DatabaseErrorKind::UniqueViolation, _)) => {
+            return Err(Error::OrganisationNameTaken);
+        }
+        Err(e) => return Err(e.into()),
+    }
+
+    diesel::insert_into(organisation_aliases::table)
+        .values((
+            organisation_aliases::name.eq(&organisation.name),
+            organisation_aliases::organisation_id.eq(organisation.id),
+        ))
+        .execute(conn)?;
+
+    Ok(())
+}
+
+#[cfg(all(test, feature = "sqlite"))]
+mod tests {
+    use super::{user_organisation_permissions, Organisation};
+    use crate::{
+        invitations::Invitee, permissions::UserPermission, schema::team_members, test_actor,
+        test_user,
+    };
+    use diesel::prelude::*;
+    use std::sync::Arc;
+
+    #[tokio::test]
+    async fn delete_member_removes_team_memberships() {
+        let conn = crate::test_pool();
+        let owner = test_user(&conn, "owner").await;
+        let member = test_user(&conn, "member").await;
+
+        Organisation::create(
+            conn.clone(),
+            "org".into(),
+            String::new(),
+            false,
+            test_actor(owner),
+        )
+        .await
+        .unwrap();
+        let organisation = Arc::new(
+            Organisation::find_by_name(conn.clone(), owner, "org".into())
+                .await
+                .unwrap(),
+        );
+
+        diesel::insert_into(user_organisation_permissions::table)
+            .values((
+                user_organisation_permissions::user_id.eq(member),
+                user_organisation_permissions::organisation_id.eq(organisation.organisation.id),
+                user_organisation_permissions::permissions.eq(UserPermission::VISIBLE.bits()),
+            ))
+            .execute(&conn.get().unwrap())
+            .unwrap();
+
+        let team = organisation
+            .clone()
+            .create_team(
+                conn.clone(),
+                test_actor(owner),
+                "team".into(),
+                String::new(),
+            )
+            .await
+            .unwrap();
+        let team = Arc::new(
+            organisation
+                .clone()
+                .team(conn.clone(), team.name)
+                .await
+                .unwrap(),
+        );
+        team.clone()
+            .update_organisation_permissions(conn.clone(), test_actor(owner), UserPermission::all())
+            .await
+            .unwrap();
+        team.clone()
+            .add_member(conn.clone(), test_actor(owner), member)
+            .await
+            .unwrap();
+
+        // only members of the organisation can be added to its teams
+        let outsider = test_user(&conn, "outsider").await;
+        assert!(matches!(
+            team.clone()
+                .add_member(conn.clone(), test_actor(owner), outsider)
+                .await,
+            Err(crate::Error::NotOrganisationMember)
+        ));
+
+        let permissions = Organisation::find_by_name(conn.clone(), member, "org".into())
+            .await
+            .unwrap()
+            .permissions();
+        assert_eq!(permissions, UserPermission::all());
+
+        organisation
+            .clone()
+            .delete_member(conn.clone(), test_actor(owner), member)
+            .await
+            .unwrap();
+
+        let memberships: i64 = team_members::table
+            .filter(team_members::user_id.eq(member))
+            .count()
+            .get_result(&conn.get().unwrap())
+            .unwrap();
+        assert_eq!(memberships, 0);
+
+        // a membership left behind by anything else still doesn't grant the team's permissions
+        diesel::insert_into(team_members::table)
+            .values((
+                team_members::team_id.eq(team.team.id),
+                team_members::user_id.eq(member),
+            ))
+            .execute(&conn.get().unwrap())
+            .unwrap();
+
+        let permissions = Organisation::find_by_name(conn.clone(), member, "org".into())
+            .await
+            .unwrap()
+            .permissions();
+        assert!(permissions.is_empty());
+    }
+
+    #[tokio::test]
+    async fn cannot_change_permissions_not_held() {
+        let conn = crate::test_pool();
+        let owner = test_user(&conn, "owner").await;
+        let admin = test_user(&conn, "admin").await;
+        let member = test_user(&conn, "member").await;
+        let outsider = test_user(&conn, "outsider").await;
+
+        Organisation::create(
+            conn.clone(),
+            "org".into(),
+            String::new(),
+            false,
+            test_actor(owner),
+        )
+        .await
+        .unwrap();
+        let owner_organisation = Arc::new(
+            Organisation::find_by_name(conn.clone(), owner, "org".into())
+                .await
+                .unwrap(),
+        );
+
+        for (given_user_id, given_permissions) in [
+            (
+                admin,
+                UserPermission::VISIBLE | UserPermission::MANAGE_USERS,
+            ),
+            (member, UserPermission::VISIBLE),
+        ] {
+            diesel::insert_into(user_organisation_permissions::table)
+                .values((
+                    user_organisation_permissions::user_id.eq(given_user_id),
+                    user_organisation_permissions::organisation_id
+                        .eq(owner_organisation.organisation.id),
+                    user_organisation_permissions::permissions.eq(given_permissions.bits()),
+                ))
+                .execute(&conn.get().unwrap())
+                .unwrap();
+        }
+
+        let organisation = Arc::new(
+            Organisation::find_by_name(conn.clone(), admin, "org".into())
+                .await
+                .unwrap(),
+        );
+        let is_missing_manage_organisation = |res: crate::Result<_>| {
+            matches!(
+                res,
+                Err(crate::Error::MissingOrganisationPermission(v))
+                    if v == UserPermission::MANAGE_ORGANISATION
+            )
+        };
+
+        // granting permissions the admin holds is fine, anything else isn't
+        assert_eq!(
+            organisation
+                .clone()
+                .update_permissions(
+                    conn.clone(),
+                    test_actor(admin),
+                    member,
+                    UserPermission::VISIBLE | UserPermission::MANAGE_USERS,
+                )
+                .await
+                .unwrap(),
+            1
+        );
+        assert!(is_missing_manage_organisation(
+            organisation
+                .clone()
+                .update_permissions(
+                    conn.clone(),
+                    test_actor(admin),
+                    member,
+                    UserPermission::VISIBLE | UserPermission::MANAGE_ORGANISATION,
+                )
+                .await
+                .map(|_| ())
+        ));
+        assert!(matches!(
+            organisation
+                .clone()
+                .update_permissions(
+                    conn.clone(),
+                    test_actor(admin),
+                    admin,
+                    UserPermission::all()
+                )
+                .await,
+            Err(crate::Error::MissingOrganisationPermission(_))
+        ));
+        assert!(is_missing_manage_organisation(
+            organisation
+                .clone()
+                .invite(
+                    conn.clone(),
+                    test_actor(admin),
+                    Invitee::User(outsider),
+                    UserPermission::VISIBLE | UserPermission::MANAGE_ORGANISATION,
+                    (chrono::Utc::now() + chrono::Duration::days(1)).naive_utc(),
+                )
+                .await
+                .map(|_| ())
+        ));
+
+        // nor can they be taken away from those that hold them
+        assert!(matches!(
+            organisation
+                .clone()
+                .update_permissions(
+                    conn.clone(),
+                    test_actor(admin),
+                    owner,
+                    UserPermission::VISIBLE
+                )
+                .await,
+            Err(crate::Error::MissingOrganisationPermission(_))
+        ));
+        assert!(matches!(
+            organisation
+                .clone()
+                .delete_member(conn.clone(), test_actor(admin), owner)
+                .await,
+            Err(crate::Error::MissingOrganisationPermission(_))
+        ));
+
+        // teams grant their permissions to every member, so joining or leaving one counts too
+        let team = owner_organisation
+            .clone()
+            .create_team(
+                conn.clone(),
+                test_actor(owner),
+                "admins".into(),
+                String::new(),
+            )
+            .await
+            .unwrap();
+        Arc::new(
+            owner_organisation
+                .clone()
+                .team(conn.clone(), team.name.clone())
+                .await
+                .unwrap(),
+        )
+        .update_organisation_permissions(conn.clone(), test_actor(owner), UserPermission::all())
+        .await
+        .unwrap();
+
+        let team = Arc::new(
+            organisation
+                .clone()
+                .team(conn.clone(), team.name)
+                .await
+                .unwrap(),
+        );
+        assert!(matches!(
+            team.clone()
+                .add_member(conn.clone(), test_actor(admin), member)
+                .await,
+            Err(crate::Error::MissingOrganisationPermission(_))
+        ));
+        assert!(matches!(
+            team.clone()
+                .update_organisation_permissions(
+                    conn.clone(),
+                    test_actor(admin),
+                    UserPermission::VISIBLE,
+                )
+                .await,
+            Err(crate::Error::MissingOrganisationPermission(_))
+        ));
+
+        let permissions = Organisation::find_by_name(conn.clone(), member, "org".into())
+            .await
+            .unwrap()
+            .permissions();
+        assert_eq!(
+            permissions,
+            UserPermission::VISIBLE | UserPermission::MANAGE_USERS
+        );
+
+        let permissions = Organisation::find_by_name(conn, owner, "org".into())
+            .await
+            .unwrap()
+            .permissions();
+        assert_eq!(permissions, UserPermission::all());
+    }
+}
@@ -4,11 +4,12 @@ use option_set::{option_set, OptionSet};
 option_set! {
     #[derive(FromSqlRow, AsExpression)]
     pub struct UserPermission: Identity + i32 {
-        const VISIBLE         = 0b0000_0000_0000_0000_0000_0000_0000_0001;
-        const PUBLISH_VERSION = 0b0000_0000_0000_0000_0000_0000_0000_0010;
-        const YANK_VERSION    = 0b0000_0000_0000_0000_0000_0000_0000_0100;
-        const MANAGE_USERS    = 0b0000_0000_0000_0000_0000_0000_0000_1000;
-        const CREATE_CRATE    = 0b0000_0000_0000_0000_0000_0000_0001_0000;
+        const VISIBLE             = 0b0000_0000_0000_0000_0000_0000_0000_0001;
+        const PUBLISH_VERSION     = 0b0000_0000_0000_0000_0000_0000_0000_0010;
+        const YANK_VERSION        = 0b0000_0000_0000_0000_0000_0000_0000_0100;
+        const MANAGE_USERS        = 0b0000_0000_0000_0000_0000_0000_0000_1000;
+        const CREATE_CRATE        = 0b0000_0000_0000_0000_0000_0000_0001_0000;
+        const MANAGE_ORGANISATION = 0b0000_0000_0000_0000_0000_0000_0010_0000;
     }
 }
 
@@ -28,6 +29,19 @@ impl UserPermission {
             [Self::CREATE_CRATE, Self::PUBLISH_VERSION],
         ]
     }
+
+    /// Checks that a user holding these permissions is able to change someone's permissions from
+    /// `previous` to `given`. Users can only grant or take away permissions they hold themselves,
+    /// otherwise anyone able to manage users could give themselves `MANAGE_ORGANISATION`.
+    pub(crate) fn check_can_change(self, previous: Self, given: Self) -> crate::Result<()> {
+        let changed = previous ^ given;
+
+        if self.contains(changed) {
+            Ok(())
+        } else {
+            Err(crate::Error::MissingOrganisationPermission(changed - self))
+        }
+    }
 }
 
 impl<B: diesel::backend::Backend> diesel::deserialize::FromSql<diesel::sql_types::Integer, B>
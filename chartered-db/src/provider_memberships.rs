@@ -0,0 +1,352 @@
+//! Organisation and team memberships driven by an external identity provider, rather than by
+//! someone with `MANAGE_USERS` clicking around the frontend.
+//!
+//! Memberships added this way are tagged with the name of the provider that granted them, so
+//! they can be updated or removed again the next time the user logs in with that provider. Any
+//! membership that was added by hand (or by another provider) is left alone.
+//!
+//! Providers can also accept invitations to organisations on the user's behalf, memberships
+//! granted by an invitation belong to whoever sent it rather than the provider.
+
+use crate::{
+    audit::{AuditAction, AuditActor, NewAuditEvent},
+    permissions::UserPermission,
+    schema::{organisations, team_members, teams, user_organisation_permissions},
+    teams::Team,
+    ConnectionPool, Result,
+};
+use diesel::{insert_into, prelude::*};
+use std::collections::{HashMap, HashSet};
+use tracing::warn;
+
+/// All the organisation permissions and team memberships an identity provider has granted to a
+/// user, as of their latest login.
+#[derive(Default, Debug)]
+pub struct ProviderMemberships {
+    /// Permissions to grant, keyed by organisation name
+    pub organisations: HashMap<String, UserPermission>,
+    /// Teams to add the user to, as `(organisation name, team name)`
+    pub teams: HashSet<(String, String)>,
+    /// Organisations the user's pending invitations should be accepted for
+    pub accept_invitations: HashSet<String>,
+}
+
+impl ProviderMemberships {
+    /// Brings the memberships `provider` has previously granted to `actor` in line with this
+    /// set, adding, updating and removing memberships as required.
+    pub async fn sync(
+        self,
+        conn: ConnectionPool,
+        actor: AuditActor,
+        provider: String,
+    ) -> Result<()> {
+        tokio::task::spawn_blocking(move || {
+            let conn = conn.get()?;
+
+            conn.transaction::<_, crate::Error, _>(|| {
+                let memberships = self.resolve_aliases(&conn)?;
+
+                memberships.sync_organisations(&conn, &actor, &provider)?;
+                memberships.sync_teams(&conn, &actor, &provider)?;
+
+                let accept_invitations: Vec<_> =
+                    memberships.accept_invitations.into_iter().collect();
+                crate::invitations::accept_provider_invitations(
+                    &conn,
+                    &actor,
+                    &provider,
+                    &accept_invitations,
+                )?;
+
+                Ok(())
+            })
+        })
+        .await?
+    }
+
+    /// Maps the organisations the provider was configured with that have since been renamed to
+    /// their current names, otherwise the user would be removed from them as soon as they're
+    /// renamed.
+    fn resolve_aliases(self, conn: &crate::Connection) -> Result<Self> {
+        let resolve = |name: String| crate::organisations::resolve_alias(conn, name);
+
+        let mut organisations: HashMap<String, UserPermission> = HashMap::new();
+        for (name, given_permissions) in self.organisations {
+            // both the old and new name might be configured, so merge the two grants
+            *organisations
+                .entry(resolve(name)?)
+                .or_insert_with(UserPermission::empty) |= given_permissions;
+        }
+
+        Ok(Self {
+            organisations,
+            teams: self
+                .teams
+                .into_iter()
+                .map(|(organisation, team)| Ok((resolve(organisation)?, team)))
+                .collect::<QueryResult<_>>()?,
+            accept_invitations: self
+                .accept_invitations
+                .into_iter()
+                .map(resolve)
+                .collect::<QueryResult<_>>()?,
+        })
+    }
+
+    fn sync_organisations(
+        &self,
+        conn: &crate::Connection,
+        actor: &AuditActor,
+        provider: &str,
+    ) -> Result<()> {
+        use crate::schema::user_organisation_permissions::dsl::{
+            id, organisation_id, permissions, provider as provider_column, user_id,
+        };
+
+        let wanted: HashMap<i32, UserPermission> = organisations::table
+            .filter(organisations::name.eq_any(self.organisations.keys()))
+            .select((organisations::id, organisations::name))
+            .load::<(i32, String)>(conn)?
+            .into_iter()
+            .map(|(org_id, name)| (org_id, self.organisations[&name]))
+            .collect();
+
+        if wanted.len() != self.organisations.len() {
+            warn!(
+                "{} has been mapped to one or more organisations that don't exist",
+                provider
+            );
+        }
+
+        let existing: Vec<(i32, i32, UserPermission, Option<String>)> =
+            user_organisation_permissions::table
+                .filter(user_id.eq(actor.user_id))
+                .select((id, organisation_id, permissions, provider_column))
+                .load(conn)?;
+
+        for (row_id, org_id, previous_permissions, granted_by) in &existing {
+            // memberships that were added by hand or by another provider aren't ours to touch
+            if granted_by.as_deref() != Some(provider) {
+                continue;
+            }
+
+            match wanted.get(org_id) {
+                None => {
+                    diesel::delete(user_organisation_permissions::table.filter(id.eq(row_id)))
+                        .execute(conn)?;
+
+                    NewAuditEvent::new(AuditAction::OrganisationMemberRemoved)
+                        .organisation(*org_id)
+                        .target_user(actor.user_id)
+                        .before(serde_json::json!({
+                            "permissions": previous_permissions,
+                            "provider": provider,
+                        }))
+                        .record(conn, actor)?;
+
+                    crate::teams::remove_organisation_memberships(
+                        conn,
+                        actor,
+                        *org_id,
+                        actor.user_id,
+                    )?;
+                }
+                Some(given_permissions) if given_permissions != previous_permissions => {
+                    diesel::update(user_organisation_permissions::table.filter(id.eq(row_id)))
+                        .set(permissions.eq(given_permissions.bits()))
+                        .execute(conn)?;
+
+                    NewAuditEvent::new(AuditAction::OrganisationMemberUpdated)
+                        .organisation(*org_id)
+                        .target_user(actor.user_id)
+                        .before(serde_json::json!({
+                            "permissions": previous_permissions,
+                            "provider": provider,
+                        }))
+                        .after(serde_json::json!({
+                            "permissions": given_permissions,
+                            "provider": provider,
+                        }))
+                        .record(conn, actor)?;
+                }
+                Some(_) => {}
+            }
+        }
+
+        for (org_id, given_permissions) in wanted {
+            if existing.iter().any(|(_, v, _, _)| *v == org_id) {
+                continue;
+            }
+
+            insert_into(user_organisation_permissions::table)
+                .values((
+                    user_id.eq(actor.user_id),
+                    organisation_id.eq(org_id),
+                    permissions.eq(given_permissions.bits()),
+                    provider_column.eq(provider),
+                ))
+                .execute(conn)?;
+
+            NewAuditEvent::new(AuditAction::OrganisationMemberAdded)
+                .organisation(org_id)
+                .target_user(actor.user_id)
+                .after(serde_json::json!({
+                    "permissions": given_permissions,
+                    "provider": provider,
+                }))
+                .record(conn, actor)?;
+        }
+
+        Ok(())
+    }
+
+    fn sync_teams(
+        &self,
+        conn: &crate::Connection,
+        actor: &AuditActor,
+        provider: &str,
+    ) -> Result<()> {
+        use crate::schema::team_members::dsl::{id, provider as provider_column, team_id, user_id};
+
+        let wanted: HashMap<i32, Team> = teams::table
+            .inner_join(organisations::table)
+            .filter(organisations::name.eq_any(self.teams.iter().map(|(org, _)| org)))
+            .select((teams::all_columns, organisations::name))
+            .load::<(Team, String)>(conn)?
+            .into_iter()
+            .filter(|(team, org)| self.teams.contains(&(org.clone(), team.name.clone())))
+            .map(|(team, _)| (team.id, team))
+            .collect();
+
+        if wanted.len() != self.teams.len() {
+            warn!(
+                "{} has been mapped to one or more teams that don't exist",
+                provider
+            );
+        }
+
+        let existing: Vec<(i32, Team, Option<String>)> = team_members::table
+            .inner_join(teams::table)
+            .filter(user_id.eq(actor.user_id))
+            .select((id, teams::all_columns, provider_column))
+            .load(conn)?;
+
+        for (row_id, team, granted_by) in &existing {
+            if granted_by.as_deref() != Some(provider) || wanted.contains_key(&team.id) {
+                continue;
+            }
+
+            diesel::delete(team_members::table.filter(id.eq(row_id))).execute(conn)?;
+
+            NewAuditEvent::new(AuditAction::TeamMemberRemoved)
+                .organisation(team.organisation_id)
+                .target_user(actor.user_id)
+                .before(team.audit_state())
+                .record(conn, actor)?;
+        }
+
+        for (given_team_id, team) in wanted {
+            if existing.iter().any(|(_, v, _)| v.id == given_team_id) {
+                continue;
+            }
+
+            insert_into(team_members::table)
+                .values((
+                    team_id.eq(given_team_id),
+                    user_id.eq(actor.user_id),
+                    provider_column.eq(provider),
+                ))
+                .execute(conn)?;
+
+            NewAuditEvent::new(AuditAction::TeamMemberAdded)
+                .organisation(team.organisation_id)
+                .target_user(actor.user_id)
+                .after(team.audit_state())
+                .record(conn, actor)?;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(all(test, feature = "sqlite"))]
+mod tests {
+    use super::ProviderMemberships;
+    use crate::{organisations::Organisation, permissions::UserPermission, test_actor, test_user};
+    use std::{collections::HashSet, sync::Arc};
+
+    #[tokio::test]
+    async fn renamed_organisation() {
+        let conn = crate::test_pool();
+        let owner = test_user(&conn, "owner").await;
+        let member = test_user(&conn, "member").await;
+
+        Organisation::create(
+            conn.clone(),
+            "old-name".into(),
+            String::new(),
+            false,
+            test_actor(owner),
+        )
+        .await
+        .unwrap();
+        let organisation = Arc::new(
+            Organisation::find_by_name(conn.clone(), owner, "old-name".into())
+                .await
+                .unwrap(),
+        );
+        organisation
+            .clone()
+            .create_team(
+                conn.clone(),
+                test_actor(owner),
+                "team".into(),
+                String::new(),
+            )
+            .await
+            .unwrap();
+
+        // the provider's configuration still refers to the organisation by its old name
+        let sync = || {
+            ProviderMemberships {
+                organisations: [("old-name".to_string(), UserPermission::VISIBLE)]
+                    .into_iter()
+                    .collect(),
+                teams: [("old-name".to_string(), "team".to_string())]
+                    .into_iter()
+                    .collect(),
+                accept_invitations: HashSet::new(),
+            }
+            .sync(conn.clone(), test_actor(member), "provider".into())
+        };
+
+        sync().await.unwrap();
+
+        organisation
+            .update(
+                conn.clone(),
+                test_actor(owner),
+                Some("new-name".into()),
+                None,
+                None,
+            )
+            .await
+            .unwrap();
+
+        sync().await.unwrap();
+
+        let organisation = Arc::new(
+            Organisation::find_by_name(conn.clone(), member, "new-name".into())
+                .await
+                .unwrap(),
+        );
+        assert_eq!(organisation.permissions(), UserPermission::VISIBLE);
+
+        let team = organisation
+            .team(conn.clone(), "team".into())
+            .await
+            .unwrap();
+        let members = Arc::new(team).members(conn.clone()).await.unwrap();
+        assert_eq!(members.len(), 1);
+    }
+}
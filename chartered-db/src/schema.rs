@@ -1,3 +1,69 @@
+table! {
+    audit_events (id) {
+        id -> Integer,
+        action -> Text,
+        user_id -> Integer,
+        organisation_id -> Nullable<Integer>,
+        crate_id -> Nullable<Integer>,
+        target_user_id -> Nullable<Integer>,
+        ip -> Nullable<Text>,
+        user_agent -> Nullable<Text>,
+        state_before -> Nullable<Binary>,
+        state_after -> Nullable<Binary>,
+        created_at -> Timestamp,
+    }
+}
+
+table! {
+    crate_advisories (id) {
+        id -> Integer,
+        uuid -> Binary,
+        crate_id -> Integer,
+        kind -> Text,
+        affected_versions -> Text,
+        message -> Text,
+        user_id -> Integer,
+        created_at -> Timestamp,
+        withdrawn_at -> Nullable<Timestamp>,
+        withdrawn_by_user_id -> Nullable<Integer>,
+    }
+}
+
+table! {
+    crate_aliases (id) {
+        id -> Integer,
+        crate_id -> Integer,
+        organisation_id -> Integer,
+        name -> Text,
+        expires_at -> Timestamp,
+        created_at -> Timestamp,
+    }
+}
+
+table! {
+    crate_version_dependencies (id) {
+        id -> Integer,
+        crate_version_id -> Integer,
+        name -> Text,
+        req -> Text,
+        kind -> Text,
+        optional -> Bool,
+        registry -> Nullable<Text>,
+        dependency_crate_id -> Nullable<Integer>,
+    }
+}
+
+table! {
+    crate_version_yanks (id) {
+        id -> Integer,
+        crate_version_id -> Integer,
+        yanked -> Bool,
+        reason -> Nullable<Text>,
+        user_id -> Integer,
+        created_at -> Timestamp,
+    }
+}
+
 table! {
     crate_versions (id) {
         id -> Integer,
@@ -30,6 +96,29 @@ table! {
     }
 }
 
+table! {
+    organisation_aliases (id) {
+        id -> Integer,
+        name -> Text,
+        organisation_id -> Integer,
+        created_at -> Timestamp,
+    }
+}
+
+table! {
+    organisation_invitations (id) {
+        id -> Integer,
+        uuid -> Binary,
+        organisation_id -> Integer,
+        invited_by -> Integer,
+        user_id -> Nullable<Integer>,
+        email -> Nullable<Text>,
+        permissions -> Integer,
+        expires_at -> Timestamp,
+        created_at -> Timestamp,
+    }
+}
+
 table! {
     organisations (id) {
         id -> Integer,
@@ -37,6 +126,7 @@ table! {
         name -> Text,
         description -> Text,
         public -> Bool,
+        require_two_factor -> Bool,
     }
 }
 
@@ -48,6 +138,43 @@ table! {
     }
 }
 
+table! {
+    team_crate_permissions (id) {
+        id -> Integer,
+        team_id -> Integer,
+        crate_id -> Integer,
+        permissions -> Integer,
+    }
+}
+
+table! {
+    team_members (id) {
+        id -> Integer,
+        team_id -> Integer,
+        user_id -> Integer,
+        provider -> Nullable<Text>,
+    }
+}
+
+table! {
+    team_organisation_permissions (id) {
+        id -> Integer,
+        team_id -> Integer,
+        organisation_id -> Integer,
+        permissions -> Integer,
+    }
+}
+
+table! {
+    teams (id) {
+        id -> Integer,
+        uuid -> Binary,
+        organisation_id -> Integer,
+        name -> Text,
+        description -> Text,
+    }
+}
+
 table! {
     user_crate_permissions (id) {
         id -> Integer,
@@ -63,6 +190,16 @@ table! {
         user_id -> Integer,
         organisation_id -> Integer,
         permissions -> Integer,
+        provider -> Nullable<Text>,
+    }
+}
+
+table! {
+    user_recovery_codes (id) {
+        id -> Integer,
+        user_id -> Integer,
+        code_hash -> Text,
+        used_at -> Nullable<Timestamp>,
     }
 }
 
@@ -91,6 +228,44 @@ table! {
     }
 }
 
+table! {
+    user_tokens (id) {
+        id -> Integer,
+        user_id -> Integer,
+        purpose -> Text,
+        token_hash -> Text,
+        email -> Nullable<Text>,
+        expires_at -> Timestamp,
+        used_at -> Nullable<Timestamp>,
+        created_at -> Timestamp,
+        state -> Nullable<Text>,
+    }
+}
+
+table! {
+    user_totp (id) {
+        id -> Integer,
+        user_id -> Integer,
+        secret -> Binary,
+        last_used_step -> Nullable<BigInt>,
+        confirmed_at -> Nullable<Timestamp>,
+        created_at -> Timestamp,
+    }
+}
+
+table! {
+    user_webauthn_credentials (id) {
+        id -> Integer,
+        uuid -> Binary,
+        name -> Text,
+        user_id -> Integer,
+        credential_id -> Binary,
+        credential -> Text,
+        created_at -> Timestamp,
+        last_used_at -> Nullable<Timestamp>,
+    }
+}
+
 table! {
     users (id) {
         id -> Integer,
@@ -102,28 +277,68 @@ table! {
         email -> Nullable<Text>,
         external_profile_url -> Nullable<Text>,
         picture_url -> Nullable<Text>,
+        email_verified_at -> Nullable<Timestamp>,
+        failed_login_attempts -> Integer,
+        locked_until -> Nullable<Timestamp>,
     }
 }
 
+joinable!(audit_events -> crates (crate_id));
+joinable!(audit_events -> organisations (organisation_id));
+joinable!(crate_advisories -> crates (crate_id));
+joinable!(crate_aliases -> crates (crate_id));
+joinable!(crate_aliases -> organisations (organisation_id));
+joinable!(crate_version_dependencies -> crate_versions (crate_version_id));
+joinable!(crate_version_dependencies -> crates (dependency_crate_id));
+joinable!(crate_version_yanks -> crate_versions (crate_version_id));
+joinable!(crate_version_yanks -> users (user_id));
 joinable!(crate_versions -> crates (crate_id));
 joinable!(crate_versions -> users (user_id));
 joinable!(crates -> organisations (organisation_id));
+joinable!(organisation_aliases -> organisations (organisation_id));
+joinable!(organisation_invitations -> organisations (organisation_id));
+joinable!(team_crate_permissions -> crates (crate_id));
+joinable!(team_crate_permissions -> teams (team_id));
+joinable!(team_members -> teams (team_id));
+joinable!(team_members -> users (user_id));
+joinable!(team_organisation_permissions -> organisations (organisation_id));
+joinable!(team_organisation_permissions -> teams (team_id));
+joinable!(teams -> organisations (organisation_id));
 joinable!(user_crate_permissions -> crates (crate_id));
 joinable!(user_crate_permissions -> users (user_id));
 joinable!(user_organisation_permissions -> organisations (organisation_id));
 joinable!(user_organisation_permissions -> users (user_id));
+joinable!(user_recovery_codes -> users (user_id));
 joinable!(user_sessions -> user_ssh_keys (user_ssh_key_id));
 joinable!(user_sessions -> users (user_id));
 joinable!(user_ssh_keys -> users (user_id));
+joinable!(user_tokens -> users (user_id));
+joinable!(user_totp -> users (user_id));
+joinable!(user_webauthn_credentials -> users (user_id));
 
 allow_tables_to_appear_in_same_query!(
+    audit_events,
+    crate_advisories,
+    crate_aliases,
+    crate_version_dependencies,
+    crate_version_yanks,
     crate_versions,
     crates,
+    organisation_aliases,
+    organisation_invitations,
     organisations,
     server_private_keys,
+    team_crate_permissions,
+    team_members,
+    team_organisation_permissions,
+    teams,
     user_crate_permissions,
     user_organisation_permissions,
+    user_recovery_codes,
     user_sessions,
     user_ssh_keys,
+    user_tokens,
+    user_totp,
+    user_webauthn_credentials,
     users,
 );
@@ -0,0 +1,684 @@
+//! Teams are groups of users within an organisation that can be granted permissions on the
+//! organisation and its crates in one go, rather than granting them to each user individually.
+//!
+//! The permissions granted to each team a user is a member of are ORed into the permissions
+//! they've been granted directly, so a team can only ever add permissions - never take them away.
+
+use crate::{
+    audit::{AuditAction, AuditActor, NewAuditEvent},
+    coalesce,
+    crates::{Crate, CrateWithPermissions},
+    organisations::{Organisation, OrganisationWithPermissions},
+    permissions::UserPermission,
+    schema::{
+        crates, team_crate_permissions, team_members, team_organisation_permissions, teams,
+        user_organisation_permissions, users,
+    },
+    users::User,
+    uuid::SqlUuid,
+    ConnectionPool, Error, Result,
+};
+use diesel::{
+    insert_into,
+    prelude::*,
+    result::{DatabaseErrorKind, Error as DieselError},
+    Associations, Identifiable, Queryable,
+};
+use std::sync::Arc;
+
+#[derive(Identifiable, Queryable, Associations, PartialEq, Eq, Hash, Debug)]
+#[belongs_to(Organisation)]
+pub struct Team {
+    pub id: i32,
+    pub uuid: SqlUuid,
+    pub organisation_id: i32,
+    pub name: String,
+    pub description: String,
+}
+
+impl Team {
+    pub(crate) fn audit_state(&self) -> serde_json::Value {
+        serde_json::json!({ "uuid": self.uuid.to_string(), "name": self.name })
+    }
+}
+
+/// Builds an SQL expression ORing together the permissions granted by `table` (either
+/// `team_crate_permissions` or `team_organisation_permissions`) to every team `user_id` is a
+/// member of, where `column` matches `target` in the outer query (ie. `crates.id`).
+///
+/// Teams only grant permissions to users that are still members of the organisation the team
+/// belongs to, so someone removed from an organisation can't keep access through a team.
+///
+/// There's no portable `BIT_OR` aggregate between SQLite and Postgres so each permission is
+/// checked for individually instead.
+pub(crate) fn team_permissions_sql(
+    user_id: i32,
+    table: &str,
+    column: &str,
+    target: &str,
+) -> String {
+    let all = UserPermission::all().bits();
+
+    let checks: Vec<_> = (0..i32::BITS)
+        .map(|i| 1_i32 << i)
+        .filter(|bit| all & bit != 0)
+        .map(|bit| {
+            format!(
+                "CASE WHEN EXISTS (\
+                    SELECT 1 FROM {table} \
+                    INNER JOIN team_members ON team_members.team_id = {table}.team_id \
+                    INNER JOIN teams ON teams.id = team_members.team_id \
+                    INNER JOIN user_organisation_permissions \
+                        ON user_organisation_permissions.organisation_id = teams.organisation_id \
+                        AND user_organisation_permissions.user_id = team_members.user_id \
+                    WHERE team_members.user_id = {user_id} \
+                    AND {table}.{column} = {target} \
+                    AND ({table}.permissions & {bit}) <> 0\
+                ) THEN {bit} ELSE 0 END"
+            )
+        })
+        .collect();
+
+    format!("({})", checks.join(" | "))
+}
+
+/// Looks up a team by name within the given organisation.
+fn find_team(
+    conn: &crate::Connection,
+    given_organisation_id: i32,
+    given_name: &str,
+) -> Result<Team> {
+    teams::table
+        .filter(teams::organisation_id.eq(given_organisation_id))
+        .filter(teams::name.eq(given_name))
+        .get_result(conn)
+        .optional()?
+        .ok_or(Error::MissingTeam)
+}
+
+/// Grabs the permissions the team has been granted on its organisation.
+fn granted_organisation_permissions(
+    conn: &crate::Connection,
+    team: &Team,
+) -> Result<UserPermission> {
+    Ok(team_organisation_permissions::table
+        .filter(team_organisation_permissions::team_id.eq(team.id))
+        .filter(team_organisation_permissions::organisation_id.eq(team.organisation_id))
+        .select(team_organisation_permissions::permissions)
+        .get_result::<UserPermission>(conn)
+        .optional()?
+        .unwrap_or_else(UserPermission::empty))
+}
+
+/// Removes the user from every team in the organisation, called when they're removed from the
+/// organisation itself.
+pub(crate) fn remove_organisation_memberships(
+    conn: &crate::Connection,
+    actor: &AuditActor,
+    given_organisation_id: i32,
+    given_user_id: i32,
+) -> Result<()> {
+    let memberships: Vec<(i32, Team)> = team_members::table
+        .inner_join(teams::table)
+        .filter(teams::organisation_id.eq(given_organisation_id))
+        .filter(team_members::user_id.eq(given_user_id))
+        .select((team_members::id, teams::all_columns))
+        .load(conn)?;
+
+    for (membership_id, team) in memberships {
+        diesel::delete(team_members::table.find(membership_id)).execute(conn)?;
+
+        NewAuditEvent::new(AuditAction::TeamMemberRemoved)
+            .organisation(team.organisation_id)
+            .target_user(given_user_id)
+            .before(team.audit_state())
+            .record(conn, actor)?;
+    }
+
+    Ok(())
+}
+
+impl OrganisationWithPermissions {
+    /// Lists all the teams in the organisation along with the permissions each team has been
+    /// granted on the organisation.
+    pub async fn teams(
+        self: Arc<Self>,
+        conn: ConnectionPool,
+    ) -> Result<Vec<(Team, UserPermission)>> {
+        if !self.permissions().contains(UserPermission::VISIBLE) {
+            return Err(Error::MissingOrganisationPermission(
+                UserPermission::VISIBLE,
+            ));
+        }
+
+        tokio::task::spawn_blocking(move || {
+            let conn = conn.get()?;
+
+            Ok(Team::belonging_to(self.organisation())
+                .left_join(team_organisation_permissions::table.on(
+                    team_organisation_permissions::team_id.eq(teams::id).and(
+                        team_organisation_permissions::organisation_id.eq(teams::organisation_id),
+                    ),
+                ))
+                .select((
+                    teams::all_columns,
+                    coalesce(team_organisation_permissions::permissions.nullable(), 0),
+                ))
+                .order_by(teams::name)
+                .load(&conn)?)
+        })
+        .await?
+    }
+
+    pub async fn team(
+        self: Arc<Self>,
+        conn: ConnectionPool,
+        given_name: String,
+    ) -> Result<TeamWithPermissions> {
+        if !self.permissions().contains(UserPermission::VISIBLE) {
+            return Err(Error::MissingOrganisationPermission(
+                UserPermission::VISIBLE,
+            ));
+        }
+
+        tokio::task::spawn_blocking(move || {
+            let conn = conn.get()?;
+
+            Ok(TeamWithPermissions {
+                team: find_team(&conn, self.organisation().id, &given_name)?,
+                permissions: self.permissions(),
+            })
+        })
+        .await?
+    }
+
+    pub async fn create_team(
+        self: Arc<Self>,
+        conn: ConnectionPool,
+        actor: AuditActor,
+        given_name: String,
+        given_description: String,
+    ) -> Result<Team> {
+        use crate::schema::teams::dsl::{description, name, organisation_id, uuid};
+
+        if !self.permissions().contains(UserPermission::MANAGE_USERS) {
+            return Err(Error::MissingOrganisationPermission(
+                UserPermission::MANAGE_USERS,
+            ));
+        }
+
+        tokio::task::spawn_blocking(move || {
+            let conn = conn.get()?;
+
+            conn.transaction::<_, crate::Error, _>(|| {
+                let generated_uuid = SqlUuid::random();
+
+                let res = insert_into(teams::table)
+                    .values((
+                        uuid.eq(generated_uuid),
+                        organisation_id.eq(self.organisation().id),
+                        name.eq(&given_name),
+                        description.eq(&given_description),
+                    ))
+                    .execute(&conn);
+
+                match res {
+                    Ok(_) => {}
+                    Err(DieselError::DatabaseError(DatabaseErrorKind::UniqueViolation, _)) => {
+                        return Err(Error::TeamNameTaken);
+                    }
+                    Err(e) => return Err(e.into()),
+                }
+
+                let team: Team = teams::table
+                    .filter(uuid.eq(generated_uuid))
+                    .get_result(&conn)?;
+
+                NewAuditEvent::new(AuditAction::TeamCreated)
+                    .organisation(team.organisation_id)
+                    .after(team.audit_state())
+                    .record(&conn, &actor)?;
+
+                Ok(team)
+            })
+        })
+        .await?
+    }
+}
+
+/// A team along with the permissions the requesting user has on the organisation it belongs
+/// to, which are used to check whether they can manage the team.
+#[derive(Debug)]
+pub struct TeamWithPermissions {
+    pub team: Team,
+    pub permissions: UserPermission,
+}
+
+impl TeamWithPermissions {
+    pub async fn members(self: Arc<Self>, conn: ConnectionPool) -> Result<Vec<User>> {
+        tokio::task::spawn_blocking(move || {
+            let conn = conn.get()?;
+
+            Ok(team_members::table
+                .filter(team_members::team_id.eq(self.team.id))
+                .inner_join(users::table)
+                .select(users::all_columns)
+                .load(&conn)?)
+        })
+        .await?
+    }
+
+    /// Grabs the permissions this team has been granted on the organisation.
+    pub async fn organisation_permissions(
+        self: Arc<Self>,
+        conn: ConnectionPool,
+    ) -> Result<UserPermission> {
+        tokio::task::spawn_blocking(move || {
+            let conn = conn.get()?;
+            granted_organisation_permissions(&conn, &self.team)
+        })
+        .await?
+    }
+
+    /// Lists all the crates this team has been granted permissions on, and the permissions
+    /// it's been granted.
+    pub async fn crates(
+        self: Arc<Self>,
+        conn: ConnectionPool,
+    ) -> Result<Vec<(Crate, UserPermission)>> {
+        tokio::task::spawn_blocking(move || {
+            let conn = conn.get()?;
+
+            Ok(team_crate_permissions::table
+                .filter(team_crate_permissions::team_id.eq(self.team.id))
+                .inner_join(crates::table)
+                .select((crates::all_columns, team_crate_permissions::permissions))
+                .order_by(crates::name)
+                .load(&conn)?)
+        })
+        .await?
+    }
+
+    /// Adds the user to the team, they must already be a member of the organisation the team
+    /// belongs to - users are brought into an organisation by inviting them to it.
+    pub async fn add_member(
+        self: Arc<Self>,
+        conn: ConnectionPool,
+        actor: AuditActor,
+        given_user_id: i32,
+    ) -> Result<()> {
+        use crate::schema::team_members::dsl::{team_id, user_id};
+
+        if !self.permissions.contains(UserPermission::MANAGE_USERS) {
+            return Err(Error::MissingOrganisationPermission(
+                UserPermission::MANAGE_USERS,
+            ));
+        }
+
+        tokio::task::spawn_blocking(move || {
+            let conn = conn.get()?;
+
+            conn.transaction::<_, crate::Error, _>(|| {
+                let is_member = user_organisation_permissions::table
+                    .filter(user_organisation_permissions::user_id.eq(given_user_id))
+                    .filter(
+                        user_organisation_permissions::organisation_id
+                            .eq(self.team.organisation_id),
+                    )
+                    .count()
+                    .get_result::<i64>(&conn)?
+                    > 0;
+
+                if !is_member {
+                    return Err(Error::NotOrganisationMember);
+                }
+
+                // joining the team grants the user whatever the team has on the organisation
+                self.permissions.check_can_change(
+                    UserPermission::empty(),
+                    granted_organisation_permissions(&conn, &self.team)?,
+                )?;
+
+                insert_into(team_members::table)
+                    .values((team_id.eq(self.team.id), user_id.eq(given_user_id)))
+                    .execute(&conn)?;
+
+                NewAuditEvent::new(AuditAction::TeamMemberAdded)
+                    .organisation(self.team.organisation_id)
+                    .target_user(given_user_id)
+                    .after(self.team.audit_state())
+                    .record(&conn, &actor)?;
+
+                Ok(())
+            })
+        })
+        .await?
+    }
+
+    pub async fn remove_member(
+        self: Arc<Self>,
+        conn: ConnectionPool,
+        actor: AuditActor,
+        given_user_id: i32,
+    ) -> Result<bool> {
+        use crate::schema::team_members::dsl::{team_id, user_id};
+
+        if !self.permissions.contains(UserPermission::MANAGE_USERS) {
+            return Err(Error::MissingOrganisationPermission(
+                UserPermission::MANAGE_USERS,
+            ));
+        }
+
+        tokio::task::spawn_blocking(move || {
+            let conn = conn.get()?;
+
+            conn.transaction::<_, crate::Error, _>(|| {
+                self.permissions.check_can_change(
+                    granted_organisation_permissions(&conn, &self.team)?,
+                    UserPermission::empty(),
+                )?;
+
+                let rows = diesel::delete(
+                    team_members::table
+                        .filter(team_id.eq(self.team.id))
+                        .filter(user_id.eq(given_user_id)),
+                )
+                .execute(&conn)?;
+
+                if rows > 0 {
+                    NewAuditEvent::new(AuditAction::TeamMemberRemoved)
+                        .organisation(self.team.organisation_id)
+                        .target_user(given_user_id)
+                        .before(self.team.audit_state())
+                        .record(&conn, &actor)?;
+                }
+
+                Ok(rows > 0)
+            })
+        })
+        .await?
+    }
+
+    /// Sets the permissions this team has been granted on the organisation, replacing any
+    /// permissions previously granted.
+    pub async fn update_organisation_permissions(
+        self: Arc<Self>,
+        conn: ConnectionPool,
+        actor: AuditActor,
+        given_permissions: UserPermission,
+    ) -> Result<()> {
+        use crate::schema::team_organisation_permissions::dsl::{
+            organisation_id, permissions, team_id,
+        };
+
+        if !self.permissions.contains(UserPermission::MANAGE_USERS) {
+            return Err(Error::MissingOrganisationPermission(
+                UserPermission::MANAGE_USERS,
+            ));
+        }
+
+        tokio::task::spawn_blocking(move || {
+            let conn = conn.get()?;
+
+            conn.transaction::<_, crate::Error, _>(|| {
+                let previous_permissions = team_organisation_permissions::table
+                    .filter(team_id.eq(self.team.id))
+                    .filter(organisation_id.eq(self.team.organisation_id))
+                    .select(permissions)
+                    .get_result::<UserPermission>(&conn)
+                    .optional()?;
+
+                self.permissions.check_can_change(
+                    previous_permissions.unwrap_or_else(UserPermission::empty),
+                    given_permissions,
+                )?;
+
+                if previous_permissions.is_some() {
+                    diesel::update(
+                        team_organisation_permissions::table
+                            .filter(team_id.eq(self.team.id))
+                            .filter(organisation_id.eq(self.team.organisation_id)),
+                    )
+                    .set(permissions.eq(given_permissions.bits()))
+                    .execute(&conn)?;
+                } else {
+                    insert_into(team_organisation_permissions::table)
+                        .values((
+                            team_id.eq(self.team.id),
+                            organisation_id.eq(self.team.organisation_id),
+                            permissions.eq(given_permissions.bits()),
+                        ))
+                        .execute(&conn)?;
+                }
+
+                NewAuditEvent::new(AuditAction::TeamPermissionsUpdated)
+                    .organisation(self.team.organisation_id)
+                    .before(serde_json::json!({
+                        "team": self.team.audit_state(),
+                        "permissions": previous_permissions.unwrap_or_else(UserPermission::empty),
+                    }))
+                    .after(serde_json::json!({
+                        "team": self.team.audit_state(),
+                        "permissions": given_permissions,
+                    }))
+                    .record(&conn, &actor)?;
+
+                Ok(())
+            })
+        })
+        .await?
+    }
+
+    /// Deletes the team along with its memberships and any permissions it's been granted.
+    pub async fn delete(self: Arc<Self>, conn: ConnectionPool, actor: AuditActor) -> Result<()> {
+        if !self.permissions.contains(UserPermission::MANAGE_USERS) {
+            return Err(Error::MissingOrganisationPermission(
+                UserPermission::MANAGE_USERS,
+            ));
+        }
+
+        tokio::task::spawn_blocking(move || {
+            let conn = conn.get()?;
+
+            conn.transaction::<_, crate::Error, _>(|| {
+                // every member loses whatever the team has on the organisation
+                self.permissions.check_can_change(
+                    granted_organisation_permissions(&conn, &self.team)?,
+                    UserPermission::empty(),
+                )?;
+
+                diesel::delete(team_members::table.filter(team_members::team_id.eq(self.team.id)))
+                    .execute(&conn)?;
+                diesel::delete(
+                    team_organisation_permissions::table
+                        .filter(team_organisation_permissions::team_id.eq(self.team.id)),
+                )
+                .execute(&conn)?;
+                diesel::delete(
+                    team_crate_permissions::table
+                        .filter(team_crate_permissions::team_id.eq(self.team.id)),
+                )
+                .execute(&conn)?;
+                diesel::delete(teams::table.filter(teams::id.eq(self.team.id))).execute(&conn)?;
+
+                NewAuditEvent::new(AuditAction::TeamDeleted)
+                    .organisation(self.team.organisation_id)
+                    .before(self.team.audit_state())
+                    .record(&conn, &actor)?;
+
+                Ok(())
+            })
+        })
+        .await?
+    }
+}
+
+impl CrateWithPermissions {
+    /// Lists all the teams that have been granted permissions on this crate directly, and the
+    /// permissions they've been granted.
+    pub async fn teams(
+        self: Arc<Self>,
+        conn: ConnectionPool,
+    ) -> Result<Vec<(Team, UserPermission)>> {
+        if !self.permissions.contains(UserPermission::MANAGE_USERS) {
+            return Err(Error::MissingCratePermission(UserPermission::MANAGE_USERS));
+        }
+
+        tokio::task::spawn_blocking(move || {
+            let conn = conn.get()?;
+
+            Ok(team_crate_permissions::table
+                .filter(team_crate_permissions::crate_id.eq(self.crate_.id))
+                .inner_join(teams::table)
+                .select((teams::all_columns, team_crate_permissions::permissions))
+                .order_by(teams::name)
+                .load(&conn)?)
+        })
+        .await?
+    }
+
+    pub async fn insert_team_permissions(
+        self: Arc<Self>,
+        conn: ConnectionPool,
+        actor: AuditActor,
+        given_team_name: String,
+        given_permissions: UserPermission,
+    ) -> Result<()> {
+        use crate::schema::team_crate_permissions::dsl::{crate_id, permissions, team_id};
+
+        if !self.permissions.contains(UserPermission::MANAGE_USERS) {
+            return Err(Error::MissingCratePermission(UserPermission::MANAGE_USERS));
+        }
+
+        tokio::task::spawn_blocking(move || {
+            let conn = conn.get()?;
+
+            conn.transaction::<_, crate::Error, _>(|| {
+                let team = find_team(&conn, self.crate_.organisation_id, &given_team_name)?;
+
+                insert_into(team_crate_permissions::table)
+                    .values((
+                        team_id.eq(team.id),
+                        crate_id.eq(self.crate_.id),
+                        permissions.eq(given_permissions.bits()),
+                    ))
+                    .execute(&conn)?;
+
+                NewAuditEvent::new(AuditAction::CrateTeamAdded)
+                    .crate_(&self.crate_)
+                    .after(serde_json::json!({
+                        "team": team.audit_state(),
+                        "permissions": given_permissions,
+                    }))
+                    .record(&conn, &actor)?;
+
+                Ok(())
+            })
+        })
+        .await?
+    }
+
+    pub async fn update_team_permissions(
+        self: Arc<Self>,
+        conn: ConnectionPool,
+        actor: AuditActor,
+        given_team_name: String,
+        given_permissions: UserPermission,
+    ) -> Result<usize> {
+        use crate::schema::team_crate_permissions::dsl::{crate_id, permissions, team_id};
+
+        if !self.permissions.contains(UserPermission::MANAGE_USERS) {
+            return Err(Error::MissingCratePermission(UserPermission::MANAGE_USERS));
+        }
+
+        tokio::task::spawn_blocking(move || {
+            let conn = conn.get()?;
+
+            conn.transaction::<_, crate::Error, _>(|| {
+                let team = find_team(&conn, self.crate_.organisation_id, &given_team_name)?;
+
+                let Some(previous_permissions) = team_crate_permissions::table
+                    .filter(team_id.eq(team.id))
+                    .filter(crate_id.eq(self.crate_.id))
+                    .select(permissions)
+                    .get_result::<UserPermission>(&conn)
+                    .optional()?
+                else {
+                    return Ok(0);
+                };
+
+                let rows = diesel::update(
+                    team_crate_permissions::table
+                        .filter(team_id.eq(team.id))
+                        .filter(crate_id.eq(self.crate_.id)),
+                )
+                .set(permissions.eq(given_permissions.bits()))
+                .execute(&conn)?;
+
+                NewAuditEvent::new(AuditAction::CrateTeamUpdated)
+                    .crate_(&self.crate_)
+                    .before(serde_json::json!({
+                        "team": team.audit_state(),
+                        "permissions": previous_permissions,
+                    }))
+                    .after(serde_json::json!({
+                        "team": team.audit_state(),
+                        "permissions": given_permissions,
+                    }))
+                    .record(&conn, &actor)?;
+
+                Ok(rows)
+            })
+        })
+        .await?
+    }
+
+    pub async fn delete_team(
+        self: Arc<Self>,
+        conn: ConnectionPool,
+        actor: AuditActor,
+        given_team_name: String,
+    ) -> Result<()> {
+        use crate::schema::team_crate_permissions::dsl::{crate_id, permissions, team_id};
+
+        if !self.permissions.contains(UserPermission::MANAGE_USERS) {
+            return Err(Error::MissingCratePermission(UserPermission::MANAGE_USERS));
+        }
+
+        tokio::task::spawn_blocking(move || {
+            let conn = conn.get()?;
+
+            conn.transaction::<_, crate::Error, _>(|| {
+                let team = find_team(&conn, self.crate_.organisation_id, &given_team_name)?;
+
+                let Some(previous_permissions) = team_crate_permissions::table
+                    .filter(team_id.eq(team.id))
+                    .filter(crate_id.eq(self.crate_.id))
+                    .select(permissions)
+                    .get_result::<UserPermission>(&conn)
+                    .optional()?
+                else {
+                    return Ok(());
+                };
+
+                diesel::delete(
+                    team_crate_permissions::table
+                        .filter(team_id.eq(team.id))
+                        .filter(crate_id.eq(self.crate_.id)),
+                )
+                .execute(&conn)?;
+
+                NewAuditEvent::new(AuditAction::CrateTeamRemoved)
+                    .crate_(&self.crate_)
+                    .before(serde_json::json!({
+                        "team": team.audit_state(),
+                        "permissions": previous_permissions,
+                    }))
+                    .record(&conn, &actor)?;
+
+                Ok(())
+            })
+        })
+        .await?
+    }
+}
@@ -0,0 +1,401 @@
+//! Moves crates, along with all their versions, between organisations.
+//!
+//! A transfer can optionally leave an alias behind in the organisation the crate was moved out
+//! of, so registries using the old organisation's index continue to resolve the crate until the
+//! alias expires. Anyone able to see the old organisation is able to see the crate through the
+//! alias, whether or not they've been given access to the organisation it was moved to.
+
+use crate::{
+    audit::{AuditAction, AuditActor, NewAuditEvent},
+    crates::CrateWithPermissions,
+    organisations::{Organisation, OrganisationWithPermissions},
+    permissions::UserPermission,
+    schema::{crate_aliases, crates, organisations, team_crate_permissions},
+    ConnectionPool, Error, Result,
+};
+use chrono::{NaiveDateTime, Utc};
+use diesel::{insert_into, prelude::*, Associations, Identifiable, Queryable};
+use std::sync::Arc;
+
+#[derive(Identifiable, Queryable, Associations, PartialEq, Eq, Hash, Debug)]
+#[belongs_to(Organisation)]
+pub struct CrateAlias {
+    pub id: i32,
+    /// The crate the alias resolves to
+    pub crate_id: i32,
+    /// The organisation the crate was transferred out of
+    pub organisation_id: i32,
+    /// The name of the crate at the time it was transferred
+    pub name: String,
+    pub expires_at: NaiveDateTime,
+    pub created_at: NaiveDateTime,
+}
+
+/// Finds the id of the crate with the given name in the organisation, falling back to any
+/// unexpired alias left behind by the crate being transferred out of it.
+pub(crate) fn resolve_crate(
+    conn: &crate::Connection,
+    given_org_name: &str,
+    given_crate_name: &str,
+) -> QueryResult<Option<i32>> {
+    let crate_id = crates::table
+        .inner_join(organisations::table)
+        .filter(organisations::name.eq(given_org_name))
+        .filter(crates::name.eq(given_crate_name))
+        .select(crates::id)
+        .get_result(conn)
+        .optional()?;
+
+    if crate_id.is_some() {
+        return Ok(crate_id);
+    }
+
+    crate_aliases::table
+        .inner_join(organisations::table)
+        .filter(organisations::name.eq(given_org_name))
+        .filter(crate_aliases::name.eq(given_crate_name))
+        .filter(crate_aliases::expires_at.gt(Utc::now().naive_utc()))
+        .select(crate_aliases::crate_id)
+        .get_result(conn)
+        .optional()
+}
+
+/// Lists the ids of all the crates in the organisation, including any that have been transferred
+/// out of it and left an unexpired alias behind.
+pub(crate) fn organisation_crate_ids(
+    conn: &crate::Connection,
+    given_org_name: &str,
+) -> QueryResult<Vec<i32>> {
+    let mut crate_ids: Vec<i32> = crates::table
+        .inner_join(organisations::table)
+        .filter(organisations::name.eq(given_org_name))
+        .select(crates::id)
+        .load(conn)?;
+
+    crate_ids.extend(aliased_crate_ids(conn, given_org_name)?);
+
+    Ok(crate_ids)
+}
+
+/// Lists the ids of the crates that have been transferred out of the organisation and left an
+/// unexpired alias behind.
+pub(crate) fn aliased_crate_ids(
+    conn: &crate::Connection,
+    given_org_name: &str,
+) -> QueryResult<Vec<i32>> {
+    crate_aliases::table
+        .inner_join(organisations::table)
+        .filter(organisations::name.eq(given_org_name))
+        .filter(crate_aliases::expires_at.gt(Utc::now().naive_utc()))
+        .select(crate_aliases::crate_id)
+        .load(conn)
+}
+
+/// The permissions granted on crates through the aliases they left behind in the organisation,
+/// those able to see the organisation are able to see crates transferred out of it until their
+/// aliases expire, even if they've not been given access to the organisation the crates were
+/// moved to. Otherwise the crates would drop out of the old organisation's index for everyone
+/// that hasn't been added to the new one yet, which is what the alias is there to prevent.
+pub(crate) fn alias_permissions(
+    conn: &crate::Connection,
+    requesting_user_id: i32,
+    given_org_name: &str,
+) -> Result<UserPermission> {
+    match crate::organisations::find_with_permissions(conn, requesting_user_id, given_org_name) {
+        Ok(organisation) => Ok(organisation.permissions() & UserPermission::VISIBLE),
+        Err(Error::MissingOrganisation) => Ok(UserPermission::empty()),
+        Err(e) => Err(e),
+    }
+}
+
+/// Removes any alias with the given name in the organisation, called when a real crate takes the
+/// name so the two can't be confused.
+pub(crate) fn remove_alias(
+    conn: &crate::Connection,
+    given_organisation_id: i32,
+    given_crate_name: &str,
+) -> QueryResult<usize> {
+    diesel::delete(
+        crate_aliases::table
+            .filter(crate_aliases::organisation_id.eq(given_organisation_id))
+            .filter(crate_aliases::name.eq(given_crate_name)),
+    )
+    .execute(conn)
+}
+
+impl CrateWithPermissions {
+    /// Moves the crate and all its versions to the `destination` organisation, requiring
+    /// `MANAGE_USERS` on the crate and `CREATE_CRATE` on the destination.
+    ///
+    /// If `alias_expires_at` is given, the crate will continue to resolve under its current
+    /// organisation until then. Teams belong to an organisation, so any permissions granted to
+    /// teams directly on the crate are removed.
+    pub async fn transfer(
+        self: Arc<Self>,
+        conn: ConnectionPool,
+        actor: AuditActor,
+        destination: Arc<OrganisationWithPermissions>,
+        alias_expires_at: Option<NaiveDateTime>,
+    ) -> Result<()> {
+        if !self.permissions.contains(UserPermission::MANAGE_USERS) {
+            return Err(Error::MissingCratePermission(UserPermission::MANAGE_USERS));
+        }
+
+        if !destination
+            .permissions()
+            .contains(UserPermission::CREATE_CRATE)
+        {
+            return Err(Error::MissingOrganisationPermission(
+                UserPermission::CREATE_CRATE,
+            ));
+        }
+
+        if destination.organisation().id == self.crate_.organisation_id {
+            return Err(Error::AlreadyInOrganisation);
+        }
+
+        tokio::task::spawn_blocking(move || {
+            let conn = conn.get()?;
+
+            conn.transaction::<_, crate::Error, _>(|| {
+                let crate_ = &self.crate_;
+                let destination = destination.organisation();
+
+                let source: Organisation = organisations::table
+                    .find(crate_.organisation_id)
+                    .get_result(&conn)?;
+
+                let name_taken = crates::table
+                    .filter(crates::organisation_id.eq(destination.id))
+                    .filter(crates::name.eq(&crate_.name))
+                    .count()
+                    .get_result::<i64>(&conn)?;
+
+                if name_taken > 0 {
+                    return Err(Error::CrateNameTaken);
+                }
+
+                diesel::update(crates::table.find(crate_.id))
+                    .set(crates::organisation_id.eq(destination.id))
+                    .execute(&conn)?;
+
+                diesel::delete(
+                    team_crate_permissions::table
+                        .filter(team_crate_permissions::crate_id.eq(crate_.id)),
+                )
+                .execute(&conn)?;
+
+                remove_alias(&conn, destination.id, &crate_.name)?;
+
+                if let Some(alias_expires_at) = alias_expires_at {
+                    insert_into(crate_aliases::table)
+                        .values((
+                            crate_aliases::crate_id.eq(crate_.id),
+                            crate_aliases::organisation_id.eq(source.id),
+                            crate_aliases::name.eq(&crate_.name),
+                            crate_aliases::expires_at.eq(alias_expires_at),
+                        ))
+                        .execute(&conn)?;
+                }
+
+                let before = serde_json::json!({ "organisation": source.name });
+                let after = serde_json::json!({
+                    "organisation": destination.name,
+                    "alias": alias_expires_at.is_some(),
+                });
+
+                // recorded against both organisations so the transfer shows up in each of their
+                // histories
+                for organisation_id in [source.id, destination.id] {
+                    NewAuditEvent::new(AuditAction::CrateTransferred)
+                        .crate_(crate_)
+                        .organisation(organisation_id)
+                        .before(before.clone())
+                        .after(after.clone())
+                        .record(&conn, &actor)?;
+                }
+
+                Ok(())
+            })
+        })
+        .await?
+    }
+}
+
+#[cfg(all(test, feature = "sqlite"))]
+mod tests {
+    use crate::{
+        crates::Crate, organisations::Organisation, permissions::UserPermission,
+        schema::crate_aliases, schema::user_organisation_permissions, test_actor, test_crate,
+        test_publish, test_user, Error,
+    };
+    use chrono::{Duration, Utc};
+    use diesel::prelude::*;
+    use std::sync::Arc;
+
+    #[tokio::test]
+    async fn transfer() {
+        let conn = crate::test_pool();
+        let owner = test_user(&conn, "owner").await;
+        let member = test_user(&conn, "member").await;
+
+        let crate_ = test_crate(&conn, owner, "source", "widget").await;
+        test_publish(&conn, &crate_, owner, "1.0.0").await;
+        test_crate(&conn, owner, "destination", "existing").await;
+
+        // the member can only see the organisation the crate is being moved out of
+        diesel::insert_into(user_organisation_permissions::table)
+            .values((
+                user_organisation_permissions::user_id.eq(member),
+                user_organisation_permissions::organisation_id.eq(crate_.crate_.organisation_id),
+                user_organisation_permissions::permissions.eq(UserPermission::VISIBLE.bits()),
+            ))
+            .execute(&conn.get().unwrap())
+            .unwrap();
+
+        let destination = Arc::new(
+            Organisation::find_by_name(conn.clone(), owner, "destination".into())
+                .await
+                .unwrap(),
+        );
+        crate_
+            .clone()
+            .transfer(
+                conn.clone(),
+                test_actor(owner),
+                destination.clone(),
+                Some((Utc::now() + Duration::days(1)).naive_utc()),
+            )
+            .await
+            .unwrap();
+
+        let find = |user_id: i32, organisation: &str| {
+            Crate::find_by_name(
+                conn.clone(),
+                user_id,
+                organisation.to_string(),
+                "widget".to_string(),
+            )
+        };
+        let listed = |user_id: i32, organisation: &str| {
+            let conn = conn.clone();
+            let organisation = organisation.to_string();
+
+            async move {
+                Crate::list_with_versions(conn, user_id, organisation)
+                    .await
+                    .unwrap()
+                    .keys()
+                    .any(|crate_| crate_.name == "widget")
+            }
+        };
+
+        let moved = find(owner, "destination").await.unwrap();
+        assert_eq!(moved.crate_.organisation_id, destination.organisation().id);
+        assert!(listed(owner, "destination").await);
+
+        // the alias keeps the crate visible to the old organisation's members, and nothing more
+        let aliased = find(member, "source").await.unwrap();
+        assert_eq!(aliased.crate_.id, crate_.crate_.id);
+        assert_eq!(aliased.permissions, UserPermission::VISIBLE);
+        assert!(listed(member, "source").await);
+        assert!(matches!(
+            find(member, "destination").await,
+            Err(Error::MissingCratePermission(_))
+        ));
+        assert!(!listed(member, "destination").await);
+
+        diesel::update(crate_aliases::table)
+            .set(crate_aliases::expires_at.eq((Utc::now() - Duration::seconds(1)).naive_utc()))
+            .execute(&conn.get().unwrap())
+            .unwrap();
+
+        assert!(matches!(
+            find(member, "source").await,
+            Err(Error::MissingCrate)
+        ));
+        assert!(matches!(
+            find(owner, "source").await,
+            Err(Error::MissingCrate)
+        ));
+        assert!(!listed(member, "source").await);
+        assert!(!listed(owner, "source").await);
+    }
+
+    #[tokio::test]
+    async fn name_collisions() {
+        let conn = crate::test_pool();
+        let owner = test_user(&conn, "owner").await;
+
+        let crate_ = test_crate(&conn, owner, "source", "widget").await;
+        let other = test_crate(&conn, owner, "other", "widget").await;
+        test_crate(&conn, owner, "destination", "unrelated").await;
+
+        let organisation = |name: &str| {
+            let conn = conn.clone();
+            let name = name.to_string();
+
+            async move { Arc::new(Organisation::find_by_name(conn, owner, name).await.unwrap()) }
+        };
+
+        assert!(matches!(
+            crate_
+                .clone()
+                .transfer(
+                    conn.clone(),
+                    test_actor(owner),
+                    organisation("source").await,
+                    None
+                )
+                .await,
+            Err(Error::AlreadyInOrganisation)
+        ));
+
+        crate_
+            .clone()
+            .transfer(
+                conn.clone(),
+                test_actor(owner),
+                organisation("destination").await,
+                Some((Utc::now() + Duration::days(1)).naive_utc()),
+            )
+            .await
+            .unwrap();
+
+        // the destination already has a crate by that name now
+        assert!(matches!(
+            other
+                .clone()
+                .transfer(
+                    conn.clone(),
+                    test_actor(owner),
+                    organisation("destination").await,
+                    None
+                )
+                .await,
+            Err(Error::CrateNameTaken)
+        ));
+
+        // a new crate taking the name in the old organisation replaces the alias
+        let replacement = Crate::create(
+            conn.clone(),
+            test_actor(owner),
+            "source".to_string(),
+            "widget".to_string(),
+        )
+        .await
+        .unwrap();
+        assert_ne!(replacement.crate_.id, crate_.crate_.id);
+
+        let found = Crate::find_by_name(conn.clone(), owner, "source".into(), "widget".into())
+            .await
+            .unwrap();
+        assert_eq!(found.crate_.id, replacement.crate_.id);
+
+        let aliases: i64 = crate_aliases::table
+            .count()
+            .get_result(&conn.get().unwrap())
+            .unwrap();
+        assert_eq!(aliases, 0);
+    }
+}
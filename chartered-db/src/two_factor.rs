@@ -0,0 +1,381 @@
+//! Storage for time-based one-time password (TOTP) secrets and the recovery codes issued
+//! alongside them.
+//!
+//! Secrets are stored encrypted, and recovery codes are stored hashed. Both operations are done
+//! by the caller, so all we're doing here is moving bytes around and making sure codes can't be
+//! used more than once.
+
+use crate::{
+    audit::{AuditAction, AuditActor, NewAuditEvent},
+    organisations::OrganisationWithPermissions,
+    permissions::UserPermission,
+    schema::{organisations, user_recovery_codes, user_totp},
+    users::User,
+    ConnectionPool, Error, Result,
+};
+use diesel::{insert_into, prelude::*, Associations, Identifiable, Queryable};
+use std::sync::Arc;
+
+#[derive(Identifiable, Queryable, Associations, PartialEq, Eq, Hash, Debug)]
+#[belongs_to(User)]
+#[table_name = "user_totp"]
+pub struct UserTotp {
+    pub id: i32,
+    pub user_id: i32,
+    /// The encrypted secret
+    pub secret: Vec<u8>,
+    pub last_used_step: Option<i64>,
+    pub confirmed_at: Option<chrono::NaiveDateTime>,
+    pub created_at: chrono::NaiveDateTime,
+}
+
+/// Builds an SQL expression that masks out the permissions to publish, yank, create crates and
+/// manage users or the organisation itself if the organisation in the outer query requires
+/// two-factor authentication and `user_id` hasn't enabled it, this should be ANDed with all the
+/// permissions the user has been granted on the organisation or any of its crates.
+///
+/// `MANAGE_ORGANISATION` has to be masked too, otherwise the requirement could be turned off by
+/// the very members it applies to.
+pub(crate) fn two_factor_permissions_mask_sql(user_id: i32) -> String {
+    let requires_two_factor = UserPermission::PUBLISH_VERSION
+        | UserPermission::YANK_VERSION
+        | UserPermission::MANAGE_USERS
+        | UserPermission::CREATE_CRATE
+        | UserPermission::MANAGE_ORGANISATION;
+
+    format!(
+        "(CASE WHEN organisations.require_two_factor AND NOT EXISTS(SELECT 1 FROM user_totp WHERE \
+         user_totp.user_id = {} AND user_totp.confirmed_at IS NOT NULL) THEN {} ELSE {} END)",
+        user_id,
+        (UserPermission::all() - requires_two_factor).bits(),
+        UserPermission::all().bits(),
+    )
+}
+
+impl UserTotp {
+    /// Grabs the TOTP secret for the given user, this may not have been confirmed yet.
+    pub async fn find(conn: ConnectionPool, given_user_id: i32) -> Result<Option<UserTotp>> {
+        tokio::task::spawn_blocking(move || {
+            let conn = conn.get()?;
+
+            Ok(user_totp::table
+                .filter(user_totp::user_id.eq(given_user_id))
+                .get_result(&conn)
+                .optional()?)
+        })
+        .await?
+    }
+
+    #[must_use]
+    pub fn is_confirmed(&self) -> bool {
+        self.confirmed_at.is_some()
+    }
+
+    /// Marks the code for the given time step as used, returning false if a code for this step
+    /// (or a later one) has already been used so codes can't be replayed.
+    pub async fn record_use(self: Arc<Self>, conn: ConnectionPool, step: i64) -> Result<bool> {
+        tokio::task::spawn_blocking(move || {
+            let conn = conn.get()?;
+            Ok(record_use(&conn, self.id, step)? == 1)
+        })
+        .await?
+    }
+
+    /// Confirms the user has successfully set up their authenticator using a code for the given
+    /// time step, enabling two-factor authentication on their account and replacing any
+    /// recovery codes they had with the given (hashed) codes.
+    pub async fn confirm(
+        self: Arc<Self>,
+        conn: ConnectionPool,
+        actor: AuditActor,
+        step: i64,
+        recovery_code_hashes: Vec<String>,
+    ) -> Result<bool> {
+        tokio::task::spawn_blocking(move || {
+            let conn = conn.get()?;
+
+            conn.transaction::<_, crate::Error, _>(|| {
+                if self.is_confirmed() {
+                    return Err(Error::TwoFactorAlreadyEnabled);
+                }
+
+                if record_use(&conn, self.id, step)? == 0 {
+                    return Ok(false);
+                }
+
+                diesel::update(user_totp::table.filter(user_totp::id.eq(self.id)))
+                    .set(user_totp::confirmed_at.eq(diesel::dsl::now))
+                    .execute(&conn)?;
+
+                replace_recovery_codes(&conn, self.user_id, &recovery_code_hashes)?;
+
+                NewAuditEvent::new(AuditAction::TwoFactorEnabled)
+                    .target_user(self.user_id)
+                    .record(&conn, &actor)?;
+
+                Ok(true)
+            })
+        })
+        .await?
+    }
+}
+
+fn record_use(conn: &crate::Connection, totp_id: i32, step: i64) -> QueryResult<usize> {
+    diesel::update(
+        user_totp::table.filter(user_totp::id.eq(totp_id)).filter(
+            user_totp::last_used_step
+                .is_null()
+                .or(user_totp::last_used_step.lt(step)),
+        ),
+    )
+    .set(user_totp::last_used_step.eq(step))
+    .execute(conn)
+}
+
+fn replace_recovery_codes(
+    conn: &crate::Connection,
+    given_user_id: i32,
+    code_hashes: &[String],
+) -> QueryResult<()> {
+    use crate::schema::user_recovery_codes::dsl::{code_hash, user_id};
+
+    diesel::delete(user_recovery_codes::table.filter(user_id.eq(given_user_id))).execute(conn)?;
+
+    insert_into(user_recovery_codes::table)
+        .values(
+            code_hashes
+                .iter()
+                .map(|hash| (user_id.eq(given_user_id), code_hash.eq(hash)))
+                .collect::<Vec<_>>(),
+        )
+        .execute(conn)?;
+
+    Ok(())
+}
+
+impl User {
+    /// Stores a new, unconfirmed, TOTP secret for the user. The secret won't be required to
+    /// login until it has been confirmed with [`UserTotp::confirm`].
+    pub async fn begin_totp_enrolment(
+        self: Arc<Self>,
+        conn: ConnectionPool,
+        encrypted_secret: Vec<u8>,
+    ) -> Result<()> {
+        tokio::task::spawn_blocking(move || {
+            let conn = conn.get()?;
+
+            conn.transaction::<_, crate::Error, _>(|| {
+                let existing: Option<UserTotp> = user_totp::table
+                    .filter(user_totp::user_id.eq(self.id))
+                    .get_result(&conn)
+                    .optional()?;
+
+                match existing {
+                    Some(existing) if existing.is_confirmed() => {
+                        return Err(Error::TwoFactorAlreadyEnabled)
+                    }
+                    // the user never finished enrolling last time, so we'll just start again
+                    Some(existing) => {
+                        diesel::delete(user_totp::table.filter(user_totp::id.eq(existing.id)))
+                            .execute(&conn)?;
+                    }
+                    None => {}
+                }
+
+                insert_into(user_totp::table)
+                    .values((
+                        user_totp::user_id.eq(self.id),
+                        user_totp::secret.eq(encrypted_secret),
+                    ))
+                    .execute(&conn)?;
+
+                Ok(())
+            })
+        })
+        .await?
+    }
+
+    /// Removes the user's TOTP secret and all their recovery codes, turning two-factor
+    /// authentication off for their account.
+    pub async fn disable_two_factor(
+        self: Arc<Self>,
+        conn: ConnectionPool,
+        actor: AuditActor,
+    ) -> Result<()> {
+        tokio::task::spawn_blocking(move || {
+            let conn = conn.get()?;
+
+            conn.transaction::<_, crate::Error, _>(|| {
+                diesel::delete(
+                    user_recovery_codes::table.filter(user_recovery_codes::user_id.eq(self.id)),
+                )
+                .execute(&conn)?;
+
+                let rows = diesel::delete(user_totp::table.filter(user_totp::user_id.eq(self.id)))
+                    .execute(&conn)?;
+
+                if rows > 0 {
+                    NewAuditEvent::new(AuditAction::TwoFactorDisabled)
+                        .target_user(self.id)
+                        .record(&conn, &actor)?;
+                }
+
+                Ok(())
+            })
+        })
+        .await?
+    }
+
+    /// Marks the recovery code with the given hash as used, returning false if there's no
+    /// unused code with the hash.
+    pub async fn use_recovery_code(
+        conn: ConnectionPool,
+        given_user_id: i32,
+        given_code_hash: String,
+    ) -> Result<bool> {
+        use crate::schema::user_recovery_codes::dsl::{code_hash, used_at, user_id};
+
+        tokio::task::spawn_blocking(move || {
+            let conn = conn.get()?;
+
+            let rows = diesel::update(
+                user_recovery_codes::table
+                    .filter(user_id.eq(given_user_id))
+                    .filter(code_hash.eq(given_code_hash))
+                    .filter(used_at.is_null()),
+            )
+            .set(used_at.eq(diesel::dsl::now))
+            .execute(&conn)?;
+
+            Ok(rows == 1)
+        })
+        .await?
+    }
+
+    /// Counts the recovery codes the user has left.
+    pub async fn remaining_recovery_codes(self: Arc<Self>, conn: ConnectionPool) -> Result<i64> {
+        tokio::task::spawn_blocking(move || {
+            let conn = conn.get()?;
+
+            Ok(user_recovery_codes::table
+                .filter(user_recovery_codes::user_id.eq(self.id))
+                .filter(user_recovery_codes::used_at.is_null())
+                .count()
+                .get_result(&conn)?)
+        })
+        .await?
+    }
+
+    /// Replaces all the user's recovery codes with a new set of (hashed) codes.
+    pub async fn regenerate_recovery_codes(
+        self: Arc<Self>,
+        conn: ConnectionPool,
+        actor: AuditActor,
+        recovery_code_hashes: Vec<String>,
+    ) -> Result<()> {
+        tokio::task::spawn_blocking(move || {
+            let conn = conn.get()?;
+
+            conn.transaction::<_, crate::Error, _>(|| {
+                replace_recovery_codes(&conn, self.id, &recovery_code_hashes)?;
+
+                NewAuditEvent::new(AuditAction::RecoveryCodesRegenerated)
+                    .target_user(self.id)
+                    .record(&conn, &actor)?;
+
+                Ok(())
+            })
+        })
+        .await?
+    }
+}
+
+impl OrganisationWithPermissions {
+    /// Sets whether members of the organisation need two-factor authentication enabled to make
+    /// use of any permissions beyond `VISIBLE`.
+    pub async fn set_require_two_factor(
+        self: Arc<Self>,
+        conn: ConnectionPool,
+        actor: AuditActor,
+        required: bool,
+    ) -> Result<()> {
+        if !self
+            .permissions()
+            .contains(UserPermission::MANAGE_ORGANISATION)
+        {
+            return Err(Error::MissingOrganisationPermission(
+                UserPermission::MANAGE_ORGANISATION,
+            ));
+        }
+
+        tokio::task::spawn_blocking(move || {
+            let conn = conn.get()?;
+
+            conn.transaction::<_, crate::Error, _>(|| {
+                diesel::update(
+                    organisations::table.filter(organisations::id.eq(self.organisation().id)),
+                )
+                .set(organisations::require_two_factor.eq(required))
+                .execute(&conn)?;
+
+                NewAuditEvent::new(AuditAction::OrganisationUpdated)
+                    .organisation(self.organisation().id)
+                    .before(serde_json::json!({
+                        "require_two_factor": self.organisation().require_two_factor,
+                    }))
+                    .after(serde_json::json!({ "require_two_factor": required }))
+                    .record(&conn, &actor)?;
+
+                Ok(())
+            })
+        })
+        .await?
+    }
+}
+
+#[cfg(all(test, feature = "sqlite"))]
+mod tests {
+    use crate::{organisations::Organisation, permissions::UserPermission, test_actor, test_user};
+    use std::sync::Arc;
+
+    #[tokio::test]
+    async fn require_two_factor() {
+        let conn = crate::test_pool();
+        let owner = test_user(&conn, "owner").await;
+
+        Organisation::create(
+            conn.clone(),
+            "org".into(),
+            String::new(),
+            false,
+            test_actor(owner),
+        )
+        .await
+        .unwrap();
+        let organisation = Arc::new(
+            Organisation::find_by_name(conn.clone(), owner, "org".into())
+                .await
+                .unwrap(),
+        );
+
+        organisation
+            .set_require_two_factor(conn.clone(), test_actor(owner), true)
+            .await
+            .unwrap();
+
+        // without two-factor authentication the owner can't turn the requirement back off
+        let organisation = Arc::new(
+            Organisation::find_by_name(conn.clone(), owner, "org".into())
+                .await
+                .unwrap(),
+        );
+        assert_eq!(organisation.permissions(), UserPermission::VISIBLE);
+        assert!(matches!(
+            organisation
+                .set_require_two_factor(conn.clone(), test_actor(owner), false)
+                .await,
+            Err(crate::Error::MissingOrganisationPermission(v))
+                if v == UserPermission::MANAGE_ORGANISATION
+        ));
+    }
+}
@@ -0,0 +1,503 @@
+//! Single-use, expiring tokens that are mailed out to users to prove they have access to an email
+//! address, either to verify it or to reset their password, or handed out once a user has given
+//! their password so they can continue on to give their second factor. Along with everything that
+//! happens once one of those tokens has been used.
+//!
+//! Passkey logins are tracked the same way, keyed by the ceremony's id with the ceremony's state
+//! stored alongside it.
+//!
+//! Only the hash of each token is stored, hashing is done by the caller.
+
+use crate::{
+    audit::{AuditAction, AuditActor, NewAuditEvent},
+    schema::{user_sessions, user_tokens, users},
+    users::User,
+    ConnectionPool, Result,
+};
+use chrono::NaiveDateTime;
+use diesel::{insert_into, prelude::*};
+use std::sync::Arc;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum UserTokenPurpose {
+    EmailVerification,
+    PasswordReset,
+    PreAuth,
+    WebauthnLogin,
+}
+
+impl UserTokenPurpose {
+    fn as_str(self) -> &'static str {
+        match self {
+            Self::EmailVerification => "email_verification",
+            Self::PasswordReset => "password_reset",
+            Self::PreAuth => "pre_auth",
+            Self::WebauthnLogin => "webauthn_login",
+        }
+    }
+}
+
+/// Replaces any outstanding tokens the user has for `purpose` with the given token.
+fn replace_token(
+    conn: &crate::Connection,
+    given_user_id: i32,
+    purpose: UserTokenPurpose,
+    given_token_hash: &str,
+    given_email: Option<&str>,
+    given_expires_at: NaiveDateTime,
+) -> QueryResult<()> {
+    use crate::schema::user_tokens::dsl::{
+        email, expires_at, purpose as purpose_column, token_hash, used_at, user_id,
+    };
+
+    diesel::delete(
+        user_tokens::table
+            .filter(user_id.eq(given_user_id))
+            .filter(purpose_column.eq(purpose.as_str()))
+            .filter(used_at.is_null()),
+    )
+    .execute(conn)?;
+
+    insert_into(user_tokens::table)
+        .values((
+            user_id.eq(given_user_id),
+            purpose_column.eq(purpose.as_str()),
+            token_hash.eq(given_token_hash),
+            email.eq(given_email),
+            expires_at.eq(given_expires_at),
+        ))
+        .execute(conn)?;
+
+    Ok(())
+}
+
+/// Marks the token with the given hash as used, returning the user it was issued to and the
+/// email it was issued for, as long as it hasn't already been used or expired.
+fn consume_token(
+    conn: &crate::Connection,
+    purpose: UserTokenPurpose,
+    given_token_hash: &str,
+) -> QueryResult<Option<(i32, Option<String>)>> {
+    use crate::schema::user_tokens::dsl::{
+        email, expires_at, id, purpose as purpose_column, token_hash, used_at, user_id,
+    };
+
+    let token: Option<(i32, i32, Option<String>)> = user_tokens::table
+        .filter(token_hash.eq(given_token_hash))
+        .filter(purpose_column.eq(purpose.as_str()))
+        .filter(used_at.is_null())
+        .filter(expires_at.gt(chrono::Utc::now().naive_utc()))
+        .select((id, user_id, email))
+        .get_result(conn)
+        .optional()?;
+
+    let Some((token_id, token_user_id, token_email)) = token else {
+        return Ok(None);
+    };
+
+    // guards against the same token being used twice concurrently
+    let updated = diesel::update(
+        user_tokens::table
+            .filter(id.eq(token_id))
+            .filter(used_at.is_null()),
+    )
+    .set(used_at.eq(diesel::dsl::now))
+    .execute(conn)?;
+
+    Ok((updated == 1).then(|| (token_user_id, token_email)))
+}
+
+/// Logs out every web session the user has other than `keep_session_id`. Sessions belonging to
+/// SSH keys are left alone as they're regenerated on the next pull anyway.
+fn delete_web_sessions(
+    conn: &crate::Connection,
+    given_user_id: i32,
+    keep_session_id: Option<i32>,
+) -> QueryResult<()> {
+    use crate::schema::user_sessions::dsl::{id, user_id, user_ssh_key_id};
+
+    diesel::delete(
+        user_sessions::table
+            .filter(user_id.eq(given_user_id))
+            .filter(user_ssh_key_id.is_null())
+            .filter(id.ne_all(keep_session_id)),
+    )
+    .execute(conn)?;
+
+    Ok(())
+}
+
+impl User {
+    /// Changes the user's password, logging out every other web session they have and
+    /// invalidating any password reset tokens they've been sent.
+    pub async fn change_password(
+        self: Arc<Self>,
+        conn: ConnectionPool,
+        actor: AuditActor,
+        password_hash: String,
+        keep_session_id: i32,
+    ) -> Result<()> {
+        tokio::task::spawn_blocking(move || {
+            let conn = conn.get()?;
+
+            conn.transaction::<_, crate::Error, _>(|| {
+                diesel::update(users::table.filter(users::id.eq(self.id)))
+                    .set(users::password.eq(password_hash))
+                    .execute(&conn)?;
+
+                diesel::delete(
+                    user_tokens::table
+                        .filter(user_tokens::user_id.eq(self.id))
+                        .filter(user_tokens::purpose.eq(UserTokenPurpose::PasswordReset.as_str()))
+                        .filter(user_tokens::used_at.is_null()),
+                )
+                .execute(&conn)?;
+
+                delete_web_sessions(&conn, self.id, Some(keep_session_id))?;
+
+                NewAuditEvent::new(AuditAction::PasswordChanged)
+                    .target_user(self.id)
+                    .record(&conn, &actor)?;
+
+                Ok(())
+            })
+        })
+        .await?
+    }
+
+    /// Changes the user's email, marking it as unverified until the given verification token
+    /// has been used.
+    pub async fn set_email(
+        self: Arc<Self>,
+        conn: ConnectionPool,
+        actor: AuditActor,
+        given_email: String,
+        verification_token_hash: String,
+        expires_at: NaiveDateTime,
+    ) -> Result<()> {
+        tokio::task::spawn_blocking(move || {
+            let conn = conn.get()?;
+
+            conn.transaction::<_, crate::Error, _>(|| {
+                diesel::update(users::table.filter(users::id.eq(self.id)))
+                    .set((
+                        users::email.eq(&given_email),
+                        users::email_verified_at.eq(None::<NaiveDateTime>),
+                    ))
+                    .execute(&conn)?;
+
+                replace_token(
+                    &conn,
+                    self.id,
+                    UserTokenPurpose::EmailVerification,
+                    &verification_token_hash,
+                    Some(&given_email),
+                    expires_at,
+                )?;
+
+                NewAuditEvent::new(AuditAction::EmailChanged)
+                    .target_user(self.id)
+                    .before(serde_json::json!({ "email": self.email }))
+                    .after(serde_json::json!({ "email": given_email }))
+                    .record(&conn, &actor)?;
+
+                Ok(())
+            })
+        })
+        .await?
+    }
+
+    /// Marks the email the given verification token was sent to as verified, returning false if
+    /// the token is invalid or the user has changed their email since it was sent.
+    pub async fn verify_email(
+        conn: ConnectionPool,
+        verification_token_hash: String,
+        ip: Option<String>,
+        user_agent: Option<String>,
+    ) -> Result<bool> {
+        tokio::task::spawn_blocking(move || {
+            let conn = conn.get()?;
+
+            conn.transaction::<_, crate::Error, _>(|| {
+                let Some((user_id, Some(token_email))) = consume_token(
+                    &conn,
+                    UserTokenPurpose::EmailVerification,
+                    &verification_token_hash,
+                )?
+                else {
+                    return Ok(false);
+                };
+
+                let updated = diesel::update(
+                    users::table
+                        .filter(users::id.eq(user_id))
+                        .filter(users::email.eq(&token_email)),
+                )
+                .set(users::email_verified_at.eq(diesel::dsl::now))
+                .execute(&conn)?;
+
+                if updated == 0 {
+                    return Ok(false);
+                }
+
+                NewAuditEvent::new(AuditAction::EmailVerified)
+                    .target_user(user_id)
+                    .after(serde_json::json!({ "email": token_email }))
+                    .record(
+                        &conn,
+                        &AuditActor {
+                            user_id,
+                            ip,
+                            user_agent,
+                        },
+                    )?;
+
+                Ok(true)
+            })
+        })
+        .await?
+    }
+
+    /// Finds all the users that have verified the given email and have a password that could be
+    /// reset.
+    pub async fn find_by_verified_email(
+        conn: ConnectionPool,
+        given_email: String,
+    ) -> Result<Vec<User>> {
+        tokio::task::spawn_blocking(move || {
+            let conn = conn.get()?;
+
+            Ok(users::table
+                .filter(users::email.eq(given_email))
+                .filter(users::email_verified_at.is_not_null())
+                .filter(users::password.is_not_null())
+                .load(&conn)?)
+        })
+        .await?
+    }
+
+    /// Stores a password reset token for the user, replacing any they've been sent previously.
+    pub async fn create_password_reset_token(
+        self: Arc<Self>,
+        conn: ConnectionPool,
+        reset_token_hash: String,
+        expires_at: NaiveDateTime,
+    ) -> Result<()> {
+        tokio::task::spawn_blocking(move || {
+            let conn = conn.get()?;
+
+            Ok(replace_token(
+                &conn,
+                self.id,
+                UserTokenPurpose::PasswordReset,
+                &reset_token_hash,
+                self.email.as_deref(),
+                expires_at,
+            )?)
+        })
+        .await?
+    }
+
+    /// Finds the user the given password reset token was sent to, as long as it hasn't been used
+    /// or expired. The token is left as it is, it's only used up by `reset_password`.
+    pub async fn find_by_password_reset_token(
+        conn: ConnectionPool,
+        reset_token_hash: String,
+    ) -> Result<Option<User>> {
+        use crate::schema::user_tokens::dsl::{expires_at, purpose, token_hash, used_at};
+
+        tokio::task::spawn_blocking(move || {
+            let conn = conn.get()?;
+
+            Ok(user_tokens::table
+                .inner_join(users::table)
+                .filter(token_hash.eq(reset_token_hash))
+                .filter(purpose.eq(UserTokenPurpose::PasswordReset.as_str()))
+                .filter(used_at.is_null())
+                .filter(expires_at.gt(chrono::Utc::now().naive_utc()))
+                .select(users::all_columns)
+                .get_result(&conn)
+                .optional()?)
+        })
+        .await?
+    }
+
+    /// Sets a new password for the user the given reset token was sent to, unlocks their account
+    /// and logs out all their web sessions, returning false if the token is invalid.
+    pub async fn reset_password(
+        conn: ConnectionPool,
+        reset_token_hash: String,
+        password_hash: String,
+        ip: Option<String>,
+        user_agent: Option<String>,
+    ) -> Result<bool> {
+        tokio::task::spawn_blocking(move || {
+            let conn = conn.get()?;
+
+            conn.transaction::<_, crate::Error, _>(|| {
+                let Some((user_id, _)) =
+                    consume_token(&conn, UserTokenPurpose::PasswordReset, &reset_token_hash)?
+                else {
+                    return Ok(false);
+                };
+
+                // proving access to the account's email is enough to lift any lockout on it
+                diesel::update(users::table.filter(users::id.eq(user_id)))
+                    .set((
+                        users::password.eq(password_hash),
+                        users::failed_login_attempts.eq(0),
+                        users::locked_until.eq(None::<NaiveDateTime>),
+                    ))
+                    .execute(&conn)?;
+
+                delete_web_sessions(&conn, user_id, None)?;
+
+                NewAuditEvent::new(AuditAction::PasswordReset)
+                    .target_user(user_id)
+                    .record(
+                        &conn,
+                        &AuditActor {
+                            user_id,
+                            ip,
+                            user_agent,
+                        },
+                    )?;
+
+                Ok(true)
+            })
+        })
+        .await?
+    }
+
+    /// Stores a pre-auth token for the user, replacing any they've been given previously so only
+    /// the most recent login attempt can be continued.
+    pub async fn create_pre_auth_token(
+        self: Arc<Self>,
+        conn: ConnectionPool,
+        pre_auth_token_hash: String,
+        expires_at: NaiveDateTime,
+    ) -> Result<()> {
+        tokio::task::spawn_blocking(move || {
+            let conn = conn.get()?;
+
+            Ok(replace_token(
+                &conn,
+                self.id,
+                UserTokenPurpose::PreAuth,
+                &pre_auth_token_hash,
+                None,
+                expires_at,
+            )?)
+        })
+        .await?
+    }
+
+    /// Uses up the given pre-auth token, returning the user it was issued to if it was valid. The
+    /// token can't be used again regardless of whether the login it was for goes on to succeed.
+    pub async fn consume_pre_auth_token(
+        conn: ConnectionPool,
+        pre_auth_token_hash: String,
+    ) -> Result<Option<User>> {
+        tokio::task::spawn_blocking(move || {
+            let conn = conn.get()?;
+
+            conn.transaction::<_, crate::Error, _>(|| {
+                let Some((user_id, _)) =
+                    consume_token(&conn, UserTokenPurpose::PreAuth, &pre_auth_token_hash)?
+                else {
+                    return Ok(None);
+                };
+
+                Ok(Some(users::table.find(user_id).get_result(&conn)?))
+            })
+        })
+        .await?
+    }
+
+    /// Stores the state of a passkey login started for the user, to be taken back out with
+    /// `take_webauthn_login` once the user's authenticator has responded. Any number of logins
+    /// can be in progress at once, so starting one can't cancel another.
+    pub async fn create_webauthn_login(
+        self: Arc<Self>,
+        conn: ConnectionPool,
+        ceremony_id_hash: String,
+        given_state: String,
+        given_expires_at: NaiveDateTime,
+    ) -> Result<()> {
+        use crate::schema::user_tokens::dsl::{expires_at, purpose, state, token_hash, user_id};
+
+        tokio::task::spawn_blocking(move || {
+            let conn = conn.get()?;
+
+            // logins that were never completed are cleared out as new ones come in
+            diesel::delete(
+                user_tokens::table
+                    .filter(purpose.eq(UserTokenPurpose::WebauthnLogin.as_str()))
+                    .filter(expires_at.lt(chrono::Utc::now().naive_utc())),
+            )
+            .execute(&conn)?;
+
+            insert_into(user_tokens::table)
+                .values((
+                    user_id.eq(self.id),
+                    purpose.eq(UserTokenPurpose::WebauthnLogin.as_str()),
+                    token_hash.eq(ceremony_id_hash),
+                    state.eq(given_state),
+                    expires_at.eq(given_expires_at),
+                ))
+                .execute(&conn)?;
+
+            Ok(())
+        })
+        .await?
+    }
+
+    /// Removes the passkey login with the given ceremony id, returning the user it was started
+    /// for and its state as long as it hasn't expired. Each login can only be taken once, so its
+    /// challenge can't be replayed.
+    pub async fn take_webauthn_login(
+        conn: ConnectionPool,
+        ceremony_id_hash: String,
+    ) -> Result<Option<(User, String)>> {
+        use crate::schema::user_tokens::dsl::{
+            expires_at, id, purpose, state, token_hash, user_id,
+        };
+
+        tokio::task::spawn_blocking(move || {
+            let conn = conn.get()?;
+
+            conn.transaction::<_, crate::Error, _>(|| {
+                let ceremony: Option<(i32, i32, Option<String>, NaiveDateTime)> =
+                    user_tokens::table
+                        .filter(token_hash.eq(ceremony_id_hash))
+                        .filter(purpose.eq(UserTokenPurpose::WebauthnLogin.as_str()))
+                        .select((id, user_id, state, expires_at))
+                        .get_result(&conn)
+                        .optional()?;
+
+                let Some((
+                    ceremony_id,
+                    ceremony_user_id,
+                    Some(ceremony_state),
+                    ceremony_expires_at,
+                )) = ceremony
+                else {
+                    return Ok(None);
+                };
+
+                // guards against the same ceremony being completed twice concurrently
+                let deleted =
+                    diesel::delete(user_tokens::table.filter(id.eq(ceremony_id))).execute(&conn)?;
+
+                if deleted != 1 || ceremony_expires_at < chrono::Utc::now().naive_utc() {
+                    return Ok(None);
+                }
+
+                let user = users::table.find(ceremony_user_id).get_result(&conn)?;
+
+                Ok(Some((user, ceremony_state)))
+            })
+        })
+        .await?
+    }
+}
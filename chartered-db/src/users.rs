@@ -1,7 +1,10 @@
 use super::{
+    audit::{AuditAction, AuditActor, NewAuditEvent},
     crates::UserCratePermission,
     permissions::UserPermission,
-    schema::{user_crate_permissions, user_sessions, user_ssh_keys, users},
+    schema::{
+        user_crate_permissions, user_sessions, user_ssh_keys, user_webauthn_credentials, users,
+    },
     uuid::SqlUuid,
     ConnectionPool, Error, Result,
 };
@@ -17,7 +20,7 @@ use rand::{thread_rng, Rng};
 use std::sync::Arc;
 use thrussh_keys::PublicKeyBase64;
 
-#[derive(Identifiable, Queryable, Associations, PartialEq, Eq, Hash, Debug)]
+#[derive(Identifiable, Queryable, Associations, PartialEq, Eq, Hash, Clone, Debug)]
 pub struct User {
     pub id: i32,
     pub uuid: SqlUuid,
@@ -28,6 +31,9 @@ pub struct User {
     pub email: Option<String>,
     pub external_profile_url: Option<String>,
     pub picture_url: Option<String>,
+    pub email_verified_at: Option<chrono::NaiveDateTime>,
+    pub failed_login_attempts: i32,
+    pub locked_until: Option<chrono::NaiveDateTime>,
 }
 
 impl User {
@@ -135,47 +141,75 @@ impl User {
 
     /// Lookup the user in the database by username, or create the user if it doesn't yet
     /// exist. The user will be created with no password so it cannot be logged into using
-    /// standard `password` auth, and must be logged into using OAuth.
+    /// standard `password` auth, and must be logged into using OAuth or LDAP.
+    ///
+    /// The profile of an existing user is updated with the given values, so changes made at the
+    /// provider are picked up the next time the user logs in. The email address is only treated
+    /// as verified if `given_email_verified` is set, or if it's the same address the user had
+    /// already verified.
+    #[allow(clippy::too_many_arguments)]
     pub async fn find_or_create(
         conn: ConnectionPool,
         given_username: String,
         given_name: Option<String>,
         given_nick: Option<String>,
         given_email: Option<String>,
+        given_email_verified: bool,
         given_external_profile_url: Option<reqwest::Url>,
         given_picture_url: Option<reqwest::Url>,
     ) -> Result<User> {
         use crate::schema::users::dsl::{
-            email, external_profile_url, name, nick, picture_url, username, uuid,
+            email, email_verified_at, external_profile_url, name, nick, picture_url, username, uuid,
         };
 
         tokio::task::spawn_blocking(move || {
             let conn = conn.get()?;
 
-            let user: Option<User> = crate::schema::users::table
-                .filter(username.eq(&given_username))
-                .get_result(&conn)
-                .optional()?;
-
-            if let Some(user) = user {
-                return Ok(user);
-            }
-
-            diesel::insert_into(users::table)
-                .values((
-                    username.eq(&given_username),
-                    uuid.eq(SqlUuid::random()),
-                    name.eq(&given_name),
-                    nick.eq(&given_nick),
-                    email.eq(&given_email),
+            conn.transaction::<_, crate::Error, _>(|| {
+                let existing = users::table
+                    .filter(username.eq(&given_username))
+                    .select((email, email_verified_at))
+                    .get_result::<(Option<String>, Option<chrono::NaiveDateTime>)>(&conn)
+                    .optional()?;
+
+                let verified_at = match existing {
+                    // an address the user has already verified stays verified
+                    Some((previous_email, Some(verified_at))) if previous_email == given_email => {
+                        Some(verified_at)
+                    }
+                    _ if given_email_verified && given_email.is_some() => {
+                        Some(chrono::Utc::now().naive_utc())
+                    }
+                    _ => None,
+                };
+
+                let profile = (
+                    name.eq(given_name),
+                    nick.eq(given_nick),
+                    email.eq(given_email),
+                    email_verified_at.eq(verified_at),
                     external_profile_url.eq(given_external_profile_url.map(|v| v.to_string())),
                     picture_url.eq(given_picture_url.map(|v| v.to_string())),
-                ))
-                .execute(&conn)?;
+                );
+
+                if existing.is_some() {
+                    diesel::update(users::table.filter(username.eq(&given_username)))
+                        .set(profile)
+                        .execute(&conn)?;
+                } else {
+                    diesel::insert_into(users::table)
+                        .values((
+                            username.eq(&given_username),
+                            uuid.eq(SqlUuid::random()),
+                            profile,
+                        ))
+                        .execute(&conn)?;
+                }
 
-            Ok(crate::schema::users::table
-                .filter(username.eq(given_username))
-                .get_result(&conn)?)
+                Ok(crate::schema::users::table
+                    .filter(username.eq(given_username))
+                    .get_result(&conn)?)
+            })
         })
         .await?
     }
@@ -212,6 +246,7 @@ impl User {
     pub async fn insert_ssh_key(
         self: Arc<Self>,
         conn: ConnectionPool,
+        actor: AuditActor,
         ssh_key: &str,
     ) -> Result<()> {
         let mut split = ssh_key.split_whitespace();
@@ -229,16 +264,28 @@ impl User {
 
             let conn = conn.get()?;
 
-            insert_into(crate::schema::user_ssh_keys::dsl::user_ssh_keys)
-                .values((
-                    uuid.eq(SqlUuid::random()),
-                    name.eq(parsed_name),
-                    ssh_key.eq(parsed_key.public_key_bytes()),
-                    user_id.eq(self.id),
-                ))
-                .execute(&conn)?;
-
-            Ok(())
+            let generated_uuid = SqlUuid::random();
+
+            conn.transaction::<_, crate::Error, _>(|| {
+                insert_into(crate::schema::user_ssh_keys::dsl::user_ssh_keys)
+                    .values((
+                        uuid.eq(generated_uuid),
+                        name.eq(&parsed_name),
+                        ssh_key.eq(parsed_key.public_key_bytes()),
+                        user_id.eq(self.id),
+                    ))
+                    .execute(&conn)?;
+
+                NewAuditEvent::new(AuditAction::SshKeyAdded)
+                    .target_user(self.id)
+                    .after(serde_json::json!({
+                        "uuid": generated_uuid.to_string(),
+                        "name": parsed_name,
+                    }))
+                    .record(&conn, &actor)?;
+
+                Ok(())
+            })
         })
         .await?
     }
@@ -246,6 +293,7 @@ impl User {
     pub async fn delete_user_ssh_key_by_uuid(
         self: Arc<Self>,
         conn: ConnectionPool,
+        actor: AuditActor,
         ssh_key_id: uuid::Uuid,
     ) -> Result<bool> {
         use crate::schema::user_ssh_keys::dsl::{user_id, user_ssh_keys, uuid};
@@ -253,14 +301,30 @@ impl User {
         tokio::task::spawn_blocking(move || {
             let conn = conn.get()?;
 
-            let rows = diesel::delete(
-                user_ssh_keys
+            conn.transaction::<_, crate::Error, _>(|| {
+                let Some(key) = user_ssh_keys
                     .filter(user_id.eq(self.id))
-                    .filter(uuid.eq(SqlUuid(ssh_key_id))),
-            )
-            .execute(&conn)?;
-
-            Ok(rows > 0)
+                    .filter(uuid.eq(SqlUuid(ssh_key_id)))
+                    .get_result::<UserSshKey>(&conn)
+                    .optional()?
+                else {
+                    return Ok(false);
+                };
+
+                diesel::delete(user_ssh_keys.filter(crate::schema::user_ssh_keys::id.eq(key.id)))
+                    .execute(&conn)?;
+
+                NewAuditEvent::new(AuditAction::SshKeyDeleted)
+                    .target_user(self.id)
+                    .before(serde_json::json!({
+                        "uuid": key.uuid.to_string(),
+                        "name": key.name,
+                        "fingerprint": key.fingerprint().ok(),
+                    }))
+                    .record(&conn, &actor)?;
+
+                Ok(true)
+            })
         })
         .await?
     }
@@ -283,6 +347,103 @@ impl User {
         .await?
     }
 
+    /// Stores a WebAuthn credential the user has just registered. The credential itself is
+    /// opaque to us, it's serialised and verified by the caller.
+    pub async fn insert_webauthn_credential(
+        self: Arc<Self>,
+        conn: ConnectionPool,
+        actor: AuditActor,
+        given_name: String,
+        given_credential_id: Vec<u8>,
+        given_credential: String,
+    ) -> Result<()> {
+        use crate::schema::user_webauthn_credentials::dsl::{
+            credential, credential_id, name, user_id, uuid,
+        };
+
+        tokio::task::spawn_blocking(move || {
+            let conn = conn.get()?;
+
+            let generated_uuid = SqlUuid::random();
+
+            conn.transaction::<_, crate::Error, _>(|| {
+                insert_into(user_webauthn_credentials::table)
+                    .values((
+                        uuid.eq(generated_uuid),
+                        name.eq(&given_name),
+                        user_id.eq(self.id),
+                        credential_id.eq(given_credential_id),
+                        credential.eq(given_credential),
+                    ))
+                    .execute(&conn)?;
+
+                NewAuditEvent::new(AuditAction::WebauthnCredentialAdded)
+                    .target_user(self.id)
+                    .after(serde_json::json!({
+                        "uuid": generated_uuid.to_string(),
+                        "name": given_name,
+                    }))
+                    .record(&conn, &actor)?;
+
+                Ok(())
+            })
+        })
+        .await?
+    }
+
+    pub async fn delete_webauthn_credential_by_uuid(
+        self: Arc<Self>,
+        conn: ConnectionPool,
+        actor: AuditActor,
+        credential_uuid: uuid::Uuid,
+    ) -> Result<bool> {
+        use crate::schema::user_webauthn_credentials::dsl::{id, user_id, uuid};
+
+        tokio::task::spawn_blocking(move || {
+            let conn = conn.get()?;
+
+            conn.transaction::<_, crate::Error, _>(|| {
+                let Some(credential) = user_webauthn_credentials::table
+                    .filter(user_id.eq(self.id))
+                    .filter(uuid.eq(SqlUuid(credential_uuid)))
+                    .get_result::<UserWebauthnCredential>(&conn)
+                    .optional()?
+                else {
+                    return Ok(false);
+                };
+
+                diesel::delete(user_webauthn_credentials::table.filter(id.eq(credential.id)))
+                    .execute(&conn)?;
+
+                NewAuditEvent::new(AuditAction::WebauthnCredentialDeleted)
+                    .target_user(self.id)
+                    .before(serde_json::json!({
+                        "uuid": credential.uuid.to_string(),
+                        "name": credential.name,
+                    }))
+                    .record(&conn, &actor)?;
+
+                Ok(true)
+            })
+        })
+        .await?
+    }
+
+    /// Get all the WebAuthn credentials the user has registered.
+    pub async fn list_webauthn_credentials(
+        self: Arc<Self>,
+        conn: ConnectionPool,
+    ) -> Result<Vec<UserWebauthnCredential>> {
+        tokio::task::spawn_blocking(move || {
+            let conn = conn.get()?;
+
+            Ok(user_webauthn_credentials::table
+                .filter(user_webauthn_credentials::user_id.eq(self.id))
+                .load(&conn)?)
+        })
+        .await?
+    }
+
     pub async fn accessible_crates(
         self: Arc<Self>,
         conn: ConnectionPool,
@@ -432,32 +593,64 @@ impl UserSession {
         .await?
     }
 
-    pub async fn delete(self: Arc<Self>, conn: ConnectionPool) -> Result<bool> {
+    pub async fn delete(self: Arc<Self>, conn: ConnectionPool, actor: AuditActor) -> Result<bool> {
         tokio::task::spawn_blocking(move || {
             let conn = conn.get()?;
 
-            let res = diesel::delete(user_sessions::table)
-                .filter(user_sessions::id.eq(self.id))
-                .execute(&conn)?;
+            conn.transaction::<_, crate::Error, _>(|| {
+                let res = diesel::delete(user_sessions::table)
+                    .filter(user_sessions::id.eq(self.id))
+                    .execute(&conn)?;
 
-            Ok(res > 0)
+                if res > 0 {
+                    self.audit_deletion().record(&conn, &actor)?;
+                }
+
+                Ok(res > 0)
+            })
         })
         .await?
     }
 
-    pub async fn delete_by_uuid(conn: ConnectionPool, uuid: uuid::Uuid) -> Result<bool> {
+    pub async fn delete_by_uuid(
+        conn: ConnectionPool,
+        actor: AuditActor,
+        uuid: uuid::Uuid,
+    ) -> Result<bool> {
         tokio::task::spawn_blocking(move || {
             let conn = conn.get()?;
 
-            let res = diesel::delete(user_sessions::table)
-                .filter(user_sessions::uuid.eq(SqlUuid(uuid)))
-                .execute(&conn)?;
+            conn.transaction::<_, crate::Error, _>(|| {
+                let Some(session) = user_sessions::table
+                    .filter(user_sessions::uuid.eq(SqlUuid(uuid)))
+                    .get_result::<Self>(&conn)
+                    .optional()?
+                else {
+                    return Ok(false);
+                };
 
-            Ok(res > 0)
+                diesel::delete(user_sessions::table)
+                    .filter(user_sessions::id.eq(session.id))
+                    .execute(&conn)?;
+
+                session.audit_deletion().record(&conn, &actor)?;
+
+                Ok(true)
+            })
         })
         .await?
     }
 
+    fn audit_deletion(&self) -> NewAuditEvent {
+        NewAuditEvent::new(AuditAction::SessionDeleted)
+            .target_user(self.user_id)
+            .before(serde_json::json!({
+                "uuid": self.uuid.to_string(),
+                "user_agent": self.user_agent,
+                "ip": self.ip,
+            }))
+    }
+
     pub async fn extend(
         self: Arc<Self>,
         conn: ConnectionPool,
@@ -568,3 +761,57 @@ impl UserSshKey {
         Ok(hex)
     }
 }
+
+#[derive(Identifiable, Queryable, Associations, PartialEq, Eq, Hash, Debug)]
+#[belongs_to(User)]
+pub struct UserWebauthnCredential {
+    pub id: i32,
+    pub uuid: SqlUuid,
+    pub name: String,
+    pub user_id: i32,
+    pub credential_id: Vec<u8>,
+    /// The serialised credential, including its public key and signature counter
+    pub credential: String,
+    pub created_at: chrono::NaiveDateTime,
+    pub last_used_at: Option<chrono::NaiveDateTime>,
+}
+
+impl UserWebauthnCredential {
+    /// Grabs the most recently registered credential belonging to anyone, used as a template when
+    /// there's no real credential to hand.
+    pub async fn find_latest(conn: ConnectionPool) -> Result<Option<UserWebauthnCredential>> {
+        tokio::task::spawn_blocking(move || {
+            let conn = conn.get()?;
+
+            Ok(user_webauthn_credentials::table
+                .order_by(user_webauthn_credentials::id.desc())
+                .first(&conn)
+                .optional()?)
+        })
+        .await?
+    }
+
+    /// Updates the last used time of this credential, along with the serialised credential
+    /// itself if it changed as a result of being used (ie. its signature counter was bumped).
+    pub async fn update_last_used(
+        self: Arc<Self>,
+        conn: ConnectionPool,
+        updated_credential: Option<String>,
+    ) -> Result<()> {
+        use crate::schema::user_webauthn_credentials::dsl::{credential, id, last_used_at};
+
+        tokio::task::spawn_blocking(move || {
+            let conn = conn.get()?;
+
+            diesel::update(user_webauthn_credentials::table.filter(id.eq(self.id)))
+                .set((
+                    last_used_at.eq(diesel::dsl::now),
+                    credential.eq(updated_credential.as_ref().unwrap_or(&self.credential)),
+                ))
+                .execute(&conn)
+                .map(|_| ())
+                .map_err(Into::into)
+        })
+        .await?
+    }
+}
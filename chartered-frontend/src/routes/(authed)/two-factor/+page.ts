@@ -0,0 +1,5 @@
+export function load(): App.PageData {
+    return {
+        title: 'Two-Factor Authentication',
+    };
+}
@@ -1,5 +1,6 @@
 import { get, writable } from 'svelte/store';
 import { goto } from '$app/navigation';
+import { getAssertion, type RequestOptions } from '../webauthn';
 
 /**
  * The base URL of the chartered-web instance
@@ -107,20 +108,39 @@ interface LoginResponse {
 type LoginResult = LoginResponse & Error;
 
 /**
- * Attempt to log the user in using password-based auth with the given credentials,
+ * Response type of /web/v1/auth/login/password, /web/v1/auth/login/ldap and
+ * /web/v1/auth/login/oauth/complete if the user has two-factor authentication enabled, the
+ * token needs to be sent back along with a code from the user's authenticator to get a session.
+ */
+interface TwoFactorChallenge {
+    pre_auth_token: string;
+    expires: string;
+}
+type PasswordLoginResult = (LoginResponse | TwoFactorChallenge) & Error;
+
+/**
+ * Attempt to log the user in using password-based or LDAP auth with the given credentials,
  * throwing an error if the credentials are invalid or another error occurred.
  *
+ * If the user has two-factor authentication enabled, a pre-auth token is returned that
+ * needs to be passed to `loginTotp` along with a code to finish logging in.
+ *
  * @param username username to attempt to log in with
  * @param password password to attempt to log in with
+ * @param method whether to check the credentials against chartered itself or the LDAP directory
  */
-export async function login(username: string, password: string) {
+export async function login(
+    username: string,
+    password: string,
+    method: 'password' | 'ldap' = 'password',
+): Promise<string | null> {
     // call the backend and attempt the authentication
-    const result = await fetch(`${BASE_URL}/web/v1/public/auth/login/password`, {
+    const result = await fetch(`${BASE_URL}/web/v1/public/auth/login/${method}`, {
         method: 'POST',
         headers: { 'Content-Type': 'application/json' },
         body: JSON.stringify({ username, password }),
     });
-    const json: LoginResult = await result.json();
+    const json: PasswordLoginResult = await result.json();
 
     // server returned an error, forward it on - there's nothing else we
     // can do here
@@ -128,6 +148,11 @@ export async function login(username: string, password: string) {
         throw new Error(json.error);
     }
 
+    // the user needs to give us a code before they get a session
+    if ('pre_auth_token' in json) {
+        return json.pre_auth_token;
+    }
+
     // we got a successful response back from the server, get in there son
     auth.set({
         auth_key: json.key,
@@ -135,17 +160,100 @@ export async function login(username: string, password: string) {
         picture_url: json.picture_url,
         uuid: json.user_uuid,
     });
+
+    return null;
+}
+
+/**
+ * Finish logging in a user with two-factor authentication enabled, throwing an error if the
+ * code is invalid or the pre-auth token has expired.
+ *
+ * @param preAuthToken token returned by `login`
+ * @param code code from the user's authenticator, or one of their recovery codes
+ */
+export async function loginTotp(preAuthToken: string, code: string) {
+    const result = await fetch(`${BASE_URL}/web/v1/public/auth/login/totp`, {
+        method: 'POST',
+        headers: { 'Content-Type': 'application/json' },
+        body: JSON.stringify({ pre_auth_token: preAuthToken, code }),
+    });
+    const json: LoginResult = await result.json();
+
+    if (json.error) {
+        throw new Error(json.error);
+    }
+
+    auth.set({
+        auth_key: json.key,
+        expires: Date.parse(json.expires),
+        picture_url: json.picture_url,
+        uuid: json.user_uuid,
+    });
+}
+
+/**
+ * Response type of /web/v1/public/auth/webauthn/login/begin, contains the challenge for the
+ * user's authenticator to sign and the state of the login to send back along with it.
+ */
+interface WebauthnLoginBeginResponse {
+    options: RequestOptions;
+    state: string;
+}
+type WebauthnLoginBeginResult = WebauthnLoginBeginResponse & Error;
+
+/**
+ * Attempt to log the user in using one of the passkeys they've registered, throwing an error if
+ * the user has no passkeys, the browser refused to sign the challenge or the assertion was
+ * rejected.
+ *
+ * @param username username of the user to log in as
+ */
+export async function loginWebauthn(username: string) {
+    const beginResult = await fetch(`${BASE_URL}/web/v1/public/auth/webauthn/login/begin`, {
+        method: 'POST',
+        headers: { 'Content-Type': 'application/json' },
+        body: JSON.stringify({ username }),
+    });
+    const begin: WebauthnLoginBeginResult = await beginResult.json();
+
+    if (begin.error) {
+        throw new Error(begin.error);
+    }
+
+    // get the user's authenticator to sign the challenge
+    const credential = await getAssertion(begin.options);
+
+    const result = await fetch(`${BASE_URL}/web/v1/public/auth/webauthn/login/complete`, {
+        method: 'POST',
+        headers: { 'Content-Type': 'application/json' },
+        body: JSON.stringify({ state: begin.state, credential }),
+    });
+    const json: LoginResult = await result.json();
+
+    if (json.error) {
+        throw new Error(json.error);
+    }
+
+    auth.set({
+        auth_key: json.key,
+        expires: Date.parse(json.expires),
+        picture_url: json.picture_url,
+        uuid: json.user_uuid,
+    });
 }
 
 /**
  * Attempt to log the user in using the OAuth callback throwing an error if an error occurred.
  *
+ * If the user has two-factor authentication enabled, a pre-auth token is returned that
+ * needs to be passed to `loginTotp` along with a code to finish logging in.
+ *
  * @param params URL search parameters
  */
-export async function handleOAuthCallback(params: string) {
+export async function handleOAuthCallback(params: string): Promise<string | null> {
     // call the backend and attempt the authentication
     const result = await fetch(`${BASE_URL}/web/v1/public/auth/login/oauth/complete${params}`);
-    const json: LoginResult = await result.json();
+    const json: PasswordLoginResult = await result.json();
 
     // server returned an error, forward it on - there's nothing else we
     // can do here
@@ -153,6 +261,11 @@ export async function handleOAuthCallback(params: string) {
         throw new Error(json.error);
     }
 
+    // the user needs to give us a code before they get a session
+    if ('pre_auth_token' in json) {
+        return json.pre_auth_token;
+    }
+
     // we got a successful response back from the server, get in there son
     auth.set({
         auth_key: json.key,
@@ -160,6 +273,8 @@ export async function handleOAuthCallback(params: string) {
         picture_url: json.picture_url,
         uuid: json.user_uuid,
     });
+
+    return null;
 }
 
 /**
@@ -241,10 +356,12 @@ export async function logout() {
 
 /**
  * A list of possible authentication methods for the user, returning OAuth providers and
- * whether password auth is enabled.
+ * whether password, LDAP and WebAuthn auth are enabled.
  */
 interface OAuthProviders {
     password: boolean;
+    ldap: boolean;
+    webauthn: boolean;
     providers: string[];
 }
 
@@ -289,3 +406,54 @@ export async function register(username: string, password: string) {
         throw new Error('Failed to register, please try again later.');
     }
 }
+
+/**
+ * Sends a POST request to one of the public auth endpoints, throwing an error if the backend
+ * returned one.
+ *
+ * @param url url (without base) to send request to
+ * @param body JSON body to send along with the request
+ */
+async function publicPost(url: string, body: object) {
+    const result = await fetch(`${BASE_URL}${url}`, {
+        method: 'POST',
+        headers: { 'Content-Type': 'application/json' },
+        body: JSON.stringify(body),
+    });
+    const json: Error = await result.json();
+
+    if (json.error) {
+        throw new Error(json.error);
+    }
+}
+
+/**
+ * Ask the backend to send a password reset link to the given email. The backend won't tell us
+ * whether there's actually an account with that email verified.
+ *
+ * @param email email address the user has verified on their account
+ */
+export async function forgotPassword(email: string) {
+    await publicPost('/web/v1/public/auth/password/forgot', { email });
+}
+
+/**
+ * Set a new password for the user the reset link was sent to, throwing an error if the link
+ * has expired or the password doesn't meet the server's requirements.
+ *
+ * @param token token from the reset link
+ * @param password new password for the user
+ */
+export async function resetPassword(token: string, password: string) {
+    await publicPost('/web/v1/public/auth/password/reset', { token, password });
+}
+
+/**
+ * Mark the email the verification link was sent to as verified, throwing an error if the link
+ * has expired or already been used.
+ *
+ * @param token token from the verification link
+ */
+export async function verifyEmail(token: string) {
+    await publicPost('/web/v1/public/auth/email/verify', { token });
+}
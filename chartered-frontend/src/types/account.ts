@@ -0,0 +1,24 @@
+/**
+ * The result of a `GET /web/v1/auth/email`.
+ */
+export interface EmailStatus {
+    email?: string;
+    verified: boolean;
+    mail_enabled: boolean;
+}
+
+/**
+ * The result of a `PUT /web/v1/auth/email`, whether a verification link was mailed to the new
+ * address.
+ */
+export interface SetEmailResult {
+    verification_sent: boolean;
+    error?: string;
+}
+
+/**
+ * The result of a `POST /web/v1/auth/password`.
+ */
+export interface ChangePasswordResult {
+    error?: string;
+}
@@ -0,0 +1,35 @@
+/**
+ * The result of a `GET /web/v1/invitations`, the invitations the current user has been sent.
+ */
+export interface UserInvitationList {
+    invitations: UserInvitation[];
+}
+
+export interface UserInvitation {
+    uuid: string;
+    organisation: string;
+    organisation_description: string;
+    permissions: string[];
+    expires_at: string;
+}
+
+/**
+ * The result of a `GET /web/v1/organisations/:org/invitations`, the invitations the organisation
+ * has sent that haven't been responded to yet.
+ */
+export interface OrganisationInvitationList {
+    invitations: OrganisationInvitation[];
+}
+
+export interface OrganisationInvitation {
+    uuid: string;
+    user?: {
+        uuid: string;
+        display_name: string;
+        picture_url?: string;
+    };
+    email?: string;
+    permissions: string[];
+    expires_at: string;
+    created_at: string;
+}
@@ -9,9 +9,12 @@ export interface OrganisationListItem {
 }
 
 export interface OrganisationDetail {
+    name: string;
     description: string;
     crates: OrganisationCrate[];
     public: boolean;
+    require_two_factor: boolean;
+    can_manage_organisation: boolean;
 }
 
 export interface OrganisationCrate {
@@ -0,0 +1,32 @@
+/**
+ * The result of a `GET /web/v1/auth/totp`.
+ */
+export interface TwoFactorStatus {
+    enabled: boolean;
+    remaining_recovery_codes: number;
+}
+
+/**
+ * The result of a `PUT /web/v1/auth/totp`, contains the secret the user needs to add to their
+ * authenticator before confirming it.
+ */
+export interface EnrolTotpResult {
+    secret: string;
+    uri: string;
+    error?: string;
+}
+
+/**
+ * The result of a `POST /web/v1/auth/totp/confirm` or `POST /web/v1/auth/totp/recovery-codes`.
+ */
+export interface RecoveryCodesResult {
+    recovery_codes: string[];
+    error?: string;
+}
+
+/**
+ * The result of a `DELETE /web/v1/auth/totp`.
+ */
+export interface DisableTotpResult {
+    error?: string;
+}
@@ -0,0 +1,35 @@
+import type { CreationOptions } from '../webauthn';
+
+/**
+ * The result of a `GET /web/v1/auth/webauthn/credentials`
+ */
+export interface WebauthnCredentials {
+    credentials: WebauthnCredential[];
+}
+
+/**
+ * A singular credential from `WebauthnCredentials`.
+ */
+export interface WebauthnCredential {
+    uuid: string;
+    name: string;
+    created_at: string;
+    last_used_at?: string;
+}
+
+/**
+ * The result of a `POST /web/v1/auth/webauthn/register/begin`.
+ */
+export interface RegisterBeginResult {
+    options: CreationOptions;
+    state: string;
+    error?: string;
+}
+
+/**
+ * The result of a `POST /web/v1/auth/webauthn/register/complete` or
+ * `DELETE /web/v1/auth/webauthn/credentials/:uuid`.
+ */
+export interface WebauthnCredentialResult {
+    error?: string;
+}
@@ -0,0 +1,110 @@
+/**
+ * Helpers for passing WebAuthn options from chartered-web to the browser's credentials API and
+ * back again. The backend encodes every binary field as unpadded url-safe base64, whereas the
+ * browser wants (and gives back) `ArrayBuffer`s.
+ */
+
+function decode(value: string): ArrayBuffer {
+    const base64 = value.replace(/-/g, '+').replace(/_/g, '/');
+    const padded = base64 + '='.repeat((4 - (base64.length % 4)) % 4);
+    return Uint8Array.from(atob(padded), (c) => c.charCodeAt(0)).buffer;
+}
+
+function encode(value: ArrayBuffer): string {
+    return btoa(String.fromCharCode(...new Uint8Array(value)))
+        .replace(/\+/g, '-')
+        .replace(/\//g, '_')
+        .replace(/=+$/, '');
+}
+
+interface CredentialDescriptor {
+    id: string;
+    type: PublicKeyCredentialType;
+}
+
+/**
+ * `options` returned by `/web/v1/auth/webauthn/register/begin`, only the fields we need to
+ * decode are typed.
+ */
+export interface CreationOptions {
+    publicKey: Omit<PublicKeyCredentialCreationOptions, 'challenge' | 'user' | 'excludeCredentials'> & {
+        challenge: string;
+        user: Omit<PublicKeyCredentialUserEntity, 'id'> & { id: string };
+        excludeCredentials?: CredentialDescriptor[];
+    };
+}
+
+/**
+ * `options` returned by `/web/v1/public/auth/webauthn/login/begin`, only the fields we need to
+ * decode are typed.
+ */
+export interface RequestOptions {
+    publicKey: Omit<PublicKeyCredentialRequestOptions, 'challenge' | 'allowCredentials'> & {
+        challenge: string;
+        allowCredentials?: CredentialDescriptor[];
+    };
+}
+
+function decodeDescriptors(descriptors?: CredentialDescriptor[]): PublicKeyCredentialDescriptor[] {
+    return (descriptors || []).map((v) => ({ ...v, id: decode(v.id) }));
+}
+
+/**
+ * Asks the browser to create a new credential using the options returned by
+ * `/web/v1/auth/webauthn/register/begin`, returning the credential in the form the backend
+ * expects.
+ *
+ * @param options `options` returned by the backend
+ */
+export async function createCredential(options: CreationOptions): Promise<object> {
+    const publicKey: PublicKeyCredentialCreationOptions = {
+        ...options.publicKey,
+        challenge: decode(options.publicKey.challenge),
+        user: { ...options.publicKey.user, id: decode(options.publicKey.user.id) },
+        excludeCredentials: decodeDescriptors(options.publicKey.excludeCredentials),
+    };
+
+    const credential = (await navigator.credentials.create({ publicKey })) as PublicKeyCredential;
+    const response = credential.response as AuthenticatorAttestationResponse;
+
+    return {
+        id: credential.id,
+        rawId: encode(credential.rawId),
+        type: credential.type,
+        extensions: credential.getClientExtensionResults(),
+        response: {
+            attestationObject: encode(response.attestationObject),
+            clientDataJSON: encode(response.clientDataJSON),
+        },
+    };
+}
+
+/**
+ * Asks the browser to sign the challenge returned by `/web/v1/public/auth/webauthn/login/begin`
+ * with one of the user's credentials, returning the assertion in the form the backend expects.
+ *
+ * @param options `options` returned by the backend
+ */
+export async function getAssertion(options: RequestOptions): Promise<object> {
+    const publicKey: PublicKeyCredentialRequestOptions = {
+        ...options.publicKey,
+        challenge: decode(options.publicKey.challenge),
+        allowCredentials: decodeDescriptors(options.publicKey.allowCredentials),
+    };
+
+    const credential = (await navigator.credentials.get({ publicKey })) as PublicKeyCredential;
+    const response = credential.response as AuthenticatorAssertionResponse;
+
+    return {
+        id: credential.id,
+        rawId: encode(credential.rawId),
+        type: credential.type,
+        extensions: credential.getClientExtensionResults(),
+        response: {
+            authenticatorData: encode(response.authenticatorData),
+            clientDataJSON: encode(response.clientDataJSON),
+            signature: encode(response.signature),
+            userHandle: response.userHandle ? encode(response.userHandle) : null,
+        },
+    };
+}
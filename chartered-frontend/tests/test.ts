@@ -86,7 +86,7 @@ test('can create an organisation and add a user to it', async ({ page }) => {
     await page.locator('input[placeholder="Start typing a username..."]').fill(username2);
     await page.locator(`button:has-text("${username2}")`).click();
     await page
-        .locator(`text=${username2} VISIBLE PUBLISH_VERSION YANK_VERSION MANAGE_USERS CREATE_CRATE Save >> button`)
+        .locator(`text=${username2} VISIBLE PUBLISH_VERSION YANK_VERSION MANAGE_USERS CREATE_CRATE MANAGE_ORGANISATION Save >> button`)
         .click();
 
     // refresh the page to ensure the user was added
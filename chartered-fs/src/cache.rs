@@ -0,0 +1,269 @@
+//! An on-disk cache of objects read from remote storage, so frequently downloaded objects can be
+//! served without going back to the bucket each time. Once the cache grows past its maximum size
+//! the least recently used objects are removed from it.
+//!
+//! Objects are cached as they're stored, so encrypted objects stay encrypted on disk. Objects are
+//! laid out the same way as [`Local`] lays them out, and the cache is rebuilt from what's on disk
+//! when it's opened, using each object's modification time as when it was last used, which is
+//! bumped every time an object is read from the cache.
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    path::{Path, PathBuf},
+    sync::{Mutex, PoisonError},
+    time::SystemTime,
+};
+
+use tokio::{
+    fs::File,
+    io::{AsyncRead, AsyncWriteExt},
+};
+
+use crate::{Error, FileContent, Local, Reference};
+
+#[derive(Debug)]
+pub struct Cache {
+    path: PathBuf,
+    max_size: u64,
+    state: Mutex<State>,
+}
+
+#[derive(Debug, Default)]
+struct State {
+    entries: HashMap<Reference, Entry>,
+    /// Cached objects keyed by when they were last used, the first being the least recently used
+    recency: BTreeMap<u64, Reference>,
+    size: u64,
+    clock: u64,
+}
+
+#[derive(Debug)]
+struct Entry {
+    size: u64,
+    last_used: u64,
+}
+
+impl State {
+    /// Marks the object as just having been used, returning whether it's cached.
+    fn touch(&mut self, reference: &Reference) -> bool {
+        let Some(entry) = self.entries.get_mut(reference) else {
+            return false;
+        };
+
+        self.clock += 1;
+        self.recency.remove(&entry.last_used);
+        self.recency.insert(self.clock, reference.clone());
+        entry.last_used = self.clock;
+
+        true
+    }
+
+    fn insert(&mut self, reference: Reference, size: u64) {
+        self.remove(&reference);
+
+        self.clock += 1;
+        self.recency.insert(self.clock, reference.clone());
+        self.entries.insert(
+            reference,
+            Entry {
+                size,
+                last_used: self.clock,
+            },
+        );
+        self.size += size;
+    }
+
+    fn remove(&mut self, reference: &Reference) {
+        if let Some(entry) = self.entries.remove(reference) {
+            self.recency.remove(&entry.last_used);
+            self.size -= entry.size;
+        }
+    }
+
+    /// Removes the least recently used objects until the cache is no larger than `max_size`,
+    /// returning the objects that were removed.
+    fn evict(&mut self, max_size: u64) -> Vec<Reference> {
+        let mut evicted = Vec::new();
+
+        while self.size > max_size {
+            let Some((_, reference)) = self.recency.pop_first() else {
+                break;
+            };
+
+            if let Some(entry) = self.entries.remove(&reference) {
+                self.size -= entry.size;
+            }
+
+            evicted.push(reference);
+        }
+
+        evicted
+    }
+}
+
+impl Cache {
+    /// Opens the cache at `path`, picking up any objects cached before it was last closed.
+    pub async fn open(path: PathBuf, max_size: u64) -> Result<Self, Error> {
+        let mut objects = Vec::new();
+        let mut directories = vec![path.clone()];
+
+        while let Some(directory) = directories.pop() {
+            let mut entries = match tokio::fs::read_dir(&directory).await {
+                Ok(v) => v,
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => continue,
+                Err(e) => return Err(e.into()),
+            };
+
+            while let Some(entry) = entries.next_entry().await? {
+                let metadata = entry.metadata().await?;
+
+                if metadata.is_dir() {
+                    directories.push(entry.path());
+                    continue;
+                }
+
+                let reference = entry
+                    .path()
+                    .strip_prefix(&path)
+                    .ok()
+                    .and_then(Path::to_str)
+                    .and_then(Local::reference_from_path);
+
+                if let Some(reference) = reference {
+                    objects.push((
+                        metadata.modified().unwrap_or(SystemTime::UNIX_EPOCH),
+                        reference,
+                        metadata.len(),
+                    ));
+                } else if entry.file_name().to_string_lossy().starts_with(".tmp-") {
+                    // left behind by an insert that never completed
+                    remove_file(&entry.path()).await?;
+                }
+            }
+        }
+
+        objects.sort_by_key(|(last_used, ..)| *last_used);
+
+        let mut state = State::default();
+        for (_, reference, size) in objects {
+            state.insert(reference, size);
+        }
+
+        let cache = Self {
+            path,
+            max_size,
+            state: Mutex::new(state),
+        };
+        cache.evict().await?;
+
+        Ok(cache)
+    }
+
+    fn state(&self) -> std::sync::MutexGuard<'_, State> {
+        self.state.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    fn object_path(&self, reference: &Reference) -> PathBuf {
+        self.path.join(Local::relative_path(reference))
+    }
+
+    /// Opens the cached copy of the object, if there is one.
+    pub async fn get(&self, reference: &Reference) -> Result<Option<FileContent>, Error> {
+        if !self.state().touch(reference) {
+            return Ok(None);
+        }
+
+        let file = match File::open(self.object_path(reference)).await {
+            Ok(file) => file,
+            // evicted between being touched and being opened
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                self.state().remove(reference);
+                return Ok(None);
+            }
+            Err(e) => return Err(e.into()),
+        };
+
+        // the modification time is how recently the object was used when the cache is next
+        // opened, failing to update it just means it'll be evicted sooner than it should be
+        let file = file.into_std().await;
+        let _res = file.set_modified(SystemTime::now());
+        let file = File::from_std(file);
+
+        Ok(Some(FileContent {
+            length: file.metadata().await?.len(),
+            reader: Box::new(file),
+        }))
+    }
+
+    /// Streams the object into the cache, evicting the least recently used objects if the cache
+    /// has grown too large, and returns the cached copy opened for reading. Objects larger than
+    /// the cache itself aren't kept, but are still read back from the file they were streamed to.
+    pub async fn insert<R: AsyncRead + Unpin>(
+        &self,
+        reference: &Reference,
+        mut data: R,
+    ) -> Result<FileContent, Error> {
+        let path = self.object_path(reference);
+        let temp_path = self.path.join(format!(".tmp-{}", uuid::Uuid::new_v4()));
+        tokio::fs::create_dir_all(path.parent().unwrap_or(&self.path)).await?;
+
+        let res = async {
+            let mut file = File::create(&temp_path).await?;
+            let size = tokio::io::copy(&mut data, &mut file).await?;
+            file.flush().await?;
+
+            // opened before it's moved, so it can still be read if it's evicted before the
+            // caller is done with it
+            let reader = File::open(&temp_path).await?;
+
+            if size > self.max_size {
+                tokio::fs::remove_file(&temp_path).await?;
+            } else {
+                tokio::fs::rename(&temp_path, &path).await?;
+            }
+
+            Ok::<_, Error>((size, reader))
+        }
+        .await;
+
+        let (size, reader) = match res {
+            Ok(v) => v,
+            Err(e) => {
+                let _res = tokio::fs::remove_file(&temp_path).await;
+                return Err(e);
+            }
+        };
+
+        if size <= self.max_size {
+            self.state().insert(reference.clone(), size);
+            self.evict().await?;
+        }
+
+        Ok(FileContent {
+            length: size,
+            reader: Box::new(reader),
+        })
+    }
+
+    pub async fn remove(&self, reference: &Reference) -> Result<(), Error> {
+        self.state().remove(reference);
+        remove_file(&self.object_path(reference)).await
+    }
+
+    async fn evict(&self) -> Result<(), Error> {
+        let evicted = self.state().evict(self.max_size);
+
+        for reference in evicted {
+            remove_file(&self.object_path(&reference)).await?;
+        }
+
+        Ok(())
+    }
+}
+
+async fn remove_file(path: &Path) -> Result<(), Error> {
+    match tokio::fs::remove_file(path).await {
+        Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e.into()),
+        _ => Ok(()),
+    }
+}
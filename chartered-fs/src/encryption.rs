@@ -0,0 +1,117 @@
+//! Envelope encryption for objects at rest. Each object is encrypted with its own randomly
+//! generated data key, which is itself encrypted with the configured master key and stored at the
+//! start of the object:
+//!
+//! ```text
+//! MAGIC | master key nonce (12) | wrapped data key (48) | data key nonce (12) | ciphertext
+//! ```
+//!
+//! Both the data key and the object are bound to the object's [`Reference`] as associated data, so
+//! an object (or its wrapped data key) copied over another in the bucket fails to decrypt rather
+//! than being served in place of the original.
+//!
+//! Objects are sealed and opened as a whole rather than in chunks, so they're held in memory whilst
+//! being encrypted or decrypted. This puts a practical limit on the size of objects that can be
+//! stored with encryption enabled, which crates comfortably fit into. Objects written before
+//! encryption was enabled don't start with [`MAGIC`] and are read as they are.
+
+use chacha20poly1305::{
+    aead::{Aead, AeadCore, KeyInit, OsRng, Payload},
+    ChaCha20Poly1305, Key, Nonce,
+};
+
+use crate::{Error, Reference};
+
+/// Marks the start of an encrypted object.
+pub(crate) const MAGIC: &[u8; 8] = b"CHTDENC1";
+
+const NONCE_LEN: usize = 12;
+
+/// Length of a data key once it's been encrypted with the master key, including its tag.
+const WRAPPED_KEY_LEN: usize = 32 + 16;
+
+const HEADER_LEN: usize = MAGIC.len() + NONCE_LEN + WRAPPED_KEY_LEN + NONCE_LEN;
+
+#[derive(Clone)]
+pub struct Encryption {
+    master_key: Key,
+}
+
+impl Encryption {
+    #[must_use]
+    pub fn new(master_key: Key) -> Self {
+        Self { master_key }
+    }
+
+    pub(crate) fn seal(&self, reference: &Reference, plaintext: &[u8]) -> Result<Vec<u8>, Error> {
+        let aad = associated_data(reference);
+        let data_key = ChaCha20Poly1305::generate_key(&mut OsRng);
+
+        let master_key_nonce = ChaCha20Poly1305::generate_nonce(&mut OsRng);
+        let wrapped_key = ChaCha20Poly1305::new(&self.master_key).encrypt(
+            &master_key_nonce,
+            Payload {
+                msg: data_key.as_slice(),
+                aad: &aad,
+            },
+        )?;
+
+        let data_key_nonce = ChaCha20Poly1305::generate_nonce(&mut OsRng);
+        let ciphertext = ChaCha20Poly1305::new(&data_key).encrypt(
+            &data_key_nonce,
+            Payload {
+                msg: plaintext,
+                aad: &aad,
+            },
+        )?;
+
+        let mut sealed = Vec::with_capacity(HEADER_LEN + ciphertext.len());
+        sealed.extend_from_slice(MAGIC);
+        sealed.extend_from_slice(&master_key_nonce);
+        sealed.extend_from_slice(&wrapped_key);
+        sealed.extend_from_slice(&data_key_nonce);
+        sealed.extend_from_slice(&ciphertext);
+
+        Ok(sealed)
+    }
+
+    pub(crate) fn open(&self, reference: &Reference, sealed: &[u8]) -> Result<Vec<u8>, Error> {
+        if sealed.len() < HEADER_LEN || !sealed.starts_with(MAGIC) {
+            return Err(chacha20poly1305::aead::Error.into());
+        }
+
+        let aad = associated_data(reference);
+
+        let (master_key_nonce, rest) = sealed[MAGIC.len()..].split_at(NONCE_LEN);
+        let (wrapped_key, rest) = rest.split_at(WRAPPED_KEY_LEN);
+        let (data_key_nonce, ciphertext) = rest.split_at(NONCE_LEN);
+
+        let data_key = ChaCha20Poly1305::new(&self.master_key).decrypt(
+            Nonce::from_slice(master_key_nonce),
+            Payload {
+                msg: wrapped_key,
+                aad: &aad,
+            },
+        )?;
+
+        Ok(ChaCha20Poly1305::new(Key::from_slice(&data_key)).decrypt(
+            Nonce::from_slice(data_key_nonce),
+            Payload {
+                msg: ciphertext,
+                aad: &aad,
+            },
+        )?)
+    }
+}
+
+/// Associated data both layers of an object are bound to, the format marker followed by the
+/// object's reference.
+fn associated_data(reference: &Reference) -> Vec<u8> {
+    [&MAGIC[..], reference.to_string().as_bytes()].concat()
+}
+
+impl std::fmt::Debug for Encryption {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Encryption").finish_non_exhaustive()
+    }
+}
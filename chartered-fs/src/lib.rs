@@ -2,25 +2,40 @@
 #![deny(rust_2018_idioms)]
 #![allow(clippy::missing_errors_doc)]
 
-use std::{path::PathBuf, time::Duration};
+mod cache;
+mod encryption;
+mod s3_reader;
+
+use cache::Cache;
+pub use encryption::Encryption;
+use s3_reader::S3ObjectReader;
+
+use std::{
+    ops::Range,
+    path::{Path, PathBuf},
+    time::{Duration, SystemTime},
+};
 
 use async_trait::async_trait;
-use aws_sdk_s3::error::{GetObjectError, PutObjectError};
+use aws_sdk_s3::error::{DeleteObjectError, GetObjectError, ListObjectsV2Error, PutObjectError};
 use aws_sdk_s3::{
-    model::ObjectCannedAcl,
+    model::{ObjectCannedAcl, ServerSideEncryption, StorageClass},
     presigning::config::PresigningConfig,
     types::{ByteStream, SdkError},
     Endpoint,
 };
-use bytes::Bytes;
 use itertools::Itertools;
 use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
 use thiserror::Error;
 use tokio::{
     fs::File,
-    io::{AsyncReadExt, AsyncWriteExt},
+    io::{AsyncRead, AsyncReadExt, AsyncSeek, AsyncSeekExt, AsyncWriteExt},
 };
 
+/// Size of the buffer used when streaming objects to and from storage
+const BUFFER_SIZE: usize = 64 * 1024;
+
 #[derive(Debug, Error)]
 pub enum Error {
     #[error("failed to parse filesystem uri: {0}")]
@@ -31,10 +46,26 @@ pub enum Error {
     S3Put(#[from] SdkError<PutObjectError>),
     #[error("failed to get object from s3: {0}")]
     S3Get(#[from] SdkError<GetObjectError>),
+    #[error("failed to delete object from s3: {0}")]
+    S3Delete(#[from] SdkError<DeleteObjectError>),
+    #[error("failed to list objects in s3: {0}")]
+    S3List(#[from] SdkError<ListObjectsV2Error>),
     #[error("i/o failure: {0}")]
     Io(#[from] std::io::Error),
     #[error("failed to parse uuid: {0}")]
     UuidParse(#[from] uuid::Error),
+    #[error("invalid sha256 hash in file reference")]
+    InvalidHash,
+    #[error("failed to encrypt or decrypt object: {0}")]
+    Cipher(#[from] chacha20poly1305::aead::Error),
+    #[error("object is encrypted but no encryption key is configured")]
+    MissingEncryptionKey,
+    #[error("object doesn't match its checksum (expected {expected}, got {actual})")]
+    ChecksumMismatch { expected: String, actual: String },
+    #[error("no file system is configured to read {0}")]
+    NoFileSystem(String),
+    #[error("invalid value for `{0}` in uri")]
+    InvalidUriParameter(&'static str),
     #[error("path missing from uri")]
     MissingPath,
     #[error("invalid uri: {0}")]
@@ -51,41 +82,63 @@ pub enum FileSystem {
     Local(Local),
 }
 
+impl Error {
+    /// Whether the error was caused by the object not existing in storage.
+    #[must_use]
+    pub fn is_not_found(&self) -> bool {
+        match self {
+            Self::Io(e) => e.kind() == std::io::ErrorKind::NotFound,
+            Self::S3Get(SdkError::ServiceError { err, .. }) => err.is_no_such_key(),
+            _ => false,
+        }
+    }
+}
+
 impl FileSystem {
+    /// Parses a storage URI, ie. `s3://host/bucket/path` or `file:///path`. Objects are stored
+    /// under a random UUID unless `content_addressed=true` is given in the URI's query, in which
+    /// case they're stored by their SHA-256 hash instead. S3 takes further options in the query,
+    /// see [`S3::from_uri`].
     pub async fn from_str(s: &str) -> Result<Self, Error> {
         let uri = url::Url::parse(s)?;
+        let content_addressed = query_flag(&uri, "content_addressed");
 
         Ok(match uri.scheme() {
-            "s3" => {
-                let mut shared_config = aws_config::from_env();
-
-                if let Some(host) = uri.host() {
-                    shared_config = shared_config.endpoint_resolver(Endpoint::immutable(
-                        format!("https://{}", host)
-                            .parse()
-                            .map_err(Error::InvalidUri)?,
-                    ));
-                }
-
-                let shared_config = shared_config.load().await;
+            "s3" => Self::S3(S3::from_uri(&uri, content_addressed).await?),
+            "file" => {
+                let mut path = uri.clone();
+                path.set_query(None);
 
-                let client = aws_sdk_s3::Client::new(&shared_config);
-
-                let mut path = uri.path_segments().ok_or(Error::MissingPath)?;
-
-                Self::S3(S3 {
-                    bucket: path.next().ok_or(Error::MissingBucket)?.to_string(),
-                    path: Itertools::intersperse(path, "/").collect(),
-                    client,
+                Self::Local(Local {
+                    path: path.to_file_path().map_err(|()| Error::MissingPath)?,
+                    content_addressed,
+                    encryption: None,
                 })
             }
-            "file" => Self::Local(Local {
-                path: uri.to_file_path().map_err(|()| Error::MissingPath)?,
-            }),
             _ => return Err(Error::UnknownFileSystemKind),
         })
     }
 
+    /// Encrypts objects written from now on with `encryption`, objects that were encrypted when
+    /// they were written are decrypted on read regardless.
+    #[must_use]
+    pub fn with_encryption(mut self, encryption: Option<Encryption>) -> Self {
+        match &mut self {
+            Self::S3(v) => v.encryption = encryption,
+            Self::Local(v) => v.encryption = encryption,
+        }
+
+        self
+    }
+
+    #[must_use]
+    pub fn kind(&self) -> FileSystemKind {
+        match self {
+            Self::S3(_) => S3::KIND,
+            Self::Local(_) => Local::KIND,
+        }
+    }
+
     pub async fn read(&self, file_ref: FileReference) -> Result<FilePointer, Error> {
         match self {
             Self::S3(v) => v.read(file_ref).await,
@@ -93,15 +146,107 @@ impl FileSystem {
         }
     }
 
-    pub async fn write(&self, data: Bytes) -> Result<FileReference, Error> {
+    /// Reads the object, checking its contents match `expected_sha256` before returning them.
+    /// Objects that are redirected to can't be checked here, and are instead checked by cargo.
+    pub async fn read_verified(
+        &self,
+        file_ref: FileReference,
+        expected_sha256: &[u8; 32],
+    ) -> Result<FilePointer, Error> {
+        match self.read(file_ref).await? {
+            FilePointer::Content(mut content) => {
+                content.verify(expected_sha256).await?;
+                Ok(FilePointer::Content(content))
+            }
+            FilePointer::Redirect(uri) => Ok(FilePointer::Redirect(uri)),
+        }
+    }
+
+    pub async fn fetch(&self, file_ref: &FileReference) -> Result<FileContent, Error> {
+        match self {
+            Self::S3(v) => v.fetch(file_ref).await,
+            Self::Local(v) => v.fetch(file_ref).await,
+        }
+    }
+
+    pub async fn verify(
+        &self,
+        file_ref: &FileReference,
+        expected_sha256: &[u8; 32],
+    ) -> Result<(), Error> {
+        match self {
+            Self::S3(v) => v.verify(file_ref, expected_sha256).await,
+            Self::Local(v) => v.verify(file_ref, expected_sha256).await,
+        }
+    }
+
+    /// Moves any objects stored in the layout used before sharding into their sharded
+    /// directories, see [`Local::shard_flat_objects`]. S3 objects were never sharded so this does
+    /// nothing for them.
+    pub async fn shard_flat_objects(&self) -> Result<usize, Error> {
+        match self {
+            Self::S3(_) => Ok(0),
+            Self::Local(v) => v.shard_flat_objects().await,
+        }
+    }
+
+    pub async fn write<R: AsyncRead + Send + Unpin>(
+        &self,
+        data: R,
+    ) -> Result<FileReference, Error> {
         match self {
             Self::S3(v) => v.write(data).await,
             Self::Local(v) => v.write(data).await,
         }
     }
+
+    pub async fn write_as<R: AsyncRead + Send + Unpin>(
+        &self,
+        reference: Reference,
+        data: R,
+    ) -> Result<FileReference, Error> {
+        match self {
+            Self::S3(v) => v.write_as(Some(reference), data).await,
+            Self::Local(v) => v.write_as(Some(reference), data).await,
+        }
+    }
+
+    pub async fn delete(&self, file_ref: &FileReference) -> Result<(), Error> {
+        match self {
+            Self::S3(v) => v.delete(file_ref).await,
+            Self::Local(v) => v.delete(file_ref).await,
+        }
+    }
+
+    pub async fn list(&self) -> Result<Vec<StoredObject>, Error> {
+        match self {
+            Self::S3(v) => v.list().await,
+            Self::Local(v) => v.list().await,
+        }
+    }
+}
+
+fn query_param(uri: &url::Url, key: &str) -> Option<String> {
+    uri.query_pairs()
+        .find(|(k, _)| k == key)
+        .map(|(_, v)| v.into_owned())
+}
+
+fn query_flag(uri: &url::Url, key: &str) -> bool {
+    query_param(uri, key).as_deref() == Some("true")
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+/// Parses the query parameter `key` as a `T`, erroring if it's set to something that isn't one.
+fn parse_query_param<T: std::str::FromStr>(
+    uri: &url::Url,
+    key: &'static str,
+) -> Result<Option<T>, Error> {
+    query_param(uri, key)
+        .map(|v| v.parse().map_err(|_| Error::InvalidUriParameter(key)))
+        .transpose()
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
 pub enum FileSystemKind {
     Local,
     S3,
@@ -128,15 +273,132 @@ impl std::str::FromStr for FileSystemKind {
     }
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+/// Identifies an object within a file system, either by a random UUID or, when content
+/// addressing is enabled, by the SHA-256 hash of its contents.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum Reference {
+    Uuid(uuid::Uuid),
+    Sha256([u8; 32]),
+}
+
+impl Reference {
+    /// Path of the object relative to the root of the file system. Hashes are sharded into
+    /// directories by their first two bytes so no single directory grows too large.
+    #[must_use]
+    pub fn path(&self) -> String {
+        match self {
+            Self::Uuid(uuid) => uuid.to_string(),
+            Self::Sha256(hash) => {
+                let hash = hex::encode(hash);
+                format!("sha256/{}/{}/{}", &hash[..2], &hash[2..4], hash)
+            }
+        }
+    }
+
+    /// Parses a reference back out of a path returned by [`Reference::path`], returning `None` if
+    /// the path doesn't belong to an object.
+    #[must_use]
+    pub fn from_path(path: &str) -> Option<Self> {
+        let reference = match path.strip_prefix("sha256/") {
+            Some(rest) => {
+                let mut out = [0_u8; 32];
+                hex::decode_to_slice(rest.rsplit('/').next()?, &mut out).ok()?;
+                Self::Sha256(out)
+            }
+            None => Self::Uuid(uuid::Uuid::parse_str(path).ok()?),
+        };
+
+        // make sure the object is where we'd expect it to be, rather than just ending in
+        // something that looks like a reference
+        (reference.path() == path).then_some(reference)
+    }
+}
+
+impl std::fmt::Display for Reference {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Uuid(uuid) => write!(f, "{}", uuid),
+            Self::Sha256(hash) => write!(f, "sha256:{}", hex::encode(hash)),
+        }
+    }
+}
+
+impl std::str::FromStr for Reference {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.strip_prefix("sha256:") {
+            Some(hash) => {
+                let mut out = [0_u8; 32];
+                hex::decode_to_slice(hash, &mut out).map_err(|_| Error::InvalidHash)?;
+                Ok(Self::Sha256(out))
+            }
+            None => Ok(Self::Uuid(uuid::Uuid::from_str(s)?)),
+        }
+    }
+}
+
+/// Identifies an object along with the file system it was written to, formatted as
+/// `kind[@backend]:reference`, ie. `s3@primary:sha256:abcd...`.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
 pub struct FileReference {
     file_system: FileSystemKind,
-    reference: uuid::Uuid,
+    /// Name of the configured backend the object was written to, for when more than one backend
+    /// of the same kind is in use
+    backend: Option<String>,
+    reference: Reference,
+}
+
+impl FileReference {
+    #[must_use]
+    pub fn new(file_system: FileSystemKind, reference: Reference) -> Self {
+        Self {
+            file_system,
+            backend: None,
+            reference,
+        }
+    }
+
+    /// Records the name of the backend the object was written to.
+    #[must_use]
+    pub fn with_backend(mut self, backend: Option<String>) -> Self {
+        self.backend = backend;
+        self
+    }
+
+    /// The kind of file system the object was written to.
+    #[must_use]
+    pub fn file_system(&self) -> FileSystemKind {
+        self.file_system
+    }
+
+    /// The name of the backend the object was written to, if it was written to a named backend.
+    #[must_use]
+    pub fn backend(&self) -> Option<&str> {
+        self.backend.as_deref()
+    }
+
+    #[must_use]
+    pub fn reference(&self) -> &Reference {
+        &self.reference
+    }
+
+    /// The SHA-256 hash of the object's contents, if it was stored content-addressed.
+    #[must_use]
+    pub fn sha256(&self) -> Option<&[u8; 32]> {
+        match &self.reference {
+            Reference::Sha256(hash) => Some(hash),
+            Reference::Uuid(_) => None,
+        }
+    }
 }
 
 impl std::fmt::Display for FileReference {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "{}:{}", self.file_system, self.reference)
+        match &self.backend {
+            Some(backend) => write!(f, "{}@{}:{}", self.file_system, backend, self.reference),
+            None => write!(f, "{}:{}", self.file_system, self.reference),
+        }
     }
 }
 
@@ -145,40 +407,387 @@ impl std::str::FromStr for FileReference {
 
     fn from_str(s: &str) -> Result<Self, Self::Err> {
         let mut split = s.splitn(2, ':');
-        let file_system = FileSystemKind::from_str(split.next().unwrap_or_default())?;
-        let reference = uuid::Uuid::from_str(split.next().unwrap_or_default())?;
+        let mut file_system = split.next().unwrap_or_default().splitn(2, '@');
+        let kind = FileSystemKind::from_str(file_system.next().unwrap_or_default())?;
+        let backend = file_system.next().map(ToString::to_string);
+        let reference = Reference::from_str(split.next().unwrap_or_default())?;
         Ok(FileReference {
-            file_system,
+            file_system: kind,
+            backend,
             reference,
         })
     }
 }
 
-#[derive(Debug, PartialEq, Eq)]
+/// An object found when listing the contents of a file system.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StoredObject {
+    pub file_ref: FileReference,
+    pub size: u64,
+    pub last_modified: SystemTime,
+}
+
+#[derive(Debug)]
 pub enum FilePointer {
-    Content(Vec<u8>),
+    Content(FileContent),
     Redirect(http::Uri),
 }
 
+/// A readable, seekable handle to an object's contents.
+pub trait ObjectReader: AsyncRead + AsyncSeek + Send + Sync + Unpin {}
+
+impl<T: AsyncRead + AsyncSeek + Send + Sync + Unpin> ObjectReader for T {}
+
+/// An object that can be streamed straight from storage without being buffered into memory.
+pub struct FileContent {
+    pub length: u64,
+    pub reader: Box<dyn ObjectReader>,
+}
+
+impl FileContent {
+    /// Seeks to the start of `range` and returns a reader that ends at the end of it, `range` must
+    /// be within `0..length`.
+    pub async fn into_range(
+        mut self,
+        range: Range<u64>,
+    ) -> Result<impl AsyncRead + Send + Unpin, Error> {
+        self.reader
+            .seek(std::io::SeekFrom::Start(range.start))
+            .await?;
+        Ok(self.reader.take(range.end - range.start))
+    }
+
+    /// Hashes the entire object, erroring if it doesn't match `expected_sha256`, and rewinds the
+    /// reader back to the start.
+    pub async fn verify(&mut self, expected_sha256: &[u8; 32]) -> Result<(), Error> {
+        let actual = sha256_of(&mut self.reader).await?;
+        self.reader.seek(std::io::SeekFrom::Start(0)).await?;
+        check_sha256(expected_sha256, &actual)
+    }
+
+    /// Reads the entire object into memory.
+    pub async fn into_bytes(mut self) -> Result<Vec<u8>, Error> {
+        let mut contents = Vec::with_capacity(usize::try_from(self.length).unwrap_or_default());
+        self.reader.read_to_end(&mut contents).await?;
+        Ok(contents)
+    }
+
+    /// Decrypts the object stored under `reference` into memory if it was encrypted when it was
+    /// written, objects that weren't are returned as they are.
+    async fn unseal(
+        mut self,
+        encryption: Option<&Encryption>,
+        reference: &Reference,
+    ) -> Result<Self, Error> {
+        let mut magic = [0_u8; encryption::MAGIC.len()];
+        let sealed = match self.reader.read_exact(&mut magic).await {
+            Ok(_) => &magic == encryption::MAGIC,
+            Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => false,
+            Err(e) => return Err(e.into()),
+        };
+        self.reader.seek(std::io::SeekFrom::Start(0)).await?;
+
+        if !sealed {
+            return Ok(self);
+        }
+
+        let encryption = encryption.ok_or(Error::MissingEncryptionKey)?;
+        let plaintext = encryption.open(reference, &self.into_bytes().await?)?;
+
+        Ok(Self {
+            length: plaintext.len() as u64,
+            reader: Box::new(std::io::Cursor::new(plaintext)),
+        })
+    }
+}
+
+impl std::fmt::Debug for FileContent {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("FileContent")
+            .field("length", &self.length)
+            .finish_non_exhaustive()
+    }
+}
+
+/// Streams `data` through SHA-256 in chunks.
+async fn sha256_of<R: AsyncRead + Unpin>(mut data: R) -> Result<[u8; 32], Error> {
+    let mut buf = vec![0_u8; BUFFER_SIZE];
+    let mut sha256 = Sha256::new();
+
+    loop {
+        let read = data.read(&mut buf).await?;
+        if read == 0 {
+            break;
+        }
+
+        sha256.update(&buf[..read]);
+    }
+
+    Ok(sha256.finalize().into())
+}
+
+fn check_sha256(expected: &[u8; 32], actual: &[u8; 32]) -> Result<(), Error> {
+    if expected == actual {
+        Ok(())
+    } else {
+        Err(Error::ChecksumMismatch {
+            expected: hex::encode(expected),
+            actual: hex::encode(actual),
+        })
+    }
+}
+
+/// Hashes of an object computed whilst it was being streamed to storage.
+struct Spooled {
+    length: u64,
+    sha256: [u8; 32],
+    md5: md5::Digest,
+}
+
+/// Streams `data` into `out` in chunks, hashing it as it goes so the whole object never has to be
+/// held in memory.
+async fn spool<R, W>(mut data: R, out: &mut W) -> Result<Spooled, Error>
+where
+    R: AsyncRead + Unpin,
+    W: tokio::io::AsyncWrite + Unpin,
+{
+    let mut buf = vec![0_u8; BUFFER_SIZE];
+    let mut length = 0;
+    let mut sha256 = Sha256::new();
+    let mut md5 = md5::Context::new();
+
+    loop {
+        let read = data.read(&mut buf).await?;
+        if read == 0 {
+            break;
+        }
+
+        sha256.update(&buf[..read]);
+        md5.consume(&buf[..read]);
+        out.write_all(&buf[..read]).await?;
+        length += read as u64;
+    }
+
+    out.flush().await?;
+
+    Ok(Spooled {
+        length,
+        sha256: sha256.finalize().into(),
+        md5: md5.compute(),
+    })
+}
+
+/// Spools `data` into `out`, encrypting it with `encryption` first if given, and returns the
+/// reference `create_ref` gives the object from the SHA-256 of its plaintext. Content-addressed
+/// objects keep the same reference whether they're encrypted or not.
+///
+/// Encrypted objects are bound to their reference so it has to be known before they're sealed,
+/// which means the whole object is held in memory whilst it's encrypted.
+async fn spool_sealed<R, W, F>(
+    mut data: R,
+    out: &mut W,
+    encryption: Option<&Encryption>,
+    create_ref: F,
+) -> Result<(Spooled, FileReference), Error>
+where
+    R: AsyncRead + Unpin,
+    W: tokio::io::AsyncWrite + Unpin,
+    F: FnOnce([u8; 32]) -> Result<FileReference, Error> + Send,
+{
+    let Some(encryption) = encryption else {
+        let spooled = spool(data, out).await?;
+        let file_ref = create_ref(spooled.sha256)?;
+        return Ok((spooled, file_ref));
+    };
+
+    let mut plaintext = Vec::new();
+    data.read_to_end(&mut plaintext).await?;
+
+    let sha256: [u8; 32] = Sha256::digest(&plaintext).into();
+    let file_ref = create_ref(sha256)?;
+    let sealed = encryption.seal(&file_ref.reference, &plaintext)?;
+
+    Ok((
+        Spooled {
+            sha256,
+            ..spool(&sealed[..], out).await?
+        },
+        file_ref,
+    ))
+}
+
 #[async_trait]
-pub trait FileSystemIo {
+pub trait FileSystemIo: Sync {
     const KIND: FileSystemKind;
 
     async fn read(&self, file_ref: FileReference) -> Result<FilePointer, Error>;
-    async fn write(&self, data: Bytes) -> Result<FileReference, Error>;
+    async fn write<R: AsyncRead + Send + Unpin>(&self, data: R) -> Result<FileReference, Error> {
+        self.write_as(None, data).await
+    }
 
-    #[must_use]
-    fn create_ref() -> FileReference {
-        FileReference {
-            file_system: Self::KIND,
-            reference: uuid::Uuid::new_v4(),
-        }
+    /// Writes the object under the given `reference` rather than creating a new one for it, used
+    /// to copy an object between file systems whilst keeping the same reference.
+    async fn write_as<R: AsyncRead + Send + Unpin>(
+        &self,
+        reference: Option<Reference>,
+        data: R,
+    ) -> Result<FileReference, Error>;
+
+    /// Removes an object from the file system, deleting an object that doesn't exist isn't an
+    /// error.
+    async fn delete(&self, file_ref: &FileReference) -> Result<(), Error>;
+
+    /// Lists every object stored in the file system.
+    async fn list(&self) -> Result<Vec<StoredObject>, Error>;
+
+    /// Reads the object's contents from storage, even if [`FileSystemIo::read`] would redirect
+    /// to them instead.
+    async fn fetch(&self, file_ref: &FileReference) -> Result<FileContent, Error>;
+
+    /// Reads the object's entire contents from storage and checks they match `expected_sha256`.
+    async fn verify(
+        &self,
+        file_ref: &FileReference,
+        expected_sha256: &[u8; 32],
+    ) -> Result<(), Error> {
+        self.fetch(file_ref).await?.verify(expected_sha256).await
+    }
+
+    /// Whether objects should be stored by the hash of their contents rather than a random UUID,
+    /// writing the same contents twice will then result in the same reference.
+    fn content_addressed(&self) -> bool;
+
+    /// Creates a reference for an object with the given hash, which is only used if the file
+    /// system is content-addressed, unless an existing `reference` is being written to. Existing
+    /// references to a hash must match the object's hash.
+    fn create_ref(
+        &self,
+        reference: Option<Reference>,
+        sha256: [u8; 32],
+    ) -> Result<FileReference, Error> {
+        let reference = match reference {
+            Some(Reference::Sha256(expected)) => {
+                check_sha256(&expected, &sha256)?;
+                Reference::Sha256(expected)
+            }
+            Some(reference) => reference,
+            None if self.content_addressed() => Reference::Sha256(sha256),
+            None => Reference::Uuid(uuid::Uuid::new_v4()),
+        };
+
+        Ok(FileReference::new(Self::KIND, reference))
     }
 }
 
 #[derive(Debug)]
 pub struct Local {
     pub path: PathBuf,
+    pub content_addressed: bool,
+    pub encryption: Option<Encryption>,
+}
+
+impl Local {
+    /// Path of the object relative to the root of the file system. Like hashes, UUIDs are sharded
+    /// into directories by their first two bytes.
+    fn relative_path(reference: &Reference) -> String {
+        match reference {
+            Reference::Uuid(uuid) => {
+                let uuid = uuid.to_string();
+                format!("{}/{}/{}", &uuid[..2], &uuid[2..4], uuid)
+            }
+            Reference::Sha256(_) => reference.path(),
+        }
+    }
+
+    fn object_path(&self, reference: &Reference) -> PathBuf {
+        self.path.join(Path::new(&Self::relative_path(reference)))
+    }
+
+    /// Where an object was stored before UUIDs were sharded, see [`Local::shard_flat_objects`].
+    fn flat_path(&self, reference: &Reference) -> Option<PathBuf> {
+        match reference {
+            Reference::Uuid(_) => Some(self.path.join(reference.path())),
+            Reference::Sha256(_) => None,
+        }
+    }
+
+    /// Parses a reference out of a path relative to the root of the file system, in either its
+    /// sharded or flat form.
+    fn reference_from_path(path: &str) -> Option<Reference> {
+        Reference::from_path(path).or_else(|| {
+            let reference = Reference::Uuid(uuid::Uuid::parse_str(path.rsplit('/').next()?).ok()?);
+            (Self::relative_path(&reference) == path).then_some(reference)
+        })
+    }
+
+    /// Opens the object, falling back to where it would have been stored before sharding.
+    async fn open(&self, reference: &Reference) -> Result<File, Error> {
+        let path = self.object_path(reference);
+
+        let e = match File::open(&path).await {
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => e,
+            res => return Ok(res?),
+        };
+
+        let Some(flat_path) = self.flat_path(reference) else {
+            return Err(e.into());
+        };
+
+        match File::open(flat_path).await {
+            // the object may have been sharded in between the two attempts
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(File::open(path).await?),
+            res => Ok(res?),
+        }
+    }
+
+    /// Moves objects written before UUIDs were sharded from the root of the file system into
+    /// their sharded directories, returning how many were moved. Objects remain readable while
+    /// this is running.
+    pub async fn shard_flat_objects(&self) -> Result<usize, Error> {
+        let mut entries = match tokio::fs::read_dir(&self.path).await {
+            Ok(v) => v,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(0),
+            Err(e) => return Err(e.into()),
+        };
+
+        let mut moved = 0;
+
+        while let Some(entry) = entries.next_entry().await? {
+            let reference = match entry.file_name().to_str().and_then(Reference::from_path) {
+                Some(reference @ Reference::Uuid(_)) => reference,
+                _ => continue,
+            };
+
+            if !entry.file_type().await?.is_file() {
+                continue;
+            }
+
+            let path = self.object_path(&reference);
+            let parent = path.parent().unwrap_or(&self.path);
+
+            tokio::fs::create_dir_all(parent).await?;
+            tokio::fs::rename(entry.path(), &path).await?;
+            sync_dir(parent).await?;
+
+            moved += 1;
+        }
+
+        if moved > 0 {
+            sync_dir(&self.path).await?;
+        }
+
+        Ok(moved)
+    }
+}
+
+/// Flushes a directory's entries to disk, so a file renamed into or out of it survives a crash.
+/// Directories can't be opened as files on Windows, so this does nothing there.
+async fn sync_dir(path: &Path) -> Result<(), Error> {
+    if cfg!(unix) {
+        File::open(path).await?.sync_all().await?;
+    }
+
+    Ok(())
 }
 
 #[async_trait]
@@ -186,32 +795,293 @@ impl FileSystemIo for Local {
     const KIND: FileSystemKind = FileSystemKind::Local;
 
     async fn read(&self, file_ref: FileReference) -> Result<FilePointer, Error> {
-        let path = self.path.join(file_ref.reference.to_string());
-        let mut file = File::open(path).await?;
+        Ok(FilePointer::Content(self.fetch(&file_ref).await?))
+    }
 
-        let mut contents = vec![];
-        file.read_to_end(&mut contents).await?;
+    async fn write_as<R: AsyncRead + Send + Unpin>(
+        &self,
+        reference: Option<Reference>,
+        data: R,
+    ) -> Result<FileReference, Error> {
+        // objects are streamed to a temporary file and only moved into place once they've been
+        // completely written, so a crash can never leave a truncated object behind. this also
+        // means the reference of a content-addressed object is known before it's moved
+        let temp_path = self.path.join(format!(".tmp-{}", uuid::Uuid::new_v4()));
+        tokio::fs::create_dir_all(&self.path).await?;
 
-        Ok(FilePointer::Content(contents))
-    }
+        let file_ref = async {
+            let mut file = File::create(&temp_path).await?;
+            let (_, file_ref) = spool_sealed(data, &mut file, self.encryption.as_ref(), |sha256| {
+                self.create_ref(reference, sha256)
+            })
+            .await?;
+            file.sync_all().await?;
+            Ok::<_, Error>(file_ref)
+        }
+        .await;
 
-    async fn write(&self, data: Bytes) -> Result<FileReference, Error> {
-        let file_ref = Self::create_ref();
-        let path = self.path.join(file_ref.reference.to_string());
+        let file_ref = match file_ref {
+            Ok(v) => v,
+            Err(e) => {
+                let _res = tokio::fs::remove_file(&temp_path).await;
+                return Err(e);
+            }
+        };
 
-        let mut file = File::create(path).await?;
-        file.write_all(&data).await?;
+        // a content-addressed object that already exists is replaced rather than skipped, the
+        // contents are the same either way but replacing it bumps its modification time so the
+        // garbage collector's grace period covers the publish that's now reusing it
+        let path = self.object_path(&file_ref.reference);
+        let parent = path.parent().unwrap_or(&self.path);
+        tokio::fs::create_dir_all(parent).await?;
+        tokio::fs::rename(&temp_path, &path).await?;
+        sync_dir(parent).await?;
 
         Ok(file_ref)
     }
+
+    async fn delete(&self, file_ref: &FileReference) -> Result<(), Error> {
+        let paths = std::iter::once(self.object_path(&file_ref.reference))
+            .chain(self.flat_path(&file_ref.reference));
+
+        for path in paths {
+            match tokio::fs::remove_file(path).await {
+                Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(e.into()),
+                _ => {}
+            }
+        }
+
+        Ok(())
+    }
+
+    async fn list(&self) -> Result<Vec<StoredObject>, Error> {
+        let mut objects = Vec::new();
+        let mut directories = vec![self.path.clone()];
+
+        while let Some(directory) = directories.pop() {
+            let mut entries = match tokio::fs::read_dir(&directory).await {
+                Ok(v) => v,
+                // nothing has been written yet
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => continue,
+                Err(e) => return Err(e.into()),
+            };
+
+            while let Some(entry) = entries.next_entry().await? {
+                let metadata = entry.metadata().await?;
+
+                if metadata.is_dir() {
+                    directories.push(entry.path());
+                    continue;
+                }
+
+                let path = entry.path();
+                let reference = path
+                    .strip_prefix(&self.path)
+                    .ok()
+                    .and_then(Path::to_str)
+                    .and_then(Self::reference_from_path);
+
+                if let Some(reference) = reference {
+                    objects.push(StoredObject {
+                        file_ref: FileReference::new(Self::KIND, reference),
+                        size: metadata.len(),
+                        last_modified: metadata.modified()?,
+                    });
+                }
+            }
+        }
+
+        Ok(objects)
+    }
+
+    async fn fetch(&self, file_ref: &FileReference) -> Result<FileContent, Error> {
+        let file = self.open(&file_ref.reference).await?;
+        let length = file.metadata().await?.len();
+
+        FileContent {
+            length,
+            reader: Box::new(file),
+        }
+        .unseal(self.encryption.as_ref(), &file_ref.reference)
+        .await
+    }
+
+    fn content_addressed(&self) -> bool {
+        self.content_addressed
+    }
 }
 
 #[derive(Debug)]
 #[allow(dead_code)]
 pub struct S3 {
+    /// Endpoint the bucket is accessed through, if not AWS itself
+    endpoint: Option<String>,
     bucket: String,
     path: String,
     client: aws_sdk_s3::Client,
+    /// How long presigned URLs that clients are redirected to are valid for
+    presign_expiry: Duration,
+    server_side_encryption: Option<ServerSideEncryption>,
+    /// KMS key objects are encrypted with when using `aws:kms` server-side encryption
+    sse_kms_key_id: Option<String>,
+    storage_class: Option<StorageClass>,
+    content_addressed: bool,
+    encryption: Option<Encryption>,
+    /// Serve objects through us rather than redirecting to the bucket
+    proxy: bool,
+    /// Where proxied objects are cached
+    cache: Option<Cache>,
+}
+
+impl S3 {
+    /// Builds a client for an `s3://host[:port]/bucket/path` URI, where `host` is the endpoint
+    /// to connect to. The URI's query can also contain:
+    ///
+    /// - `region`, the region the bucket is in, defaulting to the one in the environment
+    /// - `endpoint_scheme`, `https` (the default) or `http` for endpoints without TLS
+    /// - `path_style=true`, to address the bucket in the path rather than the host, as MinIO
+    ///   and most other S3-compatible stores expect
+    /// - `presign_expiry_secs`, how long download redirects are valid for (600 by default)
+    /// - `server_side_encryption`, `AES256` or `aws:kms`, along with `sse_kms_key_id` for the
+    ///   latter
+    /// - `storage_class`, ie. `STANDARD_IA`
+    /// - `proxy=true`, to serve objects through us rather than redirecting to the bucket, along
+    ///   with `cache_path` and `cache_size_mb` (1024 by default) to cache them on disk
+    pub async fn from_uri(uri: &url::Url, content_addressed: bool) -> Result<Self, Error> {
+        let mut shared_config = aws_config::from_env();
+
+        if let Some(region) = query_param(uri, "region") {
+            shared_config = shared_config.region(aws_sdk_s3::Region::new(region));
+        }
+
+        let endpoint = match uri.host() {
+            Some(host) => {
+                let scheme = query_param(uri, "endpoint_scheme");
+                let scheme = match scheme.as_deref() {
+                    None | Some("https") => "https",
+                    Some("http") => "http",
+                    Some(_) => return Err(Error::InvalidUriParameter("endpoint_scheme")),
+                };
+
+                Some(match uri.port() {
+                    Some(port) => format!("{}://{}:{}", scheme, host, port),
+                    None => format!("{}://{}", scheme, host),
+                })
+            }
+            None => None,
+        };
+
+        if let Some(endpoint) = &endpoint {
+            shared_config = shared_config.endpoint_resolver(Endpoint::immutable(
+                endpoint.parse().map_err(Error::InvalidUri)?,
+            ));
+        }
+
+        let shared_config = shared_config.load().await;
+
+        let mut config = aws_sdk_s3::config::Builder::from(&shared_config);
+        if query_flag(uri, "path_style") {
+            config = config.force_path_style(true);
+        }
+
+        let client = aws_sdk_s3::Client::from_conf(config.build());
+
+        let cache = match query_param(uri, "cache_path") {
+            Some(path) => {
+                let size_mb = parse_query_param(uri, "cache_size_mb")?.unwrap_or(1024_u64);
+                Some(Cache::open(path.into(), size_mb.saturating_mul(1024 * 1024)).await?)
+            }
+            None => None,
+        };
+
+        // the sdk accepts anything for these, so values it doesn't know about would otherwise
+        // only be turned away by the bucket on the first upload
+        let server_side_encryption = query_param(uri, "server_side_encryption")
+            .map(|v| match ServerSideEncryption::from(v.as_str()) {
+                ServerSideEncryption::Unknown(_) => {
+                    Err(Error::InvalidUriParameter("server_side_encryption"))
+                }
+                v => Ok(v),
+            })
+            .transpose()?;
+        let storage_class = query_param(uri, "storage_class")
+            .map(|v| match StorageClass::from(v.as_str()) {
+                StorageClass::Unknown(_) => Err(Error::InvalidUriParameter("storage_class")),
+                v => Ok(v),
+            })
+            .transpose()?;
+
+        let sse_kms_key_id = query_param(uri, "sse_kms_key_id");
+        if sse_kms_key_id.is_some() && server_side_encryption != Some(ServerSideEncryption::AwsKms)
+        {
+            return Err(Error::InvalidUriParameter("sse_kms_key_id"));
+        }
+
+        let mut path = uri.path_segments().ok_or(Error::MissingPath)?;
+
+        Ok(Self {
+            endpoint,
+            bucket: path.next().ok_or(Error::MissingBucket)?.to_string(),
+            path: Itertools::intersperse(path, "/").collect(),
+            client,
+            presign_expiry: Duration::from_secs(
+                parse_query_param(uri, "presign_expiry_secs")?.unwrap_or(600),
+            ),
+            server_side_encryption,
+            sse_kms_key_id,
+            storage_class,
+            content_addressed,
+            encryption: None,
+            proxy: query_flag(uri, "proxy"),
+            cache,
+        })
+    }
+
+    fn key(&self, reference: &Reference) -> String {
+        format!("{}/{}", self.path, reference.path())
+    }
+
+    /// Starts downloading the object as it's stored, without decrypting it.
+    async fn get_object(&self, reference: &Reference) -> Result<ByteStream, Error> {
+        Ok(self
+            .client
+            .get_object()
+            .key(self.key(reference))
+            .bucket(&self.bucket)
+            .send()
+            .await?
+            .body)
+    }
+
+    /// Reads the object from the cache, or from the bucket if it isn't cached yet, in which case
+    /// it's streamed into the cache and served from there.
+    async fn fetch_cached(&self, file_ref: &FileReference) -> Result<FileContent, Error> {
+        let Some(cache) = &self.cache else {
+            return self.fetch(file_ref).await;
+        };
+
+        let content = match cache.get(&file_ref.reference).await? {
+            Some(content) => content,
+            None => {
+                let body = Box::pin(
+                    self.get_object(&file_ref.reference)
+                        .await?
+                        .into_async_read(),
+                );
+
+                match cache.insert(&file_ref.reference, body).await {
+                    Ok(content) => content,
+                    // failing to cache the object shouldn't stop it from being served, so it's
+                    // downloaded again without the cache. the next read will try caching it again
+                    Err(_) => return self.fetch(file_ref).await,
+                }
+            }
+        };
+
+        content
+            .unseal(self.encryption.as_ref(), &file_ref.reference)
+            .await
+    }
 }
 
 #[async_trait]
@@ -219,50 +1089,242 @@ impl FileSystemIo for S3 {
     const KIND: FileSystemKind = FileSystemKind::S3;
 
     async fn read(&self, file_ref: FileReference) -> Result<FilePointer, Error> {
+        // clients can't decrypt objects themselves, so encrypted objects are served through us
+        // rather than redirecting to the bucket
+        if self.proxy || self.encryption.is_some() {
+            return Ok(FilePointer::Content(self.fetch_cached(&file_ref).await?));
+        }
+
         Ok(FilePointer::Redirect(
             self.client
                 .get_object()
-                .key(format!("{}/{}", self.path, file_ref.reference))
+                .key(self.key(&file_ref.reference))
                 .bucket(&self.bucket)
-                .presigned(PresigningConfig::expires_in(Duration::from_secs(600))?)
+                .presigned(PresigningConfig::expires_in(self.presign_expiry)?)
                 .await?
                 .uri()
                 .clone(),
         ))
     }
 
-    async fn write(&self, data: Bytes) -> Result<FileReference, Error> {
-        let file_ref = Self::create_ref();
+    async fn write_as<R: AsyncRead + Send + Unpin>(
+        &self,
+        reference: Option<Reference>,
+        data: R,
+    ) -> Result<FileReference, Error> {
+        // s3 needs to know the length and md5 of the object before we start uploading it, so
+        // it's spooled to a temporary file rather than being held in memory
+        let temp_path = std::env::temp_dir().join(format!("chartered-fs-{}", uuid::Uuid::new_v4()));
+
+        let res = async {
+            let (spooled, file_ref) = {
+                let mut file = File::create(&temp_path).await?;
+                spool_sealed(data, &mut file, self.encryption.as_ref(), |sha256| {
+                    self.create_ref(reference, sha256)
+                })
+                .await?
+            };
+
+            // writes are idempotent for content-addressed objects, as the key is derived from the
+            // contents rewriting it just results in the same object
+            self.client
+                .put_object()
+                .key(self.key(&file_ref.reference))
+                .content_md5(base64::encode(&*spooled.md5))
+                .content_length(i64::try_from(spooled.length).unwrap_or(i64::MAX))
+                .body(
+                    ByteStream::from_path(&temp_path)
+                        .await
+                        .map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e))?,
+                )
+                .bucket(&self.bucket)
+                .acl(ObjectCannedAcl::Private)
+                .set_server_side_encryption(self.server_side_encryption.clone())
+                .set_ssekms_key_id(self.sse_kms_key_id.clone())
+                .set_storage_class(self.storage_class.clone())
+                .send()
+                .await?;
+
+            Ok::<_, Error>(file_ref)
+        }
+        .await;
+
+        let _res = tokio::fs::remove_file(&temp_path).await;
+
+        res
+    }
 
+    async fn delete(&self, file_ref: &FileReference) -> Result<(), Error> {
         self.client
-            .put_object()
-            .key(format!("{}/{}", self.path, file_ref.reference))
-            .content_md5(base64::encode(&*md5::compute(&data)))
-            .body(ByteStream::new(data.into()))
+            .delete_object()
+            .key(self.key(&file_ref.reference))
             .bucket(&self.bucket)
-            .acl(ObjectCannedAcl::Private)
             .send()
             .await?;
 
-        Ok(file_ref)
+        if let Some(cache) = &self.cache {
+            cache.remove(&file_ref.reference).await?;
+        }
+
+        Ok(())
+    }
+
+    async fn list(&self) -> Result<Vec<StoredObject>, Error> {
+        let prefix = format!("{}/", self.path);
+        let mut objects = Vec::new();
+        let mut continuation_token = None;
+
+        loop {
+            let res = self
+                .client
+                .list_objects_v2()
+                .bucket(&self.bucket)
+                .prefix(&prefix)
+                .set_continuation_token(continuation_token)
+                .send()
+                .await?;
+
+            for object in res.contents().unwrap_or_default() {
+                let reference = object
+                    .key()
+                    .and_then(|key| key.strip_prefix(&prefix))
+                    .and_then(Reference::from_path);
+
+                if let Some(reference) = reference {
+                    let last_modified = object
+                        .last_modified()
+                        .and_then(|v| u64::try_from(v.secs()).ok())
+                        .map_or(SystemTime::UNIX_EPOCH, |secs| {
+                            SystemTime::UNIX_EPOCH + Duration::from_secs(secs)
+                        });
+
+                    objects.push(StoredObject {
+                        file_ref: FileReference::new(Self::KIND, reference),
+                        size: u64::try_from(object.size()).unwrap_or_default(),
+                        last_modified,
+                    });
+                }
+            }
+
+            match res.next_continuation_token() {
+                Some(token) if res.is_truncated() => continuation_token = Some(token.to_string()),
+                _ => break,
+            }
+        }
+
+        Ok(objects)
+    }
+
+    async fn fetch(&self, file_ref: &FileReference) -> Result<FileContent, Error> {
+        // streamed straight out of the bucket, the reader makes a new ranged request for the
+        // object if it's seeked elsewhere, ie. to serve a range request
+        let key = self.key(&file_ref.reference);
+        let output = self
+            .client
+            .get_object()
+            .key(&key)
+            .bucket(&self.bucket)
+            .send()
+            .await?;
+        let reader = S3ObjectReader::new(self.client.clone(), self.bucket.clone(), key, output);
+
+        FileContent {
+            length: reader.length(),
+            reader: Box::new(reader),
+        }
+        .unseal(self.encryption.as_ref(), &file_ref.reference)
+        .await
+    }
+
+    fn content_addressed(&self) -> bool {
+        self.content_addressed
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use super::{FilePointer, FileSystem, FileSystemIo};
-    use bytes::Bytes;
+    use super::{FileContent, FilePointer, FileReference, FileSystem, FileSystemIo, Reference};
+    use sha2::{Digest, Sha256};
+    use std::str::FromStr;
+    use tokio::io::AsyncReadExt;
+
+    async fn read_content(fs: &impl FileSystemIo, file_ref: FileReference) -> FileContent {
+        match fs.read(file_ref).await.unwrap() {
+            FilePointer::Content(content) => content,
+            FilePointer::Redirect(uri) => panic!("unexpected redirect to {}", uri),
+        }
+    }
 
     #[tokio::test]
     #[allow(clippy::pedantic)]
     async fn parse_filesystem() {
-        // assert!(matches!(
-        //     FileSystem::from_str("s3://10.0.64.101:9000/my-bucket/my-location"),
-        //     Ok(FileSystem::S3(inner)) if inner.host == "10.0.64.101" && inner.bucket == "my-bucket" && inner.path == "my-location"
-        // ));
+        assert!(matches!(
+            FileSystem::from_str("s3://10.0.64.101:9000/my-bucket/my-location?endpoint_scheme=http").await,
+            Ok(FileSystem::S3(inner)) if inner.endpoint.as_deref() == Some("http://10.0.64.101:9000") && inner.bucket == "my-bucket" && inner.path == "my-location"
+        ));
+        assert!(matches!(
+            FileSystem::from_str("s3://s3.eu-west-1.amazonaws.com/my-bucket/a/b?presign_expiry_secs=60&storage_class=STANDARD_IA").await,
+            Ok(FileSystem::S3(inner)) if inner.endpoint.as_deref() == Some("https://s3.eu-west-1.amazonaws.com") && inner.path == "a/b" && inner.presign_expiry.as_secs() == 60 && inner.storage_class == Some(aws_sdk_s3::model::StorageClass::StandardIa)
+        ));
+        assert!(matches!(
+            FileSystem::from_str("s3://10.0.64.101:9000/my-bucket?endpoint_scheme=ftp").await,
+            Err(super::Error::InvalidUriParameter("endpoint_scheme"))
+        ));
+        assert!(matches!(
+            FileSystem::from_str("s3://10.0.64.101:9000/my-bucket?presign_expiry_secs=soon").await,
+            Err(super::Error::InvalidUriParameter("presign_expiry_secs"))
+        ));
+        assert!(matches!(
+            FileSystem::from_str("s3://10.0.64.101:9000/my-bucket?server_side_encryption=aws:kms&sse_kms_key_id=my-key").await,
+            Ok(FileSystem::S3(inner)) if inner.server_side_encryption == Some(aws_sdk_s3::model::ServerSideEncryption::AwsKms) && inner.sse_kms_key_id.as_deref() == Some("my-key")
+        ));
+        assert!(matches!(
+            FileSystem::from_str("s3://10.0.64.101:9000/my-bucket?server_side_encryption=rot13")
+                .await,
+            Err(super::Error::InvalidUriParameter("server_side_encryption"))
+        ));
+        assert!(matches!(
+            FileSystem::from_str("s3://10.0.64.101:9000/my-bucket?storage_class=CHEAP").await,
+            Err(super::Error::InvalidUriParameter("storage_class"))
+        ));
+        assert!(matches!(
+            FileSystem::from_str("s3://10.0.64.101:9000/my-bucket?server_side_encryption=AES256&sse_kms_key_id=my-key").await,
+            Err(super::Error::InvalidUriParameter("sse_kms_key_id"))
+        ));
         assert!(matches!(
             FileSystem::from_str("file:///tmp/chartered").await,
-            Ok(FileSystem::Local(inner)) if inner.path.to_str().unwrap() == "/tmp/chartered"
+            Ok(FileSystem::Local(inner)) if inner.path.to_str().unwrap() == "/tmp/chartered" && !inner.content_addressed
+        ));
+        assert!(matches!(
+            FileSystem::from_str("file:///tmp/chartered?content_addressed=true").await,
+            Ok(FileSystem::Local(inner)) if inner.path.to_str().unwrap() == "/tmp/chartered" && inner.content_addressed
+        ));
+    }
+
+    #[test]
+    fn parse_file_reference() {
+        let uuid = "local:67e55044-10b1-426f-9247-bb680e5fe0c8";
+        let file_ref = FileReference::from_str(uuid).unwrap();
+        assert!(file_ref.sha256().is_none());
+        assert_eq!(file_ref.to_string(), uuid);
+        assert_eq!(
+            file_ref.reference.path(),
+            "67e55044-10b1-426f-9247-bb680e5fe0c8"
+        );
+
+        let hash = "s3:sha256:bef57ec7f53a6d40beb640a780a639c83bc29ac8a9816f1fc6c5c6dcd93c4721";
+        let file_ref = FileReference::from_str(hash).unwrap();
+        assert!(file_ref.sha256().is_some());
+        assert_eq!(file_ref.to_string(), hash);
+        assert_eq!(
+            file_ref.reference.path(),
+            "sha256/be/f5/bef57ec7f53a6d40beb640a780a639c83bc29ac8a9816f1fc6c5c6dcd93c4721"
+        );
+
+        assert!(FileReference::from_str("local:sha256:abc").is_err());
+        assert!(matches!(
+            FileReference::from_str("local:not-a-uuid"),
+            Err(super::Error::UuidParse(_))
         ));
     }
 
@@ -271,11 +1333,513 @@ mod tests {
     async fn local() {
         let fs = super::Local {
             path: "/tmp".into(),
+            content_addressed: false,
+            encryption: None,
+        };
+        let file_ref = fs.write(&b"abcdef"[..]).await.unwrap();
+        assert!(matches!(file_ref.reference, Reference::Uuid(_)));
+
+        let content = read_content(&fs, file_ref.clone()).await;
+        assert_eq!(content.length, 6);
+        assert_eq!(content.into_bytes().await.unwrap(), b"abcdef");
+
+        let mut range = String::new();
+        read_content(&fs, file_ref)
+            .await
+            .into_range(2..5)
+            .await
+            .unwrap()
+            .read_to_string(&mut range)
+            .await
+            .unwrap();
+        assert_eq!(range, "cde");
+    }
+
+    #[tokio::test]
+    #[allow(clippy::pedantic)]
+    async fn local_content_addressed() {
+        let path = std::env::temp_dir().join(format!("chartered-fs-cas-{}", std::process::id()));
+        let fs = super::Local {
+            path: path.clone(),
+            content_addressed: true,
+            encryption: None,
         };
-        let file_ref = fs.write(Bytes::from_static(b"abcdef")).await.unwrap();
+
+        let first = fs.write(&b"abcdef"[..]).await.unwrap();
+        let object_path = path.join(first.reference.path());
+        std::fs::File::options()
+            .write(true)
+            .open(&object_path)
+            .unwrap()
+            .set_modified(std::time::SystemTime::UNIX_EPOCH)
+            .unwrap();
+
+        // writing the same contents again rewrites the object, bumping its modification time
+        let second = fs.write(&b"abcdef"[..]).await.unwrap();
+        assert_eq!(first, second);
+        assert_eq!(
+            hex::encode(first.sha256().unwrap()),
+            "bef57ec7f53a6d40beb640a780a639c83bc29ac8a9816f1fc6c5c6dcd93c4721"
+        );
+        assert!(object_path.is_file());
+        assert!(
+            std::fs::metadata(&object_path).unwrap().modified().unwrap()
+                > std::time::SystemTime::UNIX_EPOCH
+        );
+
+        let other = fs.write(&b"ghijkl"[..]).await.unwrap();
+        assert_ne!(first, other);
+
         assert_eq!(
-            fs.read(file_ref).await.unwrap(),
-            FilePointer::Content(Vec::from(b"abcdef".as_ref()))
+            read_content(&fs, first).await.into_bytes().await.unwrap(),
+            b"abcdef"
         );
+
+        std::fs::remove_dir_all(path).unwrap();
+    }
+
+    #[tokio::test]
+    #[allow(clippy::pedantic)]
+    async fn local_list_and_delete() {
+        let path = std::env::temp_dir().join(format!("chartered-fs-list-{}", std::process::id()));
+        let uuid_fs = super::Local {
+            path: path.clone(),
+            content_addressed: false,
+            encryption: None,
+        };
+        let hash_fs = super::Local {
+            path: path.clone(),
+            content_addressed: true,
+            encryption: None,
+        };
+
+        assert!(uuid_fs.list().await.unwrap().is_empty());
+
+        let by_uuid = uuid_fs.write(&b"abcdef"[..]).await.unwrap();
+        let by_hash = hash_fs.write(&b"ghijkl"[..]).await.unwrap();
+        std::fs::write(path.join("not-an-object"), b"").unwrap();
+
+        let mut listed: Vec<_> = uuid_fs
+            .list()
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|v| (v.file_ref, v.size))
+            .collect();
+        listed.sort_by_key(|(file_ref, _)| file_ref.sha256().is_some());
+        assert_eq!(listed, vec![(by_uuid.clone(), 6), (by_hash.clone(), 6)]);
+
+        uuid_fs.delete(&by_uuid).await.unwrap();
+        // deleting an object that's already gone is fine
+        uuid_fs.delete(&by_uuid).await.unwrap();
+
+        let listed: Vec<_> = uuid_fs
+            .list()
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|v| v.file_ref)
+            .collect();
+        assert_eq!(listed, vec![by_hash]);
+
+        std::fs::remove_dir_all(path).unwrap();
+    }
+
+    #[test]
+    fn reference_from_path() {
+        for reference in [
+            "67e55044-10b1-426f-9247-bb680e5fe0c8",
+            "sha256:bef57ec7f53a6d40beb640a780a639c83bc29ac8a9816f1fc6c5c6dcd93c4721",
+        ] {
+            let reference = Reference::from_str(reference).unwrap();
+            assert_eq!(Reference::from_path(&reference.path()), Some(reference));
+        }
+
+        assert_eq!(
+            Reference::from_path(".tmp-67e55044-10b1-426f-9247-bb680e5fe0c8"),
+            None
+        );
+        assert_eq!(
+            Reference::from_path(
+                "sha256/aa/bb/bef57ec7f53a6d40beb640a780a639c83bc29ac8a9816f1fc6c5c6dcd93c4721"
+            ),
+            None
+        );
+    }
+
+    #[test]
+    fn file_reference_backend() {
+        for file_ref in [
+            "local:67e55044-10b1-426f-9247-bb680e5fe0c8",
+            "s3@replica:67e55044-10b1-426f-9247-bb680e5fe0c8",
+            "s3@primary:sha256:bef57ec7f53a6d40beb640a780a639c83bc29ac8a9816f1fc6c5c6dcd93c4721",
+        ] {
+            assert_eq!(
+                FileReference::from_str(file_ref).unwrap().to_string(),
+                file_ref
+            );
+        }
+
+        let file_ref =
+            FileReference::from_str("s3@replica:67e55044-10b1-426f-9247-bb680e5fe0c8").unwrap();
+        assert_eq!(file_ref.backend(), Some("replica"));
+        assert_eq!(
+            file_ref.reference(),
+            &Reference::from_str("67e55044-10b1-426f-9247-bb680e5fe0c8").unwrap()
+        );
+    }
+
+    #[tokio::test]
+    #[allow(clippy::pedantic)]
+    async fn local_verify() {
+        let path = std::env::temp_dir().join(format!("chartered-fs-verify-{}", std::process::id()));
+        let fs = super::Local {
+            path: path.clone(),
+            content_addressed: false,
+            encryption: None,
+        };
+
+        let mut expected = [0_u8; 32];
+        hex::decode_to_slice(
+            "bef57ec7f53a6d40beb640a780a639c83bc29ac8a9816f1fc6c5c6dcd93c4721",
+            &mut expected,
+        )
+        .unwrap();
+
+        let file_ref = fs.write(&b"abcdef"[..]).await.unwrap();
+        fs.verify(&file_ref, &expected).await.unwrap();
+
+        // the reader is rewound after verifying so the whole object can still be served
+        let mut content = read_content(&fs, file_ref.clone()).await;
+        content.verify(&expected).await.unwrap();
+        assert_eq!(content.into_bytes().await.unwrap(), b"abcdef");
+
+        std::fs::write(fs.object_path(&file_ref.reference), b"abcdeg").unwrap();
+        assert!(matches!(
+            fs.verify(&file_ref, &expected).await,
+            Err(super::Error::ChecksumMismatch { .. })
+        ));
+        assert!(matches!(
+            read_content(&fs, file_ref.clone())
+                .await
+                .verify(&expected)
+                .await,
+            Err(super::Error::ChecksumMismatch { .. })
+        ));
+
+        fs.delete(&file_ref).await.unwrap();
+        assert!(fs
+            .verify(&file_ref, &expected)
+            .await
+            .unwrap_err()
+            .is_not_found());
+
+        std::fs::remove_dir_all(path).unwrap();
+    }
+
+    #[tokio::test]
+    #[allow(clippy::pedantic)]
+    async fn cache_evicts_least_recently_used() {
+        let path = std::env::temp_dir().join(format!("chartered-fs-cache-{}", std::process::id()));
+        let reference = |n: u8| Reference::Sha256([n; 32]);
+
+        let cache = super::Cache::open(path.clone(), 10).await.unwrap();
+        let inserted = cache.insert(&reference(1), &b"aaaa"[..]).await.unwrap();
+        assert_eq!(inserted.into_bytes().await.unwrap(), b"aaaa");
+        cache.insert(&reference(2), &b"bbbb"[..]).await.unwrap();
+
+        // using the first object makes the second the least recently used
+        assert!(cache.get(&reference(1)).await.unwrap().is_some());
+        cache.insert(&reference(3), &b"cccc"[..]).await.unwrap();
+
+        assert!(cache.get(&reference(2)).await.unwrap().is_none());
+        let content = cache.get(&reference(1)).await.unwrap().unwrap();
+        assert_eq!(content.into_bytes().await.unwrap(), b"aaaa");
+
+        // objects larger than the cache are served but never cached
+        let inserted = cache.insert(&reference(4), &[0_u8; 11][..]).await.unwrap();
+        assert_eq!(inserted.into_bytes().await.unwrap(), [0_u8; 11]);
+        assert!(cache.get(&reference(4)).await.unwrap().is_none());
+
+        // reads bump the modification time, which is what the order is rebuilt from on reopening
+        let object_path = path.join(super::Local::relative_path(&reference(1)));
+        std::fs::File::options()
+            .write(true)
+            .open(&object_path)
+            .unwrap()
+            .set_modified(std::time::SystemTime::UNIX_EPOCH)
+            .unwrap();
+        assert!(cache.get(&reference(1)).await.unwrap().is_some());
+        assert!(
+            std::fs::metadata(&object_path).unwrap().modified().unwrap()
+                > std::time::SystemTime::UNIX_EPOCH
+        );
+
+        // the cache is picked back up from disk when it's reopened
+        drop(cache);
+        let cache = super::Cache::open(path.clone(), 10).await.unwrap();
+        assert!(cache.get(&reference(1)).await.unwrap().is_some());
+        assert!(cache.get(&reference(3)).await.unwrap().is_some());
+
+        std::fs::remove_dir_all(path).unwrap();
+    }
+
+    #[tokio::test]
+    #[allow(clippy::pedantic)]
+    async fn local_encryption() {
+        let path =
+            std::env::temp_dir().join(format!("chartered-fs-encryption-{}", std::process::id()));
+        let encryption = super::Encryption::new([7_u8; 32].into());
+        let mut fs = super::Local {
+            path: path.clone(),
+            content_addressed: true,
+            encryption: None,
+        };
+
+        // objects written before encryption was enabled are still readable
+        let plain_ref = fs.write(&b"abcdef"[..]).await.unwrap();
+        fs.encryption = Some(encryption.clone());
+
+        let file_ref = fs.write(&b"ghijkl"[..]).await.unwrap();
+        let stored = std::fs::read(fs.object_path(&file_ref.reference)).unwrap();
+        assert!(!stored.windows(6).any(|v| v == b"ghijkl"));
+
+        // content-addressed references are of the plaintext, not the ciphertext
+        let mut expected = [0_u8; 32];
+        hex::decode_to_slice(
+            "54f6ee81b58accbc57adbceb0f50264897626060071dc9e92f897e7b373deb93",
+            &mut expected,
+        )
+        .unwrap();
+        assert_eq!(file_ref.sha256(), Some(&expected));
+        fs.verify(&file_ref, &expected).await.unwrap();
+
+        for (file_ref, contents) in [(&plain_ref, b"abcdef"), (&file_ref, b"ghijkl")] {
+            let content = read_content(&fs, file_ref.clone()).await;
+            assert_eq!(content.length, 6);
+            assert_eq!(content.into_bytes().await.unwrap(), contents);
+        }
+
+        // objects are bound to their reference, so one can't be swapped in for another
+        let other_ref = fs.write(&b"mnopqr"[..]).await.unwrap();
+        std::fs::copy(
+            fs.object_path(&other_ref.reference),
+            fs.object_path(&file_ref.reference),
+        )
+        .unwrap();
+        assert!(matches!(
+            fs.fetch(&file_ref).await,
+            Err(super::Error::Cipher(_))
+        ));
+        std::fs::write(fs.object_path(&file_ref.reference), &stored).unwrap();
+
+        fs.encryption = Some(super::Encryption::new([8_u8; 32].into()));
+        assert!(matches!(
+            fs.fetch(&file_ref).await,
+            Err(super::Error::Cipher(_))
+        ));
+
+        fs.encryption = None;
+        assert!(matches!(
+            fs.fetch(&file_ref).await,
+            Err(super::Error::MissingEncryptionKey)
+        ));
+
+        std::fs::remove_dir_all(path).unwrap();
+    }
+
+    #[tokio::test]
+    #[allow(clippy::pedantic)]
+    async fn local_shard_flat_objects() {
+        let path = std::env::temp_dir().join(format!("chartered-fs-shard-{}", std::process::id()));
+        let fs = super::Local {
+            path: path.clone(),
+            content_addressed: false,
+            encryption: None,
+        };
+
+        let file_ref =
+            FileReference::from_str("local:67e55044-10b1-426f-9247-bb680e5fe0c8").unwrap();
+        let sharded_path = path.join("67/e5/67e55044-10b1-426f-9247-bb680e5fe0c8");
+        let flat_path = path.join("67e55044-10b1-426f-9247-bb680e5fe0c8");
+        assert_eq!(fs.object_path(&file_ref.reference), sharded_path);
+
+        // objects written before sharding are still readable and listed from their old location
+        std::fs::create_dir_all(&path).unwrap();
+        std::fs::write(&flat_path, b"abcdef").unwrap();
+        assert_eq!(
+            read_content(&fs, file_ref.clone())
+                .await
+                .into_bytes()
+                .await
+                .unwrap(),
+            b"abcdef"
+        );
+        assert_eq!(fs.list().await.unwrap()[0].file_ref, file_ref);
+
+        assert_eq!(fs.shard_flat_objects().await.unwrap(), 1);
+        assert!(!flat_path.exists());
+        assert!(sharded_path.is_file());
+        assert_eq!(fs.shard_flat_objects().await.unwrap(), 0);
+
+        assert_eq!(
+            read_content(&fs, file_ref.clone())
+                .await
+                .into_bytes()
+                .await
+                .unwrap(),
+            b"abcdef"
+        );
+        assert_eq!(fs.list().await.unwrap()[0].file_ref, file_ref);
+
+        // no temporary files are left behind by writes
+        fs.write(&b"ghijkl"[..]).await.unwrap();
+        assert!(std::fs::read_dir(&path).unwrap().all(|entry| !entry
+            .unwrap()
+            .file_name()
+            .to_string_lossy()
+            .starts_with(".tmp")));
+
+        std::fs::remove_dir_all(path).unwrap();
+    }
+
+    /// Connects to the S3-compatible store at `CHARTERED_TEST_S3_URI`, ie. a local MinIO with
+    /// `?endpoint_scheme=http&path_style=true&region=us-east-1`, skipping the test if it isn't
+    /// set. Credentials are taken from the environment.
+    async fn test_s3(options: &str) -> Option<super::S3> {
+        let uri = std::env::var("CHARTERED_TEST_S3_URI").ok()?;
+        let separator = if uri.contains('?') { '&' } else { '?' };
+
+        let fs = match FileSystem::from_str(&format!("{}{}{}", uri, separator, options)).await {
+            Ok(FileSystem::S3(fs)) => fs,
+            res => panic!("expected an s3 file system, got {:?}", res),
+        };
+
+        // the bucket will already exist if the tests have been run against it before
+        let _res = fs.client.create_bucket().bucket(&fs.bucket).send().await;
+
+        Some(fs)
+    }
+
+    #[tokio::test]
+    #[allow(clippy::pedantic)]
+    async fn s3_integration() {
+        let Some(fs) = test_s3("presign_expiry_secs=60&storage_class=STANDARD").await else {
+            return;
+        };
+
+        let mut expected = [0_u8; 32];
+        hex::decode_to_slice(
+            "bef57ec7f53a6d40beb640a780a639c83bc29ac8a9816f1fc6c5c6dcd93c4721",
+            &mut expected,
+        )
+        .unwrap();
+
+        let file_ref = fs.write(&b"abcdef"[..]).await.unwrap();
+        fs.verify(&file_ref, &expected).await.unwrap();
+        assert_eq!(
+            fs.fetch(&file_ref)
+                .await
+                .unwrap()
+                .into_bytes()
+                .await
+                .unwrap(),
+            b"abcdef"
+        );
+
+        // downloads are redirected to the configured endpoint rather than to aws
+        match fs.read(file_ref.clone()).await.unwrap() {
+            FilePointer::Redirect(uri) => {
+                let uri = uri.to_string();
+                assert!(uri.starts_with(fs.endpoint.as_deref().unwrap()), "{}", uri);
+                assert!(uri.contains("X-Amz-Expires=60"), "{}", uri);
+            }
+            FilePointer::Content(_) => panic!("expected a redirect"),
+        }
+
+        assert!(fs
+            .list()
+            .await
+            .unwrap()
+            .iter()
+            .any(|object| object.file_ref == file_ref && object.size == 6));
+
+        fs.delete(&file_ref).await.unwrap();
+        assert!(fs.fetch(&file_ref).await.unwrap_err().is_not_found());
+    }
+
+    #[tokio::test]
+    #[allow(clippy::pedantic)]
+    async fn s3_integration_proxy() {
+        let cache_path =
+            std::env::temp_dir().join(format!("chartered-fs-s3-cache-{}", std::process::id()));
+        let Some(mut fs) = test_s3(&format!(
+            "proxy=true&content_addressed=true&cache_path={}",
+            cache_path.display()
+        ))
+        .await
+        else {
+            return;
+        };
+        fs.encryption = Some(super::Encryption::new([7_u8; 32].into()));
+
+        let file_ref = fs.write(&b"abcdef"[..]).await.unwrap();
+
+        // served through us, and from the cache once it's been read once
+        for _ in 0..2 {
+            let content = read_content(&fs, file_ref.clone()).await;
+            assert_eq!(content.into_bytes().await.unwrap(), b"abcdef");
+        }
+
+        let cached = std::fs::read(cache_path.join(file_ref.reference.path())).unwrap();
+        assert!(!cached.windows(6).any(|v| v == b"abcdef"));
+
+        fs.delete(&file_ref).await.unwrap();
+        assert!(!cache_path.join(file_ref.reference.path()).exists());
+
+        std::fs::remove_dir_all(cache_path).unwrap();
+    }
+
+    #[tokio::test]
+    #[allow(clippy::pedantic)]
+    async fn s3_integration_proxy_stream() {
+        let Some(fs) = test_s3("proxy=true").await else {
+            return;
+        };
+
+        // large enough to take a few reads, and with no two bytes at the same position in each
+        // buffer being equal so a read from the wrong place is noticed
+        let contents: Vec<u8> = (0..super::BUFFER_SIZE * 3 + 100)
+            .map(|i| (i % 251) as u8)
+            .collect();
+        let expected: [u8; 32] = Sha256::digest(&contents).into();
+
+        let file_ref = fs.write(&contents[..]).await.unwrap();
+
+        // verifying rewinds the object once it's been read through, which restarts the stream
+        let mut content = read_content(&fs, file_ref.clone()).await;
+        assert_eq!(content.length, contents.len() as u64);
+        content.verify(&expected).await.unwrap();
+        assert_eq!(content.into_bytes().await.unwrap(), contents);
+
+        let range = super::BUFFER_SIZE as u64 + 7..super::BUFFER_SIZE as u64 * 2 + 13;
+        let mut ranged = Vec::new();
+        read_content(&fs, file_ref.clone())
+            .await
+            .into_range(range.clone())
+            .await
+            .unwrap()
+            .read_to_end(&mut ranged)
+            .await
+            .unwrap();
+        assert_eq!(ranged, &contents[range.start as usize..range.end as usize]);
+
+        // objects shorter than the bytes checked for encryption are served from what was kept
+        let short_ref = fs.write(&b"abc"[..]).await.unwrap();
+        let content = read_content(&fs, short_ref.clone()).await;
+        assert_eq!(content.into_bytes().await.unwrap(), b"abc");
+
+        fs.delete(&file_ref).await.unwrap();
+        fs.delete(&short_ref).await.unwrap();
     }
 }
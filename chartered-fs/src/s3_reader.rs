@@ -0,0 +1,188 @@
+//! A seekable reader over an object in an S3 bucket, so proxied objects can be streamed through
+//! us without first being buffered into memory.
+//!
+//! The SDK's body can only be read from start to end, so seeking to anywhere other than the
+//! current position starts a new ranged request from the position being seeked to once the next
+//! read is made. The first few bytes of the object are kept around as they're read, so checking
+//! whether an object is encrypted and then rewinding doesn't need a second request.
+
+use std::{
+    future::Future,
+    io::SeekFrom,
+    pin::Pin,
+    sync::{Mutex, PoisonError},
+    task::{ready, Context, Poll},
+};
+
+use aws_sdk_s3::{error::GetObjectError, output::GetObjectOutput, types::SdkError};
+use tokio::io::{AsyncRead, AsyncSeek, ReadBuf};
+
+use crate::encryption;
+
+/// How many bytes from the start of the object are kept, enough to check for
+/// [`encryption::MAGIC`].
+const HEAD_LEN: usize = encryption::MAGIC.len();
+
+type GetObjectFuture =
+    Pin<Box<dyn Future<Output = Result<GetObjectOutput, SdkError<GetObjectError>>> + Send>>;
+
+enum Body {
+    /// A ranged request has been made from `start`, and we're waiting on its response. The
+    /// request is only ever polled through a mutable reference, the mutex is just there to make
+    /// the reader `Sync`
+    Requesting {
+        start: u64,
+        request: Mutex<GetObjectFuture>,
+    },
+    /// The body of a request, which will next be read from `position`
+    Streaming {
+        position: u64,
+        reader: Pin<Box<dyn AsyncRead + Send + Sync>>,
+    },
+}
+
+pub(crate) struct S3ObjectReader {
+    client: aws_sdk_s3::Client,
+    bucket: String,
+    key: String,
+    length: u64,
+    /// Where the next read will be made from in the object
+    position: u64,
+    /// The first [`HEAD_LEN`] bytes of the object, once they've been read
+    head: Vec<u8>,
+    body: Body,
+}
+
+impl S3ObjectReader {
+    /// Reads the object `output` is the response to a `GetObject` request for, with no range.
+    pub(crate) fn new(
+        client: aws_sdk_s3::Client,
+        bucket: String,
+        key: String,
+        output: GetObjectOutput,
+    ) -> Self {
+        Self {
+            client,
+            bucket,
+            key,
+            length: u64::try_from(output.content_length()).unwrap_or_default(),
+            position: 0,
+            head: Vec::with_capacity(HEAD_LEN),
+            body: Body::Streaming {
+                position: 0,
+                reader: Box::pin(output.body.into_async_read()),
+            },
+        }
+    }
+
+    pub(crate) fn length(&self) -> u64 {
+        self.length
+    }
+
+    /// Starts a new request for the object from the current position onwards.
+    fn request(&mut self) {
+        let request = self
+            .client
+            .get_object()
+            .bucket(&self.bucket)
+            .key(&self.key)
+            .range(format!("bytes={}-", self.position))
+            .send();
+
+        self.body = Body::Requesting {
+            start: self.position,
+            request: Mutex::new(Box::pin(request)),
+        };
+    }
+}
+
+impl AsyncRead for S3ObjectReader {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        let this = self.get_mut();
+
+        loop {
+            if this.position >= this.length || buf.remaining() == 0 {
+                return Poll::Ready(Ok(()));
+            }
+
+            // rewound to somewhere within the head of the object
+            if let Some(head) = usize::try_from(this.position)
+                .ok()
+                .and_then(|position| this.head.get(position..))
+                .filter(|head| !head.is_empty())
+            {
+                let read = head.len().min(buf.remaining());
+                buf.put_slice(&head[..read]);
+                this.position += read as u64;
+                return Poll::Ready(Ok(()));
+            }
+
+            match &mut this.body {
+                Body::Streaming { position, reader } if *position == this.position => {
+                    let filled = buf.filled().len();
+                    ready!(reader.as_mut().poll_read(cx, buf))?;
+                    let read = &buf.filled()[filled..];
+
+                    if read.is_empty() {
+                        return Poll::Ready(Err(std::io::ErrorKind::UnexpectedEof.into()));
+                    }
+
+                    // only bytes that follow on from the head are kept, anything read from
+                    // elsewhere in the object can't be
+                    if this.head.len() < HEAD_LEN && this.head.len() as u64 == *position {
+                        let keep = read.len().min(HEAD_LEN - this.head.len());
+                        this.head.extend_from_slice(&read[..keep]);
+                    }
+
+                    *position += read.len() as u64;
+                    this.position = *position;
+
+                    return Poll::Ready(Ok(()));
+                }
+                Body::Requesting { start, request } => {
+                    let start = *start;
+                    let request = request.get_mut().unwrap_or_else(PoisonError::into_inner);
+                    let output = ready!(request.as_mut().poll(cx))
+                        .map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e))?;
+
+                    this.body = Body::Streaming {
+                        position: start,
+                        reader: Box::pin(output.body.into_async_read()),
+                    };
+                }
+                // we've been seeked away from where the current body is at
+                Body::Streaming { .. } => this.request(),
+            }
+        }
+    }
+}
+
+impl AsyncSeek for S3ObjectReader {
+    fn start_seek(self: Pin<&mut Self>, position: SeekFrom) -> std::io::Result<()> {
+        let this = self.get_mut();
+
+        let position = match position {
+            SeekFrom::Start(v) => Some(v),
+            SeekFrom::End(v) => this.length.checked_add_signed(v),
+            SeekFrom::Current(v) => this.position.checked_add_signed(v),
+        };
+
+        // a new request is only made once we're next read from, if we've actually moved
+        this.position = position.ok_or_else(|| {
+            std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "invalid seek to a negative or overflowing position",
+            )
+        })?;
+
+        Ok(())
+    }
+
+    fn poll_complete(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<std::io::Result<u64>> {
+        Poll::Ready(Ok(self.position))
+    }
+}
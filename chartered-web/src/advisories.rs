@@ -0,0 +1,321 @@
+//! Loads a [RustSec]-format advisory database from a local directory, this can either be a git
+//! checkout of the [advisory-db] or our own internal advisories written in the same format.
+//!
+//! Advisories are matched against crates purely by name, as the format has no concept of
+//! organisations - so an advisory for `foo` will be shown against `foo` in every organisation
+//! as well as any dependencies on `foo`.
+//!
+//! [RustSec]: https://rustsec.org/
+//! [advisory-db]: https://github.com/rustsec/advisory-db
+
+use semver::{Comparator, Version, VersionReq};
+use serde::{de::Error as SerdeDeError, Deserialize};
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+};
+use thiserror::Error;
+use tracing::{info, warn};
+
+#[derive(Error, Debug)]
+pub enum Error {
+    #[error("Failed to read advisory database at {0}: {1}")]
+    Io(PathBuf, std::io::Error),
+    #[error("Failed to parse advisory: {0}")]
+    Parse(#[from] toml::de::Error),
+    #[error("Advisory is missing its TOML front matter")]
+    MissingFrontMatter,
+}
+
+#[derive(Default, Debug)]
+pub struct AdvisoryDatabase {
+    advisories: HashMap<String, Vec<Advisory>>,
+}
+
+impl AdvisoryDatabase {
+    /// Recursively loads all the advisories from the given directory, withdrawn advisories and
+    /// files that fail to parse are skipped over.
+    pub fn load(path: &Path) -> Result<Self, Error> {
+        let mut db = Self::default();
+        db.load_dir(path)?;
+
+        info!(
+            "Loaded {} advisories from {}",
+            db.advisories.values().map(Vec::len).sum::<usize>(),
+            path.display()
+        );
+
+        Ok(db)
+    }
+
+    fn load_dir(&mut self, path: &Path) -> Result<(), Error> {
+        let entries = std::fs::read_dir(path).map_err(|e| Error::Io(path.to_path_buf(), e))?;
+
+        for entry in entries {
+            let path = entry.map_err(|e| Error::Io(path.to_path_buf(), e))?.path();
+
+            // skip over `.git`, `.github`, etc.
+            if path
+                .file_name()
+                .and_then(|v| v.to_str())
+                .is_none_or(|v| v.starts_with('.'))
+            {
+                continue;
+            }
+
+            if path.is_dir() {
+                self.load_dir(&path)?;
+                continue;
+            }
+
+            let advisory = match path.extension().and_then(|v| v.to_str()) {
+                Some("md") => std::fs::read_to_string(&path)
+                    .map_err(|e| Error::Io(path.clone(), e))
+                    .and_then(|v| Advisory::from_markdown(&v)),
+                Some("toml") => std::fs::read_to_string(&path)
+                    .map_err(|e| Error::Io(path.clone(), e))
+                    .and_then(|v| Ok(toml::from_str(&v)?)),
+                _ => continue,
+            };
+
+            match advisory {
+                Ok(advisory) if advisory.metadata.withdrawn.is_some() => {}
+                Ok(advisory) => self
+                    .advisories
+                    .entry(advisory.metadata.package.clone())
+                    .or_default()
+                    .push(advisory),
+                Err(e) => warn!("Skipping advisory {}: {}", path.display(), e),
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Returns all the advisories affecting the given version of a crate.
+    pub fn for_version(&self, name: &str, version: &str) -> Vec<&Advisory> {
+        let Ok(version) = Version::parse(version) else {
+            return Vec::new();
+        };
+
+        self.advisories
+            .get(name)
+            .into_iter()
+            .flatten()
+            .filter(|advisory| advisory.affects(&version))
+            .collect()
+    }
+
+    /// Returns all the advisories affecting any version of a crate that the given requirement
+    /// will allow, ie. those that the requirement needs bumping past.
+    pub fn for_requirement(&self, name: &str, req: &str) -> Vec<&Advisory> {
+        let Ok(req) = VersionReq::parse(req) else {
+            return Vec::new();
+        };
+
+        self.advisories
+            .get(name)
+            .into_iter()
+            .flatten()
+            .filter(|advisory| advisory.affects_requirement(&req))
+            .collect()
+    }
+}
+
+#[derive(Deserialize, Debug)]
+pub struct Advisory {
+    #[serde(rename = "advisory")]
+    pub metadata: AdvisoryMetadata,
+    #[serde(default)]
+    pub versions: AdvisoryVersions,
+}
+
+impl Advisory {
+    /// Parses the advisory from the current advisory-db format, a markdown file prefixed
+    /// with a fenced TOML block containing the metadata, with the title and description
+    /// following it in markdown.
+    fn from_markdown(content: &str) -> Result<Self, Error> {
+        let front_matter = content
+            .trim_start()
+            .strip_prefix("```toml")
+            .ok_or(Error::MissingFrontMatter)?;
+        let end = front_matter
+            .find("\n```")
+            .ok_or(Error::MissingFrontMatter)?;
+
+        let mut advisory: Self = toml::from_str(&front_matter[..end])?;
+
+        if let Some(body) = front_matter[end + 4..].trim().strip_prefix("# ") {
+            let (title, description) = body.split_once('\n').unwrap_or((body, ""));
+            advisory.metadata.title = title.trim().to_string();
+            advisory.metadata.description = description.trim().to_string();
+        }
+
+        Ok(advisory)
+    }
+
+    /// Versions are affected by the advisory unless they've been explicitly listed as
+    /// patched or unaffected.
+    #[must_use]
+    pub fn affects(&self, version: &Version) -> bool {
+        !self
+            .versions
+            .patched
+            .iter()
+            .chain(&self.versions.unaffected)
+            .any(|req| req.matches(version))
+    }
+
+    /// Whether any version allowed by `req` is affected by the advisory. Whether a version
+    /// matches a requirement can only change at the versions its comparators are bounded by, so
+    /// it's enough to check each of the bounds of both `req` and the advisory's ranges rather
+    /// than every version in between.
+    #[must_use]
+    pub fn affects_requirement(&self, req: &VersionReq) -> bool {
+        let advisory_comparators = self
+            .versions
+            .patched
+            .iter()
+            .chain(&self.versions.unaffected)
+            .flat_map(|req| &req.comparators);
+
+        std::iter::once(Version::new(0, 0, 0))
+            .chain(
+                req.comparators
+                    .iter()
+                    .chain(advisory_comparators)
+                    .flat_map(comparator_bounds),
+            )
+            .any(|version| req.matches(&version) && self.affects(&version))
+    }
+}
+
+#[derive(Deserialize, Debug)]
+pub struct AdvisoryMetadata {
+    pub id: String,
+    pub package: String,
+    pub date: String,
+    pub url: Option<String>,
+    #[serde(default)]
+    pub aliases: Vec<String>,
+    pub informational: Option<String>,
+    pub withdrawn: Option<String>,
+    // only set in the metadata for the legacy `.toml` format, otherwise this is pulled
+    // from the markdown
+    #[serde(default)]
+    pub title: String,
+    #[serde(default)]
+    pub description: String,
+}
+
+#[derive(Deserialize, Default, Debug)]
+pub struct AdvisoryVersions {
+    #[serde(default, deserialize_with = "deserialize_version_reqs")]
+    pub patched: Vec<VersionReq>,
+    #[serde(default, deserialize_with = "deserialize_version_reqs")]
+    pub unaffected: Vec<VersionReq>,
+}
+
+fn deserialize_version_reqs<'de, D: serde::Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Vec<VersionReq>, D::Error> {
+    Vec::<String>::deserialize(deserializer)?
+        .iter()
+        .map(|v| VersionReq::parse(v).map_err(D::Error::custom))
+        .collect()
+}
+
+/// Every version a comparator can start or stop matching at, the version it names and the next
+/// patch, minor and major versions after it - ie. `^0.0.3` stops at `0.0.4`, `~1.2` at `1.3.0`
+/// and `^1` at `2.0.0`.
+fn comparator_bounds(c: &Comparator) -> [Version; 4] {
+    let minor = c.minor.unwrap_or(0);
+    let patch = c.patch.unwrap_or(0);
+
+    [
+        comparator_version(c),
+        Version::new(c.major, minor, patch + 1),
+        Version::new(c.major, minor + 1, 0),
+        Version::new(c.major + 1, 0, 0),
+    ]
+}
+
+fn comparator_version(c: &Comparator) -> Version {
+    let mut version = Version::new(c.major, c.minor.unwrap_or(0), c.patch.unwrap_or(0));
+    version.pre = c.pre.clone();
+    version
+}
+
+#[cfg(test)]
+mod test {
+    use super::Advisory;
+    use semver::{Version, VersionReq};
+
+    #[test]
+    fn affects_requirement() {
+        let advisory = Advisory::from_markdown(
+            "```toml\n\
+            [advisory]\n\
+            id = \"RUSTSEC-0000-0000\"\n\
+            package = \"foo\"\n\
+            date = \"2022-10-19\"\n\
+            \n\
+            [versions]\n\
+            patched = [\">= 1.6.0\"]\n\
+            unaffected = [\"< 1.5.0\"]\n\
+            ```\n",
+        )
+        .unwrap();
+        let affects = |req| advisory.affects_requirement(&VersionReq::parse(req).unwrap());
+
+        // neither end of the range is affected, but the 1.5.x releases in the middle are
+        assert!(affects("^1.2"));
+        assert!(affects(">=1.0, <2"));
+        assert!(affects("=1.5.3"));
+        assert!(affects("~1.5"));
+        assert!(affects("<3"));
+        assert!(affects("*"));
+
+        assert!(!affects("^1.6"));
+        assert!(!affects("~1.4"));
+        assert!(!affects("^0.3.1"));
+        assert!(!affects("=2.0.0"));
+    }
+
+    #[test]
+    fn parse_markdown() {
+        let advisory = Advisory::from_markdown(
+            "```toml\n\
+            [advisory]\n\
+            id = \"RUSTSEC-2019-0001\"\n\
+            package = \"ammonia\"\n\
+            date = \"2019-04-27\"\n\
+            categories = [\"denial-of-service\"]\n\
+            \n\
+            [versions]\n\
+            patched = [\">= 2.1.0\"]\n\
+            unaffected = [\"< 1.0.0\"]\n\
+            ```\n\
+            \n\
+            # Uncontrolled recursion leads to abort in HTML serialization\n\
+            \n\
+            Affected versions of this crate did use recursion for serialization of HTML\n",
+        )
+        .unwrap();
+
+        assert_eq!(advisory.metadata.id, "RUSTSEC-2019-0001");
+        assert_eq!(advisory.metadata.package, "ammonia");
+        assert_eq!(
+            advisory.metadata.title,
+            "Uncontrolled recursion leads to abort in HTML serialization"
+        );
+        assert_eq!(
+            advisory.metadata.description,
+            "Affected versions of this crate did use recursion for serialization of HTML"
+        );
+
+        assert!(advisory.affects(&Version::parse("1.2.0").unwrap()));
+        assert!(!advisory.affects(&Version::parse("2.1.0").unwrap()));
+        assert!(!advisory.affects(&Version::parse("0.7.0").unwrap()));
+    }
+}
@@ -1,15 +1,29 @@
 //! Called by cargo to download a crate, depending on how we're configured we'll either serve
 //! the crate directly from the disk - or we'll redirect cargo elsewhere to download the
 //! crate. It all really depends on the `FileSystem` in use in `chartered-fs`.
+//!
+//! Crates served from the disk are streamed rather than being loaded into memory, and a single
+//! byte range can be requested using the `Range` header. Encrypted crates are decrypted into
+//! memory and always served directly, as cargo can't decrypt them itself.
 
 use axum::{
+    body::StreamBody,
     extract,
+    http::{header, HeaderMap, HeaderValue, StatusCode},
     response::{IntoResponse, Redirect, Response},
 };
 use chartered_db::{crates::Crate, users::User, ConnectionPool};
-use chartered_fs::{FilePointer, FileReference, FileSystem};
-use std::{str::FromStr, sync::Arc};
+use chartered_fs::{FilePointer, FileReference};
+
+use std::{ops::Range, str::FromStr, sync::Arc};
 use thiserror::Error;
+use tokio_util::io::ReaderStream;
+
+use crate::{
+    config::Config,
+    integrity::{FailureKind, IntegrityFailure, IntegrityMonitor},
+    storage::Storage,
+};
 
 pub async fn handle(
     extract::Path((_session_key, organisation, name, version)): extract::Path<(
@@ -20,10 +34,13 @@ pub async fn handle(
     )>,
     extract::Extension(db): extract::Extension<ConnectionPool>,
     extract::Extension(user): extract::Extension<Arc<User>>,
-    extract::Extension(fs): extract::Extension<Arc<FileSystem>>,
+    extract::Extension(storage): extract::Extension<Arc<Storage>>,
+    extract::Extension(config): extract::Extension<Arc<Config>>,
+    extract::Extension(monitor): extract::Extension<Arc<IntegrityMonitor>>,
+    headers: HeaderMap,
 ) -> Result<ResponseOrRedirect, Error> {
     let crate_with_permissions =
-        Arc::new(Crate::find_by_name(db.clone(), user.id, organisation, name).await?);
+        Arc::new(Crate::find_by_name(db.clone(), user.id, organisation.clone(), name).await?);
 
     // we shouldn't really hold back this request from progressing whilst waiting
     // on the downloads increment to complete so we'll just tokio::spawn it
@@ -43,27 +60,135 @@ pub async fn handle(
     // we can use to get a `FilePointer` that is either on the disk and already available
     // to us or is stored elsewhere but we have a link to that we can redirect to.
     let file_ref = FileReference::from_str(&version.filesystem_object).map_err(Box::new)?;
-    let res = fs.read(file_ref).await.map_err(Box::new)?;
+
+    let res = if config.integrity.verify_downloads {
+        let mut checksum = [0_u8; 32];
+        hex::decode_to_slice(&version.checksum, &mut checksum).map_err(|_| Error::Checksum)?;
+
+        match storage.read(file_ref, Some(&checksum)).await {
+            Ok(v) => v,
+            Err(e) => {
+                monitor
+                    .report(&IntegrityFailure {
+                        organisation,
+                        crate_name: crate_with_permissions.crate_.name.clone(),
+                        version: version.version,
+                        filesystem_object: version.filesystem_object,
+                        kind: FailureKind::from_error(&e),
+                    })
+                    .await;
+
+                return Err(Box::new(e).into());
+            }
+        }
+    } else {
+        storage.read(file_ref, None).await.map_err(Box::new)?
+    };
 
     match res {
         FilePointer::Redirect(uri) => {
             Ok(ResponseOrRedirect::Redirect(Redirect::to(&uri.to_string())))
         }
-        FilePointer::Content(content) => Ok(ResponseOrRedirect::Response(content)),
+        FilePointer::Content(content) => {
+            let length = content.length;
+            let range = headers
+                .get(header::RANGE)
+                .and_then(|v| v.to_str().ok())
+                .and_then(|v| parse_range(v, length));
+
+            let (status, range) = match range {
+                Some(Some(range)) => (StatusCode::PARTIAL_CONTENT, range),
+                Some(None) => return Ok(ResponseOrRedirect::RangeNotSatisfiable(length)),
+                None => (StatusCode::OK, 0..length),
+            };
+
+            let mut response_headers = HeaderMap::new();
+            response_headers.insert(header::ACCEPT_RANGES, HeaderValue::from_static("bytes"));
+            response_headers.insert(
+                header::CONTENT_TYPE,
+                HeaderValue::from_static("application/octet-stream"),
+            );
+            response_headers.insert(header::CONTENT_LENGTH, (range.end - range.start).into());
+
+            if status == StatusCode::PARTIAL_CONTENT {
+                response_headers.insert(
+                    header::CONTENT_RANGE,
+                    HeaderValue::from_str(&format!(
+                        "bytes {}-{}/{}",
+                        range.start,
+                        range.end - 1,
+                        length
+                    ))
+                    .expect("content range is always a valid header value"),
+                );
+            }
+
+            let reader = content.into_range(range).await.map_err(Box::new)?;
+            let body = StreamBody::new(ReaderStream::new(reader));
+
+            Ok(ResponseOrRedirect::Response(
+                (status, response_headers, body).into_response(),
+            ))
+        }
+    }
+}
+
+/// Parses a `Range` header against an object of `length` bytes. Returns `None` if the header
+/// should be ignored and the whole object served, which is the case for anything other than a
+/// single range of bytes, or `Some(None)` if the range can't be satisfied.
+fn parse_range(header: &str, length: u64) -> Option<Option<Range<u64>>> {
+    let spec = header.trim().strip_prefix("bytes=")?;
+
+    if spec.contains(',') {
+        return None;
+    }
+
+    let (start, end) = spec.split_once('-')?;
+    let (start, end) = (start.trim(), end.trim());
+
+    let range = if start.is_empty() {
+        // a suffix range, ie. the last `n` bytes of the object
+        let suffix: u64 = end.parse().ok()?;
+        length.saturating_sub(suffix)..length
+    } else {
+        let start: u64 = start.parse().ok()?;
+        let end = if end.is_empty() {
+            length
+        } else {
+            let end: u64 = end.parse().ok()?;
+            if end < start {
+                return None;
+            }
+            end.saturating_add(1).min(length)
+        };
+        start..end
+    };
+
+    if range.start >= range.end {
+        return Some(None);
     }
+
+    Some(Some(range))
 }
 
 /// Returns either bytes directly to the client or redirects them elsewhere.
 pub enum ResponseOrRedirect {
-    Response(Vec<u8>),
+    Response(Response),
     Redirect(Redirect),
+    /// The requested range was outside of the object, which is `.0` bytes long.
+    RangeNotSatisfiable(u64),
 }
 
 impl IntoResponse for ResponseOrRedirect {
     fn into_response(self) -> Response {
         match self {
-            Self::Response(v) => v.into_response(),
+            Self::Response(v) => v,
             Self::Redirect(v) => v.into_response(),
+            Self::RangeNotSatisfiable(length) => (
+                StatusCode::RANGE_NOT_SATISFIABLE,
+                [(header::CONTENT_RANGE, format!("bytes */{}", length))],
+            )
+                .into_response(),
         }
     }
 }
@@ -76,6 +201,8 @@ pub enum Error {
     File(#[from] Box<chartered_fs::Error>),
     #[error("The requested version does not exist for the crate")]
     NoVersion,
+    #[error("The checksum stored for the version is invalid")]
+    Checksum,
 }
 
 impl Error {
@@ -84,10 +211,32 @@ impl Error {
 
         match self {
             Self::Database(e) => e.status_code(),
-            Self::File(_) => StatusCode::INTERNAL_SERVER_ERROR,
+            Self::File(_) | Self::Checksum => StatusCode::INTERNAL_SERVER_ERROR,
             Self::NoVersion => StatusCode::NOT_FOUND,
         }
     }
 }
 
 define_error_response!(Error);
+
+#[cfg(test)]
+mod test {
+    use super::parse_range;
+
+    #[test]
+    fn range() {
+        assert_eq!(parse_range("bytes=0-499", 1000), Some(Some(0..500)));
+        assert_eq!(parse_range("bytes=500-", 1000), Some(Some(500..1000)));
+        assert_eq!(parse_range("bytes=-200", 1000), Some(Some(800..1000)));
+        assert_eq!(parse_range("bytes=900-2000", 1000), Some(Some(900..1000)));
+        assert_eq!(parse_range("bytes=-2000", 1000), Some(Some(0..1000)));
+
+        assert_eq!(parse_range("bytes=1000-", 1000), Some(None));
+        assert_eq!(parse_range("bytes=-0", 1000), Some(None));
+
+        assert_eq!(parse_range("bytes=0-1,5-6", 1000), None);
+        assert_eq!(parse_range("bytes=5-1", 1000), None);
+        assert_eq!(parse_range("items=0-1", 1000), None);
+        assert_eq!(parse_range("bytes=a-b", 1000), None);
+    }
+}
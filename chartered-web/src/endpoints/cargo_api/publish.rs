@@ -2,23 +2,30 @@
 //!
 //! This can also potentially create the crate under the given organisation if the crate doesn't
 //! already exist and the user has the `CREATE_CRATE` permissions.
+//!
+//! If configured to, any dependencies that allow a version affected by an advisory in the
+//! advisory database will be returned to cargo as warnings.
 
+use crate::{advisories::AdvisoryDatabase, config::Config, storage::Storage};
 use axum::extract;
 use bytes::Bytes;
-use chartered_db::{crates::Crate, users::User, ConnectionPool};
-use chartered_fs::FileSystem;
+use chartered_db::{audit::AuditActor, crates::Crate, users::User, ConnectionPool};
 use chartered_types::cargo::{CrateDependency, CrateFeatures, CrateVersion};
 use nom_bytes::BytesWrapper;
 use serde::{Deserialize, Serialize};
 use sha2::{Digest, Sha256};
 use std::{borrow::Cow, convert::TryInto, sync::Arc};
 use thiserror::Error;
+use tracing::warn;
 
 pub async fn handle(
     extract::Path((_session_key, organisation)): extract::Path<(String, String)>,
     extract::Extension(db): extract::Extension<ConnectionPool>,
     extract::Extension(user): extract::Extension<Arc<User>>,
-    extract::Extension(fs): extract::Extension<Arc<FileSystem>>,
+    extract::Extension(actor): extract::Extension<AuditActor>,
+    extract::Extension(storage): extract::Extension<Arc<Storage>>,
+    extract::Extension(config): extract::Extension<Arc<Config>>,
+    extract::Extension(advisory_db): extract::Extension<Arc<AdvisoryDatabase>>,
     body: Bytes,
 ) -> Result<axum::response::Json<PublishCrateResponse>, Error> {
     // cargo sends the crate metadata and the crate itself packed together, we'll parse these
@@ -48,7 +55,7 @@ pub async fn handle(
         Err(chartered_db::Error::MissingCrate) => {
             let new_crate = Crate::create(
                 db.clone(),
-                user.id,
+                actor.clone(),
                 organisation,
                 metadata.inner.name.to_string(),
             )
@@ -63,22 +70,54 @@ pub async fn handle(
 
     // writes the file to the filesystem and takes a `FileReference` we can store in the
     // db to.. reference this file when it's needed (ie. on download)
-    let file_ref = fs.write(crate_bytes).await.map_err(Box::new)?;
+    let file_ref = storage.write(&crate_bytes[..]).await.map_err(Box::new)?;
+
+    let mut warnings = PublishCrateResponseWarnings::default();
+
+    if config
+        .advisory_db
+        .as_ref()
+        .is_some_and(|v| v.warn_on_publish)
+    {
+        for dep in &metadata.inner.deps {
+            for advisory in advisory_db.for_requirement(&dep.name, &dep.version_req) {
+                warnings.other.push(format!(
+                    "dependency `{} = \"{}\"` allows versions affected by {}: {}",
+                    dep.name, dep.version_req, advisory.metadata.id, advisory.metadata.title,
+                ));
+            }
+        }
+    }
 
     // and finally, publish the version!
-    crate_with_permissions
+    let res = crate_with_permissions
         .publish_version(
             db,
-            user,
-            file_ref,
+            actor,
+            file_ref.clone(),
             checksum,
             metadata_bytes.len().try_into().unwrap(),
             metadata.inner.into(),
             metadata.meta,
         )
-        .await?;
+        .await;
+
+    if let Err(e) = res {
+        // nothing references the file we just wrote, content-addressed files may be shared with
+        // another version though so they're left for the storage gc to clean up
+        if file_ref.sha256().is_none() {
+            if let Err(e) = storage.delete(&file_ref).await {
+                warn!(
+                    "Failed to remove unpublished crate file {}: {}",
+                    file_ref, e
+                );
+            }
+        }
+
+        return Err(e.into());
+    }
 
-    Ok(axum::response::Json(PublishCrateResponse::default()))
+    Ok(axum::response::Json(PublishCrateResponse { warnings }))
 }
 
 /// Cargo sends the metadata and crate packed together and prepended with a single `u32`
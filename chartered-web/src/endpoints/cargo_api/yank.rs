@@ -4,7 +4,7 @@
 //! If a crate is yanked, cargo will refuse to download it.
 
 use axum::{extract, Json};
-use chartered_db::{crates::Crate, users::User, ConnectionPool};
+use chartered_db::{audit::AuditActor, crates::Crate, users::User, ConnectionPool};
 use serde::Serialize;
 use std::sync::Arc;
 use thiserror::Error;
@@ -18,12 +18,13 @@ pub async fn handle_yank(
     )>,
     extract::Extension(db): extract::Extension<ConnectionPool>,
     extract::Extension(user): extract::Extension<Arc<User>>,
+    extract::Extension(actor): extract::Extension<AuditActor>,
 ) -> Result<Json<Response>, Error> {
     let crate_with_permissions =
         Arc::new(Crate::find_by_name(db.clone(), user.id, organisation, name).await?);
 
     crate_with_permissions
-        .yank_version(db, version, true)
+        .yank_version(db, actor, version, true, None)
         .await?;
 
     Ok(Json(Response { ok: true }))
@@ -38,12 +39,13 @@ pub async fn handle_unyank(
     )>,
     extract::Extension(db): extract::Extension<ConnectionPool>,
     extract::Extension(user): extract::Extension<Arc<User>>,
+    extract::Extension(actor): extract::Extension<AuditActor>,
 ) -> Result<Json<Response>, Error> {
     let crate_with_permissions =
         Arc::new(Crate::find_by_name(db.clone(), user.id, organisation, name).await?);
 
     crate_with_permissions
-        .yank_version(db, version, false)
+        .yank_version(db, actor, version, false, None)
         .await?;
 
     Ok(Json(Response { ok: true }))
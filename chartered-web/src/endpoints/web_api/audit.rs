@@ -0,0 +1,154 @@
+//! Lists the audit log for the requesting user's own account, covering the changes made to it
+//! that don't belong to any organisation - ie. sessions, SSH keys, passkeys, two-factor
+//! authentication and lockouts. Events for organisations are listed by
+//! [`super::organisations`] to those that can manage them.
+//!
+//! Events are returned most recent first, a page at a time.
+
+use axum::{extract, Json};
+use chartered_db::{
+    audit::{AuditAction, AuditEventWithUsers, AuditState},
+    users::User,
+    uuid::Uuid,
+    ConnectionPool,
+};
+use chrono::{DateTime, TimeZone, Utc};
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+use thiserror::Error;
+
+const DEFAULT_PER_PAGE: i64 = 50;
+const MAX_PER_PAGE: i64 = 100;
+
+pub async fn handle_get(
+    extract::Extension(db): extract::Extension<ConnectionPool>,
+    extract::Extension(user): extract::Extension<Arc<User>>,
+    extract::Query(req): extract::Query<RequestParams>,
+) -> Result<Json<Response>, Error> {
+    let page = Page::new(req.page, req.per_page).ok_or(Error::InvalidPage)?;
+
+    // fetch an extra event so we know whether there's another page after this one
+    let events = user
+        .audit_events(db, page.offset, page.per_page + 1)
+        .await?;
+
+    Ok(Json(page.response(events)))
+}
+
+/// The page of events requested.
+pub(super) struct Page {
+    page: i64,
+    pub(super) per_page: i64,
+    pub(super) offset: i64,
+}
+
+impl Page {
+    /// Pages start from 1, returns `None` if the page is too far out to be fetched.
+    pub(super) fn new(page: Option<i64>, per_page: Option<i64>) -> Option<Self> {
+        let page = page.unwrap_or(1).max(1);
+        let per_page = per_page.unwrap_or(DEFAULT_PER_PAGE).clamp(1, MAX_PER_PAGE);
+        let offset = (page - 1).checked_mul(per_page)?;
+
+        Some(Self {
+            page,
+            per_page,
+            offset,
+        })
+    }
+
+    /// Builds the response from the events fetched, which should include an extra event past
+    /// the end of the page if there's another page after it.
+    pub(super) fn response(&self, mut events: Vec<AuditEventWithUsers>) -> Response {
+        let next_page = if events.len() > usize::try_from(self.per_page).unwrap_or(usize::MAX) {
+            events.pop();
+            self.page.checked_add(1)
+        } else {
+            None
+        };
+
+        Response {
+            events: events.into_iter().map(ResponseEvent::from).collect(),
+            next_page,
+        }
+    }
+}
+
+#[derive(Deserialize)]
+pub struct RequestParams {
+    page: Option<i64>,
+    per_page: Option<i64>,
+}
+
+#[derive(Serialize)]
+pub struct Response {
+    events: Vec<ResponseEvent>,
+    next_page: Option<i64>,
+}
+
+#[derive(Serialize)]
+pub struct ResponseEvent {
+    action: AuditAction,
+    user: ResponseUser,
+    target_user: Option<ResponseUser>,
+    #[serde(rename = "crate")]
+    crate_name: Option<String>,
+    ip: Option<String>,
+    user_agent: Option<String>,
+    before: Option<AuditState>,
+    after: Option<AuditState>,
+    created_at: DateTime<Utc>,
+}
+
+impl From<AuditEventWithUsers> for ResponseEvent {
+    fn from(v: AuditEventWithUsers) -> Self {
+        Self {
+            action: v.event.action,
+            user: v.user.into(),
+            target_user: v.target_user.map(Into::into),
+            crate_name: v.crate_name,
+            ip: v.event.ip,
+            user_agent: v.event.user_agent,
+            before: v.event.state_before,
+            after: v.event.state_after,
+            created_at: Utc.from_local_datetime(&v.event.created_at).unwrap(),
+        }
+    }
+}
+
+#[derive(Serialize)]
+pub struct ResponseUser {
+    uuid: Uuid,
+    display_name: String,
+    picture_url: Option<String>,
+}
+
+impl From<User> for ResponseUser {
+    fn from(user: User) -> Self {
+        Self {
+            uuid: user.uuid.0,
+            display_name: user.display_name().to_string(),
+            picture_url: user.picture_url,
+        }
+    }
+}
+
+#[derive(Error, Debug)]
+pub enum Error {
+    #[error("{0}")]
+    Database(#[from] chartered_db::Error),
+    #[error("The requested page is out of range")]
+    InvalidPage,
+}
+
+impl Error {
+    pub fn status_code(&self) -> axum::http::StatusCode {
+        use axum::http::StatusCode;
+
+        match self {
+            Self::Database(e) => e.status_code(),
+            Self::InvalidPage => StatusCode::BAD_REQUEST,
+        }
+    }
+}
+
+define_error_response!(Error);
@@ -0,0 +1,153 @@
+//! Sets the email address of the requesting user and verifies they have access to it by mailing
+//! them a single-use link. Only verified emails can be used to reset a forgotten password.
+
+use crate::{config::Config, endpoints::ErrorResponse, mail::Mailer};
+
+use axum::{extract, Json};
+use chartered_db::{audit::AuditActor, users::User, ConnectionPool};
+use chrono::{Duration, Utc};
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+use std::{net::IpAddr, sync::Arc};
+
+/// How long a verification link is valid for
+const VERIFICATION_TOKEN_LIFETIME_HOURS: i64 = 24;
+
+/// Returns the user's current email and whether they've verified it.
+pub async fn handle_get(
+    extract::Extension(mailer): extract::Extension<Arc<Mailer>>,
+    extract::Extension(user): extract::Extension<Arc<User>>,
+) -> Json<GetResponse> {
+    Json(GetResponse {
+        email: user.email.clone(),
+        verified: user.email_verified_at.is_some(),
+        mail_enabled: mailer.is_enabled(),
+    })
+}
+
+/// Changes the user's email, sending a verification link to the new address if the server is
+/// able to send email.
+pub async fn handle_put(
+    extract::Extension(config): extract::Extension<Arc<Config>>,
+    extract::Extension(db): extract::Extension<ConnectionPool>,
+    extract::Extension(mailer): extract::Extension<Arc<Mailer>>,
+    extract::Extension(user): extract::Extension<Arc<User>>,
+    extract::Extension(actor): extract::Extension<AuditActor>,
+    extract::Json(req): extract::Json<PutRequest>,
+) -> Result<Json<PutResponse>, Error> {
+    let email = req.email.trim();
+    email
+        .parse::<lettre::Address>()
+        .map_err(|_| Error::InvalidEmail)?;
+
+    let (token, token_hash) = super::generate_mailed_token();
+    let expires = Utc::now() + Duration::hours(VERIFICATION_TOKEN_LIFETIME_HOURS);
+
+    user.clone()
+        .set_email(
+            db,
+            actor,
+            email.to_string(),
+            token_hash,
+            expires.naive_utc(),
+        )
+        .await?;
+
+    if !mailer.is_enabled() {
+        return Ok(Json(PutResponse {
+            verification_sent: false,
+        }));
+    }
+
+    let mut link = config.frontend_base_uri.join("auth/verify-email")?;
+    link.query_pairs_mut().append_pair("token", &token);
+
+    let body = format!(
+        "Please confirm this is the email address for the Chartered account {} by following the \
+         link below within the next 24 hours:\n\n{}\n\nIf you didn't add this email to your \
+         account, you can safely ignore this email.",
+        user.username, link
+    );
+
+    mailer
+        .send(email, "Verify your Chartered email address", body)
+        .await?;
+
+    Ok(Json(PutResponse {
+        verification_sent: true,
+    }))
+}
+
+/// Marks the email the verification link was sent to as verified.
+pub async fn handle_verify(
+    extract::Extension(db): extract::Extension<ConnectionPool>,
+    extract::Json(req): extract::Json<VerifyRequest>,
+    user_agent: Option<extract::TypedHeader<headers::UserAgent>>,
+    extract::Extension(addr): extract::Extension<IpAddr>,
+) -> Result<Json<ErrorResponse>, Error> {
+    let verified = User::verify_email(
+        db,
+        super::hash_mailed_token(&req.token),
+        Some(addr.to_string()),
+        user_agent.map(|extract::TypedHeader(v)| v.as_str().to_string()),
+    )
+    .await?;
+
+    if verified {
+        Ok(Json(ErrorResponse { error: None }))
+    } else {
+        Err(Error::InvalidToken)
+    }
+}
+
+#[derive(Serialize)]
+pub struct GetResponse {
+    email: Option<String>,
+    verified: bool,
+    mail_enabled: bool,
+}
+
+#[derive(Deserialize)]
+pub struct PutRequest {
+    email: String,
+}
+
+#[derive(Serialize)]
+pub struct PutResponse {
+    verification_sent: bool,
+}
+
+#[derive(Deserialize)]
+pub struct VerifyRequest {
+    token: String,
+}
+
+#[derive(Error, Debug)]
+pub enum Error {
+    #[error("{0}")]
+    Database(#[from] chartered_db::Error),
+    #[error("Failed to build verification link: {0}")]
+    Url(#[from] url::ParseError),
+    #[error("Failed to send verification email: {0}")]
+    Mail(#[from] crate::mail::Error),
+    #[error("Email address is invalid")]
+    InvalidEmail,
+    #[error("This verification link is invalid or has expired")]
+    InvalidToken,
+}
+
+impl Error {
+    pub fn status_code(&self) -> axum::http::StatusCode {
+        use axum::http::StatusCode;
+
+        match self {
+            Self::Database(e) => e.status_code(),
+            Self::Url(_) => StatusCode::INTERNAL_SERVER_ERROR,
+            Self::Mail(_) => StatusCode::BAD_GATEWAY,
+            Self::InvalidEmail | Self::InvalidToken => StatusCode::BAD_REQUEST,
+        }
+    }
+}
+
+define_error_response!(Error);
@@ -0,0 +1,282 @@
+//! LDAP-based authentication, for users that only exist in a directory rather than in chartered
+//! itself.
+//!
+//! We first bind as the configured service account (or anonymously) to search for the user's
+//! entry, then bind again as that entry using the password the user gave us to check it. There's
+//! no registration step here, a local user is created the first time they log in and has their
+//! profile updated from their entry on every login after that.
+//!
+//! Failed logins count towards the same lockout as password logins, though only once the user
+//! has logged in before as there's no local user to lock out until then.
+
+use crate::config::{Config, LdapConfig};
+
+use axum::{extract, Json};
+use chartered_db::{audit::AuditActor, users::User, ConnectionPool};
+use ldap3::{ldap_escape, LdapConnAsync, LdapConnSettings, Scope, SearchEntry};
+use serde::Deserialize;
+use thiserror::Error;
+
+use std::{net::IpAddr, sync::Arc};
+
+/// Result code returned by the directory if the credentials we bound with were wrong.
+const INVALID_CREDENTIALS: u32 = 49;
+
+pub async fn handle_login(
+    extract::Extension(config): extract::Extension<Arc<Config>>,
+    extract::Extension(db): extract::Extension<ConnectionPool>,
+    extract::Json(req): extract::Json<LoginRequest>,
+    user_agent: Option<extract::TypedHeader<headers::UserAgent>>,
+    addr: extract::Extension<IpAddr>,
+) -> Result<Json<super::totp::LoginOrChallengeResponse>, Error> {
+    let ldap_config = config
+        .auth
+        .ldap
+        .as_ref()
+        .filter(|v| v.enabled)
+        .ok_or(Error::LdapAuthDisabled)?;
+
+    // an empty password would result in an "unauthenticated" bind which most directories accept
+    // without checking anything, so these need to be turned away before we get anywhere near the
+    // directory
+    if req.username.is_empty() {
+        return Err(Error::UnknownUser);
+    } else if req.password.is_empty() {
+        return Err(Error::InvalidPassword);
+    }
+
+    // the local user is keyed by the entry's username attribute, which is almost always the one
+    // the user logs in with
+    let existing = User::find_by_username(db.clone(), format!("ldap:{}", req.username)).await?;
+
+    if existing
+        .as_ref()
+        .is_some_and(|user| user.locked_out_until().is_some())
+    {
+        return Err(Error::AccountLocked);
+    }
+
+    let entry = match authenticate(ldap_config, &req.username, &req.password).await {
+        Ok(entry) => entry,
+        Err(Error::InvalidPassword) => {
+            if let Some(user) = existing {
+                let actor = AuditActor {
+                    user_id: user.id,
+                    ip: Some(addr.0.to_string()),
+                    user_agent: user_agent.map(|extract::TypedHeader(v)| v.as_str().to_string()),
+                };
+
+                Arc::new(user)
+                    .record_failed_login(db, actor, config.auth.password.lockout.policy())
+                    .await?;
+            }
+
+            return Err(Error::InvalidPassword);
+        }
+        Err(e) => return Err(e),
+    };
+
+    let user = User::find_or_create(
+        db.clone(),
+        // same `provider:uid` format as OIDC logins, this keeps LDAP users from clashing with
+        // any password users as password auth doesn't allow `:` in usernames
+        format!("ldap:{}", entry.username),
+        entry.name,
+        Some(entry.username),
+        entry.email,
+        false,
+        None,
+        None,
+    )
+    .await?;
+
+    // the entry's username might not be the one the user logged in with
+    if user.locked_out_until().is_some() {
+        return Err(Error::AccountLocked);
+    }
+
+    // failed logins are only cleared once the second factor has been given too, if required
+    Ok(Json(
+        super::totp::login_or_challenge(db, &config, user, user_agent, addr).await?,
+    ))
+}
+
+/// The attributes we've pulled out of the user's directory entry.
+#[derive(Debug)]
+pub struct LdapUser {
+    username: String,
+    name: Option<String>,
+    email: Option<String>,
+}
+
+/// Looks up `username` in the directory and checks `password` against it by binding as the
+/// user's entry, returning the entry's attributes if the password was correct.
+pub async fn authenticate(
+    config: &LdapConfig,
+    username: &str,
+    password: &str,
+) -> Result<LdapUser, Error> {
+    let settings = LdapConnSettings::new().set_starttls(config.starttls);
+    let (conn, mut ldap) = LdapConnAsync::with_settings(settings, &config.url).await?;
+    ldap3::drive!(conn);
+
+    if let Some(bind_dn) = &config.bind_dn {
+        ldap.simple_bind(bind_dn, config.bind_password.as_deref().unwrap_or_default())
+            .await?
+            .success()?;
+    }
+
+    let attributes = &config.attributes;
+    let (entries, _) = ldap
+        .search(
+            &config.search_base,
+            Scope::Subtree,
+            &build_filter(&config.filter, username),
+            vec![
+                attributes.username.as_str(),
+                attributes.name.as_str(),
+                attributes.email.as_str(),
+            ],
+        )
+        .await?
+        .success()?;
+
+    // refuse to guess which entry the user meant if the filter is too broad
+    let mut entries = entries.into_iter();
+    let entry = match (entries.next(), entries.next()) {
+        (Some(entry), None) => SearchEntry::construct(entry),
+        (None, _) => return Err(Error::UnknownUser),
+        (Some(_), Some(_)) => return Err(Error::AmbiguousUser),
+    };
+
+    match ldap.simple_bind(&entry.dn, password).await?.success() {
+        Ok(_) => {}
+        Err(ldap3::LdapError::LdapResult { result }) if result.rc == INVALID_CREDENTIALS => {
+            return Err(Error::InvalidPassword);
+        }
+        Err(e) => return Err(e.into()),
+    }
+
+    ldap.unbind().await?;
+
+    let attribute = |name: &str| {
+        entry
+            .attrs
+            .get(name)
+            .and_then(|v| v.first())
+            .map(ToString::to_string)
+    };
+
+    Ok(LdapUser {
+        // fall back to the username the user gave us if the entry doesn't have the attribute,
+        // though this will almost always be there since it's usually what the filter matched on
+        username: attribute(&attributes.username).unwrap_or_else(|| username.to_string()),
+        name: attribute(&attributes.name),
+        email: attribute(&attributes.email),
+    })
+}
+
+/// Substitutes the escaped username into the configured filter.
+fn build_filter(filter: &str, username: &str) -> String {
+    filter.replace("{username}", &ldap_escape(username))
+}
+
+#[derive(Deserialize)]
+pub struct LoginRequest {
+    username: String,
+    password: String,
+}
+
+#[derive(Error, Debug)]
+pub enum Error {
+    #[error("Failed to query database")]
+    Database(#[from] chartered_db::Error),
+    #[error("Failed to query directory: {0}")]
+    Ldap(#[from] ldap3::LdapError),
+    #[error("{0}")]
+    TwoFactor(#[from] super::totp::Error),
+    #[error("Invalid username/password")]
+    UnknownUser,
+    #[error("Invalid username/password")]
+    InvalidPassword,
+    #[error("More than one user matched the given username")]
+    AmbiguousUser,
+    #[error("LDAP authentication is disabled")]
+    LdapAuthDisabled,
+    #[error("Too many failed login attempts, please try again later")]
+    AccountLocked,
+}
+
+impl Error {
+    pub fn status_code(&self) -> axum::http::StatusCode {
+        use axum::http::StatusCode;
+
+        match self {
+            Self::Database(_) | Self::AmbiguousUser => StatusCode::INTERNAL_SERVER_ERROR,
+            Self::Ldap(_) => StatusCode::BAD_GATEWAY,
+            Self::TwoFactor(e) => e.status_code(),
+            Self::UnknownUser | Self::InvalidPassword | Self::LdapAuthDisabled => {
+                StatusCode::FORBIDDEN
+            }
+            Self::AccountLocked => StatusCode::TOO_MANY_REQUESTS,
+        }
+    }
+}
+
+define_error_response!(Error);
+
+#[cfg(test)]
+mod test {
+    use super::{authenticate, build_filter, Error};
+    use crate::config::{LdapAttributeMapping, LdapConfig};
+
+    #[test]
+    fn filter_escapes_username() {
+        assert_eq!(
+            build_filter("(&(objectClass=person)(uid={username}))", "jordan"),
+            "(&(objectClass=person)(uid=jordan))"
+        );
+        assert_eq!(
+            build_filter("(uid={username})", "*)(uid=*").to_lowercase(),
+            "(uid=\\2a\\29\\28uid=\\2a)"
+        );
+    }
+
+    /// Runs against a real directory, ie. the `glauth/glauth` container started by CI, skipping
+    /// the test unless `CHARTERED_TEST_LDAP_URL`, `CHARTERED_TEST_LDAP_BIND_DN`,
+    /// `CHARTERED_TEST_LDAP_BIND_PASSWORD`, `CHARTERED_TEST_LDAP_SEARCH_BASE`,
+    /// `CHARTERED_TEST_LDAP_USERNAME` and `CHARTERED_TEST_LDAP_PASSWORD` are set.
+    #[tokio::test]
+    async fn directory_login() {
+        let Ok(url) = std::env::var("CHARTERED_TEST_LDAP_URL") else {
+            return;
+        };
+        let var = |name: &str| std::env::var(format!("CHARTERED_TEST_LDAP_{}", name)).unwrap();
+
+        let config = LdapConfig {
+            enabled: true,
+            url,
+            starttls: false,
+            bind_dn: Some(var("BIND_DN")),
+            bind_password: Some(var("BIND_PASSWORD")),
+            search_base: var("SEARCH_BASE"),
+            filter: "(uid={username})".to_string(),
+            attributes: LdapAttributeMapping::default(),
+        };
+
+        let username = var("USERNAME");
+        let user = authenticate(&config, &username, &var("PASSWORD"))
+            .await
+            .unwrap();
+        assert_eq!(user.username, username);
+
+        assert!(matches!(
+            authenticate(&config, &username, "definitely-not-the-password").await,
+            Err(Error::InvalidPassword)
+        ));
+        assert!(matches!(
+            authenticate(&config, "chartered-user-that-doesnt-exist", "password").await,
+            Err(Error::UnknownUser)
+        ));
+    }
+}
@@ -2,6 +2,7 @@
 //! database.
 
 use axum::{extract, Json};
+use chartered_db::audit::AuditActor;
 use chartered_db::users::UserSession;
 use chartered_db::ConnectionPool;
 use serde::Serialize;
@@ -11,8 +12,9 @@ use thiserror::Error;
 pub async fn handle(
     extract::Extension(session): extract::Extension<Arc<UserSession>>,
     extract::Extension(db): extract::Extension<ConnectionPool>,
+    extract::Extension(actor): extract::Extension<AuditActor>,
 ) -> Result<Json<LogoutResponse>, Error> {
-    session.delete(db).await?;
+    session.delete(db, actor).await?;
 
     Ok(Json(LogoutResponse { success: true }))
 }
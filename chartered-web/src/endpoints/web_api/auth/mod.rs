@@ -1,24 +1,31 @@
-use crate::middleware::rate_limit::RateLimit;
+use crate::{config::Config, middleware::rate_limit::RateLimit};
 
 use axum::{
     extract,
     handler::Handler,
-    routing::{get, post},
+    routing::{delete, get, post},
     Extension, Router,
 };
+use chacha20poly1305::{aead::Aead, ChaCha20Poly1305, KeyInit, Nonce as ChaCha20Poly1305Nonce};
 use chartered_db::{
     users::{User, UserSession},
     uuid::Uuid,
     ConnectionPool,
 };
 use serde::Serialize;
+use sha2::{Digest, Sha256};
+use thiserror::Error;
 
 use std::net::IpAddr;
 
+pub mod email;
 pub mod extend;
+pub mod ldap;
 pub mod logout;
 pub mod openid;
 pub mod password;
+pub mod totp;
+pub mod webauthn;
 
 pub fn authenticated_routes(rate_limit: &RateLimit) -> Router {
     Router::new()
@@ -30,6 +37,45 @@ pub fn authenticated_routes(rate_limit: &RateLimit) -> Router {
             "/extend",
             get(extend::handle.layer(rate_limit.with_cost(1))),
         )
+        .route(
+            "/password",
+            post(password::handle_change.layer(rate_limit.with_cost(100))),
+        )
+        .route(
+            "/email",
+            get(email::handle_get.layer(rate_limit.with_cost(1)))
+                .put(email::handle_put.layer(rate_limit.with_cost(100))),
+        )
+        .route(
+            "/totp",
+            get(totp::handle_get.layer(rate_limit.with_cost(1)))
+                .put(totp::handle_put.layer(rate_limit.with_cost(10)))
+                .delete(totp::handle_delete.layer(rate_limit.with_cost(50))),
+        )
+        .route(
+            "/totp/confirm",
+            post(totp::handle_confirm.layer(rate_limit.with_cost(50))),
+        )
+        .route(
+            "/totp/recovery-codes",
+            post(totp::handle_regenerate_recovery_codes.layer(rate_limit.with_cost(50))),
+        )
+        .route(
+            "/webauthn/credentials",
+            get(webauthn::handle_list.layer(rate_limit.with_cost(1))),
+        )
+        .route(
+            "/webauthn/credentials/:id",
+            delete(webauthn::handle_delete.layer(rate_limit.with_cost(24))),
+        )
+        .route(
+            "/webauthn/register/begin",
+            post(webauthn::handle_register_begin.layer(rate_limit.with_cost(10))),
+        )
+        .route(
+            "/webauthn/register/complete",
+            post(webauthn::handle_register_complete.layer(rate_limit.with_cost(24))),
+        )
 }
 
 pub fn unauthenticated_routes(rate_limit: &RateLimit) -> Router {
@@ -38,10 +84,38 @@ pub fn unauthenticated_routes(rate_limit: &RateLimit) -> Router {
             "/register/password",
             post(password::handle_register.layer(rate_limit.with_cost(200))),
         )
+        .route(
+            "/password/forgot",
+            post(password::handle_forgot.layer(rate_limit.with_cost(200))),
+        )
+        .route(
+            "/password/reset",
+            post(password::handle_reset.layer(rate_limit.with_cost(100))),
+        )
+        .route(
+            "/email/verify",
+            post(email::handle_verify.layer(rate_limit.with_cost(50))),
+        )
         .route(
             "/login/password",
             post(password::handle_login.layer(rate_limit.with_cost(100))),
         )
+        .route(
+            "/login/totp",
+            post(totp::handle_login.layer(rate_limit.with_cost(100))),
+        )
+        .route(
+            "/webauthn/login/begin",
+            post(webauthn::handle_login_begin.layer(rate_limit.with_cost(10))),
+        )
+        .route(
+            "/webauthn/login/complete",
+            post(webauthn::handle_login_complete.layer(rate_limit.with_cost(100))),
+        )
+        .route(
+            "/login/ldap",
+            post(ldap::handle_login.layer(rate_limit.with_cost(100))),
+        )
         .route(
             "/login/oauth/:provider/begin",
             get(openid::begin_oidc.layer(rate_limit.with_cost(1))),
@@ -96,3 +170,70 @@ pub async fn login(
         picture_url: user.picture_url,
     })
 }
+
+const NONCE_LEN: usize = 12;
+
+#[derive(Error, Debug)]
+pub enum CipherError {
+    #[error("Error during encryption/decryption")]
+    Cipher(#[from] chacha20poly1305::aead::Error),
+    #[error("Base64 error")]
+    Base64(#[from] base64::DecodeError),
+}
+
+/// Encrypts the given bytes using ChaCha20Poly1305 with the configured `encryption_key`, the
+/// nonce is appended to the end of the returned ciphertext.
+pub fn encrypt(input: &[u8], config: &Config) -> Result<Vec<u8>, CipherError> {
+    let cipher = ChaCha20Poly1305::new(&config.encryption_key);
+
+    let nonce = rand::random::<[u8; NONCE_LEN]>();
+    let nonce = ChaCha20Poly1305Nonce::from_slice(&nonce);
+
+    let mut ciphertext = cipher.encrypt(nonce, input)?;
+    ciphertext.extend_from_slice(nonce);
+
+    Ok(ciphertext)
+}
+
+/// Decrypts ciphertext returned by `encrypt`.
+pub fn decrypt(input: &[u8], config: &Config) -> Result<Vec<u8>, CipherError> {
+    let cipher = ChaCha20Poly1305::new(&config.encryption_key);
+
+    let split_at = input
+        .len()
+        .checked_sub(NONCE_LEN)
+        .ok_or(chacha20poly1305::aead::Error)?;
+    let (ciphertext, nonce) = input.split_at(split_at);
+
+    Ok(cipher.decrypt(ChaCha20Poly1305Nonce::from_slice(nonce), ciphertext)?)
+}
+
+// Encrypts the given string using ChaCha20Poly1305 and returns a url safe base64 encoded
+// version of it
+pub fn encrypt_url_safe(input: &[u8], config: &Config) -> Result<String, CipherError> {
+    Ok(base64::encode_config(
+        &encrypt(input, config)?,
+        base64::URL_SAFE_NO_PAD,
+    ))
+}
+
+// Decrypts the given string assuming it's a url safe base64 encoded ChaCha20Poly1305 cipher.
+pub fn decrypt_url_safe(input: &str, config: &Config) -> Result<Vec<u8>, CipherError> {
+    decrypt(
+        &base64::decode_config(input, base64::URL_SAFE_NO_PAD)?,
+        config,
+    )
+}
+
+/// Generates a random, url safe, token to be mailed out to a user (or handed back to them as a
+/// pre-auth token), returning it along with the hash that should be stored in its place.
+pub fn generate_mailed_token() -> (String, String) {
+    let token = base64::encode_config(rand::random::<[u8; 32]>(), base64::URL_SAFE_NO_PAD);
+    let hash = hash_mailed_token(&token);
+    (token, hash)
+}
+
+/// Hashes a token returned by `generate_mailed_token` so it can be looked up.
+pub fn hash_mailed_token(token: &str) -> String {
+    hex::encode(Sha256::digest(token.trim().as_bytes()))
+}
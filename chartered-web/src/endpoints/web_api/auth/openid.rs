@@ -2,11 +2,11 @@
 //! enabled providers so they can show them to the frontend and provide methods for actually doing
 //! the authentication.
 
+use super::{decrypt_url_safe, encrypt_url_safe};
 use crate::config::{Config, OidcClient, OidcClients};
 
 use axum::{extract, Json};
-use chacha20poly1305::{aead::Aead, ChaCha20Poly1305, KeyInit, Nonce as ChaCha20Poly1305Nonce};
-use chartered_db::{users::User, ConnectionPool};
+use chartered_db::{audit::AuditActor, users::User, ConnectionPool};
 use oauth2::{
     basic::BasicErrorResponseType, AuthorizationCode, CsrfToken, RequestTokenError, Scope,
     StandardErrorResponse, TokenResponse,
@@ -28,6 +28,8 @@ pub async fn list_providers(
 ) -> Json<ListProvidersResponse> {
     Json(ListProvidersResponse {
         password: config.auth.password.enabled,
+        ldap: config.auth.ldap.as_ref().map_or(false, |v| v.enabled),
+        webauthn: config.auth.webauthn.as_ref().map_or(false, |v| v.enabled),
         providers: oidc_clients
             .keys()
             .into_iter()
@@ -76,7 +78,9 @@ pub async fn begin_oidc(
 }
 
 /// Handles the response back from the OIDC provider, checking the state came from us, validating
-/// the token with the provider themselves and then finally logging the user in.
+/// the token with the provider themselves and then finally logging the user in - or challenging
+/// them for a code first if they've enabled two-factor authentication, the same as a password
+/// login.
 pub async fn complete_oidc(
     extract::Query(params): extract::Query<CompleteOidcParams>,
     extract::Extension(config): extract::Extension<Arc<Config>>,
@@ -85,7 +89,7 @@ pub async fn complete_oidc(
     extract::Extension(http_client): extract::Extension<reqwest::Client>,
     user_agent: Option<extract::TypedHeader<headers::UserAgent>>,
     addr: extract::Extension<IpAddr>,
-) -> Result<Json<super::LoginResponse>, Error> {
+) -> Result<Json<super::totp::LoginOrChallengeResponse>, Error> {
     // decrypt the state that we created in `begin_oidc` and parse it as json
     let state: State = serde_json::from_slice(&decrypt_url_safe(&params.state, &config)?)?;
 
@@ -95,7 +99,7 @@ pub async fn complete_oidc(
         .get(&state.provider)
         .ok_or(Error::UnknownOauthProvider)?;
 
-    let user = match client {
+    let (user, memberships) = match client {
         OidcClient::Discovered(client) => {
             let mut token: Token = client.request_token(&params.code).await?.into();
 
@@ -112,8 +116,20 @@ pub async fn complete_oidc(
                 return Err(Error::MissingToken);
             }
 
-            // get some basic info from the provider using the claims we requested in `begin_oidc`
-            UserIr::from(client.request_userinfo(&token).await?)
+            // get some basic info from the provider using the claims we requested in `begin_oidc`,
+            // keeping hold of the raw claims so we can evaluate the configured claim mappings
+            // against them
+            let claims: serde_json::Value = client.request_userinfo_custom(&token).await?;
+            let memberships = config
+                .auth
+                .oauth
+                .get(&state.provider)
+                .map(|v| v.memberships(&claims));
+
+            (
+                UserIr::from(serde_json::from_value::<Userinfo>(claims)?),
+                memberships,
+            )
         }
         OidcClient::GitHub(client) => {
             let token_result = client
@@ -132,7 +148,7 @@ pub async fn complete_oidc(
                 .json()
                 .await?;
 
-            UserIr::from(res)
+            (UserIr::from(res), None)
         }
     };
 
@@ -145,13 +161,28 @@ pub async fn complete_oidc(
         user.name,
         user.nick,
         user.email,
+        user.email_verified,
         user.profile_url,
         user.avatar_url,
     )
     .await?;
 
+    // organisation and team memberships granted through claim mappings are re-evaluated on every
+    // login, so access is revoked as soon as the provider stops sending the claim
+    if let Some(memberships) = memberships {
+        let actor = AuditActor {
+            user_id: user.id,
+            ip: Some(addr.0.to_string()),
+            user_agent: user_agent.as_ref().map(|v| v.0.to_string()),
+        };
+
+        memberships.sync(db.clone(), actor, state.provider).await?;
+    }
+
     // request looks good, log the user in!
-    Ok(Json(super::login(db, user, user_agent, addr).await?))
+    Ok(Json(
+        super::totp::login_or_challenge(db, &config, user, user_agent, addr).await?,
+    ))
 }
 
 pub struct UserIr {
@@ -159,6 +190,8 @@ pub struct UserIr {
     name: Option<String>,
     nick: Option<String>,
     email: Option<String>,
+    /// Whether the provider has verified the user owns `email`
+    email_verified: bool,
     profile_url: Option<Url>,
     avatar_url: Option<Url>,
 }
@@ -170,6 +203,8 @@ impl From<GitHubUserResponse> for UserIr {
             name: Some(v.name.unwrap_or_else(|| v.login.to_string())),
             nick: Some(v.login),
             email: Some(v.email),
+            // the public profile email isn't necessarily one GitHub has verified
+            email_verified: false,
             profile_url: v.html_url,
             avatar_url: Some(v.avatar_url),
         }
@@ -183,6 +218,7 @@ impl From<Userinfo> for UserIr {
             name: v.name,
             nick: v.nickname,
             email: v.email,
+            email_verified: v.email_verified,
             profile_url: v.profile,
             avatar_url: v.picture,
         }
@@ -199,38 +235,11 @@ pub struct GitHubUserResponse {
     email: String,
 }
 
-const NONCE_LEN: usize = 12;
-
-// Encrypts the given string using ChaCha20Poly1305 and returns a url safe base64 encoded
-// version of it
-fn encrypt_url_safe(input: &[u8], config: &Config) -> Result<String, Error> {
-    let cipher = ChaCha20Poly1305::new(&config.encryption_key);
-
-    let nonce = rand::random::<[u8; NONCE_LEN]>();
-    let nonce = ChaCha20Poly1305Nonce::from_slice(&nonce);
-
-    let mut ciphertext = cipher.encrypt(nonce, input)?;
-    ciphertext.extend_from_slice(nonce);
-
-    Ok(base64::encode_config(&ciphertext, base64::URL_SAFE_NO_PAD))
-}
-
-// Decrypts the given string assuming it's a url safe base64 encoded ChaCha20Poly1305 cipher.
-fn decrypt_url_safe(input: &str, config: &Config) -> Result<Vec<u8>, Error> {
-    let cipher = ChaCha20Poly1305::new(&config.encryption_key);
-
-    let mut ciphertext = base64::decode_config(input, base64::URL_SAFE_NO_PAD)?;
-    let ciphertext_nonce = ciphertext.split_off(ciphertext.len() - NONCE_LEN);
-    let ciphertext_nonce = ChaCha20Poly1305Nonce::from_slice(&ciphertext_nonce);
-
-    cipher
-        .decrypt(ciphertext_nonce, ciphertext.as_ref())
-        .map_err(Error::from)
-}
-
 #[derive(Serialize)]
 pub struct ListProvidersResponse {
     password: bool,
+    ldap: bool,
+    webauthn: bool,
     providers: Vec<String>,
 }
 
@@ -266,10 +275,10 @@ pub enum Error {
     OAuth(#[from] openid::error::Error),
     #[error("{0}")]
     OAuthClient(#[from] openid::error::ClientError),
-    #[error("Error during encryption/decryption")]
-    Cipher(#[from] chacha20poly1305::aead::Error),
-    #[error("Base64 error")]
-    Base64(#[from] base64::DecodeError),
+    #[error("{0}")]
+    Cipher(#[from] super::CipherError),
+    #[error("{0}")]
+    TwoFactor(#[from] super::totp::Error),
     #[error("Missing id_token")]
     MissingToken,
     #[error("Failed to request profile from OAuth provider")]
@@ -290,6 +299,7 @@ impl Error {
 
         match self {
             Self::Database(e) => e.status_code(),
+            Self::TwoFactor(e) => e.status_code(),
             Self::FetchProfile(_) | Self::RequestOAuthToken(_) => StatusCode::BAD_GATEWAY,
             _ => StatusCode::INTERNAL_SERVER_ERROR,
         }
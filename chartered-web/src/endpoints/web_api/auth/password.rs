@@ -1,17 +1,36 @@
-//! Password-based authentication, including registration and login.
+//! Password-based authentication, including registration, login, changing passwords and
+//! resetting forgotten ones.
+//!
+//! Forgotten passwords can only be reset for users that have verified their email, the reset
+//! link is mailed out to that address and can only be used once.
+//!
+//! Accounts are locked for an exponentially increasing amount of time once they've had too many
+//! failed logins in a row, on top of the usual per-IP rate limiting.
 
-use crate::config::Config;
+use crate::{
+    config::Config, endpoints::ErrorResponse, mail::Mailer, password_policy::PasswordPolicy,
+};
 
 use axum::{extract, Json};
-use chartered_db::{users::User, ConnectionPool};
+use chartered_db::{
+    audit::AuditActor,
+    users::{User, UserSession},
+    ConnectionPool,
+};
+use chrono::{Duration, Utc};
 use serde::{Deserialize, Serialize};
 use thiserror::Error;
+use tracing::warn;
 
 use std::{net::IpAddr, sync::Arc};
 
+/// How long a password reset link is valid for
+const RESET_TOKEN_LIFETIME_HOURS: i64 = 1;
+
 pub async fn handle_register(
     extract::Extension(config): extract::Extension<Arc<Config>>,
     extract::Extension(db): extract::Extension<ConnectionPool>,
+    extract::Extension(policy): extract::Extension<Arc<PasswordPolicy>>,
     extract::Json(req): extract::Json<RegisterRequest>,
 ) -> Result<Json<RegisterResponse>, RegisterError> {
     // some basic validation before we register the user
@@ -19,10 +38,10 @@ pub async fn handle_register(
         return Err(RegisterError::PasswordAuthDisabled);
     } else if !validate_username(&req.username) {
         return Err(RegisterError::InvalidUsername);
-    } else if req.password.len() < 6 {
-        return Err(RegisterError::PasswordRequirementNotMet);
     }
 
+    policy.check(&req.password, &[&req.username]).await?;
+
     let password_hash = bcrypt::hash(&req.password, bcrypt::DEFAULT_COST)?;
 
     match User::register(db, req.username, password_hash).await {
@@ -38,7 +57,7 @@ pub async fn handle_login(
     extract::Json(req): extract::Json<LoginRequest>,
     user_agent: Option<extract::TypedHeader<headers::UserAgent>>,
     addr: extract::Extension<IpAddr>,
-) -> Result<Json<super::LoginResponse>, LoginError> {
+) -> Result<Json<super::totp::LoginOrChallengeResponse>, LoginError> {
     // some basic validation before we attempt a login
     if !config.auth.password.enabled {
         return Err(LoginError::PasswordAuthDisabled);
@@ -52,6 +71,10 @@ pub async fn handle_login(
         .await?
         .ok_or(LoginError::UnknownUser)?;
 
+    if user.locked_out_until().is_some() {
+        return Err(LoginError::AccountLocked);
+    }
+
     let password_hash = user
         .password
         .as_deref()
@@ -59,12 +82,148 @@ pub async fn handle_login(
         .ok_or(LoginError::UnknownUser)?;
 
     if bcrypt::verify(&req.password, password_hash)? {
-        Ok(Json(super::login(db, user, user_agent, addr).await?))
+        // failed logins are only cleared once the second factor has been given too, if required
+        Ok(Json(
+            super::totp::login_or_challenge(db, &config, user, user_agent, addr).await?,
+        ))
     } else {
+        let actor = AuditActor {
+            user_id: user.id,
+            ip: Some(addr.0.to_string()),
+            user_agent: user_agent.map(|extract::TypedHeader(v)| v.as_str().to_string()),
+        };
+
+        Arc::new(user)
+            .record_failed_login(db, actor, config.auth.password.lockout.policy())
+            .await?;
+
         Err(LoginError::InvalidPassword)
     }
 }
 
+/// Changes the password of the requesting user, logging out all their other sessions.
+pub async fn handle_change(
+    extract::Extension(db): extract::Extension<ConnectionPool>,
+    extract::Extension(policy): extract::Extension<Arc<PasswordPolicy>>,
+    extract::Extension(user): extract::Extension<Arc<User>>,
+    extract::Extension(session): extract::Extension<Arc<UserSession>>,
+    extract::Extension(actor): extract::Extension<AuditActor>,
+    extract::Json(req): extract::Json<ChangeRequest>,
+) -> Result<Json<ErrorResponse>, ChangeError> {
+    let password_hash = user
+        .password
+        .as_deref()
+        // password is nullable for openid logins
+        .ok_or(ChangeError::NoPassword)?;
+
+    if !bcrypt::verify(&req.current_password, password_hash)? {
+        return Err(ChangeError::InvalidPassword);
+    }
+
+    let user_inputs: Vec<&str> = [Some(user.username.as_str()), user.email.as_deref()]
+        .into_iter()
+        .flatten()
+        .collect();
+    policy.check(&req.new_password, &user_inputs).await?;
+
+    let new_password_hash = bcrypt::hash(&req.new_password, bcrypt::DEFAULT_COST)?;
+    user.change_password(db, actor, new_password_hash, session.id)
+        .await?;
+
+    Ok(Json(ErrorResponse { error: None }))
+}
+
+/// Mails a password reset link to every user that has verified the given email. To avoid giving
+/// away which emails have accounts, this always succeeds regardless of whether any mail was
+/// sent.
+pub async fn handle_forgot(
+    extract::Extension(config): extract::Extension<Arc<Config>>,
+    extract::Extension(db): extract::Extension<ConnectionPool>,
+    extract::Extension(mailer): extract::Extension<Arc<Mailer>>,
+    extract::Json(req): extract::Json<ForgotRequest>,
+) -> Result<Json<ErrorResponse>, ResetError> {
+    if !config.auth.password.enabled {
+        return Err(ResetError::PasswordAuthDisabled);
+    } else if !mailer.is_enabled() {
+        return Err(ResetError::MailDisabled);
+    }
+
+    let email = req.email.trim();
+    let expires = Utc::now() + Duration::hours(RESET_TOKEN_LIFETIME_HOURS);
+
+    for user in User::find_by_verified_email(db.clone(), email.to_string()).await? {
+        let user = Arc::new(user);
+        let (token, token_hash) = super::generate_mailed_token();
+
+        user.clone()
+            .create_password_reset_token(db.clone(), token_hash, expires.naive_utc())
+            .await?;
+
+        let mut link = config.frontend_base_uri.join("auth/reset-password")?;
+        link.query_pairs_mut().append_pair("token", &token);
+
+        let body = format!(
+            "Someone, hopefully you, asked to reset the password for the Chartered account \
+             {}.\n\nYou can choose a new password by following the link below within the next \
+             hour:\n\n{}\n\nIf this wasn't you, you can safely ignore this email.",
+            user.username, link
+        );
+
+        // the user is told this succeeded regardless, so there's nothing more we can do than log
+        if let Err(e) = mailer
+            .send(email, "Reset your Chartered password", body)
+            .await
+        {
+            warn!("Failed to send password reset email: {}", e);
+        }
+    }
+
+    Ok(Json(ErrorResponse { error: None }))
+}
+
+/// Sets a new password for the user the reset link was sent to, logging out all their sessions.
+pub async fn handle_reset(
+    extract::Extension(config): extract::Extension<Arc<Config>>,
+    extract::Extension(db): extract::Extension<ConnectionPool>,
+    extract::Extension(policy): extract::Extension<Arc<PasswordPolicy>>,
+    extract::Json(req): extract::Json<ResetRequest>,
+    user_agent: Option<extract::TypedHeader<headers::UserAgent>>,
+    extract::Extension(addr): extract::Extension<IpAddr>,
+) -> Result<Json<ErrorResponse>, ResetError> {
+    if !config.auth.password.enabled {
+        return Err(ResetError::PasswordAuthDisabled);
+    }
+
+    let token_hash = super::hash_mailed_token(&req.token);
+
+    let user = User::find_by_password_reset_token(db.clone(), token_hash.clone())
+        .await?
+        .ok_or(ResetError::InvalidToken)?;
+
+    let user_inputs: Vec<&str> = [Some(user.username.as_str()), user.email.as_deref()]
+        .into_iter()
+        .flatten()
+        .collect();
+    policy.check(&req.password, &user_inputs).await?;
+
+    let password_hash = bcrypt::hash(&req.password, bcrypt::DEFAULT_COST)?;
+
+    let reset = User::reset_password(
+        db,
+        token_hash,
+        password_hash,
+        Some(addr.to_string()),
+        user_agent.map(|extract::TypedHeader(v)| v.as_str().to_string()),
+    )
+    .await?;
+
+    if reset {
+        Ok(Json(ErrorResponse { error: None }))
+    } else {
+        Err(ResetError::InvalidToken)
+    }
+}
+
 pub fn validate_username(username: &str) -> bool {
     // we use `:` as a splitter for openid logins so it isn't legal during password login
     !username.contains(':')
@@ -84,6 +243,23 @@ pub struct LoginRequest {
     password: String,
 }
 
+#[derive(Deserialize)]
+pub struct ChangeRequest {
+    current_password: String,
+    new_password: String,
+}
+
+#[derive(Deserialize)]
+pub struct ForgotRequest {
+    email: String,
+}
+
+#[derive(Deserialize)]
+pub struct ResetRequest {
+    token: String,
+    password: String,
+}
+
 #[derive(Serialize)]
 pub struct RegisterResponse {
     success: bool,
@@ -101,8 +277,8 @@ pub enum RegisterError {
     UsernameTaken,
     #[error("Password authentication is disabled")]
     PasswordAuthDisabled,
-    #[error("Password must be at least 6 characters long")]
-    PasswordRequirementNotMet,
+    #[error("{0}")]
+    PasswordRequirementNotMet(#[from] crate::password_policy::Error),
 }
 
 impl RegisterError {
@@ -111,9 +287,8 @@ impl RegisterError {
 
         match self {
             Self::Database(_) | Self::Bcrypt(_) => StatusCode::INTERNAL_SERVER_ERROR,
-            Self::InvalidUsername | Self::UsernameTaken | Self::PasswordRequirementNotMet => {
-                StatusCode::BAD_REQUEST
-            }
+            Self::InvalidUsername | Self::UsernameTaken => StatusCode::BAD_REQUEST,
+            Self::PasswordRequirementNotMet(e) => e.status_code(),
             Self::PasswordAuthDisabled => StatusCode::FORBIDDEN,
         }
     }
@@ -127,12 +302,16 @@ pub enum LoginError {
     Database(#[from] chartered_db::Error),
     #[error("Failed to hash password")]
     Bcrypt(#[from] bcrypt::BcryptError),
+    #[error("{0}")]
+    TwoFactor(#[from] super::totp::Error),
     #[error("Invalid username/password")]
     UnknownUser,
     #[error("Invalid username/password")]
     InvalidPassword,
     #[error("Password authentication is disabled")]
     PasswordAuthDisabled,
+    #[error("Too many failed login attempts, please try again later")]
+    AccountLocked,
 }
 
 impl LoginError {
@@ -141,11 +320,77 @@ impl LoginError {
 
         match self {
             Self::Database(_) | Self::Bcrypt(_) => StatusCode::INTERNAL_SERVER_ERROR,
+            Self::TwoFactor(e) => e.status_code(),
             Self::UnknownUser | Self::InvalidPassword | Self::PasswordAuthDisabled => {
                 StatusCode::FORBIDDEN
             }
+            Self::AccountLocked => StatusCode::TOO_MANY_REQUESTS,
         }
     }
 }
 
 define_error_response!(LoginError);
+
+#[derive(Error, Debug)]
+pub enum ChangeError {
+    #[error("{0}")]
+    Database(#[from] chartered_db::Error),
+    #[error("Failed to hash password")]
+    Bcrypt(#[from] bcrypt::BcryptError),
+    #[error("Your account doesn't have a password, it can only be logged into using a provider")]
+    NoPassword,
+    #[error("Current password is incorrect")]
+    InvalidPassword,
+    #[error("{0}")]
+    PasswordRequirementNotMet(#[from] crate::password_policy::Error),
+}
+
+impl ChangeError {
+    pub fn status_code(&self) -> axum::http::StatusCode {
+        use axum::http::StatusCode;
+
+        match self {
+            Self::Database(e) => e.status_code(),
+            Self::Bcrypt(_) => StatusCode::INTERNAL_SERVER_ERROR,
+            Self::NoPassword => StatusCode::BAD_REQUEST,
+            Self::PasswordRequirementNotMet(e) => e.status_code(),
+            Self::InvalidPassword => StatusCode::FORBIDDEN,
+        }
+    }
+}
+
+define_error_response!(ChangeError);
+
+#[derive(Error, Debug)]
+pub enum ResetError {
+    #[error("{0}")]
+    Database(#[from] chartered_db::Error),
+    #[error("Failed to hash password")]
+    Bcrypt(#[from] bcrypt::BcryptError),
+    #[error("Failed to build reset link: {0}")]
+    Url(#[from] url::ParseError),
+    #[error("Password authentication is disabled")]
+    PasswordAuthDisabled,
+    #[error("Password resets are unavailable as email isn't configured on this server")]
+    MailDisabled,
+    #[error("{0}")]
+    PasswordRequirementNotMet(#[from] crate::password_policy::Error),
+    #[error("This reset link is invalid or has expired, please request a new one")]
+    InvalidToken,
+}
+
+impl ResetError {
+    pub fn status_code(&self) -> axum::http::StatusCode {
+        use axum::http::StatusCode;
+
+        match self {
+            Self::Database(e) => e.status_code(),
+            Self::Bcrypt(_) | Self::Url(_) => StatusCode::INTERNAL_SERVER_ERROR,
+            Self::PasswordAuthDisabled | Self::MailDisabled => StatusCode::FORBIDDEN,
+            Self::PasswordRequirementNotMet(e) => e.status_code(),
+            Self::InvalidToken => StatusCode::BAD_REQUEST,
+        }
+    }
+}
+
+define_error_response!(ResetError);
@@ -0,0 +1,446 @@
+//! Time-based one-time password (TOTP) two-factor authentication, as described in RFC 6238.
+//!
+//! Users enrol by generating a secret and confirming they've added it to their authenticator
+//! with a valid code, at which point they're issued a set of single-use recovery codes. After
+//! that, logging in with a password returns a short-lived pre-auth token instead of a session,
+//! which must be exchanged along with a code via `/login/totp` to get an actual session. Each
+//! pre-auth token can only be exchanged once, so a wrong code means logging in again.
+
+use super::{decrypt, encrypt, CipherError};
+use crate::config::Config;
+
+use axum::{extract, Json};
+use chartered_db::{audit::AuditActor, two_factor::UserTotp, users::User, ConnectionPool};
+use chrono::{DateTime, Duration, Utc};
+use hmac::{Hmac, Mac};
+use serde::{Deserialize, Serialize};
+use sha1::Sha1;
+use sha2::{Digest, Sha256};
+use thiserror::Error;
+use url::Url;
+
+use std::{net::IpAddr, sync::Arc};
+
+/// Length of each time step in seconds
+const STEP: u64 = 30;
+/// Amount of digits in each code
+const DIGITS: u32 = 6;
+/// Amount of steps either side of the current one we'll accept codes for, to allow for clock
+/// drift between us and the user's authenticator
+const SKEW: u64 = 1;
+/// Amount of recovery codes given to the user
+const RECOVERY_CODES: usize = 10;
+/// How long the user has to enter their code after entering their password
+const PRE_AUTH_TOKEN_LIFETIME_MINUTES: i64 = 5;
+
+/// Calculates the HOTP value (RFC 4226) for the given counter.
+fn hotp(secret: &[u8], counter: u64) -> u32 {
+    let mut mac = Hmac::<Sha1>::new_from_slice(secret).expect("HMAC accepts keys of any length");
+    mac.update(&counter.to_be_bytes());
+    let hash = mac.finalize().into_bytes();
+
+    // dynamic truncation, the last nibble decides which 4 bytes of the hash make up the code
+    let offset = usize::from(hash[hash.len() - 1] & 0xf);
+    let code = u32::from_be_bytes([
+        hash[offset],
+        hash[offset + 1],
+        hash[offset + 2],
+        hash[offset + 3],
+    ]) & 0x7fff_ffff;
+
+    code % 10_u32.pow(DIGITS)
+}
+
+/// Checks the given code against the steps around `unix_time`, returning the step it was valid
+/// for if any.
+fn verify(secret: &[u8], code: &str, unix_time: u64) -> Option<i64> {
+    let current = unix_time / STEP;
+
+    (current.saturating_sub(SKEW)..=current + SKEW)
+        .find(|step| format!("{:0width$}", hotp(secret, *step), width = DIGITS as usize) == code)
+        .and_then(|step| i64::try_from(step).ok())
+}
+
+/// Normalises a user-entered code, stripping any whitespace and dashes so codes can be
+/// entered however they're displayed.
+fn normalise_code(code: &str) -> String {
+    code.chars()
+        .filter(|c| !c.is_whitespace() && *c != '-')
+        .flat_map(char::to_lowercase)
+        .collect()
+}
+
+fn hash_recovery_code(code: &str) -> String {
+    hex::encode(Sha256::digest(normalise_code(code).as_bytes()))
+}
+
+/// Generates a new set of recovery codes, returning the codes to show to the user along with
+/// their hashes to store.
+fn generate_recovery_codes() -> (Vec<String>, Vec<String>) {
+    (0..RECOVERY_CODES)
+        .map(|_| {
+            let code = hex::encode(rand::random::<[u8; 8]>());
+            let code = format!(
+                "{}-{}-{}-{}",
+                &code[0..4],
+                &code[4..8],
+                &code[8..12],
+                &code[12..16]
+            );
+            let hash = hash_recovery_code(&code);
+            (code, hash)
+        })
+        .unzip()
+}
+
+/// Checks the code given by the user is either a valid TOTP code for their secret, or one of
+/// their unused recovery codes, marking it as used if so.
+async fn check_code(
+    db: ConnectionPool,
+    config: &Config,
+    totp: Arc<UserTotp>,
+    code: &str,
+) -> Result<bool, Error> {
+    let code = normalise_code(code);
+
+    if code.len() == DIGITS as usize && code.chars().all(|c| c.is_ascii_digit()) {
+        let secret = decrypt(&totp.secret, config)?;
+        let now = u64::try_from(Utc::now().timestamp()).unwrap_or_default();
+
+        match verify(&secret, &code, now) {
+            Some(step) => Ok(totp.record_use(db, step).await?),
+            None => Ok(false),
+        }
+    } else {
+        Ok(User::use_recovery_code(db, totp.user_id, hash_recovery_code(&code)).await?)
+    }
+}
+
+/// Grabs the user's TOTP secret, as long as they've actually finished enrolling.
+async fn find_confirmed(db: ConnectionPool, user_id: i32) -> Result<Arc<UserTotp>, Error> {
+    UserTotp::find(db, user_id)
+        .await?
+        .filter(UserTotp::is_confirmed)
+        .map(Arc::new)
+        .ok_or(Error::NotEnabled)
+}
+
+/// Logs the user in if they don't have two-factor authentication enabled, otherwise returns a
+/// pre-auth token they can exchange along with a code for a session using `/login/totp`.
+pub async fn login_or_challenge(
+    db: ConnectionPool,
+    config: &Config,
+    user: User,
+    user_agent: Option<extract::TypedHeader<headers::UserAgent>>,
+    addr: extract::Extension<IpAddr>,
+) -> Result<LoginOrChallengeResponse, Error> {
+    let enabled = UserTotp::find(db.clone(), user.id)
+        .await?
+        .as_ref()
+        .map_or(false, UserTotp::is_confirmed);
+
+    if !enabled {
+        Arc::new(user.clone())
+            .clear_failed_logins(db.clone())
+            .await?;
+
+        return Ok(LoginOrChallengeResponse::Session(
+            super::login(db, user, user_agent, addr).await?,
+        ));
+    }
+
+    let expires = Utc::now() + Duration::minutes(PRE_AUTH_TOKEN_LIFETIME_MINUTES);
+    let (pre_auth_token, pre_auth_token_hash) = super::generate_mailed_token();
+
+    Arc::new(user)
+        .create_pre_auth_token(db, pre_auth_token_hash, expires.naive_utc())
+        .await?;
+
+    Ok(LoginOrChallengeResponse::TwoFactorRequired(
+        TwoFactorChallenge {
+            pre_auth_token,
+            expires,
+        },
+    ))
+}
+
+/// Exchanges a pre-auth token and a code for a session.
+pub async fn handle_login(
+    extract::Extension(config): extract::Extension<Arc<Config>>,
+    extract::Extension(db): extract::Extension<ConnectionPool>,
+    extract::Json(req): extract::Json<LoginRequest>,
+    user_agent: Option<extract::TypedHeader<headers::UserAgent>>,
+    addr: extract::Extension<IpAddr>,
+) -> Result<Json<super::LoginResponse>, Error> {
+    // the token is used up by this attempt whether or not the code is right, so codes can't be
+    // guessed more than once per password
+    let user =
+        User::consume_pre_auth_token(db.clone(), super::hash_mailed_token(&req.pre_auth_token))
+            .await?
+            .ok_or(Error::InvalidPreAuthToken)?;
+
+    // codes count towards the same lockout as passwords, otherwise anyone with the password could
+    // keep guessing codes with a new pre-auth token each time
+    if user.locked_out_until().is_some() {
+        return Err(Error::AccountLocked);
+    }
+
+    let totp = find_confirmed(db.clone(), user.id).await?;
+
+    if !check_code(db.clone(), &config, totp, &req.code).await? {
+        let actor = AuditActor {
+            user_id: user.id,
+            ip: Some(addr.0.to_string()),
+            user_agent: user_agent.map(|extract::TypedHeader(v)| v.as_str().to_string()),
+        };
+
+        Arc::new(user)
+            .record_failed_login(db, actor, config.auth.password.lockout.policy())
+            .await?;
+
+        return Err(Error::InvalidCode);
+    }
+
+    Arc::new(user.clone())
+        .clear_failed_logins(db.clone())
+        .await?;
+
+    Ok(Json(super::login(db, user, user_agent, addr).await?))
+}
+
+/// Returns whether the user has two-factor authentication enabled and how many recovery codes
+/// they have left.
+pub async fn handle_get(
+    extract::Extension(db): extract::Extension<ConnectionPool>,
+    extract::Extension(user): extract::Extension<Arc<User>>,
+) -> Result<Json<StatusResponse>, Error> {
+    let enabled = UserTotp::find(db.clone(), user.id)
+        .await?
+        .as_ref()
+        .map_or(false, UserTotp::is_confirmed);
+
+    let remaining_recovery_codes = if enabled {
+        user.remaining_recovery_codes(db).await?
+    } else {
+        0
+    };
+
+    Ok(Json(StatusResponse {
+        enabled,
+        remaining_recovery_codes,
+    }))
+}
+
+/// Generates a new secret for the user to add to their authenticator, this won't be required
+/// to login until it's been confirmed using `handle_confirm`.
+pub async fn handle_put(
+    extract::Extension(config): extract::Extension<Arc<Config>>,
+    extract::Extension(db): extract::Extension<ConnectionPool>,
+    extract::Extension(user): extract::Extension<Arc<User>>,
+) -> Result<Json<EnrolResponse>, Error> {
+    // RFC 4226 recommends a 160-bit secret
+    let secret = rand::random::<[u8; 20]>();
+    let encoded_secret = base32::encode(base32::Alphabet::RFC4648 { padding: false }, &secret);
+
+    user.clone()
+        .begin_totp_enrolment(db, encrypt(&secret, &config)?)
+        .await?;
+
+    let mut uri = Url::parse("otpauth://totp/").expect("static URL is valid");
+    uri.path_segments_mut()
+        .expect("URL has a host")
+        .pop_if_empty()
+        .push(&format!("Chartered:{}", user.display_name()));
+    uri.query_pairs_mut()
+        .append_pair("secret", &encoded_secret)
+        .append_pair("issuer", "Chartered");
+
+    Ok(Json(EnrolResponse {
+        secret: encoded_secret,
+        uri: uri.to_string(),
+    }))
+}
+
+/// Confirms the user has added the secret to their authenticator, enabling two-factor
+/// authentication on their account and issuing them their recovery codes.
+pub async fn handle_confirm(
+    extract::Extension(config): extract::Extension<Arc<Config>>,
+    extract::Extension(db): extract::Extension<ConnectionPool>,
+    extract::Extension(user): extract::Extension<Arc<User>>,
+    extract::Extension(actor): extract::Extension<AuditActor>,
+    extract::Json(req): extract::Json<CodeRequest>,
+) -> Result<Json<RecoveryCodesResponse>, Error> {
+    let totp = UserTotp::find(db.clone(), user.id)
+        .await?
+        .map(Arc::new)
+        .ok_or(Error::NotEnrolled)?;
+
+    let secret = decrypt(&totp.secret, &config)?;
+    let now = u64::try_from(Utc::now().timestamp()).unwrap_or_default();
+    let step = verify(&secret, &normalise_code(&req.code), now).ok_or(Error::InvalidCode)?;
+
+    let (recovery_codes, hashes) = generate_recovery_codes();
+
+    if totp.confirm(db, actor, step, hashes).await? {
+        Ok(Json(RecoveryCodesResponse { recovery_codes }))
+    } else {
+        Err(Error::InvalidCode)
+    }
+}
+
+/// Turns two-factor authentication off for the user, given a valid code.
+pub async fn handle_delete(
+    extract::Extension(config): extract::Extension<Arc<Config>>,
+    extract::Extension(db): extract::Extension<ConnectionPool>,
+    extract::Extension(user): extract::Extension<Arc<User>>,
+    extract::Extension(actor): extract::Extension<AuditActor>,
+    extract::Json(req): extract::Json<CodeRequest>,
+) -> Result<Json<crate::endpoints::ErrorResponse>, Error> {
+    let totp = find_confirmed(db.clone(), user.id).await?;
+
+    if !check_code(db.clone(), &config, totp, &req.code).await? {
+        return Err(Error::InvalidCode);
+    }
+
+    user.disable_two_factor(db, actor).await?;
+
+    Ok(Json(crate::endpoints::ErrorResponse { error: None }))
+}
+
+/// Replaces the user's recovery codes with a new set, given a valid code.
+pub async fn handle_regenerate_recovery_codes(
+    extract::Extension(config): extract::Extension<Arc<Config>>,
+    extract::Extension(db): extract::Extension<ConnectionPool>,
+    extract::Extension(user): extract::Extension<Arc<User>>,
+    extract::Extension(actor): extract::Extension<AuditActor>,
+    extract::Json(req): extract::Json<CodeRequest>,
+) -> Result<Json<RecoveryCodesResponse>, Error> {
+    let totp = find_confirmed(db.clone(), user.id).await?;
+
+    if !check_code(db.clone(), &config, totp, &req.code).await? {
+        return Err(Error::InvalidCode);
+    }
+
+    let (recovery_codes, hashes) = generate_recovery_codes();
+    user.regenerate_recovery_codes(db, actor, hashes).await?;
+
+    Ok(Json(RecoveryCodesResponse { recovery_codes }))
+}
+
+#[derive(Serialize)]
+#[serde(untagged)]
+pub enum LoginOrChallengeResponse {
+    Session(super::LoginResponse),
+    TwoFactorRequired(TwoFactorChallenge),
+}
+
+#[derive(Serialize)]
+pub struct TwoFactorChallenge {
+    pre_auth_token: String,
+    expires: DateTime<Utc>,
+}
+
+#[derive(Deserialize)]
+pub struct LoginRequest {
+    pre_auth_token: String,
+    code: String,
+}
+
+#[derive(Deserialize)]
+pub struct CodeRequest {
+    code: String,
+}
+
+#[derive(Serialize)]
+pub struct StatusResponse {
+    enabled: bool,
+    remaining_recovery_codes: i64,
+}
+
+#[derive(Serialize)]
+pub struct EnrolResponse {
+    secret: String,
+    uri: String,
+}
+
+#[derive(Serialize)]
+pub struct RecoveryCodesResponse {
+    recovery_codes: Vec<String>,
+}
+
+#[derive(Error, Debug)]
+pub enum Error {
+    #[error("{0}")]
+    Database(#[from] chartered_db::Error),
+    #[error("{0}")]
+    Cipher(#[from] CipherError),
+    #[error("Login attempt has expired, please login again")]
+    InvalidPreAuthToken,
+    #[error("Invalid two-factor authentication code")]
+    InvalidCode,
+    #[error("Two-factor authentication isn't enabled for this account")]
+    NotEnabled,
+    #[error("Two-factor authentication hasn't been set up for this account yet")]
+    NotEnrolled,
+    #[error("Too many failed login attempts, please try again later")]
+    AccountLocked,
+}
+
+impl Error {
+    pub fn status_code(&self) -> axum::http::StatusCode {
+        use axum::http::StatusCode;
+
+        match self {
+            Self::Database(e) => e.status_code(),
+            Self::Cipher(_) => StatusCode::INTERNAL_SERVER_ERROR,
+            Self::InvalidPreAuthToken | Self::InvalidCode => StatusCode::FORBIDDEN,
+            Self::NotEnabled | Self::NotEnrolled => StatusCode::BAD_REQUEST,
+            Self::AccountLocked => StatusCode::TOO_MANY_REQUESTS,
+        }
+    }
+}
+
+define_error_response!(Error);
+
+#[cfg(test)]
+mod test {
+    use super::{generate_recovery_codes, hash_recovery_code, hotp, verify};
+
+    // test vectors from RFC 6238 appendix B, truncated to 6 digits
+    const SECRET: &[u8] = b"12345678901234567890";
+
+    #[test]
+    fn rfc6238_vectors() {
+        for (time, code) in [
+            (59, 287_082),
+            (1_111_111_109, 81_804),
+            (1_111_111_111, 50_471),
+            (1_234_567_890, 5_924),
+            (2_000_000_000, 279_037),
+        ] {
+            assert_eq!(hotp(SECRET, time / 30), code, "time {}", time);
+        }
+    }
+
+    #[test]
+    fn verify_allows_skew() {
+        assert_eq!(verify(SECRET, "081804", 1_111_111_109), Some(37_037_036));
+        assert_eq!(
+            verify(SECRET, "081804", 1_111_111_109 + 30),
+            Some(37_037_036)
+        );
+        assert_eq!(verify(SECRET, "081804", 1_111_111_109 + 60), None);
+        assert_eq!(verify(SECRET, "81804", 1_111_111_109), None);
+    }
+
+    #[test]
+    fn recovery_codes_are_normalised() {
+        let (codes, hashes) = generate_recovery_codes();
+        assert_eq!(codes.len(), hashes.len());
+
+        let code = &codes[0];
+        assert_eq!(hash_recovery_code(code), hashes[0]);
+        assert_eq!(hash_recovery_code(&code.to_uppercase()), hashes[0]);
+        assert_eq!(hash_recovery_code(&code.replace('-', " ")), hashes[0]);
+    }
+}
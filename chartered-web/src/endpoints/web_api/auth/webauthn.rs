@@ -0,0 +1,500 @@
+//! WebAuthn (passkey) registration and login.
+//!
+//! Both ceremonies are split into a `begin` and a `complete` request. The state of a login is
+//! stored against a random ceremony id that's handed to the frontend, and deleted as soon as the
+//! login is completed so its challenge can't be replayed. Registrations can only be started by a
+//! logged in user, so their state is encrypted and handed to the frontend to send back to us in
+//! the same way as the OIDC state instead. Registered credentials are stored alongside the user's
+//! SSH keys.
+
+use super::{decrypt_url_safe, encrypt_url_safe, CipherError};
+use crate::{config::Config, endpoints::ErrorResponse};
+
+use axum::{extract, Json};
+use chartered_db::{
+    audit::AuditActor,
+    users::{User, UserWebauthnCredential},
+    uuid::Uuid,
+    ConnectionPool,
+};
+use chrono::{DateTime, Duration, TimeZone, Utc};
+use hmac::{Hmac, Mac};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use sha2::Sha256;
+use thiserror::Error;
+use tracing::warn;
+use url::Url;
+use webauthn_rs::prelude::{
+    Base64UrlSafeData, CreationChallengeResponse, Passkey, PasskeyAuthentication,
+    PasskeyRegistration, PublicKeyCredential, RegisterPublicKeyCredential,
+    RequestChallengeResponse, Webauthn, WebauthnBuilder, WebauthnError,
+};
+
+use std::{net::IpAddr, sync::Arc};
+
+/// How long the user has to complete a ceremony after beginning it
+const CEREMONY_LIFETIME_MINUTES: i64 = 5;
+
+/// Builds a relying party for the given ID, accepting credentials created by `origin`.
+fn build_webauthn(rp_id: &str, origin: &Url) -> Result<Webauthn, WebauthnError> {
+    WebauthnBuilder::new(rp_id, origin)?
+        .rp_name("Chartered")
+        .build()
+}
+
+/// Builds a relying party from the config, as long as WebAuthn is enabled.
+fn webauthn_from_config(config: &Config) -> Result<Webauthn, Error> {
+    let webauthn_config = config
+        .auth
+        .webauthn
+        .as_ref()
+        .filter(|v| v.enabled)
+        .ok_or(Error::WebauthnDisabled)?;
+
+    let rp_id = match &webauthn_config.rp_id {
+        Some(rp_id) => rp_id.as_str(),
+        None => config
+            .frontend_base_uri
+            .host_str()
+            .ok_or(Error::MissingRelyingPartyId)?,
+    };
+
+    Ok(build_webauthn(rp_id, &config.frontend_base_uri)?)
+}
+
+/// Encrypts the state of a ceremony so it can be handed to the frontend.
+fn seal<T: Serialize>(user_uuid: Uuid, state: T, config: &Config) -> Result<String, Error> {
+    let expires = Utc::now() + Duration::minutes(CEREMONY_LIFETIME_MINUTES);
+
+    Ok(encrypt_url_safe(
+        &serde_json::to_vec(&CeremonyState {
+            user_uuid,
+            expires: expires.timestamp(),
+            state,
+        })?,
+        config,
+    )?)
+}
+
+/// Decrypts the state of a ceremony returned by the frontend, as long as it hasn't expired.
+fn unseal<T: DeserializeOwned>(input: &str, config: &Config) -> Result<(Uuid, T), Error> {
+    let decrypted = decrypt_url_safe(input, config).map_err(|_| Error::InvalidState)?;
+    let state: CeremonyState<T> =
+        serde_json::from_slice(&decrypted).map_err(|_| Error::InvalidState)?;
+
+    if state.expires < Utc::now().timestamp() {
+        return Err(Error::InvalidState);
+    }
+
+    Ok((state.user_uuid, state.state))
+}
+
+/// Parses all the stored credentials for the user, skipping over any we can't make sense of.
+async fn load_passkeys(
+    db: ConnectionPool,
+    user: Arc<User>,
+) -> Result<Vec<(Arc<UserWebauthnCredential>, Passkey)>, Error> {
+    Ok(user
+        .list_webauthn_credentials(db)
+        .await?
+        .into_iter()
+        .filter_map(
+            |credential| match serde_json::from_str(&credential.credential) {
+                Ok(passkey) => Some((Arc::new(credential), passkey)),
+                Err(e) => {
+                    warn!(
+                        "Failed to parse webauthn credential with id {}: {}",
+                        credential.id, e
+                    );
+                    None
+                }
+            },
+        )
+        .collect())
+}
+
+/// Lists all the credentials the user has registered.
+pub async fn handle_list(
+    extract::Extension(db): extract::Extension<ConnectionPool>,
+    extract::Extension(user): extract::Extension<Arc<User>>,
+) -> Result<Json<ListResponse>, Error> {
+    let credentials = user
+        .list_webauthn_credentials(db)
+        .await?
+        .into_iter()
+        .map(|credential| ListResponseCredential {
+            uuid: credential.uuid.0,
+            name: credential.name,
+            created_at: Utc.from_local_datetime(&credential.created_at).unwrap(),
+            last_used_at: credential
+                .last_used_at
+                .and_then(|v| Utc.from_local_datetime(&v).single()),
+        })
+        .collect();
+
+    Ok(Json(ListResponse { credentials }))
+}
+
+/// Starts registering a new credential for the user, returning the options the frontend should
+/// pass to `navigator.credentials.create`.
+pub async fn handle_register_begin(
+    extract::Extension(config): extract::Extension<Arc<Config>>,
+    extract::Extension(db): extract::Extension<ConnectionPool>,
+    extract::Extension(user): extract::Extension<Arc<User>>,
+    extract::Json(req): extract::Json<RegisterBeginRequest>,
+) -> Result<Json<RegisterBeginResponse>, Error> {
+    let webauthn = webauthn_from_config(&config)?;
+
+    // stops the user from registering the same authenticator twice
+    let existing = load_passkeys(db, user.clone())
+        .await?
+        .into_iter()
+        .map(|(_, passkey)| passkey.cred_id().clone())
+        .collect();
+
+    let (options, registration) = webauthn.start_passkey_registration(
+        user.uuid.0,
+        &user.username,
+        user.display_name(),
+        Some(existing),
+    )?;
+
+    let name = req.name.trim();
+    let state = RegistrationState {
+        name: if name.is_empty() { "Passkey" } else { name }.to_string(),
+        registration,
+    };
+
+    Ok(Json(RegisterBeginResponse {
+        options,
+        state: seal(user.uuid.0, state, &config)?,
+    }))
+}
+
+/// Verifies the credential created by the user's authenticator and stores it against their
+/// account.
+pub async fn handle_register_complete(
+    extract::Extension(config): extract::Extension<Arc<Config>>,
+    extract::Extension(db): extract::Extension<ConnectionPool>,
+    extract::Extension(user): extract::Extension<Arc<User>>,
+    extract::Extension(actor): extract::Extension<AuditActor>,
+    extract::Json(req): extract::Json<RegisterCompleteRequest>,
+) -> Result<Json<ErrorResponse>, Error> {
+    let webauthn = webauthn_from_config(&config)?;
+
+    let (user_uuid, state): (_, RegistrationState) = unseal(&req.state, &config)?;
+    if user_uuid != user.uuid.0 {
+        return Err(Error::InvalidState);
+    }
+
+    let passkey = webauthn.finish_passkey_registration(&req.credential, &state.registration)?;
+
+    user.insert_webauthn_credential(
+        db,
+        actor,
+        state.name,
+        passkey.cred_id().0.clone(),
+        serde_json::to_string(&passkey)?,
+    )
+    .await?;
+
+    Ok(Json(ErrorResponse { error: None }))
+}
+
+pub async fn handle_delete(
+    extract::Extension(db): extract::Extension<ConnectionPool>,
+    extract::Extension(user): extract::Extension<Arc<User>>,
+    extract::Extension(actor): extract::Extension<AuditActor>,
+    extract::Path(credential_uuid): extract::Path<Uuid>,
+) -> Result<Json<ErrorResponse>, Error> {
+    let deleted = user
+        .delete_webauthn_credential_by_uuid(db, actor, credential_uuid)
+        .await?;
+
+    if deleted {
+        Ok(Json(ErrorResponse { error: None }))
+    } else {
+        Err(Error::NonExistentCredential)
+    }
+}
+
+/// Builds the options for a login that can never succeed, for usernames that don't exist or
+/// haven't registered any passkeys. The options are built from the most recently registered
+/// passkey with its credential ID swapped out for one derived from the username, so they look
+/// the same as a real login's and are the same each time for the same username. If nobody has
+/// registered a passkey there's nothing to give away, so the login is turned away outright.
+async fn decoy_login(
+    webauthn: &Webauthn,
+    db: ConnectionPool,
+    config: &Config,
+    username: &str,
+) -> Result<LoginBeginResponse, Error> {
+    let template: Passkey = match UserWebauthnCredential::find_latest(db).await? {
+        Some(credential) => serde_json::from_str(&credential.credential)?,
+        None => return Err(Error::UnknownUser),
+    };
+
+    let mut mac = Hmac::<Sha256>::new_from_slice(config.encryption_key.as_slice())
+        .expect("HMAC accepts keys of any length");
+    mac.update(username.as_bytes());
+    let mut credential_id = mac.finalize().into_bytes().to_vec();
+    credential_id.truncate(template.cred_id().0.len());
+
+    let (mut options, _) = webauthn.start_passkey_authentication(&[template])?;
+    for credential in &mut options.public_key.allow_credentials {
+        credential.id = Base64UrlSafeData(credential_id.clone());
+    }
+
+    Ok(LoginBeginResponse {
+        options,
+        // never stored, so the login can't be completed
+        state: super::generate_mailed_token().0,
+    })
+}
+
+/// Starts a login for the given user, returning the options the frontend should pass to
+/// `navigator.credentials.get`. The response is the same whether or not the user exists or has
+/// any passkeys, so it can't be used to find out either.
+pub async fn handle_login_begin(
+    extract::Extension(config): extract::Extension<Arc<Config>>,
+    extract::Extension(db): extract::Extension<ConnectionPool>,
+    extract::Json(req): extract::Json<LoginBeginRequest>,
+) -> Result<Json<LoginBeginResponse>, Error> {
+    let webauthn = webauthn_from_config(&config)?;
+
+    let Some(user) = User::find_by_username(db.clone(), req.username.clone())
+        .await?
+        .map(Arc::new)
+    else {
+        return Ok(Json(
+            decoy_login(&webauthn, db, &config, &req.username).await?,
+        ));
+    };
+
+    let passkeys: Vec<_> = load_passkeys(db.clone(), user.clone())
+        .await?
+        .into_iter()
+        .map(|(_, passkey)| passkey)
+        .collect();
+
+    if passkeys.is_empty() {
+        return Ok(Json(
+            decoy_login(&webauthn, db, &config, &req.username).await?,
+        ));
+    }
+
+    let (options, authentication) = webauthn.start_passkey_authentication(&passkeys)?;
+
+    let (ceremony_id, ceremony_id_hash) = super::generate_mailed_token();
+    let expires = Utc::now() + Duration::minutes(CEREMONY_LIFETIME_MINUTES);
+
+    user.create_webauthn_login(
+        db,
+        ceremony_id_hash,
+        serde_json::to_string(&authentication)?,
+        expires.naive_utc(),
+    )
+    .await?;
+
+    Ok(Json(LoginBeginResponse {
+        options,
+        state: ceremony_id,
+    }))
+}
+
+/// Verifies the assertion made by the user's authenticator, logging them in if it's valid.
+pub async fn handle_login_complete(
+    extract::Extension(config): extract::Extension<Arc<Config>>,
+    extract::Extension(db): extract::Extension<ConnectionPool>,
+    extract::Json(req): extract::Json<LoginCompleteRequest>,
+    user_agent: Option<extract::TypedHeader<headers::UserAgent>>,
+    addr: extract::Extension<IpAddr>,
+) -> Result<Json<super::LoginResponse>, Error> {
+    let webauthn = webauthn_from_config(&config)?;
+
+    // taking the ceremony deletes it, so each challenge can only be answered once
+    let (user, authentication) =
+        User::take_webauthn_login(db.clone(), super::hash_mailed_token(&req.state))
+            .await?
+            .ok_or(Error::InvalidState)?;
+    let authentication: PasskeyAuthentication = serde_json::from_str(&authentication)?;
+
+    let result = webauthn.finish_passkey_authentication(&req.credential, &authentication)?;
+
+    // the credential may have been deleted since the login began
+    let (credential, mut passkey) = load_passkeys(db.clone(), Arc::new(user.clone()))
+        .await?
+        .into_iter()
+        .find(|(_, passkey)| passkey.cred_id() == result.cred_id())
+        .ok_or(Error::UnknownUser)?;
+
+    // bumps the signature counter, so we can detect cloned authenticators next time
+    let updated = match passkey.update_credential(&result) {
+        Some(true) => Some(serde_json::to_string(&passkey)?),
+        _ => None,
+    };
+    credential.update_last_used(db.clone(), updated).await?;
+
+    Ok(Json(super::login(db, user, user_agent, addr).await?))
+}
+
+#[derive(Serialize, Deserialize)]
+struct CeremonyState<T> {
+    user_uuid: Uuid,
+    expires: i64,
+    state: T,
+}
+
+#[derive(Serialize, Deserialize)]
+struct RegistrationState {
+    name: String,
+    registration: PasskeyRegistration,
+}
+
+#[derive(Serialize)]
+pub struct ListResponse {
+    credentials: Vec<ListResponseCredential>,
+}
+
+#[derive(Serialize)]
+pub struct ListResponseCredential {
+    uuid: Uuid,
+    name: String,
+    created_at: DateTime<Utc>,
+    last_used_at: Option<DateTime<Utc>>,
+}
+
+#[derive(Deserialize)]
+pub struct RegisterBeginRequest {
+    #[serde(default)]
+    name: String,
+}
+
+#[derive(Serialize)]
+pub struct RegisterBeginResponse {
+    options: CreationChallengeResponse,
+    state: String,
+}
+
+#[derive(Deserialize)]
+pub struct RegisterCompleteRequest {
+    state: String,
+    credential: RegisterPublicKeyCredential,
+}
+
+#[derive(Deserialize)]
+pub struct LoginBeginRequest {
+    username: String,
+}
+
+#[derive(Serialize)]
+pub struct LoginBeginResponse {
+    options: RequestChallengeResponse,
+    state: String,
+}
+
+#[derive(Deserialize)]
+pub struct LoginCompleteRequest {
+    state: String,
+    credential: PublicKeyCredential,
+}
+
+#[derive(Error, Debug)]
+pub enum Error {
+    #[error("{0}")]
+    Database(#[from] chartered_db::Error),
+    #[error("{0}")]
+    Cipher(#[from] CipherError),
+    #[error("Error serialising credential: {0}")]
+    Serde(#[from] serde_json::Error),
+    #[error("Failed to verify credential: {0}")]
+    Webauthn(#[from] WebauthnError),
+    #[error("WebAuthn authentication is disabled")]
+    WebauthnDisabled,
+    #[error("Couldn't determine relying party ID from frontend_base_uri")]
+    MissingRelyingPartyId,
+    #[error("Request has expired, please try again")]
+    InvalidState,
+    #[error("Invalid username/passkey")]
+    UnknownUser,
+    #[error("The credential given does not exist")]
+    NonExistentCredential,
+}
+
+impl Error {
+    pub fn status_code(&self) -> axum::http::StatusCode {
+        use axum::http::StatusCode;
+
+        match self {
+            Self::Database(e) => e.status_code(),
+            Self::Cipher(_) | Self::Serde(_) | Self::MissingRelyingPartyId => {
+                StatusCode::INTERNAL_SERVER_ERROR
+            }
+            Self::Webauthn(_) | Self::InvalidState | Self::NonExistentCredential => {
+                StatusCode::BAD_REQUEST
+            }
+            Self::WebauthnDisabled | Self::UnknownUser => StatusCode::FORBIDDEN,
+        }
+    }
+}
+
+define_error_response!(Error);
+
+#[cfg(test)]
+mod test {
+    use super::build_webauthn;
+    use url::Url;
+    use webauthn_authenticator_rs::{softpasskey::SoftPasskey, WebauthnAuthenticator};
+    use webauthn_rs::prelude::{Passkey, PasskeyAuthentication, PasskeyRegistration, Uuid};
+
+    #[test]
+    fn software_authenticator_ceremonies() {
+        let origin = Url::parse("https://chartered.example.com/").unwrap();
+        let webauthn = build_webauthn("chartered.example.com", &origin).unwrap();
+        let mut authenticator = WebauthnAuthenticator::new(SoftPasskey::new());
+
+        let (options, registration) = webauthn
+            .start_passkey_registration(Uuid::new_v4(), "jordan", "Jordan", None)
+            .unwrap();
+
+        // the registration state makes a round trip through the frontend between requests
+        let registration: PasskeyRegistration =
+            serde_json::from_str(&serde_json::to_string(&registration).unwrap()).unwrap();
+
+        let credential = authenticator
+            .do_registration(origin.clone(), options)
+            .unwrap();
+        let passkey = webauthn
+            .finish_passkey_registration(&credential, &registration)
+            .unwrap();
+
+        // as does the credential through the database
+        let mut passkey: Passkey =
+            serde_json::from_str(&serde_json::to_string(&passkey).unwrap()).unwrap();
+
+        let (options, authentication) = webauthn
+            .start_passkey_authentication(&[passkey.clone()])
+            .unwrap();
+
+        // the authentication state is stored in the database between requests
+        let authentication: PasskeyAuthentication =
+            serde_json::from_str(&serde_json::to_string(&authentication).unwrap()).unwrap();
+        let assertion = authenticator
+            .do_authentication(origin.clone(), options)
+            .unwrap();
+        let result = webauthn
+            .finish_passkey_authentication(&assertion, &authentication)
+            .unwrap();
+
+        assert_eq!(result.cred_id(), passkey.cred_id());
+        assert!(passkey.update_credential(&result).is_some());
+
+        // an assertion for a different origin must be rejected
+        let other_origin = Url::parse("https://evil.example.com/").unwrap();
+        let (options, authentication) = webauthn.start_passkey_authentication(&[passkey]).unwrap();
+        let assertion = authenticator.do_authentication(other_origin, options);
+        assert!(assertion.map_or(true, |assertion| webauthn
+            .finish_passkey_authentication(&assertion, &authentication)
+            .is_err()));
+    }
+}
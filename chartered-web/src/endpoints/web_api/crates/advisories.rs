@@ -0,0 +1,172 @@
+//! Manages internal advisories for a crate, flagging versions as vulnerable or deprecated with a
+//! message explaining why. Yanking a version alone doesn't explain to consumers why their build
+//! suddenly started warning.
+//!
+//! Anyone that can see the crate can list its advisories, but raising or withdrawing them
+//! requires the `YANK_VERSION` permission.
+
+use crate::endpoints::ErrorResponse;
+use axum::{extract, Json};
+use chartered_db::{
+    advisories::{CrateAdvisory, CrateAdvisoryKind},
+    audit::AuditActor,
+    crates::Crate,
+    users::User,
+    uuid::Uuid,
+    ConnectionPool,
+};
+use chrono::{DateTime, TimeZone, Utc};
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+use thiserror::Error;
+
+pub async fn handle_get(
+    extract::Path((organisation, name)): extract::Path<(String, String)>,
+    extract::Extension(db): extract::Extension<ConnectionPool>,
+    extract::Extension(user): extract::Extension<Arc<User>>,
+) -> Result<Json<GetResponse>, Error> {
+    let crate_with_permissions =
+        Arc::new(Crate::find_by_name(db.clone(), user.id, organisation, name).await?);
+
+    let advisories = crate_with_permissions
+        .advisories(db)
+        .await?
+        .into_iter()
+        .map(|(advisory, user)| GetResponseAdvisory {
+            created_by: GetResponseAdvisoryUser {
+                uuid: user.uuid.0,
+                display_name: user.display_name().to_string(),
+                picture_url: user.picture_url,
+            },
+            withdrawn_at: advisory
+                .withdrawn_at
+                .and_then(|v| Utc.from_local_datetime(&v).single()),
+            inner: (&advisory).into(),
+        })
+        .collect();
+
+    Ok(Json(GetResponse { advisories }))
+}
+
+pub async fn handle_put(
+    extract::Path((organisation, name)): extract::Path<(String, String)>,
+    extract::Extension(db): extract::Extension<ConnectionPool>,
+    extract::Extension(user): extract::Extension<Arc<User>>,
+    extract::Extension(actor): extract::Extension<AuditActor>,
+    extract::Json(req): extract::Json<PutRequest>,
+) -> Result<Json<ResponseAdvisory>, Error> {
+    let crate_with_permissions =
+        Arc::new(Crate::find_by_name(db.clone(), user.id, organisation, name).await?);
+
+    let advisory = crate_with_permissions
+        .create_advisory(db, actor, req.kind, req.affected_versions, req.message)
+        .await?;
+
+    Ok(Json((&advisory).into()))
+}
+
+pub async fn handle_delete(
+    extract::Path((organisation, name, uuid)): extract::Path<(String, String, Uuid)>,
+    extract::Extension(db): extract::Extension<ConnectionPool>,
+    extract::Extension(user): extract::Extension<Arc<User>>,
+    extract::Extension(actor): extract::Extension<AuditActor>,
+) -> Result<Json<ErrorResponse>, Error> {
+    let crate_with_permissions =
+        Arc::new(Crate::find_by_name(db.clone(), user.id, organisation, name).await?);
+
+    if crate_with_permissions
+        .withdraw_advisory(db, actor, uuid)
+        .await?
+    {
+        Ok(Json(ErrorResponse { error: None }))
+    } else {
+        Err(Error::UnknownAdvisory)
+    }
+}
+
+#[derive(Deserialize)]
+pub struct PutRequest {
+    kind: CrateAdvisoryKind,
+    affected_versions: String,
+    message: String,
+}
+
+#[derive(Serialize)]
+pub struct GetResponse {
+    advisories: Vec<GetResponseAdvisory>,
+}
+
+#[derive(Serialize)]
+pub struct GetResponseAdvisory {
+    #[serde(flatten)]
+    inner: ResponseAdvisory,
+    created_by: GetResponseAdvisoryUser,
+    withdrawn_at: Option<DateTime<Utc>>,
+}
+
+#[derive(Serialize)]
+pub struct GetResponseAdvisoryUser {
+    uuid: Uuid,
+    display_name: String,
+    picture_url: Option<String>,
+}
+
+/// An active advisory, this is also returned alongside versions by the crate info and search
+/// endpoints.
+#[derive(Serialize)]
+pub struct ResponseAdvisory {
+    uuid: Uuid,
+    kind: CrateAdvisoryKind,
+    affected_versions: String,
+    message: String,
+    created_at: DateTime<Utc>,
+}
+
+impl From<&CrateAdvisory> for ResponseAdvisory {
+    fn from(advisory: &CrateAdvisory) -> Self {
+        Self {
+            uuid: advisory.uuid.0,
+            kind: advisory.kind,
+            affected_versions: advisory.affected_versions.clone(),
+            message: advisory.message.clone(),
+            created_at: Utc.from_local_datetime(&advisory.created_at).unwrap(),
+        }
+    }
+}
+
+#[derive(Error, Debug)]
+pub enum Error {
+    #[error("{0}")]
+    Database(#[from] chartered_db::Error),
+    #[error("The advisory given does not exist or has already been withdrawn")]
+    UnknownAdvisory,
+}
+
+impl Error {
+    pub fn status_code(&self) -> axum::http::StatusCode {
+        use axum::http::StatusCode;
+
+        match self {
+            Self::Database(e) => e.status_code(),
+            Self::UnknownAdvisory => StatusCode::NOT_FOUND,
+        }
+    }
+}
+
+define_error_response!(Error);
+
+#[cfg(test)]
+mod test {
+    use super::Error;
+    use axum::http::StatusCode;
+
+    #[test]
+    fn status_codes() {
+        // withdrawing an advisory that's already been withdrawn
+        assert_eq!(Error::UnknownAdvisory.status_code(), StatusCode::NOT_FOUND);
+        assert_eq!(
+            Error::Database(chartered_db::Error::MissingAdvisoryMessage).status_code(),
+            StatusCode::BAD_REQUEST
+        );
+    }
+}
@@ -0,0 +1,65 @@
+//! Lists all the crates in the registry that depend on the given crate, and the requirement
+//! each of their versions depends on it with. This is useful to figure out who will be affected
+//! before making a breaking change.
+//!
+//! Only crates that the requesting user can see are returned.
+
+use axum::{extract, Json};
+use chartered_db::{crates::Crate, users::User, ConnectionPool};
+use serde::Serialize;
+use std::sync::Arc;
+use thiserror::Error;
+
+pub async fn handle(
+    extract::Path((organisation, name)): extract::Path<(String, String)>,
+    extract::Extension(db): extract::Extension<ConnectionPool>,
+    extract::Extension(user): extract::Extension<Arc<User>>,
+) -> Result<Json<Response>, Error> {
+    let crate_with_permissions =
+        Arc::new(Crate::find_by_name(db.clone(), user.id, organisation, name).await?);
+
+    let dependents = crate_with_permissions
+        .dependents(db, user.id)
+        .await?
+        .into_iter()
+        .map(|v| ResponseDependent {
+            organisation: v.organisation,
+            name: v.name,
+            version: v.version,
+            req: v.req,
+            kind: v.kind,
+        })
+        .collect();
+
+    Ok(Json(Response { dependents }))
+}
+
+#[derive(Serialize)]
+pub struct Response {
+    dependents: Vec<ResponseDependent>,
+}
+
+#[derive(Serialize)]
+pub struct ResponseDependent {
+    organisation: String,
+    name: String,
+    version: String,
+    req: String,
+    kind: String,
+}
+
+#[derive(Error, Debug)]
+pub enum Error {
+    #[error("{0}")]
+    Database(#[from] chartered_db::Error),
+}
+
+impl Error {
+    pub fn status_code(&self) -> axum::http::StatusCode {
+        match self {
+            Self::Database(e) => e.status_code(),
+        }
+    }
+}
+
+define_error_response!(Error);
@@ -4,7 +4,12 @@
 //!
 //! Unlike crates.io, we're only keeping the _latest_ README pushed to the crate, so there's no
 //! need to have version-specific info responses - we'll just send an overview of each one.
+//!
+//! Any advisories from the configured advisory database that affect a version are also returned
+//! alongside it, as are any active advisories raised internally against the crate.
 
+use super::advisories::ResponseAdvisory as ResponseInternalAdvisory;
+use crate::advisories::{Advisory, AdvisoryDatabase};
 use axum::{extract, response::IntoResponse, Json};
 use chartered_db::{crates::Crate, permissions::UserPermission, users::User, ConnectionPool};
 use chartered_types::cargo::CrateVersion;
@@ -17,21 +22,34 @@ pub async fn handle(
     extract::Path((organisation, name)): extract::Path<(String, String)>,
     extract::Extension(db): extract::Extension<ConnectionPool>,
     extract::Extension(user): extract::Extension<Arc<User>>,
+    extract::Extension(advisory_db): extract::Extension<Arc<AdvisoryDatabase>>,
 ) -> Result<axum::response::Response, Error> {
     let crate_with_permissions =
         Arc::new(Crate::find_by_name(db.clone(), user.id, organisation, name).await?);
 
     // grab all versions of this crate and the person who uploaded them
-    let versions = crate_with_permissions
-        .clone()
-        .versions_with_uploader(db)
-        .await?;
+    let (versions, internal_advisories) = tokio::try_join!(
+        crate_with_permissions
+            .clone()
+            .versions_with_uploader(db.clone()),
+        crate_with_permissions.clone().active_advisories(db),
+    )?;
 
     Ok(Json(Response {
         info: (&crate_with_permissions.crate_).into(),
         versions: versions
             .into_iter()
             .map(|(v, user)| ResponseVersion {
+                advisories: advisory_db
+                    .for_version(&crate_with_permissions.crate_.name, &v.version)
+                    .into_iter()
+                    .map(ResponseAdvisory::from)
+                    .collect(),
+                internal_advisories: internal_advisories
+                    .iter()
+                    .filter(|advisory| advisory.affects(&v.version))
+                    .map(ResponseInternalAdvisory::from)
+                    .collect(),
                 size: v.size,
                 created_at: chrono::Utc.from_local_datetime(&v.created_at).unwrap(),
                 inner: v.into_cargo_format(&crate_with_permissions.crate_),
@@ -67,6 +85,40 @@ pub struct ResponseVersion<'a> {
     size: i32,
     created_at: chrono::DateTime<chrono::Utc>,
     uploader: ResponseVersionUploader,
+    advisories: Vec<ResponseAdvisory<'a>>,
+    internal_advisories: Vec<ResponseInternalAdvisory>,
+}
+
+#[derive(Serialize)]
+pub struct ResponseAdvisory<'a> {
+    id: &'a str,
+    title: &'a str,
+    description: &'a str,
+    date: &'a str,
+    url: Option<&'a str>,
+    aliases: &'a [String],
+    informational: Option<&'a str>,
+    patched: Vec<String>,
+}
+
+impl<'a> From<&'a Advisory> for ResponseAdvisory<'a> {
+    fn from(advisory: &'a Advisory) -> Self {
+        Self {
+            id: &advisory.metadata.id,
+            title: &advisory.metadata.title,
+            description: &advisory.metadata.description,
+            date: &advisory.metadata.date,
+            url: advisory.metadata.url.as_deref(),
+            aliases: &advisory.metadata.aliases,
+            informational: advisory.metadata.informational.as_deref(),
+            patched: advisory
+                .versions
+                .patched
+                .iter()
+                .map(ToString::to_string)
+                .collect(),
+        }
+    }
 }
 
 #[derive(Serialize)]
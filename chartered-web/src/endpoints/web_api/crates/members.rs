@@ -4,7 +4,8 @@
 
 use axum::{extract, Json};
 use chartered_db::{
-    crates::Crate, permissions::UserPermission, users::User, uuid::Uuid, ConnectionPool,
+    audit::AuditActor, crates::Crate, permissions::UserPermission, users::User, uuid::Uuid,
+    ConnectionPool,
 };
 use serde::{Deserialize, Serialize};
 use std::sync::Arc;
@@ -47,6 +48,7 @@ pub async fn handle_patch(
     extract::Path((organisation, name)): extract::Path<(String, String)>,
     extract::Extension(db): extract::Extension<ConnectionPool>,
     extract::Extension(user): extract::Extension<Arc<User>>,
+    extract::Extension(actor): extract::Extension<AuditActor>,
     extract::Json(req): extract::Json<PutOrPatchRequest>,
 ) -> Result<Json<ErrorResponse>, Error> {
     let crate_with_permissions =
@@ -57,7 +59,7 @@ pub async fn handle_patch(
         .ok_or(Error::InvalidUserId)?;
 
     let affected_rows = crate_with_permissions
-        .update_permissions(db, action_user.id, req.permissions)
+        .update_permissions(db, actor, action_user.id, req.permissions)
         .await?;
     if affected_rows == 0 {
         return Err(Error::UpdateConflictRemoved);
@@ -71,6 +73,7 @@ pub async fn handle_put(
     extract::Path((organisation, name)): extract::Path<(String, String)>,
     extract::Extension(db): extract::Extension<ConnectionPool>,
     extract::Extension(user): extract::Extension<Arc<User>>,
+    extract::Extension(actor): extract::Extension<AuditActor>,
     extract::Json(req): extract::Json<PutOrPatchRequest>,
 ) -> Result<Json<ErrorResponse>, Error> {
     let crate_with_permissions =
@@ -81,7 +84,7 @@ pub async fn handle_put(
         .ok_or(Error::InvalidUserId)?;
 
     crate_with_permissions
-        .insert_permissions(db, action_user.id, req.permissions)
+        .insert_permissions(db, actor, action_user.id, req.permissions)
         .await?;
 
     Ok(Json(ErrorResponse { error: None }))
@@ -92,6 +95,7 @@ pub async fn handle_delete(
     extract::Path((organisation, name)): extract::Path<(String, String)>,
     extract::Extension(db): extract::Extension<ConnectionPool>,
     extract::Extension(user): extract::Extension<Arc<User>>,
+    extract::Extension(actor): extract::Extension<AuditActor>,
     extract::Json(req): extract::Json<DeleteRequest>,
 ) -> Result<Json<ErrorResponse>, Error> {
     let crate_with_permissions =
@@ -102,7 +106,7 @@ pub async fn handle_delete(
         .ok_or(Error::InvalidUserId)?;
 
     crate_with_permissions
-        .delete_member(db, action_user.id)
+        .delete_member(db, actor, action_user.id)
         .await?;
 
     Ok(Json(ErrorResponse { error: None }))
@@ -1,13 +1,21 @@
+mod advisories;
+mod dependents;
 mod info;
 mod members;
 mod most_downloaded;
 mod recently_created;
 mod recently_updated;
 mod search;
+mod teams;
+mod transfer;
+mod yanks;
 
 use crate::middleware::rate_limit::RateLimit;
 use axum::handler::Handler;
-use axum::{routing::get, Router};
+use axum::{
+    routing::{delete, get, post},
+    Router,
+};
 
 pub fn routes(rate_limit: &RateLimit) -> Router {
     Router::new()
@@ -15,6 +23,19 @@ pub fn routes(rate_limit: &RateLimit) -> Router {
             "/:org/:crate",
             get(info::handle.layer(rate_limit.with_cost(1))),
         )
+        .route(
+            "/:org/:crate/advisories",
+            get(advisories::handle_get.layer(rate_limit.with_cost(1)))
+                .put(advisories::handle_put.layer(rate_limit.with_cost(10))),
+        )
+        .route(
+            "/:org/:crate/advisories/:uuid",
+            delete(advisories::handle_delete.layer(rate_limit.with_cost(10))),
+        )
+        .route(
+            "/:org/:crate/dependents",
+            get(dependents::handle.layer(rate_limit.with_cost(5))),
+        )
         .route(
             "/:org/:crate/members",
             get(members::handle_get.layer(rate_limit.with_cost(1)))
@@ -22,6 +43,22 @@ pub fn routes(rate_limit: &RateLimit) -> Router {
                 .put(members::handle_put.layer(rate_limit.with_cost(10)))
                 .delete(members::handle_delete.layer(rate_limit.with_cost(10))),
         )
+        .route(
+            "/:org/:crate/teams",
+            get(teams::handle_get.layer(rate_limit.with_cost(1)))
+                .patch(teams::handle_patch.layer(rate_limit.with_cost(10)))
+                .put(teams::handle_put.layer(rate_limit.with_cost(10)))
+                .delete(teams::handle_delete.layer(rate_limit.with_cost(10))),
+        )
+        .route(
+            "/:org/:crate/transfer",
+            post(transfer::handle_post.layer(rate_limit.with_cost(50))),
+        )
+        .route(
+            "/:org/:crate/yanks",
+            get(yanks::handle_get.layer(rate_limit.with_cost(1)))
+                .put(yanks::handle_put.layer(rate_limit.with_cost(50))),
+        )
         .route(
             "/recently-updated",
             get(recently_updated::handle.layer(rate_limit.with_cost(1))),
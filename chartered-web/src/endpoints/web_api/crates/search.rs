@@ -1,7 +1,9 @@
 //! Does a simple search over the crates table for a search term, the organisation and crate name
 //! are concatenated using a `/` so any substring of `org/crate` will return results. The latest
-//! version for each is also fetched so we can show them in the search results.
+//! version for each is also fetched so we can show them in the search results, along with any
+//! internal advisories raised against it.
 
+use super::advisories::ResponseAdvisory;
 use axum::{extract, Json};
 use chartered_db::{crates::Crate, permissions::UserPermission, users::User, ConnectionPool};
 use serde::{Deserialize, Serialize};
@@ -28,13 +30,22 @@ pub async fn handle(
                         let org_name = org.name.clone();
 
                         async move {
-                            let version = v.clone().latest_version(db).await?;
+                            let (version, advisories) = tokio::try_join!(
+                                v.clone().latest_version(db.clone()),
+                                v.clone().active_advisories(db),
+                            )?;
+                            let version = version.map(|v| v.version).unwrap_or_default();
 
                             Ok::<_, Error>(ResponseCrate {
                                 organisation: org_name,
                                 name: v.crate_.name.clone(),
                                 description: v.crate_.description.clone(),
-                                version: version.map(|v| v.version).unwrap_or_default(),
+                                advisories: advisories
+                                    .iter()
+                                    .filter(|advisory| advisory.affects(&version))
+                                    .map(ResponseAdvisory::from)
+                                    .collect(),
+                                version,
                                 homepage: v.crate_.homepage.clone(),
                                 repository: v.crate_.repository.clone(),
                                 permissions: v.permissions,
@@ -67,6 +78,7 @@ pub struct ResponseCrate {
     homepage: Option<String>,
     repository: Option<String>,
     permissions: UserPermission,
+    advisories: Vec<ResponseAdvisory>,
 }
 
 #[derive(Error, Debug)]
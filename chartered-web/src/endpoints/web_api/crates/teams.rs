@@ -0,0 +1,139 @@
+//! Handles crate-level team overrides, granting every member of a team within the crate's
+//! organisation permissions on top of those they already have.
+//!
+//! Works the same as the crate members endpoints, but keyed by team name rather than user.
+
+use axum::{extract, Json};
+use chartered_db::{
+    audit::AuditActor, crates::Crate, permissions::UserPermission, users::User, uuid::Uuid,
+    ConnectionPool,
+};
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+use thiserror::Error;
+
+use crate::endpoints::ErrorResponse;
+
+/// Lists all the teams that have been granted permissions on this crate.
+pub async fn handle_get(
+    extract::Path((organisation, name)): extract::Path<(String, String)>,
+    extract::Extension(db): extract::Extension<ConnectionPool>,
+    extract::Extension(user): extract::Extension<Arc<User>>,
+) -> Result<Json<GetResponse>, Error> {
+    let crate_with_permissions =
+        Arc::new(Crate::find_by_name(db.clone(), user.id, organisation, name).await?);
+
+    let teams = crate_with_permissions
+        .teams(db)
+        .await?
+        .into_iter()
+        .map(|(team, permissions)| GetResponseTeam {
+            uuid: team.uuid.0,
+            name: team.name,
+            permissions,
+        })
+        .collect();
+
+    Ok(Json(GetResponse { teams }))
+}
+
+/// Updates the permissions a team has been granted on this crate
+pub async fn handle_patch(
+    extract::Path((organisation, name)): extract::Path<(String, String)>,
+    extract::Extension(db): extract::Extension<ConnectionPool>,
+    extract::Extension(user): extract::Extension<Arc<User>>,
+    extract::Extension(actor): extract::Extension<AuditActor>,
+    extract::Json(req): extract::Json<PutOrPatchRequest>,
+) -> Result<Json<ErrorResponse>, Error> {
+    let crate_with_permissions =
+        Arc::new(Crate::find_by_name(db.clone(), user.id, organisation, name).await?);
+
+    let affected_rows = crate_with_permissions
+        .update_team_permissions(db, actor, req.team, req.permissions)
+        .await?;
+    if affected_rows == 0 {
+        return Err(Error::UpdateConflictRemoved);
+    }
+
+    Ok(Json(ErrorResponse { error: None }))
+}
+
+/// Grants a team permissions on this crate
+pub async fn handle_put(
+    extract::Path((organisation, name)): extract::Path<(String, String)>,
+    extract::Extension(db): extract::Extension<ConnectionPool>,
+    extract::Extension(user): extract::Extension<Arc<User>>,
+    extract::Extension(actor): extract::Extension<AuditActor>,
+    extract::Json(req): extract::Json<PutOrPatchRequest>,
+) -> Result<Json<ErrorResponse>, Error> {
+    let crate_with_permissions =
+        Arc::new(Crate::find_by_name(db.clone(), user.id, organisation, name).await?);
+
+    crate_with_permissions
+        .insert_team_permissions(db, actor, req.team, req.permissions)
+        .await?;
+
+    Ok(Json(ErrorResponse { error: None }))
+}
+
+/// Revokes all the permissions a team has been granted on this crate
+pub async fn handle_delete(
+    extract::Path((organisation, name)): extract::Path<(String, String)>,
+    extract::Extension(db): extract::Extension<ConnectionPool>,
+    extract::Extension(user): extract::Extension<Arc<User>>,
+    extract::Extension(actor): extract::Extension<AuditActor>,
+    extract::Json(req): extract::Json<DeleteRequest>,
+) -> Result<Json<ErrorResponse>, Error> {
+    let crate_with_permissions =
+        Arc::new(Crate::find_by_name(db.clone(), user.id, organisation, name).await?);
+
+    crate_with_permissions
+        .delete_team(db, actor, req.team)
+        .await?;
+
+    Ok(Json(ErrorResponse { error: None }))
+}
+
+#[derive(Serialize)]
+pub struct GetResponse {
+    teams: Vec<GetResponseTeam>,
+}
+
+#[derive(Serialize)]
+pub struct GetResponseTeam {
+    uuid: Uuid,
+    name: String,
+    permissions: UserPermission,
+}
+
+#[derive(Deserialize)]
+pub struct PutOrPatchRequest {
+    team: String,
+    permissions: UserPermission,
+}
+
+#[derive(Deserialize)]
+pub struct DeleteRequest {
+    team: String,
+}
+
+#[derive(Error, Debug)]
+pub enum Error {
+    #[error("{0}")]
+    Database(#[from] chartered_db::Error),
+    #[error("Permissions update conflict, team was removed from the crate")]
+    UpdateConflictRemoved,
+}
+
+impl Error {
+    pub fn status_code(&self) -> axum::http::StatusCode {
+        use axum::http::StatusCode;
+
+        match self {
+            Self::Database(e) => e.status_code(),
+            Self::UpdateConflictRemoved => StatusCode::CONFLICT,
+        }
+    }
+}
+
+define_error_response!(Error);
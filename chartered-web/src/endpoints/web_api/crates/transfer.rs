@@ -0,0 +1,64 @@
+//! Transfers a crate to another organisation, given the requesting user has the `MANAGE_USERS`
+//! permission on the crate and `CREATE_CRATE` on the organisation it's being moved to.
+
+use crate::endpoints::ErrorResponse;
+use axum::{extract, Json};
+use chartered_db::{
+    audit::AuditActor, crates::Crate, organisations::Organisation, users::User, ConnectionPool,
+};
+use chrono::{Duration, Utc};
+use serde::Deserialize;
+use std::sync::Arc;
+use thiserror::Error;
+
+/// How long a crate continues to resolve under its old organisation after being transferred, if
+/// an alias was requested
+const ALIAS_LIFETIME_DAYS: i64 = 30;
+
+pub async fn handle_post(
+    extract::Path((organisation, name)): extract::Path<(String, String)>,
+    extract::Extension(db): extract::Extension<ConnectionPool>,
+    extract::Extension(user): extract::Extension<Arc<User>>,
+    extract::Extension(actor): extract::Extension<AuditActor>,
+    extract::Json(req): extract::Json<PostRequest>,
+) -> Result<Json<ErrorResponse>, Error> {
+    let crate_with_permissions =
+        Arc::new(Crate::find_by_name(db.clone(), user.id, organisation, name).await?);
+    let destination =
+        Arc::new(Organisation::find_by_name(db.clone(), user.id, req.organisation).await?);
+
+    let alias_expires_at = req
+        .leave_alias
+        .then(|| (Utc::now() + Duration::days(ALIAS_LIFETIME_DAYS)).naive_utc());
+
+    crate_with_permissions
+        .transfer(db, actor, destination, alias_expires_at)
+        .await?;
+
+    Ok(Json(ErrorResponse { error: None }))
+}
+
+#[derive(Deserialize)]
+pub struct PostRequest {
+    /// The organisation to move the crate to
+    organisation: String,
+    /// Whether the crate should continue to resolve under its current organisation for a while
+    #[serde(default)]
+    leave_alias: bool,
+}
+
+#[derive(Error, Debug)]
+pub enum Error {
+    #[error("{0}")]
+    Database(#[from] chartered_db::Error),
+}
+
+impl Error {
+    pub fn status_code(&self) -> axum::http::StatusCode {
+        match self {
+            Self::Database(e) => e.status_code(),
+        }
+    }
+}
+
+define_error_response!(Error);
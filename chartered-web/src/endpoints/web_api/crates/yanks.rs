@@ -0,0 +1,100 @@
+//! Yanks and unyanks versions of a crate from the web UI, where, unlike cargo, a reason can be
+//! given for the yank. The full history of yanks for the crate can also be fetched so consumers
+//! can figure out why a version they depend on suddenly disappeared.
+
+use crate::endpoints::ErrorResponse;
+use axum::{extract, Json};
+use chartered_db::{audit::AuditActor, crates::Crate, users::User, uuid::Uuid, ConnectionPool};
+use chrono::{DateTime, TimeZone, Utc};
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+use thiserror::Error;
+
+pub async fn handle_get(
+    extract::Path((organisation, name)): extract::Path<(String, String)>,
+    extract::Extension(db): extract::Extension<ConnectionPool>,
+    extract::Extension(user): extract::Extension<Arc<User>>,
+) -> Result<Json<GetResponse>, Error> {
+    let crate_with_permissions =
+        Arc::new(Crate::find_by_name(db.clone(), user.id, organisation, name).await?);
+
+    let yanks = crate_with_permissions
+        .yank_history(db)
+        .await?
+        .into_iter()
+        .map(|(yank, version, user)| GetResponseYank {
+            version,
+            yanked: yank.yanked,
+            reason: yank.reason,
+            created_at: Utc.from_local_datetime(&yank.created_at).unwrap(),
+            user: GetResponseYankUser {
+                uuid: user.uuid.0,
+                display_name: user.display_name().to_string(),
+                picture_url: user.picture_url,
+            },
+        })
+        .collect();
+
+    Ok(Json(GetResponse { yanks }))
+}
+
+pub async fn handle_put(
+    extract::Path((organisation, name)): extract::Path<(String, String)>,
+    extract::Extension(db): extract::Extension<ConnectionPool>,
+    extract::Extension(user): extract::Extension<Arc<User>>,
+    extract::Extension(actor): extract::Extension<AuditActor>,
+    extract::Json(req): extract::Json<PutRequest>,
+) -> Result<Json<ErrorResponse>, Error> {
+    let crate_with_permissions =
+        Arc::new(Crate::find_by_name(db.clone(), user.id, organisation, name).await?);
+
+    crate_with_permissions
+        .yank_version(db, actor, req.version, req.yanked, req.reason)
+        .await?;
+
+    Ok(Json(ErrorResponse { error: None }))
+}
+
+#[derive(Deserialize)]
+pub struct PutRequest {
+    version: String,
+    yanked: bool,
+    reason: Option<String>,
+}
+
+#[derive(Serialize)]
+pub struct GetResponse {
+    yanks: Vec<GetResponseYank>,
+}
+
+#[derive(Serialize)]
+pub struct GetResponseYank {
+    version: String,
+    yanked: bool,
+    reason: Option<String>,
+    created_at: DateTime<Utc>,
+    user: GetResponseYankUser,
+}
+
+#[derive(Serialize)]
+pub struct GetResponseYankUser {
+    uuid: Uuid,
+    display_name: String,
+    picture_url: Option<String>,
+}
+
+#[derive(Error, Debug)]
+pub enum Error {
+    #[error("{0}")]
+    Database(#[from] chartered_db::Error),
+}
+
+impl Error {
+    pub fn status_code(&self) -> axum::http::StatusCode {
+        match self {
+            Self::Database(e) => e.status_code(),
+        }
+    }
+}
+
+define_error_response!(Error);
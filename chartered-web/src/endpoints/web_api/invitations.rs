@@ -0,0 +1,85 @@
+//! Lists the invitations to join organisations that the requesting user has been sent, either
+//! directly or to their verified email, and lets them accept or decline them.
+
+use axum::{extract, Json};
+use chartered_db::{audit::AuditActor, permissions::UserPermission, users::User, ConnectionPool};
+use serde::Serialize;
+use std::sync::Arc;
+use thiserror::Error;
+
+use crate::endpoints::ErrorResponse;
+
+pub async fn handle_list(
+    extract::Extension(db): extract::Extension<ConnectionPool>,
+    extract::Extension(user): extract::Extension<Arc<User>>,
+) -> Result<Json<ListResponse>, Error> {
+    let invitations = user
+        .pending_invitations(db)
+        .await?
+        .into_iter()
+        .map(|(invitation, organisation)| ResponseInvitation {
+            uuid: invitation.uuid.0,
+            organisation: organisation.name,
+            organisation_description: organisation.description,
+            permissions: invitation.permissions,
+            expires_at: invitation.expires_at,
+        })
+        .collect();
+
+    Ok(Json(ListResponse { invitations }))
+}
+
+/// Accepts the invitation, granting the user its permissions on the organisation.
+pub async fn handle_accept(
+    extract::Path(invitation): extract::Path<chartered_db::uuid::Uuid>,
+    extract::Extension(db): extract::Extension<ConnectionPool>,
+    extract::Extension(user): extract::Extension<Arc<User>>,
+    extract::Extension(actor): extract::Extension<AuditActor>,
+) -> Result<Json<ErrorResponse>, Error> {
+    user.respond_to_invitation(db, actor, invitation, true)
+        .await?;
+
+    Ok(Json(ErrorResponse { error: None }))
+}
+
+pub async fn handle_decline(
+    extract::Path(invitation): extract::Path<chartered_db::uuid::Uuid>,
+    extract::Extension(db): extract::Extension<ConnectionPool>,
+    extract::Extension(user): extract::Extension<Arc<User>>,
+    extract::Extension(actor): extract::Extension<AuditActor>,
+) -> Result<Json<ErrorResponse>, Error> {
+    user.respond_to_invitation(db, actor, invitation, false)
+        .await?;
+
+    Ok(Json(ErrorResponse { error: None }))
+}
+
+#[derive(Serialize)]
+pub struct ListResponse {
+    invitations: Vec<ResponseInvitation>,
+}
+
+#[derive(Serialize)]
+pub struct ResponseInvitation {
+    uuid: chartered_db::uuid::Uuid,
+    organisation: String,
+    organisation_description: String,
+    permissions: UserPermission,
+    expires_at: chrono::NaiveDateTime,
+}
+
+#[derive(Error, Debug)]
+pub enum Error {
+    #[error("{0}")]
+    Database(#[from] chartered_db::Error),
+}
+
+impl Error {
+    pub fn status_code(&self) -> axum::http::StatusCode {
+        match self {
+            Self::Database(e) => e.status_code(),
+        }
+    }
+}
+
+define_error_response!(Error);
@@ -1,5 +1,7 @@
+mod audit;
 mod auth;
 mod crates;
+mod invitations;
 mod organisations;
 mod sessions;
 mod ssh_key;
@@ -8,7 +10,7 @@ mod users;
 use crate::RateLimit;
 use axum::{
     handler::Handler,
-    routing::{delete, get},
+    routing::{delete, get, post},
     Router,
 };
 
@@ -19,6 +21,22 @@ pub fn authenticated_routes(rate_limit: &RateLimit) -> Router {
         .nest("/users", users::routes(rate_limit))
         .nest("/auth", auth::authenticated_routes(rate_limit))
         .nest("/sessions", sessions::routes(rate_limit))
+        .route(
+            "/audit",
+            get(audit::handle_get.layer(rate_limit.with_cost(5))),
+        )
+        .route(
+            "/invitations",
+            get(invitations::handle_list.layer(rate_limit.with_cost(1))),
+        )
+        .route(
+            "/invitations/:invitation/accept",
+            post(invitations::handle_accept.layer(rate_limit.with_cost(10))),
+        )
+        .route(
+            "/invitations/:invitation/decline",
+            post(invitations::handle_decline.layer(rate_limit.with_cost(10))),
+        )
         .route(
             "/ssh-key",
             get(ssh_key::handle_get.layer(rate_limit.with_cost(1)))
@@ -0,0 +1,91 @@
+//! Lists the audit log for an organisation, covering every change made to the organisation
+//! itself, its members and its crates. Only those with the `MANAGE_ORGANISATION` permission for
+//! the organisation can view it, changes to users' own accounts are listed by
+//! [`super::super::audit`] instead.
+//!
+//! Events are returned most recent first, a page at a time, and can be narrowed down by the
+//! action taken, the user that took it or the crate it was taken against.
+
+use super::super::audit::{Page, Response};
+use axum::{extract, Json};
+use chartered_db::{
+    audit::{AuditAction, AuditEventFilter},
+    organisations::Organisation,
+    users::User,
+    uuid::Uuid,
+    ConnectionPool,
+};
+use serde::Deserialize;
+use std::sync::Arc;
+use thiserror::Error;
+
+pub async fn handle_get(
+    extract::Path(organisation): extract::Path<String>,
+    extract::Extension(db): extract::Extension<ConnectionPool>,
+    extract::Extension(user): extract::Extension<Arc<User>>,
+    extract::Query(req): extract::Query<RequestParams>,
+) -> Result<Json<Response>, Error> {
+    let organisation =
+        Arc::new(Organisation::find_by_name(db.clone(), user.id, organisation).await?);
+
+    let user_id = match req.user {
+        Some(uuid) => Some(
+            User::find_by_uuid(db.clone(), uuid)
+                .await?
+                .ok_or(Error::InvalidUserId)?
+                .id,
+        ),
+        None => None,
+    };
+
+    let page = Page::new(req.page, req.per_page).ok_or(Error::InvalidPage)?;
+
+    // fetch an extra event so we know whether there's another page after this one
+    let events = organisation
+        .audit_events(
+            db,
+            AuditEventFilter {
+                action: req.action,
+                user_id,
+                crate_name: req.crate_name,
+            },
+            page.offset,
+            page.per_page + 1,
+        )
+        .await?;
+
+    Ok(Json(page.response(events)))
+}
+
+#[derive(Deserialize)]
+pub struct RequestParams {
+    page: Option<i64>,
+    per_page: Option<i64>,
+    action: Option<AuditAction>,
+    user: Option<Uuid>,
+    #[serde(rename = "crate")]
+    crate_name: Option<String>,
+}
+
+#[derive(Error, Debug)]
+pub enum Error {
+    #[error("{0}")]
+    Database(#[from] chartered_db::Error),
+    #[error("An invalid user id was given")]
+    InvalidUserId,
+    #[error("The requested page is out of range")]
+    InvalidPage,
+}
+
+impl Error {
+    pub fn status_code(&self) -> axum::http::StatusCode {
+        use axum::http::StatusCode;
+
+        match self {
+            Self::Database(e) => e.status_code(),
+            Self::InvalidUserId | Self::InvalidPage => StatusCode::BAD_REQUEST,
+        }
+    }
+}
+
+define_error_response!(Error);
@@ -1,8 +1,9 @@
-//! Allows users to create whole organisations. This endpoint currently isn't limited to any
-//! specific users so all users can create an organisation and add people to it.
+//! Allows users to create whole organisations, update their settings and delete them. Creation
+//! currently isn't limited to any specific users so all users can create an organisation and add
+//! people to it, updating and deleting requires the `MANAGE_ORGANISATION` permission.
 
 use axum::{extract, Json};
-use chartered_db::{organisations::Organisation, users::User, ConnectionPool};
+use chartered_db::{audit::AuditActor, organisations::Organisation, users::User, ConnectionPool};
 use serde::Deserialize;
 use std::sync::Arc;
 use thiserror::Error;
@@ -11,14 +12,87 @@ use crate::endpoints::ErrorResponse;
 
 pub async fn handle_put(
     extract::Extension(db): extract::Extension<ConnectionPool>,
-    extract::Extension(user): extract::Extension<Arc<User>>,
+    extract::Extension(actor): extract::Extension<AuditActor>,
     extract::Json(req): extract::Json<PutRequest>,
 ) -> Result<Json<ErrorResponse>, Error> {
-    Organisation::create(db, req.name, req.description, req.public, user.id).await?;
+    if !is_valid_name(&req.name) {
+        return Err(Error::InvalidName);
+    }
+
+    Organisation::create(db, req.name, req.description, req.public, actor).await?;
 
     Ok(Json(ErrorResponse { error: None }))
 }
 
+/// Updates the organisation's settings, renaming the organisation keeps its old name working as
+/// an alias.
+pub async fn handle_patch(
+    extract::Path(organisation): extract::Path<String>,
+    extract::Extension(db): extract::Extension<ConnectionPool>,
+    extract::Extension(user): extract::Extension<Arc<User>>,
+    extract::Extension(actor): extract::Extension<AuditActor>,
+    extract::Json(req): extract::Json<PatchRequest>,
+) -> Result<Json<ErrorResponse>, Error> {
+    let organisation =
+        Arc::new(Organisation::find_by_name(db.clone(), user.id, organisation).await?);
+
+    if let Some(name) = &req.name {
+        if !is_valid_name(name) {
+            return Err(Error::InvalidName);
+        }
+    }
+
+    if req.name.is_some() || req.description.is_some() || req.public.is_some() {
+        organisation
+            .clone()
+            .update(
+                db.clone(),
+                actor.clone(),
+                req.name,
+                req.description,
+                req.public,
+            )
+            .await?;
+    }
+
+    if let Some(require_two_factor) = req.require_two_factor {
+        organisation
+            .set_require_two_factor(db, actor, require_two_factor)
+            .await?;
+    }
+
+    Ok(Json(ErrorResponse { error: None }))
+}
+
+/// Deletes the organisation, which is only allowed once it no longer has any crates.
+pub async fn handle_delete(
+    extract::Path(organisation): extract::Path<String>,
+    extract::Extension(db): extract::Extension<ConnectionPool>,
+    extract::Extension(user): extract::Extension<Arc<User>>,
+    extract::Extension(actor): extract::Extension<AuditActor>,
+) -> Result<Json<ErrorResponse>, Error> {
+    let organisation =
+        Arc::new(Organisation::find_by_name(db.clone(), user.id, organisation).await?);
+
+    organisation.delete(db, actor).await?;
+
+    Ok(Json(ErrorResponse { error: None }))
+}
+
+/// Organisation names end up in registry URLs, so they're limited to the same characters the
+/// frontend allows on creation.
+fn is_valid_name(name: &str) -> bool {
+    !name.is_empty() && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '-')
+}
+
+#[derive(Deserialize)]
+pub struct PatchRequest {
+    name: Option<String>,
+    description: Option<String>,
+    public: Option<bool>,
+    require_two_factor: Option<bool>,
+}
+
 #[derive(Deserialize)]
 pub struct PutRequest {
     name: String,
@@ -30,12 +104,17 @@ pub struct PutRequest {
 pub enum Error {
     #[error("{0}")]
     Database(#[from] chartered_db::Error),
+    #[error("Organisation names can only contain letters, numbers and dashes")]
+    InvalidName,
 }
 
 impl Error {
     pub fn status_code(&self) -> axum::http::StatusCode {
+        use axum::http::StatusCode;
+
         match self {
             Self::Database(e) => e.status_code(),
+            Self::InvalidName => StatusCode::BAD_REQUEST,
         }
     }
 }
@@ -24,6 +24,11 @@ pub async fn handle_get(
         .permissions()
         .contains(UserPermission::MANAGE_USERS);
 
+    // checks if the requesting user can change the organisation's settings
+    let can_manage_organisation = organisation
+        .permissions()
+        .contains(UserPermission::MANAGE_ORGANISATION);
+
     // fetch both crates and members for the organisation at the same time
     let (crates, users) = tokio::try_join!(
         organisation.clone().crates(db.clone()),
@@ -31,6 +36,7 @@ pub async fn handle_get(
     )?;
 
     Ok(Json(Response {
+        name: organisation.organisation().name.to_string(),
         description: organisation.organisation().description.to_string(),
         // all the permissions the requesting user can give out for this organisation
         possible_permissions: can_manage_users.then(UserPermission::all),
@@ -52,17 +58,22 @@ pub async fn handle_get(
             })
             .collect(),
         public: organisation.organisation().public,
+        require_two_factor: organisation.organisation().require_two_factor,
+        can_manage_organisation,
     }))
 }
 
 #[derive(Serialize)]
 pub struct Response {
+    name: String,
     description: String,
     possible_permissions: Option<UserPermission>,
     implied_permissions: Option<&'static [[UserPermission; 2]]>,
     crates: Vec<ResponseCrate>,
     members: Vec<ResponseUser>,
     public: bool,
+    require_two_factor: bool,
+    can_manage_organisation: bool,
 }
 
 #[derive(Serialize)]
@@ -0,0 +1,204 @@
+//! Invites users to join an organisation, given the requesting user has the `MANAGE_USERS`
+//! permission at the organisation level. Invitations can be sent to an existing user or to an
+//! email address, and the invitee only becomes a member once they've accepted.
+
+use axum::{extract, Json};
+use chartered_db::{
+    audit::AuditActor,
+    invitations::{Invitee, OrganisationInvitation},
+    organisations::Organisation,
+    permissions::UserPermission,
+    users::User,
+    ConnectionPool,
+};
+use chrono::{Duration, Utc};
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+use thiserror::Error;
+use tracing::warn;
+
+use crate::{config::Config, endpoints::ErrorResponse, mail::Mailer};
+
+/// How long an invitation can be accepted for
+const INVITATION_LIFETIME_DAYS: i64 = 7;
+
+/// Lists all the pending invitations to the organisation
+pub async fn handle_list(
+    extract::Path(organisation): extract::Path<String>,
+    extract::Extension(db): extract::Extension<ConnectionPool>,
+    extract::Extension(user): extract::Extension<Arc<User>>,
+) -> Result<Json<ListResponse>, Error> {
+    let organisation =
+        Arc::new(Organisation::find_by_name(db.clone(), user.id, organisation).await?);
+
+    let invitations = organisation
+        .invitations(db)
+        .await?
+        .into_iter()
+        .map(|(invitation, invitee)| ResponseInvitation::new(invitation, invitee))
+        .collect();
+
+    Ok(Json(ListResponse { invitations }))
+}
+
+/// Invites a user to the organisation with a given set of permissions, mailing them a link to the
+/// invitation if we have an address for them.
+pub async fn handle_put(
+    extract::Path(organisation): extract::Path<String>,
+    extract::Extension(config): extract::Extension<Arc<Config>>,
+    extract::Extension(db): extract::Extension<ConnectionPool>,
+    extract::Extension(mailer): extract::Extension<Arc<Mailer>>,
+    extract::Extension(user): extract::Extension<Arc<User>>,
+    extract::Extension(actor): extract::Extension<AuditActor>,
+    extract::Json(req): extract::Json<PutRequest>,
+) -> Result<Json<ResponseInvitation>, Error> {
+    let organisation =
+        Arc::new(Organisation::find_by_name(db.clone(), user.id, organisation).await?);
+
+    let (invitee, invitee_user, email) = match (req.user_uuid, req.email) {
+        (Some(user_uuid), None) => {
+            let invitee = User::find_by_uuid(db.clone(), user_uuid)
+                .await?
+                .ok_or(Error::InvalidUserId)?;
+
+            // only bother existing users with an email if they've proven it's theirs
+            let email = invitee
+                .email_verified_at
+                .and_then(|_| invitee.email.clone());
+
+            (Invitee::User(invitee.id), Some(invitee), email)
+        }
+        (None, Some(email)) => {
+            let email = email.trim().to_string();
+            email
+                .parse::<lettre::Address>()
+                .map_err(|_| Error::InvalidEmail)?;
+
+            (Invitee::Email(email.clone()), None, Some(email))
+        }
+        _ => return Err(Error::InvalidInvitee),
+    };
+
+    let expires = Utc::now() + Duration::days(INVITATION_LIFETIME_DAYS);
+    let invitation = organisation
+        .clone()
+        .invite(db, actor, invitee, req.permissions, expires.naive_utc())
+        .await?;
+
+    if let (true, Some(email)) = (mailer.is_enabled(), email) {
+        let link = config.frontend_base_uri.join("invitations")?;
+        let body = format!(
+            "{} has invited you to join the {} organisation on Chartered.\n\nYou can accept the \
+             invitation within the next {} days at:\n\n{}\n\nIf you don't have an account yet, \
+             you'll need to verify this email address on your new account to see the \
+             invitation.",
+            user.display_name(),
+            organisation.organisation().name,
+            INVITATION_LIFETIME_DAYS,
+            link,
+        );
+
+        // the invitation exists regardless, so there's nothing more we can do than log
+        if let Err(e) = mailer
+            .send(&email, "You've been invited to an organisation", body)
+            .await
+        {
+            warn!("Failed to send invitation email: {}", e);
+        }
+    }
+
+    Ok(Json(ResponseInvitation::new(invitation, invitee_user)))
+}
+
+/// Withdraws an invitation before it has been accepted
+pub async fn handle_delete(
+    extract::Path((organisation, invitation)): extract::Path<(String, chartered_db::uuid::Uuid)>,
+    extract::Extension(db): extract::Extension<ConnectionPool>,
+    extract::Extension(user): extract::Extension<Arc<User>>,
+    extract::Extension(actor): extract::Extension<AuditActor>,
+) -> Result<Json<ErrorResponse>, Error> {
+    let organisation =
+        Arc::new(Organisation::find_by_name(db.clone(), user.id, organisation).await?);
+
+    organisation
+        .revoke_invitation(db, actor, invitation)
+        .await?;
+
+    Ok(Json(ErrorResponse { error: None }))
+}
+
+#[derive(Deserialize)]
+pub struct PutRequest {
+    user_uuid: Option<chartered_db::uuid::Uuid>,
+    email: Option<String>,
+    permissions: UserPermission,
+}
+
+#[derive(Serialize)]
+pub struct ListResponse {
+    invitations: Vec<ResponseInvitation>,
+}
+
+#[derive(Serialize)]
+pub struct ResponseInvitation {
+    uuid: chartered_db::uuid::Uuid,
+    user: Option<ResponseUser>,
+    email: Option<String>,
+    permissions: UserPermission,
+    expires_at: chrono::NaiveDateTime,
+    created_at: chrono::NaiveDateTime,
+}
+
+impl ResponseInvitation {
+    fn new(invitation: OrganisationInvitation, invitee: Option<User>) -> Self {
+        Self {
+            uuid: invitation.uuid.0,
+            user: invitee.map(|user| ResponseUser {
+                uuid: user.uuid.0,
+                display_name: user.display_name().to_string(),
+                picture_url: user.picture_url,
+            }),
+            email: invitation.email,
+            permissions: invitation.permissions,
+            expires_at: invitation.expires_at,
+            created_at: invitation.created_at,
+        }
+    }
+}
+
+#[derive(Serialize)]
+pub struct ResponseUser {
+    uuid: chartered_db::uuid::Uuid,
+    display_name: String,
+    picture_url: Option<String>,
+}
+
+#[derive(Error, Debug)]
+pub enum Error {
+    #[error("{0}")]
+    Database(#[from] chartered_db::Error),
+    #[error("Failed to build invitation link: {0}")]
+    Url(#[from] url::ParseError),
+    #[error("An invalid user id was given")]
+    InvalidUserId,
+    #[error("Email address is invalid")]
+    InvalidEmail,
+    #[error("Either a user or an email must be given to send the invitation to")]
+    InvalidInvitee,
+}
+
+impl Error {
+    pub fn status_code(&self) -> axum::http::StatusCode {
+        use axum::http::StatusCode;
+
+        match self {
+            Self::Database(e) => e.status_code(),
+            Self::Url(_) => StatusCode::INTERNAL_SERVER_ERROR,
+            Self::InvalidUserId | Self::InvalidEmail | Self::InvalidInvitee => {
+                StatusCode::BAD_REQUEST
+            }
+        }
+    }
+}
+
+define_error_response!(Error);
@@ -1,9 +1,11 @@
-//! CRUD methods to manage members of an organisation, given the requesting user has the
-//! `MANAGE_USERS` permission at the organisation level.
+//! Methods to manage existing members of an organisation, given the requesting user has the
+//! `MANAGE_USERS` permission at the organisation level. New members are added by sending them
+//! an invitation, see [`super::invitations`].
 
 use axum::{extract, Json};
 use chartered_db::{
-    organisations::Organisation, permissions::UserPermission, users::User, ConnectionPool,
+    audit::AuditActor, organisations::Organisation, permissions::UserPermission, users::User,
+    ConnectionPool,
 };
 use serde::Deserialize;
 use std::sync::Arc;
@@ -16,7 +18,8 @@ pub async fn handle_patch(
     extract::Path(organisation): extract::Path<String>,
     extract::Extension(db): extract::Extension<ConnectionPool>,
     extract::Extension(user): extract::Extension<Arc<User>>,
-    extract::Json(req): extract::Json<PutOrPatchRequest>,
+    extract::Extension(actor): extract::Extension<AuditActor>,
+    extract::Json(req): extract::Json<PatchRequest>,
 ) -> Result<Json<ErrorResponse>, Error> {
     let organisation =
         Arc::new(Organisation::find_by_name(db.clone(), user.id, organisation).await?);
@@ -26,7 +29,7 @@ pub async fn handle_patch(
         .ok_or(Error::InvalidUserId)?;
 
     let affected_rows = organisation
-        .update_permissions(db, action_user.id, req.permissions)
+        .update_permissions(db, actor, action_user.id, req.permissions)
         .await?;
     if affected_rows == 0 {
         return Err(Error::UpdateConflictRemoved);
@@ -35,32 +38,12 @@ pub async fn handle_patch(
     Ok(Json(ErrorResponse { error: None }))
 }
 
-/// Adds a new member to the organisation with a given set of permissions.
-pub async fn handle_put(
-    extract::Path(organisation): extract::Path<String>,
-    extract::Extension(db): extract::Extension<ConnectionPool>,
-    extract::Extension(user): extract::Extension<Arc<User>>,
-    extract::Json(req): extract::Json<PutOrPatchRequest>,
-) -> Result<Json<ErrorResponse>, Error> {
-    let organisation =
-        Arc::new(Organisation::find_by_name(db.clone(), user.id, organisation).await?);
-
-    let action_user = User::find_by_uuid(db.clone(), req.user_uuid)
-        .await?
-        .ok_or(Error::InvalidUserId)?;
-
-    organisation
-        .insert_permissions(db, action_user.id, req.permissions)
-        .await?;
-
-    Ok(Json(ErrorResponse { error: None }))
-}
-
 /// Deletes a member from the organisation entirely
 pub async fn handle_delete(
     extract::Path(organisation): extract::Path<String>,
     extract::Extension(db): extract::Extension<ConnectionPool>,
     extract::Extension(user): extract::Extension<Arc<User>>,
+    extract::Extension(actor): extract::Extension<AuditActor>,
     extract::Json(req): extract::Json<DeleteRequest>,
 ) -> Result<Json<ErrorResponse>, Error> {
     let organisation =
@@ -70,13 +53,15 @@ pub async fn handle_delete(
         .await?
         .ok_or(Error::InvalidUserId)?;
 
-    organisation.delete_member(db, action_user.id).await?;
+    organisation
+        .delete_member(db, actor, action_user.id)
+        .await?;
 
     Ok(Json(ErrorResponse { error: None }))
 }
 
 #[derive(Deserialize)]
-pub struct PutOrPatchRequest {
+pub struct PatchRequest {
     user_uuid: chartered_db::uuid::Uuid,
     permissions: UserPermission,
 }
@@ -1,12 +1,15 @@
+mod audit;
 mod crud;
 mod info;
+mod invitations;
 mod list;
 mod members;
+mod teams;
 
 use crate::middleware::rate_limit::RateLimit;
 use axum::{
     handler::Handler,
-    routing::{get, patch},
+    routing::{delete, get, patch, put},
     Router,
 };
 
@@ -19,13 +22,44 @@ pub fn routes(rate_limit: &RateLimit) -> Router {
         )
         .route(
             "/:org",
-            get(info::handle_get.layer(rate_limit.with_cost(1))),
+            get(info::handle_get.layer(rate_limit.with_cost(1)))
+                .patch(crud::handle_patch.layer(rate_limit.with_cost(10)))
+                .delete(crud::handle_delete.layer(rate_limit.with_cost(100))),
+        )
+        .route(
+            "/:org/audit",
+            get(audit::handle_get.layer(rate_limit.with_cost(5))),
         )
         .route(
             "/:org/members",
             patch(members::handle_patch)
-                .put(members::handle_put)
                 .delete(members::handle_delete)
                 .layer(rate_limit.with_cost(10)),
         )
+        .route(
+            "/:org/invitations",
+            get(invitations::handle_list.layer(rate_limit.with_cost(1)))
+                .put(invitations::handle_put.layer(rate_limit.with_cost(24))),
+        )
+        .route(
+            "/:org/invitations/:invitation",
+            delete(invitations::handle_delete.layer(rate_limit.with_cost(10))),
+        )
+        .route(
+            "/:org/teams",
+            get(teams::handle_list.layer(rate_limit.with_cost(1)))
+                .put(teams::handle_create.layer(rate_limit.with_cost(10))),
+        )
+        .route(
+            "/:org/teams/:team",
+            get(teams::handle_get.layer(rate_limit.with_cost(1)))
+                .patch(teams::handle_patch.layer(rate_limit.with_cost(10)))
+                .delete(teams::handle_delete.layer(rate_limit.with_cost(10))),
+        )
+        .route(
+            "/:org/teams/:team/members",
+            put(teams::handle_put_member)
+                .delete(teams::handle_delete_member)
+                .layer(rate_limit.with_cost(10)),
+        )
 }
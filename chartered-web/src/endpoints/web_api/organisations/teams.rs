@@ -0,0 +1,256 @@
+//! Manages teams within an organisation. Teams group together members of the organisation so
+//! permissions can be granted to all of them at once, either on the organisation itself or on
+//! individual crates.
+//!
+//! Anyone that can see the organisation can list its teams, but creating, deleting or changing
+//! the members and permissions of a team requires the `MANAGE_USERS` permission.
+
+use axum::{extract, Json};
+use chartered_db::{
+    audit::AuditActor, organisations::Organisation, permissions::UserPermission, teams::Team,
+    users::User, ConnectionPool,
+};
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+use thiserror::Error;
+
+use crate::endpoints::ErrorResponse;
+
+/// Lists all the teams within the organisation
+pub async fn handle_list(
+    extract::Path(organisation): extract::Path<String>,
+    extract::Extension(db): extract::Extension<ConnectionPool>,
+    extract::Extension(user): extract::Extension<Arc<User>>,
+) -> Result<Json<ListResponse>, Error> {
+    let organisation =
+        Arc::new(Organisation::find_by_name(db.clone(), user.id, organisation).await?);
+
+    let teams = organisation
+        .teams(db)
+        .await?
+        .into_iter()
+        .map(|(team, permissions)| ResponseTeam::new(&team, permissions))
+        .collect();
+
+    Ok(Json(ListResponse { teams }))
+}
+
+/// Creates a new team within the organisation, the team will start off with no members and no
+/// permissions.
+pub async fn handle_create(
+    extract::Path(organisation): extract::Path<String>,
+    extract::Extension(db): extract::Extension<ConnectionPool>,
+    extract::Extension(user): extract::Extension<Arc<User>>,
+    extract::Extension(actor): extract::Extension<AuditActor>,
+    extract::Json(req): extract::Json<CreateRequest>,
+) -> Result<Json<ResponseTeam>, Error> {
+    let organisation =
+        Arc::new(Organisation::find_by_name(db.clone(), user.id, organisation).await?);
+
+    let team = organisation
+        .create_team(db, actor, req.name, req.description)
+        .await?;
+
+    Ok(Json(ResponseTeam::new(&team, UserPermission::empty())))
+}
+
+/// Grabs info about a specific team including its members and all the crates it has been
+/// granted permissions on.
+pub async fn handle_get(
+    extract::Path((organisation, team)): extract::Path<(String, String)>,
+    extract::Extension(db): extract::Extension<ConnectionPool>,
+    extract::Extension(user): extract::Extension<Arc<User>>,
+) -> Result<Json<GetResponse>, Error> {
+    let organisation =
+        Arc::new(Organisation::find_by_name(db.clone(), user.id, organisation).await?);
+    let team = Arc::new(organisation.team(db.clone(), team).await?);
+
+    let (members, permissions, crates) = tokio::try_join!(
+        team.clone().members(db.clone()),
+        team.clone().organisation_permissions(db.clone()),
+        team.clone().crates(db),
+    )?;
+
+    Ok(Json(GetResponse {
+        team: ResponseTeam::new(&team.team, permissions),
+        members: members
+            .into_iter()
+            .map(|user| ResponseUser {
+                uuid: user.uuid.0,
+                display_name: user.display_name().to_string(),
+                picture_url: user.picture_url,
+            })
+            .collect(),
+        crates: crates
+            .into_iter()
+            .map(|(crate_, permissions)| ResponseCrate {
+                name: crate_.name,
+                permissions,
+            })
+            .collect(),
+    }))
+}
+
+/// Updates the permissions the team has been granted on the organisation
+pub async fn handle_patch(
+    extract::Path((organisation, team)): extract::Path<(String, String)>,
+    extract::Extension(db): extract::Extension<ConnectionPool>,
+    extract::Extension(user): extract::Extension<Arc<User>>,
+    extract::Extension(actor): extract::Extension<AuditActor>,
+    extract::Json(req): extract::Json<PatchRequest>,
+) -> Result<Json<ErrorResponse>, Error> {
+    let organisation =
+        Arc::new(Organisation::find_by_name(db.clone(), user.id, organisation).await?);
+    let team = Arc::new(organisation.team(db.clone(), team).await?);
+
+    team.update_organisation_permissions(db, actor, req.permissions)
+        .await?;
+
+    Ok(Json(ErrorResponse { error: None }))
+}
+
+/// Deletes the team, revoking any permissions it granted to its members
+pub async fn handle_delete(
+    extract::Path((organisation, team)): extract::Path<(String, String)>,
+    extract::Extension(db): extract::Extension<ConnectionPool>,
+    extract::Extension(user): extract::Extension<Arc<User>>,
+    extract::Extension(actor): extract::Extension<AuditActor>,
+) -> Result<Json<ErrorResponse>, Error> {
+    let organisation =
+        Arc::new(Organisation::find_by_name(db.clone(), user.id, organisation).await?);
+    let team = Arc::new(organisation.team(db.clone(), team).await?);
+
+    team.delete(db, actor).await?;
+
+    Ok(Json(ErrorResponse { error: None }))
+}
+
+/// Adds a member of the organisation to the team
+pub async fn handle_put_member(
+    extract::Path((organisation, team)): extract::Path<(String, String)>,
+    extract::Extension(db): extract::Extension<ConnectionPool>,
+    extract::Extension(user): extract::Extension<Arc<User>>,
+    extract::Extension(actor): extract::Extension<AuditActor>,
+    extract::Json(req): extract::Json<MemberRequest>,
+) -> Result<Json<ErrorResponse>, Error> {
+    let organisation =
+        Arc::new(Organisation::find_by_name(db.clone(), user.id, organisation).await?);
+    let team = Arc::new(organisation.team(db.clone(), team).await?);
+
+    let action_user = User::find_by_uuid(db.clone(), req.user_uuid)
+        .await?
+        .ok_or(Error::InvalidUserId)?;
+
+    team.add_member(db, actor, action_user.id).await?;
+
+    Ok(Json(ErrorResponse { error: None }))
+}
+
+/// Removes a user from the team
+pub async fn handle_delete_member(
+    extract::Path((organisation, team)): extract::Path<(String, String)>,
+    extract::Extension(db): extract::Extension<ConnectionPool>,
+    extract::Extension(user): extract::Extension<Arc<User>>,
+    extract::Extension(actor): extract::Extension<AuditActor>,
+    extract::Json(req): extract::Json<MemberRequest>,
+) -> Result<Json<ErrorResponse>, Error> {
+    let organisation =
+        Arc::new(Organisation::find_by_name(db.clone(), user.id, organisation).await?);
+    let team = Arc::new(organisation.team(db.clone(), team).await?);
+
+    let action_user = User::find_by_uuid(db.clone(), req.user_uuid)
+        .await?
+        .ok_or(Error::InvalidUserId)?;
+
+    if team.remove_member(db, actor, action_user.id).await? {
+        Ok(Json(ErrorResponse { error: None }))
+    } else {
+        Err(Error::NotAMember)
+    }
+}
+
+#[derive(Deserialize)]
+pub struct CreateRequest {
+    name: String,
+    #[serde(default)]
+    description: String,
+}
+
+#[derive(Deserialize)]
+pub struct PatchRequest {
+    permissions: UserPermission,
+}
+
+#[derive(Deserialize)]
+pub struct MemberRequest {
+    user_uuid: chartered_db::uuid::Uuid,
+}
+
+#[derive(Serialize)]
+pub struct ListResponse {
+    teams: Vec<ResponseTeam>,
+}
+
+#[derive(Serialize)]
+pub struct GetResponse {
+    #[serde(flatten)]
+    team: ResponseTeam,
+    members: Vec<ResponseUser>,
+    crates: Vec<ResponseCrate>,
+}
+
+#[derive(Serialize)]
+pub struct ResponseTeam {
+    uuid: chartered_db::uuid::Uuid,
+    name: String,
+    description: String,
+    permissions: UserPermission,
+}
+
+impl ResponseTeam {
+    fn new(team: &Team, permissions: UserPermission) -> Self {
+        Self {
+            uuid: team.uuid.0,
+            name: team.name.clone(),
+            description: team.description.clone(),
+            permissions,
+        }
+    }
+}
+
+#[derive(Serialize)]
+pub struct ResponseUser {
+    uuid: chartered_db::uuid::Uuid,
+    display_name: String,
+    picture_url: Option<String>,
+}
+
+#[derive(Serialize)]
+pub struct ResponseCrate {
+    name: String,
+    permissions: UserPermission,
+}
+
+#[derive(Error, Debug)]
+pub enum Error {
+    #[error("{0}")]
+    Database(#[from] chartered_db::Error),
+    #[error("An invalid user id was given")]
+    InvalidUserId,
+    #[error("The given user is not a member of the team")]
+    NotAMember,
+}
+
+impl Error {
+    pub fn status_code(&self) -> axum::http::StatusCode {
+        use axum::http::StatusCode;
+
+        match self {
+            Self::Database(e) => e.status_code(),
+            Self::InvalidUserId => StatusCode::BAD_REQUEST,
+            Self::NotAMember => StatusCode::NOT_FOUND,
+        }
+    }
+}
+
+define_error_response!(Error);
@@ -1,13 +1,14 @@
 use axum::{extract, Json};
-use chartered_db::{users::UserSession, ConnectionPool};
+use chartered_db::{audit::AuditActor, users::UserSession, ConnectionPool};
 use serde::{Deserialize, Serialize};
 use thiserror::Error;
 
 pub async fn handle_delete(
     extract::Extension(db): extract::Extension<ConnectionPool>,
+    extract::Extension(actor): extract::Extension<AuditActor>,
     extract::Json(req): extract::Json<Request>,
 ) -> Result<Json<Response>, Error> {
-    if UserSession::delete_by_uuid(db.clone(), req.uuid).await? {
+    if UserSession::delete_by_uuid(db.clone(), actor, req.uuid).await? {
         Ok(Json(Response { success: true }))
     } else {
         Err(Error::UnknownSession)
@@ -0,0 +1,208 @@
+//! Detects crate files that have gone missing from storage or no longer match the checksum taken
+//! when they were published, so corruption can be dealt with before cargo starts reporting
+//! checksum mismatches to users.
+//!
+//! Failures are found either when a crate is served with `verify_downloads` enabled, or by the
+//! scrub which periodically checks every version in storage, along with its copy on the replica
+//! if one is configured. Each failure is logged and, if an `alert_webhook` is configured, POSTed
+//! to it as JSON.
+
+use chartered_db::{
+    crates::{CrateVersion, StoredCrateVersion},
+    ConnectionPool,
+};
+use chartered_fs::FileReference;
+use serde::Serialize;
+use std::{str::FromStr, sync::Arc, time::Duration};
+use thiserror::Error;
+use tracing::{error, info, warn};
+use url::Url;
+
+use crate::storage::Storage;
+
+#[derive(Error, Debug)]
+pub enum Error {
+    #[error("Failed to load crate versions: {0}")]
+    Database(#[from] chartered_db::Error),
+}
+
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum FailureKind {
+    /// The object doesn't exist in storage
+    Missing,
+    /// The object's contents don't match the checksum taken when it was published
+    Corrupt,
+    /// The object couldn't be checked, ie. storage was unavailable or the version has an invalid
+    /// reference
+    Error(String),
+}
+
+impl FailureKind {
+    #[must_use]
+    pub fn from_error(e: &chartered_fs::Error) -> Self {
+        if e.is_not_found() {
+            Self::Missing
+        } else if matches!(e, chartered_fs::Error::ChecksumMismatch { .. }) {
+            Self::Corrupt
+        } else {
+            Self::Error(e.to_string())
+        }
+    }
+}
+
+#[derive(Serialize, Debug)]
+pub struct IntegrityFailure {
+    pub organisation: String,
+    pub crate_name: String,
+    pub version: String,
+    pub filesystem_object: String,
+    pub kind: FailureKind,
+}
+
+/// Where integrity failures are reported to.
+pub struct IntegrityMonitor {
+    alert_webhook: Option<Url>,
+    http_client: reqwest::Client,
+}
+
+impl IntegrityMonitor {
+    #[must_use]
+    pub fn new(alert_webhook: Option<Url>, http_client: reqwest::Client) -> Self {
+        Self {
+            alert_webhook,
+            http_client,
+        }
+    }
+
+    pub async fn report(&self, failure: &IntegrityFailure) {
+        error!(
+            "Integrity check failed for {}/{} {} ({}): {:?}",
+            failure.organisation,
+            failure.crate_name,
+            failure.version,
+            failure.filesystem_object,
+            failure.kind
+        );
+
+        if let Some(webhook) = &self.alert_webhook {
+            let res = self
+                .http_client
+                .post(webhook.clone())
+                .json(failure)
+                .send()
+                .await
+                .and_then(reqwest::Response::error_for_status);
+
+            if let Err(e) = res {
+                warn!("Failed to send integrity failure to alert webhook: {}", e);
+            }
+        }
+    }
+}
+
+/// Counts of what was found during a scrub.
+#[derive(Debug, Default)]
+pub struct ScrubReport {
+    pub checked: usize,
+    pub missing: usize,
+    pub corrupt: usize,
+    pub errored: usize,
+}
+
+/// Checks every crate version in storage against its checksum, reporting any failures to
+/// `monitor`.
+pub async fn scrub(
+    db: ConnectionPool,
+    storage: &Storage,
+    monitor: &IntegrityMonitor,
+) -> Result<ScrubReport, Error> {
+    let mut report = ScrubReport::default();
+
+    for version in CrateVersion::all_stored(db).await? {
+        report.checked += 1;
+
+        for (filesystem_object, res) in verify(storage, &version).await {
+            let Err(e) = res else {
+                continue;
+            };
+
+            let failure = IntegrityFailure {
+                kind: FailureKind::from_error(&e),
+                organisation: version.organisation.clone(),
+                crate_name: version.crate_name.clone(),
+                version: version.version.clone(),
+                filesystem_object,
+            };
+
+            match failure.kind {
+                FailureKind::Missing => report.missing += 1,
+                FailureKind::Corrupt => report.corrupt += 1,
+                FailureKind::Error(_) => report.errored += 1,
+            }
+
+            monitor.report(&failure).await;
+        }
+    }
+
+    Ok(report)
+}
+
+/// Checks the version's file, and its copy on the replica, returning the result for each
+/// alongside the reference that was checked.
+async fn verify(
+    storage: &Storage,
+    version: &StoredCrateVersion,
+) -> Vec<(String, Result<(), chartered_fs::Error>)> {
+    let parsed = FileReference::from_str(&version.filesystem_object).and_then(|file_ref| {
+        let mut checksum = [0_u8; 32];
+        hex::decode_to_slice(&version.checksum, &mut checksum)
+            .map_err(|_| chartered_fs::Error::InvalidHash)?;
+        Ok((file_ref, checksum))
+    });
+
+    let (file_ref, checksum) = match parsed {
+        Ok(v) => v,
+        Err(e) => return vec![(version.filesystem_object.clone(), Err(e))],
+    };
+
+    let mut results = vec![(
+        version.filesystem_object.clone(),
+        storage.verify(&file_ref, &checksum).await,
+    )];
+
+    if let Some((replica, replica_ref)) = storage.replica_of(&file_ref) {
+        let res = replica.fs.verify(&replica_ref, &checksum).await;
+        results.push((replica_ref.to_string(), res));
+    }
+
+    results
+}
+
+/// Runs the scrub every `interval_hours` for as long as the server is running, the first scrub is
+/// started one interval after startup.
+pub fn spawn_scrub(
+    db: ConnectionPool,
+    storage: Arc<Storage>,
+    monitor: Arc<IntegrityMonitor>,
+    interval_hours: u64,
+) {
+    let interval = Duration::from_secs(interval_hours.max(1).saturating_mul(60 * 60));
+
+    tokio::spawn(async move {
+        let mut interval =
+            tokio::time::interval_at(tokio::time::Instant::now() + interval, interval);
+
+        loop {
+            interval.tick().await;
+
+            match scrub(db.clone(), &storage, &monitor).await {
+                Ok(report) => info!(
+                    "Storage scrub checked {} versions, {} missing, {} corrupt, {} errored",
+                    report.checked, report.missing, report.corrupt, report.errored
+                ),
+                Err(e) => error!("Failed to run storage scrub: {}", e),
+            }
+        }
+    });
+}
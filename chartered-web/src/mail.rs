@@ -0,0 +1,103 @@
+//! Sends email through the configured SMTP server, used for verifying email addresses and
+//! resetting forgotten passwords.
+//!
+//! If no SMTP server is configured then the mailer is disabled, and anything that relies on
+//! sending email will be turned away.
+
+use crate::config::MailConfig;
+
+use lettre::{
+    message::{header::ContentType, Mailbox},
+    AsyncSmtpTransport, AsyncTransport, Message, Tokio1Executor,
+};
+use thiserror::Error;
+
+#[derive(Error, Debug)]
+pub enum Error {
+    #[error("Failed to connect to SMTP server: {0}")]
+    Smtp(#[from] lettre::transport::smtp::Error),
+    #[error("Invalid email address: {0}")]
+    Address(#[from] lettre::address::AddressError),
+    #[error("Failed to build email: {0}")]
+    Build(#[from] lettre::error::Error),
+    #[error("Sending email is disabled")]
+    Disabled,
+}
+
+#[derive(Default)]
+pub struct Mailer {
+    inner: Option<(AsyncSmtpTransport<Tokio1Executor>, Mailbox)>,
+}
+
+impl Mailer {
+    /// Builds a mailer from the config, the connection to the server isn't made until the first
+    /// email is sent.
+    pub fn new(config: &MailConfig) -> Result<Self, Error> {
+        let transport = AsyncSmtpTransport::<Tokio1Executor>::from_url(&config.smtp_uri)?.build();
+
+        Ok(Self {
+            inner: Some((transport, config.from.parse()?)),
+        })
+    }
+
+    #[must_use]
+    pub fn is_enabled(&self) -> bool {
+        self.inner.is_some()
+    }
+
+    /// Sends a plain text email to the given address.
+    pub async fn send(&self, to: &str, subject: &str, body: String) -> Result<(), Error> {
+        let (transport, from) = self.inner.as_ref().ok_or(Error::Disabled)?;
+
+        let message = Message::builder()
+            .from(from.clone())
+            .to(to.parse()?)
+            .subject(subject)
+            .header(ContentType::TEXT_PLAIN)
+            .body(body)?;
+
+        transport.send(message).await?;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::Mailer;
+    use crate::config::MailConfig;
+
+    /// Sends through a real SMTP server, ie. the `mailhog/mailhog` container, set
+    /// `CHARTERED_TEST_SMTP_URI` (ie. `smtp://localhost:1025`) then run with
+    /// `cargo test -- --ignored`.
+    #[tokio::test]
+    #[ignore]
+    async fn send_to_sink() {
+        let mailer = Mailer::new(&MailConfig {
+            smtp_uri: std::env::var("CHARTERED_TEST_SMTP_URI").unwrap(),
+            from: "Chartered <chartered@example.com>".to_string(),
+        })
+        .unwrap();
+
+        mailer
+            .send(
+                "jordan@example.com",
+                "Test email",
+                "Hello from chartered".to_string(),
+            )
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn disabled_by_default() {
+        let mailer = Mailer::default();
+        assert!(!mailer.is_enabled());
+        assert!(matches!(
+            mailer
+                .send("jordan@example.com", "Test email", String::new())
+                .await,
+            Err(super::Error::Disabled)
+        ));
+    }
+}
@@ -2,9 +2,16 @@
 #![deny(rust_2018_idioms)]
 #![allow(clippy::module_name_repetitions)]
 
+mod advisories;
 mod config;
 mod endpoints;
+mod integrity;
+mod mail;
 mod middleware;
+mod password_policy;
+mod storage;
+mod storage_gc;
+mod storage_migration;
 
 use crate::middleware::ip::AddIp;
 use crate::middleware::rate_limit::RateLimit;
@@ -13,10 +20,10 @@ use axum::{
     routing::get,
     Extension, Router,
 };
-use clap::{crate_name, crate_version, Parser};
+use clap::{crate_name, crate_version, Parser, Subcommand};
 use governor::Quota;
 use nonzero_ext::nonzero;
-use std::{fmt::Formatter, path::PathBuf, sync::Arc};
+use std::{fmt::Formatter, path::PathBuf, sync::Arc, time::Duration};
 use thiserror::Error;
 use tower::ServiceBuilder;
 use tower_http::cors::{AllowOrigin, CorsLayer};
@@ -30,6 +37,38 @@ pub struct Opts {
     verbose: i32,
     #[clap(short, long)]
     config: PathBuf,
+    #[clap(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Subcommand)]
+pub enum Command {
+    /// Removes objects from storage that aren't referenced by any crate version, rather than
+    /// starting the server
+    GcStorage {
+        /// Only remove objects last modified longer than this many hours ago
+        #[clap(long, default_value = "24")]
+        grace_period_hours: u64,
+        /// Report orphaned objects without removing them
+        #[clap(long)]
+        dry_run: bool,
+    },
+    /// Checks every crate version in storage against its checksum, rather than starting the
+    /// server
+    ScrubStorage,
+    /// Moves crate files stored on the local disk before they were sharded into directories
+    /// into their sharded locations, rather than starting the server
+    ShardStorage,
+    /// Copies crate files from another configured storage backend to the primary backend and
+    /// points each version at its copy, rather than starting the server
+    MigrateStorage {
+        /// Name of the backend in `storage.backends` to copy crate files from
+        #[clap(long)]
+        from: String,
+        /// How many versions to load from the database at a time
+        #[clap(long, default_value = "100")]
+        batch_size: i64,
+    },
 }
 
 #[allow(clippy::unused_async)]
@@ -61,6 +100,20 @@ async fn main() -> Result<(), InitError> {
 
     let bind_address = config.bind_address;
     let pool = chartered_db::init(&config.database_uri)?;
+    let storage = Arc::new(config.create_storage().await?);
+
+    let http_client = reqwest::Client::builder()
+        .user_agent(format!("{}/{}", crate_name!(), crate_version!()))
+        .build()?;
+    let monitor = Arc::new(config.create_integrity_monitor(http_client.clone()));
+
+    if let Some(command) = opts.command {
+        return run_command(command, pool, &storage, &monitor).await;
+    }
+
+    if let Some(hours) = config.integrity.scrub_interval_hours {
+        integrity::spawn_scrub(pool.clone(), storage.clone(), monitor.clone(), hours);
+    }
 
     // the base stack of middleware that is applied to _all_ routes
     let middleware_stack = ServiceBuilder::new()
@@ -68,9 +121,6 @@ async fn main() -> Result<(), InitError> {
         .into_inner();
 
     let config = Arc::new(config);
-    let http_client = reqwest::Client::builder()
-        .user_agent(format!("{}/{}", crate_name!(), crate_version!()))
-        .build()?;
 
     let rate_limit = RateLimit::new(Quota::per_hour(nonzero!(5000_u32)));
 
@@ -126,7 +176,11 @@ async fn main() -> Result<(), InitError> {
         )
         .layer(Extension(pool))
         .layer(Extension(Arc::new(config.create_oidc_clients().await?)))
-        .layer(Extension(Arc::new(config.get_file_system().await?)))
+        .layer(Extension(storage))
+        .layer(Extension(monitor))
+        .layer(Extension(Arc::new(config.load_advisory_db()?)))
+        .layer(Extension(Arc::new(config.create_mailer()?)))
+        .layer(Extension(Arc::new(config.create_password_policy()?)))
         .layer(Extension(config.clone()))
         .layer(Extension(http_client))
         .layer(AddIp::new(config.trusted_ip_header.clone()));
@@ -141,6 +195,55 @@ async fn main() -> Result<(), InitError> {
     Ok(())
 }
 
+/// Runs one of the maintenance subcommands to completion.
+async fn run_command(
+    command: Command,
+    pool: chartered_db::ConnectionPool,
+    storage: &storage::Storage,
+    integrity_monitor: &integrity::IntegrityMonitor,
+) -> Result<(), InitError> {
+    match command {
+        Command::GcStorage {
+            grace_period_hours,
+            dry_run,
+        } => {
+            let grace_period = Duration::from_secs(grace_period_hours.saturating_mul(60 * 60));
+            let report = storage_gc::run(pool, storage, grace_period, dry_run).await?;
+
+            info!(
+                "Found {} orphaned objects out of {}, removed {} ({} bytes)",
+                report.orphans, report.objects, report.removed, report.removed_bytes
+            );
+        }
+        Command::ScrubStorage => {
+            let report = integrity::scrub(pool, storage, integrity_monitor).await?;
+
+            info!(
+                "Checked {} versions, {} missing, {} corrupt, {} errored",
+                report.checked, report.missing, report.corrupt, report.errored
+            );
+        }
+        Command::ShardStorage => {
+            let mut moved = 0;
+            for backend in storage.backends() {
+                moved += backend.fs.shard_flat_objects().await.map_err(Box::new)?;
+            }
+
+            info!("Moved {} objects into sharded directories", moved);
+        }
+        Command::MigrateStorage { from, batch_size } => {
+            let report = storage_migration::run(pool, storage, &from, batch_size).await?;
+
+            info!(
+                "Migrated {} versions, {} skipped, {} failed",
+                report.migrated, report.skipped, report.failed
+            );
+        }
+    }
+
+    Ok(())
+}
+
 #[derive(Error)]
 pub enum InitError {
     #[error("Failed to read configuration: {0}")]
@@ -157,6 +260,14 @@ pub enum InitError {
     Cors(axum::http::header::InvalidHeaderValue),
     #[error("Failed to initialise reqwest client: {0}")]
     Reqwest(#[from] reqwest::Error),
+    #[error("Failed to garbage collect storage: {0}")]
+    StorageGc(#[from] storage_gc::Error),
+    #[error("Failed to scrub storage: {0}")]
+    Scrub(#[from] integrity::Error),
+    #[error("Storage error: {0}")]
+    Storage(#[from] Box<chartered_fs::Error>),
+    #[error("Failed to migrate storage: {0}")]
+    StorageMigration(#[from] storage_migration::Error),
 }
 
 impl std::fmt::Debug for InitError {
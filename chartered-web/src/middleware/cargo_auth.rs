@@ -87,10 +87,12 @@ where
                     .unwrap());
             }
 
-            // insert both the user and the session into extensions so handlers can
-            // get their hands on them
+            // insert the user, the session and who to attribute changes to into extensions so
+            // handlers can get their hands on them
+            let actor = super::audit_actor(&req, &user);
             req.extensions_mut().insert(user);
             req.extensions_mut().insert(session);
+            req.extensions_mut().insert(actor);
 
             // calls handlers/other middleware and drives the request to response
             let response: Response<BoxBody> = inner.call(req.try_into_request().unwrap()).await?;
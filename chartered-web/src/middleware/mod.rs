@@ -3,3 +3,21 @@ pub mod ip;
 pub mod logging;
 pub mod rate_limit;
 pub mod web_auth;
+
+use axum::{extract::RequestParts, http::header};
+use chartered_db::{audit::AuditActor, users::User};
+use std::net::IpAddr;
+
+/// Builds the `AuditActor` for the authenticated user from the IP that was added by the
+/// `AddIp` middleware and the user agent the request was sent with.
+fn audit_actor<B>(req: &RequestParts<B>, user: &User) -> AuditActor {
+    AuditActor {
+        user_id: user.id,
+        ip: req.extensions().get::<IpAddr>().map(ToString::to_string),
+        user_agent: req
+            .headers()
+            .get(header::USER_AGENT)
+            .and_then(|v| v.to_str().ok())
+            .map(ToString::to_string),
+    }
+}
@@ -0,0 +1,254 @@
+//! Rules new passwords have to meet before they're accepted, on registration, when changing a
+//! password and when resetting a forgotten one.
+//!
+//! Alongside a minimum length, passwords can be required to reach a minimum [zxcvbn] strength
+//! score and can be checked against a local list of breached passwords, such as the SHA-1
+//! "ordered by hash" list from [Have I Been Pwned]. The list is binary searched on disk rather
+//! than being loaded into memory, so it must be sorted and use uppercase hashes, optionally
+//! followed by `:count` on each line.
+//!
+//! [zxcvbn]: https://github.com/dropbox/zxcvbn
+//! [Have I Been Pwned]: https://haveibeenpwned.com/Passwords
+
+use crate::config::PasswordPolicyConfig;
+
+use sha1::{Digest, Sha1};
+use std::{
+    cmp::Ordering,
+    fs::File,
+    io::{BufRead, BufReader, Seek, SeekFrom},
+    path::{Path, PathBuf},
+};
+use thiserror::Error;
+
+/// Length of a hex-encoded SHA-1 hash
+const HASH_LENGTH: usize = 40;
+
+#[derive(Error, Debug)]
+pub enum Error {
+    #[error("Failed to read breached password list at {0}: {1}")]
+    Io(PathBuf, std::io::Error),
+    #[error("Failed to check password against breached password list")]
+    TaskJoin(#[from] tokio::task::JoinError),
+    #[error("Password must be at least {0} characters long")]
+    TooShort(usize),
+    #[error("Password is too easy to guess. {0}")]
+    TooWeak(String),
+    #[error("Password has previously appeared in a data breach, please choose another")]
+    Breached,
+}
+
+impl Error {
+    pub fn status_code(&self) -> axum::http::StatusCode {
+        use axum::http::StatusCode;
+
+        match self {
+            Self::Io(..) | Self::TaskJoin(_) => StatusCode::INTERNAL_SERVER_ERROR,
+            Self::TooShort(_) | Self::TooWeak(_) | Self::Breached => StatusCode::BAD_REQUEST,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct PasswordPolicy {
+    min_length: usize,
+    min_strength: u8,
+    breached_passwords: Option<PathBuf>,
+}
+
+impl PasswordPolicy {
+    /// Builds the policy from the config, ensuring the breached password list can be read if one
+    /// was given.
+    pub fn new(config: &PasswordPolicyConfig) -> Result<Self, Error> {
+        if let Some(path) = &config.breached_passwords {
+            File::open(path).map_err(|e| Error::Io(path.clone(), e))?;
+        }
+
+        Ok(Self {
+            min_length: config.min_length,
+            min_strength: config.min_strength,
+            breached_passwords: config.breached_passwords.clone(),
+        })
+    }
+
+    /// Checks the password meets all the rules, `user_inputs` are things like the user's username
+    /// and email which make for an easy to guess password.
+    pub async fn check(&self, password: &str, user_inputs: &[&str]) -> Result<(), Error> {
+        if password.chars().count() < self.min_length {
+            return Err(Error::TooShort(self.min_length));
+        }
+
+        if self.min_strength > 0 {
+            let warning = match zxcvbn::zxcvbn(password, user_inputs) {
+                Ok(entropy) if entropy.score() >= self.min_strength => None,
+                Ok(entropy) => Some(
+                    entropy
+                        .feedback()
+                        .as_ref()
+                        .and_then(|feedback| feedback.warning())
+                        .map(|warning| warning.to_string()),
+                ),
+                Err(_) => Some(None),
+            };
+
+            if let Some(warning) = warning {
+                return Err(Error::TooWeak(
+                    warning.unwrap_or_else(|| "Try adding another word or two.".to_string()),
+                ));
+            }
+        }
+
+        if let Some(path) = self.breached_passwords.clone() {
+            let hash = hex::encode_upper(Sha1::digest(password.as_bytes()));
+
+            let breached = tokio::task::spawn_blocking(move || {
+                is_breached(&path, &hash).map_err(|e| Error::Io(path, e))
+            })
+            .await??;
+
+            if breached {
+                return Err(Error::Breached);
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Binary searches the sorted list at `path` for a line starting with `hash`.
+fn is_breached(path: &Path, hash: &str) -> std::io::Result<bool> {
+    let file = File::open(path)?;
+    let mut lo = 0;
+    let mut hi = file.metadata()?.len();
+    let mut reader = BufReader::new(file);
+    let mut line = Vec::new();
+
+    // `lo` is always the start of a line, and if the hash is in the list its line starts
+    // somewhere in `lo..hi`
+    while lo < hi {
+        let mid = lo + (hi - lo) / 2;
+
+        // find the start of the first line at or after `mid`, if `mid` is the start of a line
+        // then the byte before it is the previous line's newline
+        let start = if mid == 0 {
+            reader.seek(SeekFrom::Start(0))?;
+            0
+        } else {
+            reader.seek(SeekFrom::Start(mid - 1))?;
+            line.clear();
+            mid - 1 + reader.read_until(b'\n', &mut line)? as u64
+        };
+
+        if start >= hi {
+            hi = mid;
+            continue;
+        }
+
+        line.clear();
+        let read = reader.read_until(b'\n', &mut line)? as u64;
+
+        match line
+            .get(..HASH_LENGTH)
+            .unwrap_or(&line)
+            .cmp(hash.as_bytes())
+        {
+            Ordering::Equal => return Ok(true),
+            Ordering::Less => lo = start + read,
+            Ordering::Greater => hi = mid,
+        }
+    }
+
+    Ok(false)
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Error, PasswordPolicy};
+    use crate::config::PasswordPolicyConfig;
+    use sha1::{Digest, Sha1};
+
+    fn policy(config: PasswordPolicyConfig) -> PasswordPolicy {
+        PasswordPolicy::new(&config).unwrap()
+    }
+
+    #[tokio::test]
+    async fn min_length() {
+        let policy = policy(PasswordPolicyConfig {
+            min_length: 8,
+            ..PasswordPolicyConfig::default()
+        });
+
+        assert!(matches!(
+            policy.check("short", &[]).await,
+            Err(Error::TooShort(8))
+        ));
+        assert!(policy.check("long enough", &[]).await.is_ok());
+        // characters rather than bytes are counted
+        assert!(policy.check("éééé", &[]).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn min_strength() {
+        let policy = policy(PasswordPolicyConfig {
+            min_strength: 3,
+            ..PasswordPolicyConfig::default()
+        });
+
+        assert!(matches!(
+            policy.check("password1", &[]).await,
+            Err(Error::TooWeak(_))
+        ));
+        assert!(matches!(
+            policy.check("jordandoyle", &["jordandoyle"]).await,
+            Err(Error::TooWeak(_))
+        ));
+        assert!(policy
+            .check("correct horse battery staple", &[])
+            .await
+            .is_ok());
+    }
+
+    #[tokio::test]
+    async fn breached() {
+        let mut hashes: Vec<_> = (0..1000)
+            .map(|i| hex::encode_upper(Sha1::digest(format!("breached{}", i))))
+            .collect();
+        hashes.sort();
+
+        let path = std::env::temp_dir().join(format!(
+            "chartered-breached-passwords-{}.txt",
+            std::process::id()
+        ));
+        std::fs::write(
+            &path,
+            hashes
+                .iter()
+                .enumerate()
+                .map(|(i, hash)| format!("{}:{}\r\n", hash, i + 1))
+                .collect::<String>(),
+        )
+        .unwrap();
+
+        let policy = policy(PasswordPolicyConfig {
+            breached_passwords: Some(path.clone()),
+            ..PasswordPolicyConfig::default()
+        });
+
+        for i in 0..1000 {
+            assert!(
+                matches!(
+                    policy.check(&format!("breached{}", i), &[]).await,
+                    Err(Error::Breached)
+                ),
+                "breached{} wasn't found",
+                i
+            );
+        }
+
+        for i in 1000..1100 {
+            assert!(policy.check(&format!("breached{}", i), &[]).await.is_ok());
+        }
+
+        std::fs::remove_file(path).unwrap();
+    }
+}
@@ -0,0 +1,221 @@
+//! The file systems crate files are stored on. Any number of named backends can be configured,
+//! new crates are written to the primary backend and, if one is configured, copied to a replica
+//! so they can still be served if the primary loses them.
+//!
+//! Reads are routed to the backend a file was written to, by the name recorded in its
+//! [`FileReference`] or, for files written before backends were named, by the kind of file system
+//! it was written to.
+
+use chartered_fs::{FilePointer, FileReference, FileSystem};
+use thiserror::Error;
+use tokio::io::AsyncRead;
+use tracing::warn;
+
+#[derive(Error, Debug)]
+pub enum Error {
+    #[error("Either `storage_uri` or `storage.primary` must be set")]
+    NoBackends,
+    #[error("Storage backend `{0}` isn't configured")]
+    UnknownBackend(String),
+    #[error("The replica must be a different backend to the primary")]
+    ReplicaIsPrimary,
+}
+
+pub struct Backend {
+    /// The name the backend was configured under, or `None` for `storage_uri`
+    pub name: Option<String>,
+    pub fs: FileSystem,
+}
+
+impl Backend {
+    async fn read(
+        &self,
+        file_ref: FileReference,
+        expected_sha256: Option<&[u8; 32]>,
+    ) -> Result<FilePointer, chartered_fs::Error> {
+        match expected_sha256 {
+            Some(expected_sha256) => self.fs.read_verified(file_ref, expected_sha256).await,
+            None => self.fs.read(file_ref).await,
+        }
+    }
+}
+
+pub struct Storage {
+    backends: Vec<Backend>,
+    primary: usize,
+    replica: Option<usize>,
+}
+
+impl Storage {
+    /// Builds storage from the configured `backends`, writing to the backend named `primary`, or
+    /// the unnamed backend if not given, and replicating to the backend named `replica`.
+    pub fn new(
+        backends: Vec<Backend>,
+        primary: Option<&str>,
+        replica: Option<&str>,
+    ) -> Result<Self, Error> {
+        let position = |name: Option<&str>| {
+            backends
+                .iter()
+                .position(|backend| backend.name.as_deref() == name)
+        };
+
+        let primary = match primary {
+            Some(name) => position(Some(name)).ok_or_else(|| Error::UnknownBackend(name.into()))?,
+            None => position(None)
+                .or_else(|| (backends.len() == 1).then_some(0))
+                .ok_or(Error::NoBackends)?,
+        };
+
+        let replica = replica
+            .map(|name| position(Some(name)).ok_or_else(|| Error::UnknownBackend(name.into())))
+            .transpose()?;
+
+        if replica == Some(primary) {
+            return Err(Error::ReplicaIsPrimary);
+        }
+
+        Ok(Self {
+            backends,
+            primary,
+            replica,
+        })
+    }
+
+    /// The backend new objects are written to.
+    #[must_use]
+    pub fn primary(&self) -> &Backend {
+        &self.backends[self.primary]
+    }
+
+    #[must_use]
+    pub fn backends(&self) -> &[Backend] {
+        &self.backends
+    }
+
+    #[must_use]
+    pub fn backend(&self, name: &str) -> Option<&Backend> {
+        self.backends
+            .iter()
+            .find(|backend| backend.name.as_deref() == Some(name))
+    }
+
+    /// The backend the object referenced by `file_ref` was written to. References without a
+    /// backend name are read from the unnamed backend, then the primary, then any other backend
+    /// of the same kind.
+    pub fn route(&self, file_ref: &FileReference) -> Result<&Backend, chartered_fs::Error> {
+        let same_kind = |backend: &&Backend| backend.fs.kind() == file_ref.file_system();
+
+        let backend = match file_ref.backend() {
+            Some(name) => self.backend(name).filter(same_kind),
+            None => self
+                .backends
+                .iter()
+                .filter(same_kind)
+                .min_by_key(|backend| (backend.name.is_some(), !self.is_primary(backend))),
+        };
+
+        backend.ok_or_else(|| chartered_fs::Error::NoFileSystem(file_ref.to_string()))
+    }
+
+    fn is_primary(&self, backend: &Backend) -> bool {
+        std::ptr::eq(backend, self.primary())
+    }
+
+    /// The replica and the reference its copy of `file_ref` is stored under, unless `file_ref`
+    /// is already stored on the replica.
+    #[must_use]
+    pub fn replica_of(&self, file_ref: &FileReference) -> Option<(&Backend, FileReference)> {
+        let replica = &self.backends[self.replica?];
+
+        if matches!(self.route(file_ref), Ok(backend) if std::ptr::eq(backend, replica)) {
+            return None;
+        }
+
+        let replica_ref = FileReference::new(replica.fs.kind(), file_ref.reference().clone())
+            .with_backend(replica.name.clone());
+
+        Some((replica, replica_ref))
+    }
+
+    /// Reads the object, checking it against `expected_sha256` if given, falling back to the
+    /// replica's copy if it can't be read from the backend it was written to.
+    pub async fn read(
+        &self,
+        file_ref: FileReference,
+        expected_sha256: Option<&[u8; 32]>,
+    ) -> Result<FilePointer, chartered_fs::Error> {
+        let res = match self.route(&file_ref) {
+            Ok(backend) => backend.read(file_ref.clone(), expected_sha256).await,
+            Err(e) => Err(e),
+        };
+
+        match (res, self.replica_of(&file_ref)) {
+            (Err(e), Some((replica, replica_ref))) => {
+                warn!(
+                    "Failed to read {}, falling back to replica: {}",
+                    file_ref, e
+                );
+                replica
+                    .read(replica_ref, expected_sha256)
+                    .await
+                    .map_err(|_| e)
+            }
+            (res, _) => res,
+        }
+    }
+
+    pub async fn verify(
+        &self,
+        file_ref: &FileReference,
+        expected_sha256: &[u8; 32],
+    ) -> Result<(), chartered_fs::Error> {
+        self.route(file_ref)?
+            .fs
+            .verify(file_ref, expected_sha256)
+            .await
+    }
+
+    /// Writes the object to the primary backend, and copies it to the replica. Failing to copy
+    /// the object to the replica isn't an error, as it'll be found by the scrub.
+    pub async fn write<R: AsyncRead + Send + Unpin>(
+        &self,
+        data: R,
+    ) -> Result<FileReference, chartered_fs::Error> {
+        let primary = self.primary();
+        let file_ref = primary
+            .fs
+            .write(data)
+            .await?
+            .with_backend(primary.name.clone());
+
+        if let Some((replica, replica_ref)) = self.replica_of(&file_ref) {
+            let res = match primary.fs.fetch(&file_ref).await {
+                Ok(content) => {
+                    replica
+                        .fs
+                        .write_as(replica_ref.reference().clone(), content.reader)
+                        .await
+                }
+                Err(e) => Err(e),
+            };
+
+            if let Err(e) = res {
+                warn!("Failed to replicate {}: {}", file_ref, e);
+            }
+        }
+
+        Ok(file_ref)
+    }
+
+    /// Removes the object from the backend it was written to and from the replica.
+    pub async fn delete(&self, file_ref: &FileReference) -> Result<(), chartered_fs::Error> {
+        self.route(file_ref)?.fs.delete(file_ref).await?;
+
+        if let Some((replica, replica_ref)) = self.replica_of(file_ref) {
+            replica.fs.delete(&replica_ref).await?;
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,182 @@
+//! Finds objects in storage that aren't referenced by any crate version and removes them. These
+//! are left behind when publishing a crate fails after its tarball has already been written,
+//! such as when two users race to publish the same version.
+//!
+//! Objects last modified within the grace period are never removed, as they may belong to a
+//! publish that's still in progress. Writing a content-addressed object that already exists
+//! rewrites it, so an object being reused by a new publish is always within the grace period. Every configured backend is collected, an object is kept if
+//! any version references it on any backend, so copies on the replica are kept too.
+
+use chartered_db::{crates::CrateVersion, ConnectionPool};
+use chartered_fs::{FileReference, Reference, StoredObject};
+use std::{
+    collections::HashSet,
+    str::FromStr,
+    time::{Duration, SystemTime},
+};
+use thiserror::Error;
+use tracing::{info, warn};
+
+use crate::storage::Storage;
+
+#[derive(Error, Debug)]
+pub enum Error {
+    #[error("Failed to load crate versions: {0}")]
+    Database(#[from] chartered_db::Error),
+    #[error("Failed to access storage: {0}")]
+    File(#[from] Box<chartered_fs::Error>),
+    #[error("A crate version references `{0}`, which isn't a valid file reference")]
+    InvalidReference(String),
+}
+
+/// What was found, and removed, during a run of the garbage collector.
+#[derive(Debug, Default)]
+pub struct Report {
+    pub objects: usize,
+    pub orphans: usize,
+    pub removed: usize,
+    pub removed_bytes: u64,
+}
+
+/// Removes every object from `storage` that isn't referenced by a crate version and was last
+/// modified longer than `grace_period` ago, or just reports them if `dry_run` is set.
+pub async fn run(
+    db: ConnectionPool,
+    storage: &Storage,
+    grace_period: Duration,
+    dry_run: bool,
+) -> Result<Report, Error> {
+    let older_than = SystemTime::now()
+        .checked_sub(grace_period)
+        .unwrap_or(SystemTime::UNIX_EPOCH);
+
+    // storage is listed before the references are loaded, so an object written by a publish that
+    // completes in between the two will have its reference loaded too
+    let mut objects = Vec::new();
+    for backend in storage.backends() {
+        let listed = backend.fs.list().await.map_err(Box::new)?;
+        objects.extend(listed.into_iter().map(|object| (backend, object)));
+    }
+
+    let referenced = parse_references(CrateVersion::all_filesystem_objects(db).await?)?;
+
+    let mut report = Report {
+        objects: objects.len(),
+        ..Report::default()
+    };
+
+    for (backend, object) in find_orphans(objects, &referenced, older_than) {
+        report.orphans += 1;
+
+        if dry_run {
+            info!(
+                "Found orphaned object {} ({} bytes)",
+                object.file_ref, object.size
+            );
+            continue;
+        }
+
+        match backend.fs.delete(&object.file_ref).await {
+            Ok(()) => {
+                info!(
+                    "Removed orphaned object {} ({} bytes)",
+                    object.file_ref, object.size
+                );
+                report.removed += 1;
+                report.removed_bytes += object.size;
+            }
+            Err(e) => warn!(
+                "Failed to remove orphaned object {}: {}",
+                object.file_ref, e
+            ),
+        }
+    }
+
+    Ok(report)
+}
+
+/// Parses the file references stored against crate versions. A reference we can't parse could be
+/// pointing at any object, so rather than skipping over it nothing is safe to remove.
+fn parse_references(
+    file_refs: impl IntoIterator<Item = String>,
+) -> Result<HashSet<Reference>, Error> {
+    file_refs
+        .into_iter()
+        .map(|v| match FileReference::from_str(&v) {
+            Ok(file_ref) => Ok(file_ref.reference().clone()),
+            Err(_) => Err(Error::InvalidReference(v)),
+        })
+        .collect()
+}
+
+/// Filters `objects` down to those not in `referenced` that were last modified before
+/// `older_than`.
+fn find_orphans<T>(
+    objects: Vec<(T, StoredObject)>,
+    referenced: &HashSet<Reference>,
+    older_than: SystemTime,
+) -> Vec<(T, StoredObject)> {
+    objects
+        .into_iter()
+        .filter(|(_, object)| object.last_modified < older_than)
+        .filter(|(_, object)| !referenced.contains(object.file_ref.reference()))
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::{find_orphans, parse_references, Error};
+    use chartered_fs::{FileReference, StoredObject};
+    use std::{
+        str::FromStr,
+        time::{Duration, SystemTime},
+    };
+
+    #[test]
+    fn orphans() {
+        let now = SystemTime::now();
+        let object = |file_ref: &str, age: u64| StoredObject {
+            file_ref: FileReference::from_str(file_ref).unwrap(),
+            size: 10,
+            last_modified: now - Duration::from_secs(age),
+        };
+
+        let referenced = object("local:67e55044-10b1-426f-9247-bb680e5fe0c8", 1000);
+        let replicated = object("s3:67e55044-10b1-426f-9247-bb680e5fe0c8", 1000);
+        let orphaned = object("local:0b9d7a3c-2a8a-4d8e-9c63-6b0a9a8c4c11", 1000);
+        let in_progress = object("local:7f0f7a4e-6a55-4c7b-8f7e-1f3d2c9b8a70", 10);
+
+        let orphans = find_orphans(
+            vec![
+                ((), referenced.clone()),
+                ((), replicated),
+                ((), orphaned.clone()),
+                ((), in_progress),
+            ],
+            &[referenced.file_ref.reference().clone()]
+                .into_iter()
+                .collect(),
+            now - Duration::from_secs(100),
+        );
+
+        assert_eq!(orphans, vec![((), orphaned)]);
+    }
+
+    #[test]
+    fn invalid_reference() {
+        let references = parse_references(vec![
+            "local:67e55044-10b1-426f-9247-bb680e5fe0c8".to_string(),
+            "s3@archive:0b9d7a3c-2a8a-4d8e-9c63-6b0a9a8c4c11".to_string(),
+        ])
+        .unwrap();
+        assert_eq!(references.len(), 2);
+
+        assert!(matches!(
+            parse_references(vec![
+                "local:67e55044-10b1-426f-9247-bb680e5fe0c8".to_string(),
+                "tape:somewhere".to_string(),
+            ]),
+            Err(Error::InvalidReference(v)) if v == "tape:somewhere"
+        ));
+    }
+}
@@ -0,0 +1,393 @@
+//! Copies crate files from one storage backend to the primary backend, ie. when moving from local
+//! disk to S3, and points each version at its new copy.
+//!
+//! Versions are migrated in batches and can be migrated while the server is running, as the
+//! backend being migrated from stays configured (see [`crate::storage`]). Only versions whose file
+//! is still read from the backend being migrated from are copied so an interrupted migration can
+//! just be started again, and files are checked against the version's checksum both before and
+//! after being copied. Files are left in place on the backend being migrated from.
+
+use chartered_db::{crates::CrateVersion, ConnectionPool};
+use chartered_fs::FileReference;
+use std::str::FromStr;
+use thiserror::Error;
+use tracing::{info, warn};
+
+use crate::storage::{Backend, Storage};
+
+#[derive(Error, Debug)]
+pub enum Error {
+    #[error("Failed to load crate versions: {0}")]
+    Database(#[from] chartered_db::Error),
+    #[error("Failed to copy crate file: {0}")]
+    File(#[from] Box<chartered_fs::Error>),
+    #[error("Storage backend `{0}` isn't configured")]
+    UnknownBackend(String),
+    #[error("The backend being migrated from can't be the primary backend")]
+    FromPrimary,
+    #[error("The checksum stored for the version is invalid")]
+    InvalidChecksum,
+}
+
+impl From<chartered_fs::Error> for Error {
+    fn from(e: chartered_fs::Error) -> Self {
+        Self::File(Box::new(e))
+    }
+}
+
+/// Counts of what happened during a migration.
+#[derive(Debug, Default)]
+pub struct Report {
+    pub migrated: usize,
+    /// Versions that were changed by something else whilst being migrated
+    pub skipped: usize,
+    pub failed: usize,
+}
+
+/// Copies the file of every version stored on the backend named `from` to the primary backend,
+/// `batch_size` versions at a time.
+pub async fn run(
+    db: ConnectionPool,
+    storage: &Storage,
+    from: &str,
+    batch_size: i64,
+) -> Result<Report, Error> {
+    let from = storage
+        .backend(from)
+        .ok_or_else(|| Error::UnknownBackend(from.to_string()))?;
+
+    if std::ptr::eq(from, storage.primary()) {
+        return Err(Error::FromPrimary);
+    }
+
+    let mut report = Report::default();
+    let mut after_id = 0;
+
+    loop {
+        let batch =
+            CrateVersion::stored_on(db.clone(), from.fs.kind(), after_id, batch_size).await?;
+
+        let Some(&(last_id, ..)) = batch.last() else {
+            break;
+        };
+        after_id = last_id;
+
+        for (id, filesystem_object, checksum) in batch {
+            // other backends of the same kind may be configured, and versions that have already
+            // been migrated between two backends of the same kind are still of that kind
+            let routed = FileReference::from_str(&filesystem_object)
+                .ok()
+                .and_then(|file_ref| storage.route(&file_ref).ok());
+            if routed.is_some_and(|backend| !std::ptr::eq(backend, from)) {
+                continue;
+            }
+
+            match migrate(db.clone(), storage, from, id, &filesystem_object, &checksum).await {
+                Ok(true) => report.migrated += 1,
+                Ok(false) => report.skipped += 1,
+                Err(e) => {
+                    warn!("Failed to migrate {}: {}", filesystem_object, e);
+                    report.failed += 1;
+                }
+            }
+        }
+
+        info!(
+            "Migrated {} versions so far, {} skipped, {} failed",
+            report.migrated, report.skipped, report.failed
+        );
+    }
+
+    Ok(report)
+}
+
+/// Copies a single version's file, returning whether the version was updated to point at the
+/// copy.
+async fn migrate(
+    db: ConnectionPool,
+    storage: &Storage,
+    from: &Backend,
+    id: i32,
+    filesystem_object: &str,
+    checksum: &str,
+) -> Result<bool, Error> {
+    let file_ref = FileReference::from_str(filesystem_object)?;
+
+    let mut expected = [0_u8; 32];
+    hex::decode_to_slice(checksum, &mut expected).map_err(|_| Error::InvalidChecksum)?;
+
+    // make sure we're not copying a file that's already corrupt
+    let mut content = from.fs.fetch(&file_ref).await?;
+    content.verify(&expected).await?;
+
+    let new_ref = storage.write(content.reader).await?;
+    storage.verify(&new_ref, &expected).await?;
+
+    Ok(CrateVersion::move_filesystem_object(
+        db,
+        id,
+        filesystem_object.to_string(),
+        new_ref.to_string(),
+    )
+    .await?)
+}
+
+#[cfg(all(test, feature = "sqlite"))]
+mod test {
+    use super::{migrate, run};
+    use crate::storage::{Backend, Storage};
+    use chartered_db::{
+        audit::AuditActor,
+        crates::{Crate, CrateVersion, CrateWithPermissions},
+        organisations::Organisation,
+        users::User,
+        ConnectionPool,
+    };
+    use chartered_fs::{FileReference, FileSystem, FileSystemKind};
+    use chartered_types::cargo::{CrateFeatures, CrateVersionMetadata};
+    use sha2::{Digest, Sha256};
+    use std::{collections::BTreeMap, path::Path, str::FromStr, sync::Arc};
+
+    const CONTENT: &[u8] = b"abcdef";
+
+    async fn backend(name: &str, uri: &str) -> Backend {
+        Backend {
+            name: Some(name.to_string()),
+            fs: FileSystem::from_str(uri).await.unwrap(),
+        }
+    }
+
+    async fn local(name: &str, path: &Path) -> Backend {
+        backend(name, &format!("file://{}", path.join(name).display())).await
+    }
+
+    /// Creates a crate owned by a new user in a database stored under `path`, as each
+    /// connection to an in-memory database would get a database of its own.
+    async fn database(path: &Path) -> (ConnectionPool, AuditActor, Arc<CrateWithPermissions>) {
+        std::fs::create_dir_all(path).unwrap();
+        let db = chartered_db::init(&format!("sqlite://{}", path.join("chartered.db").display()))
+            .unwrap();
+
+        User::register(db.clone(), "owner".to_string(), String::new())
+            .await
+            .unwrap();
+        let user_id = User::find_by_username(db.clone(), "owner".to_string())
+            .await
+            .unwrap()
+            .unwrap()
+            .id;
+        let actor = AuditActor {
+            user_id,
+            ip: None,
+            user_agent: None,
+        };
+
+        Organisation::create(
+            db.clone(),
+            "org".into(),
+            String::new(),
+            false,
+            actor.clone(),
+        )
+        .await
+        .unwrap();
+        let crate_ = Crate::create(db.clone(), actor.clone(), "org".into(), "crate".into())
+            .await
+            .unwrap();
+
+        (db, actor, Arc::new(crate_))
+    }
+
+    /// Publishes `version` with `content` stored on `backend`, recording `checksum_of` as its
+    /// checksum. Returns the version's id and storage reference.
+    async fn publish(
+        db: &ConnectionPool,
+        actor: &AuditActor,
+        crate_: &Arc<CrateWithPermissions>,
+        backend: &Backend,
+        version: &str,
+        content: &[u8],
+        checksum_of: &[u8],
+    ) -> (i32, String) {
+        let file_ref = backend
+            .fs
+            .write(content)
+            .await
+            .unwrap()
+            .with_backend(backend.name.clone());
+
+        crate_
+            .clone()
+            .publish_version(
+                db.clone(),
+                actor.clone(),
+                file_ref,
+                hex::encode(Sha256::digest(checksum_of)),
+                i32::try_from(content.len()).unwrap(),
+                chartered_types::cargo::CrateVersion {
+                    name: "crate".into(),
+                    vers: version.to_string().into(),
+                    deps: Vec::new(),
+                    features: CrateFeatures(BTreeMap::new()),
+                    links: None,
+                },
+                CrateVersionMetadata {
+                    description: None,
+                    readme: None,
+                    repository: None,
+                    homepage: None,
+                    documentation: None,
+                },
+            )
+            .await
+            .unwrap();
+
+        stored(db, crate_, version).await
+    }
+
+    async fn stored(
+        db: &ConnectionPool,
+        crate_: &Arc<CrateWithPermissions>,
+        version: &str,
+    ) -> (i32, String) {
+        let version = crate_
+            .clone()
+            .version(db.clone(), version.to_string())
+            .await
+            .unwrap()
+            .unwrap();
+
+        (version.id, version.filesystem_object)
+    }
+
+    fn expected() -> [u8; 32] {
+        let mut expected = [0_u8; 32];
+        expected.copy_from_slice(&Sha256::digest(CONTENT));
+        expected
+    }
+
+    #[tokio::test]
+    async fn local_to_local() {
+        let path =
+            std::env::temp_dir().join(format!("chartered-migration-local-{}", std::process::id()));
+        let (db, actor, crate_) = database(&path).await;
+        let storage = Storage::new(
+            vec![local("old", &path).await, local("new", &path).await],
+            Some("new"),
+            None,
+        )
+        .unwrap();
+        let old = storage.backend("old").unwrap();
+
+        let (_, good) = publish(&db, &actor, &crate_, old, "0.1.0", CONTENT, CONTENT).await;
+        let (_, corrupt) = publish(&db, &actor, &crate_, old, "0.2.0", CONTENT, b"ghijkl").await;
+
+        let report = run(db.clone(), &storage, "old", 1).await.unwrap();
+        assert_eq!((report.migrated, report.skipped, report.failed), (1, 0, 1));
+
+        // the version now points at a verified copy on the primary, the original is left alone
+        let (_, migrated) = stored(&db, &crate_, "0.1.0").await;
+        let migrated = FileReference::from_str(&migrated).unwrap();
+        assert_eq!(migrated.backend(), Some("new"));
+        assert!(std::ptr::eq(
+            storage.route(&migrated).unwrap(),
+            storage.primary()
+        ));
+        storage.verify(&migrated, &expected()).await.unwrap();
+        old.fs
+            .verify(&FileReference::from_str(&good).unwrap(), &expected())
+            .await
+            .unwrap();
+
+        // files that don't match their checksum aren't copied
+        assert_eq!(stored(&db, &crate_, "0.2.0").await.1, corrupt);
+
+        // versions that have already been migrated aren't copied again
+        let report = run(db.clone(), &storage, "old", 1).await.unwrap();
+        assert_eq!((report.migrated, report.skipped, report.failed), (0, 0, 1));
+
+        std::fs::remove_dir_all(path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn concurrent_change() {
+        let path = std::env::temp_dir().join(format!(
+            "chartered-migration-concurrent-{}",
+            std::process::id()
+        ));
+        let (db, actor, crate_) = database(&path).await;
+        let storage = Storage::new(
+            vec![local("old", &path).await, local("new", &path).await],
+            Some("new"),
+            None,
+        )
+        .unwrap();
+        let old = storage.backend("old").unwrap();
+
+        let (id, original) = publish(&db, &actor, &crate_, old, "0.1.0", CONTENT, CONTENT).await;
+        let batch = CrateVersion::stored_on(db.clone(), FileSystemKind::Local, 0, 10)
+            .await
+            .unwrap();
+        assert_eq!(batch.len(), 1);
+        let (_, filesystem_object, checksum) = &batch[0];
+        assert_eq!(filesystem_object, &original);
+
+        // something else moves the version after the migration loaded it
+        let moved = old
+            .fs
+            .write(CONTENT)
+            .await
+            .unwrap()
+            .with_backend(old.name.clone())
+            .to_string();
+        assert!(CrateVersion::move_filesystem_object(
+            db.clone(),
+            id,
+            original.clone(),
+            moved.clone()
+        )
+        .await
+        .unwrap());
+
+        let updated = migrate(db.clone(), &storage, old, id, &original, checksum)
+            .await
+            .unwrap();
+        assert!(!updated);
+        assert_eq!(stored(&db, &crate_, "0.1.0").await.1, moved);
+
+        std::fs::remove_dir_all(path).unwrap();
+    }
+
+    /// Migrates to the S3-compatible store at `CHARTERED_TEST_S3_URI`, skipping the test if it
+    /// isn't set. Unlike the `chartered-fs` tests, the bucket must already exist.
+    #[tokio::test]
+    async fn local_to_s3() {
+        let Ok(uri) = std::env::var("CHARTERED_TEST_S3_URI") else {
+            return;
+        };
+
+        let path =
+            std::env::temp_dir().join(format!("chartered-migration-s3-{}", std::process::id()));
+        let (db, actor, crate_) = database(&path).await;
+        let storage = Storage::new(
+            vec![local("old", &path).await, backend("s3", &uri).await],
+            Some("s3"),
+            None,
+        )
+        .unwrap();
+        let old = storage.backend("old").unwrap();
+
+        publish(&db, &actor, &crate_, old, "0.1.0", CONTENT, CONTENT).await;
+
+        let report = run(db.clone(), &storage, "old", 10).await.unwrap();
+        assert_eq!((report.migrated, report.skipped, report.failed), (1, 0, 0));
+
+        let (_, migrated) = stored(&db, &crate_, "0.1.0").await;
+        let migrated = FileReference::from_str(&migrated).unwrap();
+        assert_eq!(migrated.file_system(), FileSystemKind::S3);
+        storage.verify(&migrated, &expected()).await.unwrap();
+        storage.delete(&migrated).await.unwrap();
+
+        std::fs::remove_dir_all(path).unwrap();
+    }
+}
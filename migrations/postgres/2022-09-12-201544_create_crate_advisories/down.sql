@@ -0,0 +1,2 @@
+DROP INDEX crate_advisories_crate_id;
+DROP TABLE crate_advisories;
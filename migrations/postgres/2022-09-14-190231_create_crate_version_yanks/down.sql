@@ -0,0 +1,2 @@
+DROP INDEX crate_version_yanks_crate_version_id;
+DROP TABLE crate_version_yanks;
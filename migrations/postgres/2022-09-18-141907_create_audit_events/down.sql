@@ -0,0 +1,3 @@
+DROP INDEX audit_events_user_id;
+DROP INDEX audit_events_organisation_id;
+DROP TABLE audit_events;
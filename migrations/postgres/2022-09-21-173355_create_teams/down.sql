@@ -0,0 +1,7 @@
+DROP INDEX team_crate_permissions_crate_id;
+DROP INDEX team_organisation_permissions_organisation_id;
+DROP INDEX team_members_user_id;
+DROP TABLE team_crate_permissions;
+DROP TABLE team_organisation_permissions;
+DROP TABLE team_members;
+DROP TABLE teams;
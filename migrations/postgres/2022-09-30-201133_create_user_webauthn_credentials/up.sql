@@ -0,0 +1,13 @@
+CREATE TABLE user_webauthn_credentials (
+    id INTEGER PRIMARY KEY GENERATED ALWAYS AS IDENTITY,
+    uuid BYTEA NOT NULL UNIQUE,
+    name VARCHAR(255) NOT NULL,
+    user_id INTEGER NOT NULL,
+    credential_id BYTEA NOT NULL UNIQUE,
+    credential TEXT NOT NULL,
+    created_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    last_used_at TIMESTAMP,
+    FOREIGN KEY (user_id) REFERENCES users (id)
+);
+
+CREATE INDEX user_webauthn_credentials_user_id ON user_webauthn_credentials(user_id);
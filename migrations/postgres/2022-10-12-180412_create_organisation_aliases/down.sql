@@ -0,0 +1,4 @@
+UPDATE team_organisation_permissions SET permissions = permissions & ~32;
+UPDATE user_organisation_permissions SET permissions = permissions & ~32;
+
+DROP TABLE organisation_aliases;
@@ -0,0 +1,13 @@
+CREATE TABLE organisation_aliases (
+    id INTEGER PRIMARY KEY GENERATED ALWAYS AS IDENTITY,
+    name VARCHAR(255) NOT NULL UNIQUE,
+    organisation_id INTEGER NOT NULL,
+    created_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    FOREIGN KEY (organisation_id) REFERENCES organisations (id)
+);
+
+CREATE INDEX organisation_aliases_organisation_id ON organisation_aliases(organisation_id);
+
+-- MANAGE_ORGANISATION is granted to everyone that could previously manage the organisation
+UPDATE user_organisation_permissions SET permissions = permissions | 32 WHERE permissions & 8 <> 0;
+UPDATE team_organisation_permissions SET permissions = permissions | 32 WHERE permissions & 8 <> 0;
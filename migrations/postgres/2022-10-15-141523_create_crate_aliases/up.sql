@@ -0,0 +1,13 @@
+CREATE TABLE crate_aliases (
+    id INTEGER PRIMARY KEY GENERATED ALWAYS AS IDENTITY,
+    crate_id INTEGER NOT NULL,
+    organisation_id INTEGER NOT NULL,
+    name VARCHAR(255) NOT NULL,
+    expires_at TIMESTAMP NOT NULL,
+    created_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    UNIQUE (organisation_id, name),
+    FOREIGN KEY (crate_id) REFERENCES crates (id),
+    FOREIGN KEY (organisation_id) REFERENCES organisations (id)
+);
+
+CREATE INDEX crate_aliases_crate_id ON crate_aliases(crate_id);
@@ -0,0 +1 @@
+ALTER TABLE user_tokens ADD COLUMN state TEXT;
@@ -0,0 +1,3 @@
+DROP INDEX crate_version_dependencies_dependency_crate_id;
+DROP INDEX crate_version_dependencies_crate_version_id;
+DROP TABLE crate_version_dependencies;
@@ -0,0 +1,41 @@
+CREATE TABLE crate_version_dependencies (
+    id INTEGER NOT NULL PRIMARY KEY AUTOINCREMENT,
+    crate_version_id INTEGER NOT NULL,
+    name VARCHAR(255) NOT NULL,
+    req VARCHAR(255) NOT NULL,
+    kind VARCHAR(255) NOT NULL,
+    optional BOOLEAN NOT NULL DEFAULT FALSE,
+    registry VARCHAR(2048),
+    dependency_crate_id INTEGER,
+    FOREIGN KEY (crate_version_id) REFERENCES crate_versions (id)
+    FOREIGN KEY (dependency_crate_id) REFERENCES crates (id)
+);
+
+CREATE INDEX crate_version_dependencies_crate_version_id ON crate_version_dependencies(crate_version_id);
+CREATE INDEX crate_version_dependencies_dependency_crate_id ON crate_version_dependencies(dependency_crate_id);
+
+-- backfill from the json blob stored against each version, dependencies without a registry
+-- belong to the same organisation as the dependent, otherwise the last path segment of the
+-- registry's index url is the organisation name
+INSERT INTO crate_version_dependencies (crate_version_id, name, req, kind, optional, registry, dependency_crate_id)
+SELECT
+    crate_versions.id,
+    COALESCE(json_extract(dep.value, '$.package'), json_extract(dep.value, '$.name')),
+    json_extract(dep.value, '$.req'),
+    json_extract(dep.value, '$.kind'),
+    json_extract(dep.value, '$.optional'),
+    json_extract(dep.value, '$.registry'),
+    (
+        SELECT dependency.id
+        FROM crates dependency
+        INNER JOIN organisations ON organisations.id = dependency.organisation_id
+        WHERE dependency.name = COALESCE(json_extract(dep.value, '$.package'), json_extract(dep.value, '$.name'))
+        AND (
+            (json_extract(dep.value, '$.registry') IS NULL AND organisations.id = crates.organisation_id)
+            OR json_extract(dep.value, '$.registry') LIKE '%/' || organisations.name
+        )
+        LIMIT 1
+    )
+FROM crate_versions
+INNER JOIN crates ON crates.id = crate_versions.crate_id,
+json_each(CAST(crate_versions.dependencies AS TEXT)) dep;
@@ -0,0 +1,17 @@
+CREATE TABLE crate_advisories (
+    id INTEGER NOT NULL PRIMARY KEY AUTOINCREMENT,
+    uuid BINARY(128) NOT NULL UNIQUE,
+    crate_id INTEGER NOT NULL,
+    kind VARCHAR(255) NOT NULL,
+    affected_versions VARCHAR(255) NOT NULL,
+    message TEXT NOT NULL,
+    user_id INTEGER NOT NULL,
+    created_at DATETIME NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    withdrawn_at DATETIME,
+    withdrawn_by_user_id INTEGER,
+    FOREIGN KEY (crate_id) REFERENCES crates (id)
+    FOREIGN KEY (user_id) REFERENCES users (id)
+    FOREIGN KEY (withdrawn_by_user_id) REFERENCES users (id)
+);
+
+CREATE INDEX crate_advisories_crate_id ON crate_advisories(crate_id);
@@ -0,0 +1,12 @@
+CREATE TABLE crate_version_yanks (
+    id INTEGER NOT NULL PRIMARY KEY AUTOINCREMENT,
+    crate_version_id INTEGER NOT NULL,
+    yanked BOOLEAN NOT NULL,
+    reason TEXT,
+    user_id INTEGER NOT NULL,
+    created_at DATETIME NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    FOREIGN KEY (crate_version_id) REFERENCES crate_versions (id)
+    FOREIGN KEY (user_id) REFERENCES users (id)
+);
+
+CREATE INDEX crate_version_yanks_crate_version_id ON crate_version_yanks(crate_version_id);
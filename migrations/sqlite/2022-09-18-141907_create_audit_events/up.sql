@@ -0,0 +1,20 @@
+CREATE TABLE audit_events (
+    id INTEGER NOT NULL PRIMARY KEY AUTOINCREMENT,
+    action VARCHAR(255) NOT NULL,
+    user_id INTEGER NOT NULL,
+    organisation_id INTEGER,
+    crate_id INTEGER,
+    target_user_id INTEGER,
+    ip VARCHAR(255),
+    user_agent VARCHAR(255),
+    state_before BLOB,
+    state_after BLOB,
+    created_at DATETIME NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    FOREIGN KEY (user_id) REFERENCES users (id)
+    FOREIGN KEY (organisation_id) REFERENCES organisations (id)
+    FOREIGN KEY (crate_id) REFERENCES crates (id)
+    FOREIGN KEY (target_user_id) REFERENCES users (id)
+);
+
+CREATE INDEX audit_events_organisation_id ON audit_events(organisation_id);
+CREATE INDEX audit_events_user_id ON audit_events(user_id);
@@ -0,0 +1,42 @@
+CREATE TABLE teams (
+    id INTEGER NOT NULL PRIMARY KEY AUTOINCREMENT,
+    uuid BINARY(128) NOT NULL UNIQUE,
+    organisation_id INTEGER NOT NULL,
+    name VARCHAR(255) NOT NULL,
+    description TEXT NOT NULL DEFAULT '',
+    UNIQUE (organisation_id, name),
+    FOREIGN KEY (organisation_id) REFERENCES organisations (id)
+);
+
+CREATE TABLE team_members (
+    id INTEGER NOT NULL PRIMARY KEY AUTOINCREMENT,
+    team_id INTEGER NOT NULL,
+    user_id INTEGER NOT NULL,
+    UNIQUE (team_id, user_id),
+    FOREIGN KEY (team_id) REFERENCES teams (id)
+    FOREIGN KEY (user_id) REFERENCES users (id)
+);
+
+CREATE TABLE team_organisation_permissions (
+    id INTEGER NOT NULL PRIMARY KEY AUTOINCREMENT,
+    team_id INTEGER NOT NULL,
+    organisation_id INTEGER NOT NULL,
+    permissions INTEGER NOT NULL DEFAULT 0,
+    UNIQUE (team_id, organisation_id),
+    FOREIGN KEY (team_id) REFERENCES teams (id)
+    FOREIGN KEY (organisation_id) REFERENCES organisations (id)
+);
+
+CREATE TABLE team_crate_permissions (
+    id INTEGER NOT NULL PRIMARY KEY AUTOINCREMENT,
+    team_id INTEGER NOT NULL,
+    crate_id INTEGER NOT NULL,
+    permissions INTEGER NOT NULL DEFAULT 0,
+    UNIQUE (team_id, crate_id),
+    FOREIGN KEY (team_id) REFERENCES teams (id)
+    FOREIGN KEY (crate_id) REFERENCES crates (id)
+);
+
+CREATE INDEX team_members_user_id ON team_members(user_id);
+CREATE INDEX team_organisation_permissions_organisation_id ON team_organisation_permissions(organisation_id);
+CREATE INDEX team_crate_permissions_crate_id ON team_crate_permissions(crate_id);
@@ -0,0 +1,2 @@
+ALTER TABLE team_members DROP COLUMN provider;
+ALTER TABLE user_organisation_permissions DROP COLUMN provider;
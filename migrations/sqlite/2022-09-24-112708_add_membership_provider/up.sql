@@ -0,0 +1,5 @@
+-- memberships granted by an OpenID Connect provider's claim mappings are tagged with the name of
+-- the provider so they can be removed again when the claim goes away, without touching any
+-- memberships that were added by hand
+ALTER TABLE user_organisation_permissions ADD COLUMN provider VARCHAR(255);
+ALTER TABLE team_members ADD COLUMN provider VARCHAR(255);
@@ -0,0 +1,19 @@
+CREATE TABLE organisation_invitations (
+    id INTEGER NOT NULL PRIMARY KEY AUTOINCREMENT,
+    uuid BLOB NOT NULL UNIQUE,
+    organisation_id INTEGER NOT NULL,
+    invited_by INTEGER NOT NULL,
+    user_id INTEGER,
+    email VARCHAR(255),
+    permissions INTEGER NOT NULL,
+    expires_at DATETIME NOT NULL,
+    created_at DATETIME NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    CHECK (user_id IS NOT NULL OR email IS NOT NULL),
+    FOREIGN KEY (organisation_id) REFERENCES organisations (id),
+    FOREIGN KEY (invited_by) REFERENCES users (id),
+    FOREIGN KEY (user_id) REFERENCES users (id)
+);
+
+CREATE INDEX organisation_invitations_organisation_id ON organisation_invitations(organisation_id);
+CREATE INDEX organisation_invitations_user_id ON organisation_invitations(user_id);
+CREATE INDEX organisation_invitations_email ON organisation_invitations(email);